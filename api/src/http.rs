@@ -19,10 +19,12 @@ use micro_http::{HttpServer, MediaType, Request, Response, StatusCode};
 use vmm_sys_util::eventfd::EventFd;
 
 use crate::http_endpoint::{
-    error_response, ApiError, ApiRequest, ApiResponse, EventsHandler, ExitHandler, FsBackendInfo,
-    HttpError, HttpResult, InfoHandler, MetricsBackendHandler, MetricsBlobcacheHandler,
-    MetricsFilesHandler, MetricsHandler, MetricsInflightHandler, MetricsPatternHandler,
-    MountHandler, SendFuseFdHandler, TakeoverHandler,
+    error_response, ApiError, ApiRequest, ApiResponse, BlobcacheEvictHandler,
+    BlobcacheExportHandler, BlobcacheGcHandler, BlobcacheHandler, BlobcacheImportHandler,
+    BlobcacheScrubHandler, EventsHandler, ExitHandler, FsBackendInfo, HttpError, HttpResult,
+    InfoHandler, MetricsBackendHandler, MetricsBlobcacheHandler, MetricsFilesHandler,
+    MetricsHandler, MetricsInflightHandler, MetricsPatternHandler, MountHandler, PrefetchHandler,
+    SendFuseFdHandler, TakeoverHandler,
 };
 
 const HTTP_ROOT: &str = "/api/v1";
@@ -67,6 +69,13 @@ lazy_static! {
         r.routes.insert(endpoint!("/daemon/fuse/sendfd"), Box::new(SendFuseFdHandler{}));
         r.routes.insert(endpoint!("/daemon/fuse/takeover"), Box::new(TakeoverHandler{}));
         r.routes.insert(endpoint!("/mount"), Box::new(MountHandler{}));
+        r.routes.insert(endpoint!("/prefetch"), Box::new(PrefetchHandler{}));
+        r.routes.insert(endpoint!("/blobcache"), Box::new(BlobcacheHandler{}));
+        r.routes.insert(endpoint!("/blobcache/evict"), Box::new(BlobcacheEvictHandler{}));
+        r.routes.insert(endpoint!("/blobcache/export"), Box::new(BlobcacheExportHandler{}));
+        r.routes.insert(endpoint!("/blobcache/gc"), Box::new(BlobcacheGcHandler{}));
+        r.routes.insert(endpoint!("/blobcache/import"), Box::new(BlobcacheImportHandler{}));
+        r.routes.insert(endpoint!("/blobcache/scrub"), Box::new(BlobcacheScrubHandler{}));
         r.routes.insert(endpoint!("/metrics"), Box::new(MetricsHandler{}));
         r.routes.insert(endpoint!("/metrics/files"), Box::new(MetricsFilesHandler{}));
         r.routes.insert(endpoint!("/metrics/pattern"), Box::new(MetricsPatternHandler{}));
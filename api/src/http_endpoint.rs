@@ -60,6 +60,8 @@ pub enum ApiError {
     DaemonAbnormal(DaemonErrorKind),
     Events(String),
     Metrics(MetricsErrorKind),
+    /// Cannot control or monitor prefetch of a rafs mount
+    PrefetchFailure(DaemonErrorKind),
 }
 pub type ApiResult<T> = std::result::Result<T, ApiError>;
 
@@ -79,6 +81,14 @@ pub enum ApiResponsePayload {
     BackendMetrics(String),
     BlobcacheMetrics(String),
     InflightMetrics(String),
+    /// Prefetch progress of a rafs mount
+    PrefetchProgress(String),
+    /// Blobs stored in blobcache of a rafs mount
+    CachedBlobs(String),
+    /// Ids of blobs removed by blobcache gc
+    GcBlobcache(String),
+    /// Number of chunks exported to or imported from a cache archive
+    CacheArchive(String),
 }
 
 /// This is the response sent by the API server through the mpsc channel.
@@ -96,10 +106,20 @@ pub enum ApiRequest {
     ExportGlobalMetrics(Option<String>),
     ExportFilesMetrics(Option<String>),
     ExportAccessPatterns(Option<String>),
+    PersistAccessTrace((Option<String>, ApiAccessTraceCmd)),
     ExportBackendMetrics(Option<String>),
     ExportBlobcacheMetrics(Option<String>),
     ExportInflightMetrics,
     ExportFsBackendInfo(String),
+    Prefetch((String, ApiPrefetchCmd)),
+    ConfigurePrefetch((String, ApiPrefetchConf)),
+    ExportPrefetchProgress(String),
+    ScrubBlobcache(String),
+    ListBlobcache(String),
+    EvictBlobcache((String, ApiBlobcacheEvictCmd)),
+    GcBlobcache,
+    ExportBlobcache((String, ApiBlobcacheArchiveCmd)),
+    ImportBlobcache((String, ApiBlobcacheArchiveCmd)),
     SendFuseFd,
     Takeover,
     Exit,
@@ -115,6 +135,44 @@ pub struct ApiMountCmd {
     pub prefetch_files: Option<Vec<String>>,
 }
 
+/// File to which access trace is persisted, it can be replayed by `fs_prefetch.trace_file`.
+#[derive(Clone, Deserialize, Debug)]
+pub struct ApiAccessTraceCmd {
+    pub path: String,
+}
+
+/// Files or directories to be prefetched, which are relative to rafs root.
+#[derive(Clone, Deserialize, Debug)]
+pub struct ApiPrefetchCmd {
+    pub files: Vec<String>,
+}
+
+/// Prefetch settings to be changed at runtime, absent ones are left untouched.
+#[derive(Clone, Deserialize, Debug)]
+pub struct ApiPrefetchConf {
+    pub paused: Option<bool>,
+    pub threads_count: Option<usize>,
+    // In unit of Bytes and Zero means no rate limit.
+    pub bandwidth_rate: Option<u32>,
+}
+
+/// Cached data to be dropped, either of a whole blob or of files or directories which are
+/// relative to rafs root.
+#[derive(Clone, Deserialize, Debug)]
+pub struct ApiBlobcacheEvictCmd {
+    pub blob_id: Option<String>,
+    pub files: Option<Vec<String>>,
+}
+
+/// Archive of blobcache chunks to seed blobcache on other hosts. Chunks of all blobs are
+/// exported if `blob_ids` is absent.
+#[derive(Clone, Deserialize, Debug)]
+pub struct ApiBlobcacheArchiveCmd {
+    pub path: String,
+    #[serde(default)]
+    pub blob_ids: Option<Vec<String>>,
+}
+
 #[derive(Clone, Deserialize, Debug)]
 pub struct ApiUmountCmd {
     pub mountpoint: String,
@@ -152,6 +210,9 @@ pub enum HttpError {
     BlobcacheMetrics(ApiError),
     BackendMetrics(ApiError),
     FsBackendInfo(ApiError),
+    Prefetch(ApiError),
+    Scrub(ApiError),
+    Blobcache(ApiError),
 }
 
 fn success_response(body: Option<String>) -> Response {
@@ -187,7 +248,9 @@ pub fn error_response(error: HttpError, status: StatusCode) -> Response {
 
 fn translate_status_code(e: &ApiError) -> StatusCode {
     match e {
-        ApiError::DaemonAbnormal(kind) | ApiError::MountFailure(kind) => match kind {
+        ApiError::DaemonAbnormal(kind)
+        | ApiError::MountFailure(kind)
+        | ApiError::PrefetchFailure(kind) => match kind {
             DaemonErrorKind::NotReady => StatusCode::ServiceUnavailable,
             DaemonErrorKind::Unsupported => StatusCode::NotImplemented,
             DaemonErrorKind::UnexpectedEvent(_) => StatusCode::BadRequest,
@@ -217,6 +280,10 @@ fn convert_to_response<O: FnOnce(ApiError) -> HttpError>(api_resp: ApiResponse,
                 BlobcacheMetrics(d) => success_response(Some(d)),
                 FsBackendInfo(d) => success_response(Some(d)),
                 InflightMetrics(d) => success_response(Some(d)),
+                PrefetchProgress(d) => success_response(Some(d)),
+                CachedBlobs(d) => success_response(Some(d)),
+                GcBlobcache(d) => success_response(Some(d)),
+                CacheArchive(d) => success_response(Some(d)),
             }
         }
         Err(e) => {
@@ -344,6 +411,12 @@ impl EndpointHandler for MetricsPatternHandler {
                 let r = kicker(ApiRequest::ExportAccessPatterns(id));
                 Ok(convert_to_response(r, HttpError::Pattern))
             }
+            (Method::Put, Some(body)) => {
+                let id = extract_query_part(req, "id");
+                let cmd = parse_body(body)?;
+                let r = kicker(ApiRequest::PersistAccessTrace((id, cmd)));
+                Ok(convert_to_response(r, HttpError::Pattern))
+            }
             _ => Err(HttpError::BadRequest),
         }
     }
@@ -475,3 +548,161 @@ impl EndpointHandler for FsBackendInfo {
         }
     }
 }
+
+pub struct PrefetchHandler {}
+impl EndpointHandler for PrefetchHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        kicker: &dyn Fn(ApiRequest) -> ApiResponse,
+    ) -> HttpResult {
+        let mountpoint = extract_query_part(req, "mountpoint").ok_or_else(|| {
+            HttpError::QueryString("'mountpoint' should be specified in query string".to_string())
+        })?;
+        match (req.method(), req.body.as_ref()) {
+            (Method::Get, None) => {
+                let r = kicker(ApiRequest::ExportPrefetchProgress(mountpoint));
+                Ok(convert_to_response(r, HttpError::Prefetch))
+            }
+            (Method::Post, Some(body)) => {
+                let cmd = parse_body(body)?;
+                let r = kicker(ApiRequest::Prefetch((mountpoint, cmd)));
+                Ok(convert_to_response(r, HttpError::Prefetch))
+            }
+            (Method::Put, Some(body)) => {
+                let conf = parse_body(body)?;
+                let r = kicker(ApiRequest::ConfigurePrefetch((mountpoint, conf)));
+                Ok(convert_to_response(r, HttpError::Prefetch))
+            }
+            _ => Err(HttpError::BadRequest),
+        }
+    }
+}
+
+pub struct BlobcacheScrubHandler {}
+impl EndpointHandler for BlobcacheScrubHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        kicker: &dyn Fn(ApiRequest) -> ApiResponse,
+    ) -> HttpResult {
+        match (req.method(), req.body.as_ref()) {
+            (Method::Put, None) => {
+                let mountpoint = extract_query_part(req, "mountpoint").ok_or_else(|| {
+                    HttpError::QueryString(
+                        "'mountpoint' should be specified in query string".to_string(),
+                    )
+                })?;
+                let r = kicker(ApiRequest::ScrubBlobcache(mountpoint));
+                Ok(convert_to_response(r, HttpError::Scrub))
+            }
+            _ => Err(HttpError::BadRequest),
+        }
+    }
+}
+
+pub struct BlobcacheHandler {}
+impl EndpointHandler for BlobcacheHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        kicker: &dyn Fn(ApiRequest) -> ApiResponse,
+    ) -> HttpResult {
+        let mountpoint = extract_query_part(req, "mountpoint").ok_or_else(|| {
+            HttpError::QueryString("'mountpoint' should be specified in query string".to_string())
+        })?;
+        match (req.method(), req.body.as_ref()) {
+            (Method::Get, None) => {
+                let r = kicker(ApiRequest::ListBlobcache(mountpoint));
+                Ok(convert_to_response(r, HttpError::Blobcache))
+            }
+            _ => Err(HttpError::BadRequest),
+        }
+    }
+}
+
+pub struct BlobcacheEvictHandler {}
+impl EndpointHandler for BlobcacheEvictHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        kicker: &dyn Fn(ApiRequest) -> ApiResponse,
+    ) -> HttpResult {
+        match (req.method(), req.body.as_ref()) {
+            (Method::Put, Some(body)) => {
+                let mountpoint = extract_query_part(req, "mountpoint").ok_or_else(|| {
+                    HttpError::QueryString(
+                        "'mountpoint' should be specified in query string".to_string(),
+                    )
+                })?;
+                let cmd = parse_body(body)?;
+                let r = kicker(ApiRequest::EvictBlobcache((mountpoint, cmd)));
+                Ok(convert_to_response(r, HttpError::Blobcache))
+            }
+            _ => Err(HttpError::BadRequest),
+        }
+    }
+}
+
+pub struct BlobcacheGcHandler {}
+impl EndpointHandler for BlobcacheGcHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        kicker: &dyn Fn(ApiRequest) -> ApiResponse,
+    ) -> HttpResult {
+        match (req.method(), req.body.as_ref()) {
+            (Method::Put, None) => {
+                let r = kicker(ApiRequest::GcBlobcache);
+                Ok(convert_to_response(r, HttpError::Blobcache))
+            }
+            _ => Err(HttpError::BadRequest),
+        }
+    }
+}
+
+pub struct BlobcacheExportHandler {}
+impl EndpointHandler for BlobcacheExportHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        kicker: &dyn Fn(ApiRequest) -> ApiResponse,
+    ) -> HttpResult {
+        match (req.method(), req.body.as_ref()) {
+            (Method::Put, Some(body)) => {
+                let mountpoint = extract_query_part(req, "mountpoint").ok_or_else(|| {
+                    HttpError::QueryString(
+                        "'mountpoint' should be specified in query string".to_string(),
+                    )
+                })?;
+                let cmd = parse_body(body)?;
+                let r = kicker(ApiRequest::ExportBlobcache((mountpoint, cmd)));
+                Ok(convert_to_response(r, HttpError::Blobcache))
+            }
+            _ => Err(HttpError::BadRequest),
+        }
+    }
+}
+
+pub struct BlobcacheImportHandler {}
+impl EndpointHandler for BlobcacheImportHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        kicker: &dyn Fn(ApiRequest) -> ApiResponse,
+    ) -> HttpResult {
+        match (req.method(), req.body.as_ref()) {
+            (Method::Put, Some(body)) => {
+                let mountpoint = extract_query_part(req, "mountpoint").ok_or_else(|| {
+                    HttpError::QueryString(
+                        "'mountpoint' should be specified in query string".to_string(),
+                    )
+                })?;
+                let cmd = parse_body(body)?;
+                let r = kicker(ApiRequest::ImportBlobcache((mountpoint, cmd)));
+                Ok(convert_to_response(r, HttpError::Blobcache))
+            }
+            _ => Err(HttpError::BadRequest),
+        }
+    }
+}
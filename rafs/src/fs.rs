@@ -7,14 +7,20 @@
 //! RAFS: a readonly FUSE file system designed for Cloud Native.
 
 use std::any::Any;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::HashSet;
 use std::convert::TryFrom;
 use std::ffi::{CStr, OsStr};
 use std::fmt;
 use std::io::Result;
 use std::os::unix::ffi::OsStrExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
 use std::time::Duration;
 
 use nix::unistd::{getegid, geteuid};
@@ -24,13 +30,18 @@ use std::time::SystemTime;
 use fuse_rs::abi::linux_abi::Attr;
 use fuse_rs::api::filesystem::*;
 use fuse_rs::api::BackendFileSystem;
+use fuse_rs::transport::FileReadWriteVolatile;
+use vm_memory::{Bytes, VolatileSlice};
 
-use crate::metadata::{Inode, RafsInode, RafsSuper, RAFS_DEFAULT_BLOCK_SIZE};
+use crate::metadata::{Inode, RafsInode, RafsSuper, RAFS_DEFAULT_BLOCK_SIZE, RAFS_MAX_BLOCK_SIZE};
 use crate::*;
-use nydus_utils::metrics::{self, FopRecorder, StatsFop::*};
+use nydus_utils::metrics::{self, AccessTrace, FopRecorder, StatsFop::*};
 use storage::device::BlobPrefetchControl;
 use storage::*;
-use storage::{cache::PrefetchWorker, device};
+use storage::{
+    cache::{CachedBlobInfo, PrefetchPolicy, PrefetchProgress, PrefetchWorker},
+    device,
+};
 
 /// Type of RAFS fuse handle.
 pub type Handle = u64;
@@ -43,6 +54,11 @@ pub const RAFS_DEFAULT_ENTRY_TIMEOUT: u64 = RAFS_DEFAULT_ATTR_TIMEOUT;
 const DOT: &str = ".";
 const DOTDOT: &str = "..";
 
+/// Directories waiting for the locality prefetch thread, more are left to later reads.
+const LOCALITY_QUEUE_DEPTH: usize = 64;
+/// Ranges waiting for the readahead thread, more are dropped.
+const READAHEAD_QUEUE_DEPTH: usize = 64;
+
 fn default_threads_count() -> usize {
     8
 }
@@ -51,6 +67,18 @@ fn default_merging_size() -> usize {
     128 * 1024
 }
 
+fn default_directory_depth() -> u32 {
+    1
+}
+
+fn default_directory_size_limit() -> u64 {
+    4 * RAFS_DEFAULT_BLOCK_SIZE
+}
+
+fn default_readahead_budget() -> u64 {
+    32 * RAFS_DEFAULT_BLOCK_SIZE
+}
+
 #[derive(Clone, Default, Deserialize)]
 pub struct FsPrefetchControl {
     #[serde(default)]
@@ -68,6 +96,34 @@ pub struct FsPrefetchControl {
     //                        Please note that if the value is less than Rafs chunk size,
     //                        it will be raised to the chunk size.
     bandwidth_rate: u32,
+    #[serde(default)]
+    // Prefetch policy triggered by user reads, besides the hinted prefetch.
+    // none      -- only prefetch hinted files once rafs is imported
+    // directory -- prefetch files of the same directory once one of them is read
+    policy: PrefetchPolicy,
+    #[serde(default = "default_directory_depth")]
+    // Levels of sub-directories to walk for `directory` policy, 1 means only
+    // files directly residing in the directory are prefetched.
+    directory_depth: u32,
+    #[serde(default = "default_directory_size_limit")]
+    // In unit of Bytes. Upper limit of data prefetched per directory for `directory` policy.
+    directory_size_limit: u64,
+    #[serde(default)]
+    // In unit of milli-seconds. Ordinary prefetch requests are held back while the average
+    // backend latency of user IO exceeds this value, so that prefetch yields to user IO.
+    // pause_latency_ms == 0 -- prefetch never pauses.
+    pause_latency_ms: u64,
+    #[serde(default)]
+    // Access trace persisted by a previous run through API. Files in it are prefetched in
+    // order of their first access, after the hinted ones.
+    trace_file: String,
+    #[serde(default)]
+    // In unit of Bytes. Upper limit of readahead window of files read sequentially, whose
+    // data is read ahead through prefetch workers. Zero disables readahead.
+    readahead_max_window: u64,
+    #[serde(default = "default_readahead_budget")]
+    // In unit of Bytes. Upper limit of data read ahead but not yet read of all files.
+    readahead_budget: u64,
 }
 
 /// Not everything can be safely exported from configuration.
@@ -136,7 +192,18 @@ pub struct Rafs {
     device: device::RafsDevice,
     pub sb: Arc<RafsSuper>,
     digest_validate: bool,
-    fs_prefetch: bool,
+    fs_prefetch: FsPrefetchControl,
+    // Directories of files being read are handed to the locality prefetch thread,
+    // skipping the directory handed last time.
+    locality_last: AtomicU64,
+    locality_tx: Option<mpsc::SyncSender<Inode>>,
+    locality_worker: Option<thread::JoinHandle<()>>,
+    // Ranges of files read sequentially are handed to the readahead thread.
+    readahead_tx: Option<mpsc::SyncSender<(Inode, u64, u64)>>,
+    readahead_worker: Option<thread::JoinHandle<()>>,
+    // Whether prefetch is ever requested through API, prefetch workers are kept working
+    // until rafs is destroyed then.
+    prefetch_requested: Arc<Mutex<bool>>,
     initialized: bool,
     xattr_enabled: bool,
     ios: Arc<metrics::GlobalIOStats>,
@@ -146,20 +213,37 @@ pub struct Rafs {
     i_time: u64,
 }
 
+fn storage_error(e: StorageError) -> RafsError {
+    match e {
+        StorageError::Unsupported => RafsError::Unsupported,
+        StorageError::Io(e) => RafsError::Storage(e),
+    }
+}
+
 impl TryFrom<&RafsConfig> for PrefetchWorker {
     type Error = RafsError;
     fn try_from(c: &RafsConfig) -> RafsResult<Self> {
-        if c.fs_prefetch.merging_size as u64 > RAFS_DEFAULT_BLOCK_SIZE {
+        if c.fs_prefetch.merging_size as u64 > RAFS_MAX_BLOCK_SIZE {
             return Err(RafsError::Configure(
-                "Merging size can't exceed chunk size".to_string(),
+                "Merging size can't exceed max chunk size".to_string(),
             ));
         }
+        if !c.fs_prefetch.enable && c.fs_prefetch.policy != PrefetchPolicy::None {
+            warn!(
+                "Prefetch policy {:?} is ignored as prefetch is not enabled",
+                c.fs_prefetch.policy
+            );
+        }
 
         Ok(PrefetchWorker {
             enable: c.fs_prefetch.enable,
             threads_count: c.fs_prefetch.threads_count,
             merging_size: c.fs_prefetch.merging_size,
             bandwidth_rate: c.fs_prefetch.bandwidth_rate,
+            policy: c.fs_prefetch.policy,
+            pause_latency_ms: c.fs_prefetch.pause_latency_ms,
+            readahead_max_window: c.fs_prefetch.readahead_max_window,
+            readahead_budget: c.fs_prefetch.readahead_budget,
         })
     }
 }
@@ -174,6 +258,17 @@ impl Rafs {
         let mut sb = RafsSuper::new(&conf).map_err(RafsError::FillSuperblock)?;
         sb.load(r).map_err(RafsError::FillSuperblock)?;
 
+        // Prefetch limiter bursts a chunk, so merged requests are capped to chunk size of image.
+        device_conf.cache.chunk_size = sb.meta.block_size;
+        let worker = &mut device_conf.cache.prefetch_worker;
+        if worker.merging_size > sb.meta.block_size as usize {
+            warn!(
+                "Prefetch merging size {} is capped to chunk size {} of the image",
+                worker.merging_size, sb.meta.block_size
+            );
+            worker.merging_size = sb.meta.block_size as usize;
+        }
+
         let rafs = Rafs {
             id: id.to_string(),
             device: device::RafsDevice::new(
@@ -187,7 +282,13 @@ impl Rafs {
             initialized: false,
             ios: metrics::new(id),
             digest_validate: conf.digest_validate,
-            fs_prefetch: conf.fs_prefetch.enable,
+            fs_prefetch: conf.fs_prefetch.clone(),
+            locality_last: AtomicU64::new(0),
+            locality_tx: None,
+            locality_worker: None,
+            readahead_tx: None,
+            readahead_worker: None,
+            prefetch_requested: Arc::new(Mutex::new(false)),
             xattr_enabled: conf.enable_xattr,
             i_uid: geteuid().into(),
             i_gid: getegid().into(),
@@ -221,9 +322,11 @@ impl Rafs {
         info!("update sb is successful");
 
         // step 2: update device (only localfs is supported)
+        let mut device_conf = conf.device;
+        device_conf.cache.chunk_size = self.sb.meta.block_size;
         self.device
             .update(
-                conf.device,
+                device_conf,
                 self.sb.meta.get_compressor(),
                 self.sb.meta.get_digester(),
                 self.id.as_str(),
@@ -254,6 +357,9 @@ impl Rafs {
                 blob_id: b.blob_id.clone(),
                 offset: b.readahead_offset,
                 len: b.readahead_size,
+                dict_offset: b.dict_offset,
+                dict_size: b.dict_size,
+                key_id: b.key_id.clone(),
             })
             .collect::<Vec<BlobPrefetchControl>>();
 
@@ -262,9 +368,13 @@ impl Rafs {
             .map_err(RafsError::CreateDevice)?;
 
         // Device should be ready before any prefetch.
-        if self.fs_prefetch {
+        if self.fs_prefetch.enable {
+            let prefetch_files = self.append_access_trace(prefetch_files);
             let sb = self.sb.clone();
             let device = self.device.clone();
+            let on_demand =
+                self.fs_prefetch.policy != PrefetchPolicy::None || self.device.readahead_enabled();
+            let requested = self.prefetch_requested.clone();
 
             let _ = std::thread::spawn(move || {
                 let mut reader = r;
@@ -294,24 +404,76 @@ impl Rafs {
                     info!("No file to be prefetched {:?}", e);
                 });
 
-                // For now, we only have hinted prefetch. So stopping prefetch workers once
-                // it's done is Okay. But if we involve more policies someday, we have to be
-                // careful when to stop prefetch progresses.
-                device
-                    .stop_prefetch()
-                    .unwrap_or_else(|_| error!("Failed in stopping prefetch workers"));
+                // Prefetch workers are still needed by policies triggered from user IO or
+                // requests from API, they are stopped when rafs is destroyed in that case.
+                // Hold the lock so that prefetch is not stopped just after API starts it.
+                let requested = requested.lock().unwrap();
+                if !on_demand && !*requested {
+                    device
+                        .stop_prefetch()
+                        .unwrap_or_else(|_| error!("Failed in stopping prefetch workers"));
+                }
             });
+
+            if self.fs_prefetch.policy == PrefetchPolicy::Directory {
+                self.start_locality_prefetch()?;
+            }
+            if self.device.readahead_enabled() {
+                self.start_readahead()?;
+            }
         }
 
         self.initialized = true;
         Ok(())
     }
 
+    /// Replay access trace of a previous run by appending files in it to prefetch list.
+    fn append_access_trace(&self, files: Option<Vec<PathBuf>>) -> Option<Vec<PathBuf>> {
+        let path = &self.fs_prefetch.trace_file;
+        if path.is_empty() {
+            return files;
+        }
+
+        match AccessTrace::load(Path::new(path)) {
+            Ok(trace) => {
+                info!(
+                    "Replay {} files from access trace {}",
+                    trace.files.len(),
+                    path
+                );
+                let mut files = files.unwrap_or_default();
+                files.extend(trace.files);
+                Some(files)
+            }
+            Err(e) => {
+                warn!("Failed to load access trace {}, {}", path, e);
+                files
+            }
+        }
+    }
+
     /// umount a previously mounted rafs virtual path
     pub fn destroy(&mut self) -> Result<()> {
         info! {"Destroy rafs"}
 
         if self.initialized {
+            let mut stop_prefetch = *self.prefetch_requested.lock().unwrap();
+            if let Some(worker) = self.locality_worker.take() {
+                // Closing the channel makes locality prefetch thread exit.
+                self.locality_tx.take();
+                let _ = worker.join();
+                stop_prefetch = true;
+            }
+            if let Some(worker) = self.readahead_worker.take() {
+                self.readahead_tx.take();
+                let _ = worker.join();
+                stop_prefetch = true;
+            }
+            if stop_prefetch {
+                self.device
+                    .stop_prefetch()
+                    .unwrap_or_else(|_| error!("Failed in stopping prefetch workers"));
+            }
             Arc::get_mut(&mut self.sb)
                 .expect("Superblock is no longer used")
                 .destroy();
@@ -322,6 +484,283 @@ impl Rafs {
         Ok(())
     }
 
+    /// Prefetch files or directories, which are relative to rafs root, at any time after
+    /// rafs is imported. Prefetch workers are started even if prefetch is not enabled by
+    /// configuration.
+    pub fn prefetch_files(&self, files: &[PathBuf]) -> RafsResult<()> {
+        if !self.initialized {
+            return Err(RafsError::Uninitialized);
+        }
+
+        let mut inodes = Vec::with_capacity(files.len());
+        for f in files {
+            let ino = self
+                .sb
+                .ino_from_path(f.as_path())
+                .map_err(|e| RafsError::Prefetch(format!("{:?}: {}", f, e)))?;
+            inodes.push(ino);
+        }
+
+        {
+            let mut requested = self.prefetch_requested.lock().unwrap();
+            self.device.start_prefetch().map_err(storage_error)?;
+            *requested = true;
+        }
+
+        // Issuing prefetch requests may be throttled by bandwidth limiter for long, so do it
+        // in a separate thread. But collect bio descs here, superblock must not be referred
+        // from a detached thread as it is destroyed along with rafs.
+        let descs = self.collect_bio_descs(&inodes)?;
+        let device = self.device.clone();
+        thread::Builder::new()
+            .name("api_prefetch".to_string())
+            .spawn(move || {
+                for mut desc in descs {
+                    device.prefetch(&mut desc).unwrap_or_else(|e| {
+                        warn!("Prefetch error, {:?}", e);
+                        0
+                    });
+                }
+            })
+            .map_err(|e| RafsError::Prefetch(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Hold back or release prefetch requests.
+    pub fn pause_prefetch(&self, paused: bool) -> RafsResult<()> {
+        self.device.pause_prefetch(paused).map_err(storage_error)
+    }
+
+    /// Change prefetch threads count or bandwidth limit, `None` keeps the current one.
+    pub fn tune_prefetch(
+        &self,
+        threads_count: Option<usize>,
+        bandwidth_rate: Option<u32>,
+    ) -> RafsResult<()> {
+        if threads_count == Some(0) {
+            return Err(RafsError::Configure(
+                "Prefetch threads count can't be zero".to_string(),
+            ));
+        }
+
+        self.device
+            .tune_prefetch(threads_count, bandwidth_rate)
+            .map_err(storage_error)
+    }
+
+    pub fn prefetch_progress(&self) -> RafsResult<PrefetchProgress> {
+        self.device.prefetch_progress().map_err(storage_error)
+    }
+
+    /// Start verifying cached data in background, progress is reported by blobcache metrics.
+    pub fn scrub_cache(&self) -> RafsResult<()> {
+        let bios = self.collect_blob_bios(&[])?;
+        self.device.scrub(bios).map_err(storage_error)
+    }
+
+    /// Ids of blobs referred by rafs.
+    pub fn blob_ids(&self) -> Vec<String> {
+        self.sb
+            .inodes
+            .get_blobs()
+            .into_iter()
+            .map(|b| b.blob_id)
+            .collect()
+    }
+
+    pub fn list_cached_blobs(&self) -> RafsResult<Vec<CachedBlobInfo>> {
+        self.device.list_blobs().map_err(storage_error)
+    }
+
+    pub fn evict_cached_blob(&self, blob_id: &str) -> RafsResult<()> {
+        self.device.evict_blob(blob_id).map_err(storage_error)
+    }
+
+    /// Drop cached data of files or directories, which are relative to rafs root.
+    pub fn evict_cached_files(&self, files: &[PathBuf]) -> RafsResult<()> {
+        if !self.initialized {
+            return Err(RafsError::Uninitialized);
+        }
+
+        let mut inodes = Vec::with_capacity(files.len());
+        for f in files {
+            inodes.push(self.sb.ino_from_path(f.as_path()).map_err(|e| {
+                RafsError::Storage(enoent!(format!("{:?} is not found, {}", f, e)))
+            })?);
+        }
+
+        for desc in self.collect_bio_descs(&inodes)? {
+            self.device.evict_chunks(&desc).map_err(storage_error)?;
+        }
+
+        Ok(())
+    }
+
+    /// Export cached chunks of blobs `blob_ids`, or of all blobs if it's empty, to an archive
+    /// at `path`. Returning number of exported chunks.
+    pub fn export_cache(&self, blob_ids: &[String], path: &Path) -> RafsResult<usize> {
+        let bios = self.collect_blob_bios(blob_ids)?;
+        self.device
+            .export_chunks(&bios, path)
+            .map_err(storage_error)
+    }
+
+    /// Import chunks of rafs from an archive exported by rafs of the same image. Returning
+    /// number of imported chunks.
+    pub fn import_cache(&self, path: &Path) -> RafsResult<usize> {
+        let bios = self.collect_blob_bios(&[])?;
+        self.device
+            .import_chunks(&bios, path)
+            .map_err(storage_error)
+    }
+
+    /// Bios of all chunks in blobs `blob_ids`, or in all blobs if it's empty.
+    fn collect_blob_bios(&self, blob_ids: &[String]) -> RafsResult<Vec<device::RafsBio>> {
+        if !self.initialized {
+            return Err(RafsError::Uninitialized);
+        }
+
+        Ok(self
+            .collect_bio_descs(&[ROOT_ID])?
+            .into_iter()
+            .flat_map(|desc| desc.bi_vec)
+            .filter(|bio| blob_ids.is_empty() || blob_ids.contains(&bio.blob_id))
+            .collect())
+    }
+
+    /// Bio descs of files or directories `inodes`.
+    fn collect_bio_descs(&self, inodes: &[Inode]) -> RafsResult<Vec<device::RafsBioDesc>> {
+        let descs = RefCell::new(Vec::new());
+        self.sb.prefetch_files(inodes, &|desc| {
+            descs.borrow_mut().push(std::mem::take(desc));
+        })?;
+        Ok(descs.into_inner())
+    }
+
+    /// Remove cached blobs neither referred by `referenced` nor by rafs itself.
+    pub fn gc_cache(&self, referenced: &HashSet<String>) -> RafsResult<Vec<String>> {
+        let mut referenced = referenced.clone();
+        referenced.extend(self.blob_ids());
+        self.device.gc(&referenced).map_err(storage_error)
+    }
+
+    /// Start a thread prefetching files of the directory that a read file belongs to.
+    /// Chunks already in cache are skipped and requests go through the prefetch workers,
+    /// so they are throttled by the prefetch bandwidth limiter.
+    fn start_locality_prefetch(&mut self) -> RafsResult<()> {
+        let (tx, rx) = mpsc::sync_channel::<Inode>(LOCALITY_QUEUE_DEPTH);
+        let sb = self.sb.clone();
+        let device = self.device.clone();
+        let depth = self.fs_prefetch.directory_depth;
+        let size_limit = self.fs_prefetch.directory_size_limit;
+
+        let worker = thread::Builder::new()
+            .name("locality_prefetch".to_string())
+            .spawn(move || {
+                // Each directory is handled only once during the rafs life.
+                let mut handled = HashSet::new();
+                while let Ok(dir) = rx.recv() {
+                    if !handled.insert(dir) {
+                        continue;
+                    }
+                    match sb.build_locality_desc(dir, depth, size_limit, &|c| device.has(c)) {
+                        Ok(mut desc) => {
+                            if desc.bi_vec.is_empty() {
+                                continue;
+                            }
+                            trace!("locality prefetch dir {} size {}", dir, desc.bi_size);
+                            device.prefetch(&mut desc).unwrap_or_else(|e| {
+                                warn!("Prefetch error, {:?}", e);
+                                0
+                            });
+                        }
+                        Err(e) => warn!("Failed to prefetch directory {}, {}", dir, e),
+                    }
+                }
+                info!("Locality prefetch thread exits.")
+            })
+            .map_err(|e| RafsError::Prefetch(e.to_string()))?;
+
+        self.locality_tx = Some(tx);
+        self.locality_worker = Some(worker);
+
+        Ok(())
+    }
+
+    /// Hand the directory of a file being read to the locality prefetch thread. It's called
+    /// on every read, so user IO never blocks on the thread, the directory is left to later
+    /// reads if the thread falls behind.
+    fn kick_locality_prefetch(&self, tx: &mpsc::SyncSender<Inode>, parent: Inode) {
+        if self.locality_last.swap(parent, Ordering::Relaxed) == parent {
+            return;
+        }
+        match tx.try_send(parent) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => self.locality_last.store(0, Ordering::Relaxed),
+            Err(e) => warn!("Failed to kick locality prefetch, {}", e),
+        }
+    }
+
+    /// Start a thread reading ahead files read sequentially. Chunks already in cache are
+    /// skipped and requests go through the prefetch workers, like locality prefetch.
+    fn start_readahead(&mut self) -> RafsResult<()> {
+        let (tx, rx) = mpsc::sync_channel::<(Inode, u64, u64)>(READAHEAD_QUEUE_DEPTH);
+        let sb = self.sb.clone();
+        let device = self.device.clone();
+
+        let worker = thread::Builder::new()
+            .name("readahead".to_string())
+            .spawn(move || {
+                while let Ok((ino, offset, size)) = rx.recv() {
+                    match sb
+                        .get_inode(ino, false)
+                        .and_then(|inode| inode.alloc_bio_desc(offset, size as usize))
+                    {
+                        Ok(mut desc) => {
+                            desc.bi_vec
+                                .retain(|bio| !device.has(bio.chunkinfo.as_ref()));
+                            if desc.bi_vec.is_empty() {
+                                continue;
+                            }
+                            trace!("readahead inode {} offset {} size {}", ino, offset, size);
+                            device.prefetch(&mut desc).unwrap_or_else(|e| {
+                                warn!("Readahead error, {:?}", e);
+                                0
+                            });
+                        }
+                        Err(e) => warn!("Failed to read ahead inode {}, {}", ino, e),
+                    }
+                }
+                info!("Readahead thread exits.")
+            })
+            .map_err(|e| RafsError::Prefetch(e.to_string()))?;
+
+        self.readahead_tx = Some(tx);
+        self.readahead_worker = Some(worker);
+
+        Ok(())
+    }
+
+    /// Hand the range to read ahead to the readahead thread, if the file is read
+    /// sequentially. The range is dropped rather than blocking user IO if the thread falls
+    /// behind, the data is read on demand then.
+    fn kick_readahead(
+        &self,
+        tx: &mpsc::SyncSender<(Inode, u64, u64)>,
+        ino: Inode,
+        offset: u64,
+        size: usize,
+        file_size: u64,
+    ) {
+        if let Some((start, len)) = self.device.readahead(ino, offset, size, file_size) {
+            match tx.try_send((ino, start, len)) {
+                Ok(()) | Err(TrySendError::Full(_)) => {}
+                Err(e) => warn!("Failed to kick readahead, {}", e),
+            }
+        }
+    }
+
     fn xattr_supported(&self) -> bool {
         self.xattr_enabled || self.sb.meta.has_xattr()
     }
@@ -438,6 +877,45 @@ impl Rafs {
     }
 }
 
+/// File data inline in bootstrap, to be copied into fuse buffers.
+struct InlineData<'a>(&'a [u8]);
+
+impl FileReadWriteVolatile for InlineData<'_> {
+    fn read_volatile(&mut self, slice: VolatileSlice) -> Result<usize> {
+        self.read_at_volatile(slice, 0)
+    }
+
+    fn write_volatile(&mut self, _slice: VolatileSlice) -> Result<usize> {
+        Err(ebadf!("inline data is read only"))
+    }
+
+    fn read_at_volatile(&mut self, slice: VolatileSlice, offset: u64) -> Result<usize> {
+        self.read_vectored_at_volatile(&[slice], offset)
+    }
+
+    fn read_vectored_at_volatile(&mut self, bufs: &[VolatileSlice], offset: u64) -> Result<usize> {
+        let mut data = self.0.get(offset as usize..).unwrap_or(&[]);
+        let mut count = 0;
+
+        for buf in bufs {
+            if data.is_empty() {
+                break;
+            }
+            let size = cmp::min(buf.len(), data.len());
+            buf.write_slice(&data[..size], 0)
+                .map_err(|_| eio!("failed to copy inline data"))?;
+            data = &data[size..];
+            count += size;
+        }
+
+        Ok(count)
+    }
+
+    fn write_at_volatile(&mut self, _slice: VolatileSlice, _offset: u64) -> Result<usize> {
+        Err(ebadf!("inline data is read only"))
+    }
+}
+
 impl BackendFileSystem for Rafs {
     fn mount(&self) -> Result<(Entry, u64)> {
         let root_inode = self.sb.get_inode(ROOT_ID, self.digest_validate)?;
@@ -559,13 +1037,33 @@ impl FileSystem for Rafs {
             recorder.mark_success(0);
             return Ok(0);
         }
+        // Data inline in bootstrap is already in memory, device is not involved.
+        if inode.has_inline_data() {
+            let data = inode.get_inline_data()?;
+            let start = offset as usize;
+            let end = cmp::min(start + size as usize, data.len());
+            let r = w.write_from(&mut InlineData(&data[start..end]), end - start, 0);
+            if let Ok(count) = r {
+                recorder.mark_success(count);
+            }
+            return r;
+        }
         let desc = inode.alloc_bio_desc(offset, size as usize)?;
+        // FUSE read carries no path, so a hardlinked file is taken as residing in the
+        // directory recorded as its parent, which is where its first link is found in
+        // bootstrap, no matter which link it is opened through.
+        if let Some(tx) = self.locality_tx.as_ref() {
+            self.kick_locality_prefetch(tx, inode.parent());
+        }
         let start = self.ios.latency_start();
         let r = self.device.read_to(w, desc).map(|r| {
             recorder.mark_success(r);
             r
         });
         self.ios.latency_end(&start, Read);
+        if let (Ok(count), Some(tx)) = (r.as_ref(), self.readahead_tx.as_ref()) {
+            self.kick_readahead(tx, ino, offset, *count, inode.size());
+        }
         r
     }
 
@@ -777,6 +1275,35 @@ mod tests {
         Box::new(rafs)
     }
 
+    #[test]
+    fn it_should_parse_prefetch_policy() {
+        let config = r#"
+        {
+            "device": {
+              "backend": {
+                "type": "localfs",
+                "config": {
+                  "dir": "/tmp"
+                }
+              }
+            },
+            "mode": "direct",
+            "fs_prefetch": {
+              "enable": true,
+              "policy": "directory",
+              "directory_depth": 2
+            }
+          }"#;
+        let rafs_config = RafsConfig::from_str(config).unwrap();
+        let worker: PrefetchWorker = TryFrom::try_from(&rafs_config).unwrap();
+        assert_eq!(worker.policy, PrefetchPolicy::Directory);
+        assert_eq!(rafs_config.fs_prefetch.directory_depth, 2);
+        assert_eq!(
+            rafs_config.fs_prefetch.directory_size_limit,
+            4 * RAFS_DEFAULT_BLOCK_SIZE
+        );
+    }
+
     #[test]
     fn it_should_create_new_rafs_fs() {
         let rafs = new_rafs_backend();
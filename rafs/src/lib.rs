@@ -36,6 +36,7 @@ pub enum RafsError {
     CreateDevice(Error),
     Prefetch(String),
     Configure(String),
+    Storage(Error),
 }
 
 pub type RafsResult<T> = std::result::Result<T, RafsError>;
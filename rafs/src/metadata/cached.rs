@@ -169,6 +169,7 @@ pub struct CachedInode {
     i_blksize: u32,
     i_rdev: u32,
     i_target: OsString, // for symbol link
+    i_inline_data: Vec<u8>,
     i_xattr: HashMap<OsString, Vec<u8>>,
     i_data: Vec<Arc<CachedChunkInfo>>,
     i_child: Vec<Arc<CachedInode>>,
@@ -219,6 +220,19 @@ impl CachedInode {
         Ok(())
     }
 
+    fn load_inline_data(&mut self, r: &mut RafsIoReader) -> Result<()> {
+        if self.has_inline_data() {
+            if self.i_size > RAFS_MAX_INLINE_SIZE {
+                return Err(einval!("invalid inline data size"));
+            }
+            let mut data = vec![0u8; self.i_size as usize];
+            r.read_exact(data.as_mut_slice())?;
+            self.i_inline_data = data;
+            r.try_seek_aligned(self.i_size as usize);
+        }
+        Ok(())
+    }
+
     fn load_chunk_info(&mut self, r: &mut RafsIoReader) -> Result<()> {
         if self.is_reg() && self.i_child_cnt > 0 {
             let mut chunk = OndiskChunkInfo::new();
@@ -235,12 +249,13 @@ impl CachedInode {
         let mut inode = OndiskInode::new();
 
         // parse ondisk inode
-        // OndiskInode|name|symbol|xattr|chunks
+        // OndiskInode|name|symbol|xattr|inline data or chunks
         r.read_exact(inode.as_mut())?;
         self.copy_from_ondisk(&inode);
         self.load_name(inode.i_name_size as usize, r)?;
         self.load_symlink(inode.i_symlink_size as usize, r)?;
         self.load_xattr(r)?;
+        self.load_inline_data(r)?;
         self.load_chunk_info(r)?;
         self.i_blksize = sb.block_size;
         self.validate()?;
@@ -280,6 +295,9 @@ impl RafsInode for CachedInode {
         if self.is_symlink() && self.i_target.is_empty() {
             return Err(einval!("invalid inode"));
         }
+        if self.has_inline_data() && (!self.is_reg() || self.i_child_cnt != 0) {
+            return Err(einval!("invalid inode"));
+        }
         Ok(())
     }
 
@@ -295,6 +313,14 @@ impl RafsInode for CachedInode {
         }
     }
 
+    fn get_inline_data(&self) -> Result<Vec<u8>> {
+        if !self.has_inline_data() {
+            Err(einval!("inode has no inline data"))
+        } else {
+            Ok(self.i_inline_data.clone())
+        }
+    }
+
     fn get_child_by_name(&self, name: &OsStr) -> Result<Arc<dyn RafsInode>> {
         let idx = self
             .i_child
@@ -399,6 +425,14 @@ impl RafsInode for CachedInode {
         self.i_flags.contains(RafsInodeFlags::HAS_HOLE)
     }
 
+    fn has_variable_chunk(&self) -> bool {
+        self.i_flags.contains(RafsInodeFlags::VARIABLE_CHUNK)
+    }
+
+    fn has_inline_data(&self) -> bool {
+        self.i_flags.contains(RafsInodeFlags::INLINE_DATA)
+    }
+
     fn collect_descendants_inodes(
         &self,
         descendants: &mut Vec<Arc<dyn RafsInode>>,
@@ -468,6 +502,8 @@ pub struct CachedChunkInfo {
     c_block_id: Arc<RafsDigest>,
     // blob containing the block
     c_blob_index: u32,
+    // position of the block within the blob
+    c_index: u32,
     // position of the block within the file
     c_file_offset: u64,
     // offset of the block within the blob
@@ -476,6 +512,8 @@ pub struct CachedChunkInfo {
     // size of the block, compressed
     c_compr_size: u32,
     c_decompress_size: u32,
+    // position of file data within the decompressed block shared by files
+    c_batch_offset: u32,
     c_flags: RafsChunkFlags,
 }
 
@@ -498,11 +536,13 @@ impl CachedChunkInfo {
     fn copy_from_ondisk(&mut self, chunk: &OndiskChunkInfo) {
         self.c_block_id = Arc::new(chunk.block_id);
         self.c_blob_index = chunk.blob_index;
+        self.c_index = chunk.index;
         self.c_compress_offset = chunk.compress_offset;
         self.c_decompress_offset = chunk.decompress_offset;
         self.c_decompress_size = chunk.decompress_size;
         self.c_file_offset = chunk.file_offset;
         self.c_compr_size = chunk.compress_size;
+        self.c_batch_offset = chunk.batch_offset;
         self.c_flags = chunk.flags;
     }
 }
@@ -521,11 +561,13 @@ impl RafsChunkInfo for CachedChunkInfo {
     }
 
     impl_getter!(blob_index, c_blob_index, u32);
+    impl_getter!(index, c_index, u32);
     impl_getter!(compress_offset, c_compress_offset, u64);
     impl_getter!(compress_size, c_compr_size, u32);
     impl_getter!(decompress_offset, c_decompress_offset, u64);
     impl_getter!(decompress_size, c_decompress_size, u32);
     impl_getter!(file_offset, c_file_offset, u64);
+    impl_getter!(batch_offset, c_batch_offset, u32);
     impl_getter!(flags, c_flags, RafsChunkFlags);
 }
 
@@ -541,9 +583,10 @@ impl From<&OndiskChunkInfo> for CachedChunkInfo {
 mod cached_tests {
     use crate::metadata::cached::CachedInode;
     use crate::metadata::layout::{
-        OndiskBlobTable, OndiskChunkInfo, OndiskInode, OndiskInodeWrapper, XAttrs,
+        OndiskBlobTable, OndiskChunkInfo, OndiskInlineData, OndiskInode, OndiskInodeWrapper,
+        RafsInodeFlags, XAttrs,
     };
-    use crate::metadata::{align_to_rafs, RafsInode, RafsStore, RafsSuperMeta};
+    use crate::metadata::{align_to_rafs, RafsChunkInfo, RafsInode, RafsStore, RafsSuperMeta};
     use crate::{RafsIoReader, RafsIoWriter};
     use nydus_utils::ByteSize;
     use std::cmp;
@@ -655,6 +698,60 @@ mod cached_tests {
         std::fs::remove_file("/tmp/buf_2").unwrap();
     }
 
+    #[test]
+    fn test_load_inline_data() {
+        let mut f = OpenOptions::new()
+            .truncate(true)
+            .create(true)
+            .write(true)
+            .read(true)
+            .open("/tmp/buf_inline")
+            .unwrap();
+        let mut writer = Box::new(f.try_clone().unwrap()) as RafsIoWriter;
+        let mut reader = Box::new(f.try_clone().unwrap()) as RafsIoReader;
+        let file_name = OsString::from("c_inode_inline");
+        let data = b"key = value\n";
+        let mut xattr = XAttrs::default();
+        xattr.add(OsString::from("k1"), vec![1u8, 2u8, 3u8]);
+        let mut ondisk_inode = OndiskInode::new();
+        ondisk_inode.i_name_size = file_name.byte_size() as u16;
+        ondisk_inode.i_mode = libc::S_IFREG;
+        ondisk_inode.i_size = data.len() as u64;
+        ondisk_inode.i_flags = RafsInodeFlags::XATTR | RafsInodeFlags::INLINE_DATA;
+
+        let inode = OndiskInodeWrapper {
+            name: file_name.as_os_str(),
+            symlink: None,
+            inode: &ondisk_inode,
+        };
+        inode.store(&mut writer).unwrap();
+        xattr.store(&mut writer).unwrap();
+        OndiskInlineData(data).store(&mut writer).unwrap();
+        // The next inode follows padded inline data.
+        ondisk_inode.i_flags = RafsInodeFlags::empty();
+        ondisk_inode.i_size = 0;
+        inode.store(&mut writer).unwrap();
+
+        f.seek(Start(0)).unwrap();
+        let meta = Arc::new(RafsSuperMeta::default());
+        let blob_table = Arc::new(OndiskBlobTable::new());
+        let mut cached_inode = CachedInode::new(blob_table.clone(), meta.clone());
+        cached_inode.load(&meta, &mut reader).unwrap();
+        assert!(cached_inode.has_inline_data());
+        assert_eq!(cached_inode.get_inline_data().unwrap(), data);
+        assert_eq!(cached_inode.get_child_count(), 0);
+        assert!(cached_inode.get_xattr(OsStr::new("k1")).unwrap().is_some());
+
+        let mut next_inode = CachedInode::new(blob_table, meta.clone());
+        next_inode.load(&meta, &mut reader).unwrap();
+        assert_eq!(next_inode.i_name, "c_inode_inline");
+        assert!(!next_inode.has_inline_data());
+        assert!(next_inode.get_inline_data().is_err());
+
+        drop(f);
+        std::fs::remove_file("/tmp/buf_inline").unwrap();
+    }
+
     #[test]
     fn test_alloc_bio_desc() {
         let mut f = OpenOptions::new()
@@ -695,9 +792,14 @@ mod cached_tests {
         let mut meta = Arc::new(RafsSuperMeta::default());
         Arc::get_mut(&mut meta).unwrap().block_size = 1024 * 1024;
         let mut blob_table = Arc::new(OndiskBlobTable::new());
-        Arc::get_mut(&mut blob_table)
-            .unwrap()
-            .add(String::from("123333"), 0, 0);
+        Arc::get_mut(&mut blob_table).unwrap().add(
+            String::from("123333"),
+            0,
+            0,
+            0,
+            0,
+            String::new(),
+        );
         let mut cached_inode = CachedInode::new(blob_table, meta.clone());
         cached_inode.load(&meta, &mut reader).unwrap();
         let desc1 = cached_inode.alloc_bio_desc(0, 100).unwrap();
@@ -724,4 +826,91 @@ mod cached_tests {
         drop(f);
         std::fs::remove_file("/tmp/buf_3").unwrap();
     }
+
+    #[test]
+    fn test_alloc_bio_desc_block_size() {
+        for blksize in &[0x1_0000u32, 0x40_0000] {
+            let blksize = *blksize;
+            let path = format!("/tmp/buf_blksize_{}", blksize);
+            let mut f = OpenOptions::new()
+                .truncate(true)
+                .create(true)
+                .write(true)
+                .read(true)
+                .open(&path)
+                .unwrap();
+            let mut writer = Box::new(f.try_clone().unwrap()) as RafsIoWriter;
+            let mut reader = Box::new(f.try_clone().unwrap()) as RafsIoReader;
+            let file_name = OsString::from("c_inode_blksize");
+            let mut ondisk_inode = OndiskInode::new();
+            ondisk_inode.i_name_size = align_to_rafs(file_name.len()) as u16;
+            ondisk_inode.i_child_count = 4;
+            ondisk_inode.i_mode = libc::S_IFREG;
+            ondisk_inode.i_size = blksize as u64 * 3 + 100;
+
+            let inode = OndiskInodeWrapper {
+                name: file_name.as_os_str(),
+                symlink: None,
+                inode: &ondisk_inode,
+            };
+            inode.store(&mut writer).unwrap();
+
+            let mut size = ondisk_inode.i_size;
+            for i in 0..ondisk_inode.i_child_count {
+                let mut chunk = OndiskChunkInfo::new();
+                chunk.decompress_size = cmp::min(blksize as u64, size) as u32;
+                chunk.decompress_offset = i as u64 * blksize as u64;
+                chunk.compress_size = chunk.decompress_size;
+                chunk.compress_offset = chunk.decompress_offset;
+                chunk.file_offset = chunk.decompress_offset;
+                chunk.store(&mut writer).unwrap();
+                size -= chunk.decompress_size as u64;
+            }
+            f.seek(Start(0)).unwrap();
+            let mut meta = Arc::new(RafsSuperMeta::default());
+            Arc::get_mut(&mut meta).unwrap().block_size = blksize;
+            let mut blob_table = Arc::new(OndiskBlobTable::new());
+            Arc::get_mut(&mut blob_table).unwrap().add(
+                String::from("123333"),
+                0,
+                0,
+                0,
+                0,
+                String::new(),
+            );
+            let mut cached_inode = CachedInode::new(blob_table, meta.clone());
+            cached_inode.load(&meta, &mut reader).unwrap();
+
+            // Within a chunk.
+            let desc = cached_inode
+                .alloc_bio_desc(blksize as u64 + 10, 100)
+                .unwrap();
+            assert_eq!(desc.bi_vec.len(), 1);
+            assert_eq!(desc.bi_vec[0].chunkinfo.file_offset(), blksize as u64);
+            assert_eq!(desc.bi_vec[0].offset, 10);
+
+            // Across chunk boundaries.
+            let desc = cached_inode
+                .alloc_bio_desc(blksize as u64 - 100, blksize as usize + 200)
+                .unwrap();
+            assert_eq!(desc.bi_size, blksize as usize + 200);
+            assert_eq!(desc.bi_vec.len(), 3);
+            assert_eq!(desc.bi_vec[0].offset, blksize - 100);
+            assert_eq!(desc.bi_vec[0].size, 100);
+            assert_eq!(desc.bi_vec[1].size, blksize as usize);
+            assert_eq!(desc.bi_vec[2].chunkinfo.file_offset(), blksize as u64 * 2);
+            assert_eq!(desc.bi_vec[2].size, 100);
+
+            // The last chunk, reads are stopped at end of file.
+            let desc = cached_inode
+                .alloc_bio_desc(blksize as u64 * 3, blksize as usize)
+                .unwrap();
+            assert_eq!(desc.bi_size, 100);
+            assert_eq!(desc.bi_vec.len(), 1);
+            assert_eq!(desc.bi_vec[0].chunkinfo.file_offset(), blksize as u64 * 3);
+
+            drop(f);
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
 }
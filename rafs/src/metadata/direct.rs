@@ -273,10 +273,12 @@ impl DirectMapping {
             )
         };
         let mut blob_table = OndiskBlobTable::new();
-        blob_table.load_from_slice(blob_slice).map_err(|e| {
-            unsafe { libc::munmap(base as *mut u8 as *mut libc::c_void, size) };
-            e
-        })?;
+        blob_table
+            .load_from_slice(blob_slice, old_state.meta.flags)
+            .map_err(|e| {
+                unsafe { libc::munmap(base as *mut u8 as *mut libc::c_void, size) };
+                e
+            })?;
 
         // Load(Map) inode table. Safe because we have validated the inode table layout.
         // Though we have passed *mut u32 to Vec::from_raw_parts(), it will trigger invalid memory
@@ -456,10 +458,20 @@ impl RafsInode for OndiskInodeWrapper {
             0
         };
 
+        if inode.has_inline_data() && !inode.is_reg() {
+            return Err(ebadf!("invalid inode"));
+        }
+
         if inode.is_reg() {
-            let size = inode.size()
-                + xattr_size
-                + inode.i_child_count as usize * size_of::<OndiskChunkInfo>();
+            let data_size = if inode.has_inline_data() {
+                if inode.i_size > RAFS_MAX_INLINE_SIZE || inode.i_child_count != 0 {
+                    return Err(ebadf!("invalid inode"));
+                }
+                align_to_rafs(inode.i_size as usize)
+            } else {
+                inode.i_child_count as usize * size_of::<OndiskChunkInfo>()
+            };
+            let size = inode.size() + xattr_size + data_size;
             state.validate_range(self.offset, size)?;
         } else if inode.is_dir() {
             let max_ino = state.inode_table.len();
@@ -506,6 +518,26 @@ impl RafsInode for OndiskInodeWrapper {
         Ok(bytes_to_os_str(symlink).to_os_string())
     }
 
+    /// Get file data stored inline following xattrs of the inode.
+    ///
+    /// # Safety
+    /// It depends on Self::validate() to ensure valid memory layout.
+    fn get_inline_data(&self) -> Result<Vec<u8>> {
+        let state = self.state();
+        let inode = self.inode(state.deref());
+        if !inode.has_inline_data() {
+            return Err(einval!("inode has no inline data"));
+        }
+
+        let offset = self.offset + inode.size() + self.get_xattr_size()?;
+        let data = unsafe {
+            let start = state.base.add(offset);
+            slice::from_raw_parts(start, inode.i_size as usize)
+        };
+
+        Ok(data.to_vec())
+    }
+
     /// Get the child with the specified name.
     ///
     /// # Safety
@@ -717,6 +749,8 @@ impl RafsInode for OndiskInodeWrapper {
     impl_inode_wrapper!(is_hardlink, bool);
     impl_inode_wrapper!(has_xattr, bool);
     impl_inode_wrapper!(has_hole, bool);
+    impl_inode_wrapper!(has_variable_chunk, bool);
+    impl_inode_wrapper!(has_inline_data, bool);
     impl_inode_getter!(ino, i_ino, u64);
     impl_inode_getter!(parent, i_parent, u64);
     impl_inode_getter!(size, i_size, u64);
@@ -782,10 +816,12 @@ impl RafsChunkInfo for OndiskChunkInfoWrapper {
     }
 
     impl_chunkinfo_getter!(blob_index, u32);
+    impl_chunkinfo_getter!(index, u32);
     impl_chunkinfo_getter!(compress_offset, u64);
     impl_chunkinfo_getter!(compress_size, u32);
     impl_chunkinfo_getter!(decompress_offset, u64);
     impl_chunkinfo_getter!(decompress_size, u32);
     impl_chunkinfo_getter!(file_offset, u64);
+    impl_chunkinfo_getter!(batch_offset, u32);
     impl_chunkinfo_getter!(flags, RafsChunkFlags);
 }
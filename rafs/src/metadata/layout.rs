@@ -52,9 +52,13 @@ pub const RAFS_SUPERBLOCK_RESERVED_SIZE: usize = RAFS_SUPERBLOCK_SIZE - 72;
 pub const RAFS_SUPER_MAGIC: u32 = 0x5241_4653;
 pub const RAFS_SUPER_VERSION_V4: u32 = 0x400;
 pub const RAFS_SUPER_VERSION_V5: u32 = 0x500;
+/// V5 with features nydusd unaware of would read wrong, so that such nydusd refuses it.
+pub const RAFS_SUPER_VERSION_V5_1: u32 = 0x501;
 pub const RAFS_SUPER_MIN_VERSION: u32 = RAFS_SUPER_VERSION_V4;
 pub const RAFS_ALIGNMENT: usize = 8;
 pub const RAFS_ROOT_INODE: u64 = 1;
+/// Max size of file data stored inline in bootstrap.
+pub const RAFS_MAX_INLINE_SIZE: u64 = 65536;
 
 macro_rules! impl_bootstrap_converter {
     ($T: ty) => {
@@ -176,6 +180,37 @@ bitflags! {
         const HAS_XATTR = 0x0000_0020;
         // Data chunks are compressed with gzip
         const COMPRESS_GZIP = 0x0000_0040;
+        // Data chunks are compressed with zstd
+        const COMPRESS_ZSTD = 0x0000_0080;
+        // Blob table entries have offset and size of compression dictionary of blobs
+        const BLOB_DICT = 0x0000_0100;
+        /// Use sha512-256 hash algorithm to calculate digest.
+        const DIGESTER_SHA512_256 = 0x0000_0200;
+        /// Use xxh3-128 hash algorithm to calculate digest, not collision resistant.
+        const DIGESTER_XXH3_128 = 0x0000_0400;
+        // Blob table entries have id of the key which chunks of blobs are encrypted with
+        const BLOB_KEY_ID = 0x0000_0800;
+        /// Some inode has chunks of variable sizes.
+        /// Nydusd unaware of it would read such inodes wrong, so it requires version V5_1.
+        const VARIABLE_CHUNK = 0x0000_1000;
+        /// Some inode has its data inline in bootstrap.
+        /// Nydusd unaware of it would read such inodes as empty, so it requires version V5_1.
+        const INLINE_DATA = 0x0000_2000;
+        /// Some chunk is shared by small files packed into it.
+        /// Nydusd unaware of it would read such files from the head of the chunk, so it
+        /// requires version V5_1.
+        const BATCH_CHUNK = 0x0000_4000;
+    }
+}
+
+impl RafsSuperFlags {
+    /// Flags of features which require `RAFS_SUPER_VERSION_V5_1`.
+    pub fn incompat() -> Self {
+        RafsSuperFlags::BLOB_DICT
+            | RafsSuperFlags::BLOB_KEY_ID
+            | RafsSuperFlags::VARIABLE_CHUNK
+            | RafsSuperFlags::INLINE_DATA
+            | RafsSuperFlags::BATCH_CHUNK
     }
 }
 
@@ -197,6 +232,8 @@ impl Into<digest::Algorithm> for RafsSuperFlags {
         match self {
             x if x.contains(RafsSuperFlags::DIGESTER_BLAKE3) => digest::Algorithm::Blake3,
             x if x.contains(RafsSuperFlags::DIGESTER_SHA256) => digest::Algorithm::Sha256,
+            x if x.contains(RafsSuperFlags::DIGESTER_SHA512_256) => digest::Algorithm::Sha512_256,
+            x if x.contains(RafsSuperFlags::DIGESTER_XXH3_128) => digest::Algorithm::Xxh3_128,
             _ => digest::Algorithm::Blake3,
         }
     }
@@ -207,6 +244,8 @@ impl From<digest::Algorithm> for RafsSuperFlags {
         match d {
             digest::Algorithm::Blake3 => RafsSuperFlags::DIGESTER_BLAKE3,
             digest::Algorithm::Sha256 => RafsSuperFlags::DIGESTER_SHA256,
+            digest::Algorithm::Sha512_256 => RafsSuperFlags::DIGESTER_SHA512_256,
+            digest::Algorithm::Xxh3_128 => RafsSuperFlags::DIGESTER_XXH3_128,
         }
     }
 }
@@ -217,6 +256,7 @@ impl Into<compress::Algorithm> for RafsSuperFlags {
             x if x.contains(RafsSuperFlags::COMPRESS_NONE) => compress::Algorithm::None,
             x if x.contains(RafsSuperFlags::COMPRESS_LZ4_BLOCK) => compress::Algorithm::LZ4Block,
             x if x.contains(RafsSuperFlags::COMPRESS_GZIP) => compress::Algorithm::GZip,
+            x if x.contains(RafsSuperFlags::COMPRESS_ZSTD) => compress::Algorithm::Zstd,
             _ => compress::Algorithm::LZ4Block,
         }
     }
@@ -228,6 +268,7 @@ impl From<compress::Algorithm> for RafsSuperFlags {
             compress::Algorithm::None => RafsSuperFlags::COMPRESS_NONE,
             compress::Algorithm::LZ4Block => RafsSuperFlags::COMPRESS_LZ4_BLOCK,
             compress::Algorithm::GZip => RafsSuperFlags::COMPRESS_GZIP,
+            compress::Algorithm::Zstd => RafsSuperFlags::COMPRESS_ZSTD,
         }
     }
 }
@@ -261,7 +302,7 @@ impl OndiskSuperBlock {
     pub fn validate(&self) -> Result<()> {
         if self.magic() != RAFS_SUPER_MAGIC
             || self.version() < RAFS_SUPER_MIN_VERSION as u32
-            || self.version() > RAFS_SUPER_VERSION_V5 as u32
+            || self.version() > RAFS_SUPER_VERSION_V5_1 as u32
             || self.sb_size() != RAFS_SUPERBLOCK_SIZE as u32
         {
             return Err(einval!("invalid superblock"));
@@ -276,7 +317,7 @@ impl OndiskSuperBlock {
                     return Err(einval!("invalid superblock"));
                 }
             }
-            RAFS_SUPER_VERSION_V5 => {
+            RAFS_SUPER_VERSION_V5 | RAFS_SUPER_VERSION_V5_1 => {
                 if self.inodes_count() == 0
                     || self.inode_table_offset() < RAFS_SUPERBLOCK_SIZE as u64
                     || self.inode_table_offset() & 0x7 != 0
@@ -289,7 +330,25 @@ impl OndiskSuperBlock {
             }
         }
 
-        // TODO: validate block_size, flags and reserved.
+        let block_size = self.block_size() as u64;
+        if !block_size.is_power_of_two()
+            || block_size < RAFS_MIN_BLOCK_SIZE
+            || block_size > RAFS_MAX_BLOCK_SIZE
+        {
+            return Err(einval!(format!("invalid block size {}", block_size)));
+        }
+
+        let flags = RafsSuperFlags::from_bits(self.flags())
+            .ok_or_else(|| einval!(format!("invalid super flags {:x}", self.flags())))?;
+        if flags.intersects(RafsSuperFlags::incompat()) && self.version() < RAFS_SUPER_VERSION_V5_1
+        {
+            return Err(einval!(format!(
+                "super flags {} require version {:x}",
+                flags, RAFS_SUPER_VERSION_V5_1
+            )));
+        }
+
+        // TODO: validate reserved.
 
         Ok(())
     }
@@ -312,6 +371,31 @@ impl OndiskSuperBlock {
         self.s_flags |= RafsSuperFlags::HAS_XATTR.bits();
     }
 
+    pub fn set_variable_chunk(&mut self) {
+        self.set_incompat_flag(RafsSuperFlags::VARIABLE_CHUNK);
+    }
+
+    pub fn set_inline_data(&mut self) {
+        self.set_incompat_flag(RafsSuperFlags::INLINE_DATA);
+    }
+
+    pub fn set_batch_chunk(&mut self) {
+        self.set_incompat_flag(RafsSuperFlags::BATCH_CHUNK);
+    }
+
+    pub fn set_blob_dict(&mut self) {
+        self.set_incompat_flag(RafsSuperFlags::BLOB_DICT);
+    }
+
+    pub fn set_blob_key_id(&mut self) {
+        self.set_incompat_flag(RafsSuperFlags::BLOB_KEY_ID);
+    }
+
+    fn set_incompat_flag(&mut self, flag: RafsSuperFlags) {
+        self.s_flags |= flag.bits();
+        self.set_version(RAFS_SUPER_VERSION_V5_1);
+    }
+
     impl_pub_getter_setter!(magic, set_magic, s_magic, u32);
     impl_pub_getter_setter!(version, set_version, s_fs_version, u32);
     impl_pub_getter_setter!(sb_size, set_sb_size, s_sb_size, u32);
@@ -517,15 +601,38 @@ pub struct OndiskBlobTableEntry {
     // Blob's own meta should be put onto its head, so `u32` should be sufficient.
     pub readahead_offset: u32,
     pub readahead_size: u32,
+    // Zstd dictionary shared by chunks of the blob, 0 size if there is none.
+    pub dict_offset: u32,
+    pub dict_size: u32,
     pub blob_id: String,
+    // Key which chunks of the blob are encrypted with, empty if they are not encrypted.
+    pub key_id: String,
 }
 
 impl OndiskBlobTableEntry {
-    pub fn size(&self) -> usize {
-        size_of::<u32>() * 2 + self.blob_id.len()
+    /// Size of fields before blob id, dictionary fields only exist in the extended layout.
+    fn header_size(extended: bool) -> usize {
+        if extended {
+            size_of::<u32>() * 4
+        } else {
+            size_of::<u32>() * 2
+        }
+    }
+
+    pub fn size(&self, extended: bool, keyed: bool) -> usize {
+        let size = Self::header_size(extended) + self.blob_id.len();
+        if keyed {
+            size + 1 + self.key_id.len()
+        } else {
+            size
+        }
     }
 }
 
+/// Blob table, each entry is `readahead_offset: u32`, `readahead_size: u32`, and `blob_id`,
+/// split with '\0'. With `RafsSuperFlags::BLOB_DICT`, entries are in the extended layout,
+/// which has `dict_offset: u32` and `dict_size: u32` before `blob_id`. With
+/// `RafsSuperFlags::BLOB_KEY_ID`, `blob_id` of each entry is followed by '\0' and `key_id`.
 #[derive(Clone, Debug, Default)]
 pub struct OndiskBlobTable {
     pub entries: Vec<OndiskBlobTableEntry>,
@@ -538,25 +645,49 @@ impl OndiskBlobTable {
         }
     }
 
+    /// Whether any blob has compression dictionary, so that the table is stored in the
+    /// extended layout.
+    pub fn has_dict(&self) -> bool {
+        self.entries.iter().any(|e| e.dict_size != 0)
+    }
+
+    /// Whether any blob is encrypted, so that the table has key ids.
+    pub fn has_key_id(&self) -> bool {
+        self.entries.iter().any(|e| !e.key_id.is_empty())
+    }
+
     /// Get blob table size, aligned with RAFS_ALIGNMENT bytes
     pub fn size(&self) -> usize {
         if self.entries.is_empty() {
             return 0;
         }
+        let extended = self.has_dict();
+        let keyed = self.has_key_id();
         // Blob entry split with '\0'
         align_to_rafs(
             self.entries
                 .iter()
-                .fold(0usize, |size, entry| size + entry.size() + 1)
+                .fold(0usize, |size, entry| size + entry.size(extended, keyed) + 1)
                 - 1,
         )
     }
 
-    pub fn add(&mut self, blob_id: String, readahead_offset: u32, readahead_size: u32) -> u32 {
+    pub fn add(
+        &mut self,
+        blob_id: String,
+        readahead_offset: u32,
+        readahead_size: u32,
+        dict_offset: u32,
+        dict_size: u32,
+        key_id: String,
+    ) -> u32 {
         self.entries.push(OndiskBlobTableEntry {
             blob_id,
             readahead_offset,
             readahead_size,
+            dict_offset,
+            dict_size,
+            key_id,
         });
         (self.entries.len() - 1) as u32
     }
@@ -569,53 +700,77 @@ impl OndiskBlobTable {
         Ok(self.entries[blob_index as usize].clone())
     }
 
-    pub fn load(&mut self, r: &mut RafsIoReader, size: usize) -> Result<()> {
+    pub fn load(&mut self, r: &mut RafsIoReader, size: usize, flags: RafsSuperFlags) -> Result<()> {
         let mut input = vec![0u8; size];
 
         r.read_exact(&mut input)?;
-        self.load_from_slice(&input)
+        self.load_from_slice(&input, flags)
     }
-    pub fn load_from_slice(&mut self, input: &[u8]) -> Result<()> {
+
+    pub fn load_from_slice(&mut self, input: &[u8], flags: RafsSuperFlags) -> Result<()> {
+        let extended = flags.contains(RafsSuperFlags::BLOB_DICT);
+        let keyed = flags.contains(RafsSuperFlags::BLOB_KEY_ID);
+        let header_size = OndiskBlobTableEntry::header_size(extended);
         let mut input_rest = input;
+        // Ids are parsed up to '\0' before being decoded, as headers of the following entries
+        // may not be valid UTF-8.
+        let parse_id = |buf: &[u8]| -> Result<(String, usize)> {
+            let len = buf
+                .iter()
+                .position(|c| *c == b'\0')
+                .unwrap_or_else(|| buf.len());
+            let (id, _) = parse_string(&buf[..len])?;
+            Ok((id.to_string(), len))
+        };
 
         loop {
-            let split_at_64 = std::mem::size_of::<u64>();
-            let split_at_32 = std::mem::size_of::<u32>();
-
-            if input_rest.len() < split_at_64 + 1 {
-                break;
-            }
-            let (readahead, rest) = input_rest.split_at(split_at_64);
-
-            if readahead.len() < split_at_32 + 1 {
+            if input_rest.len() < header_size + 1 {
                 break;
             }
-            let (readahead_offset, readahead_size) = readahead.split_at(split_at_32);
-
-            let readahead_offset =
-                u32::from_le_bytes(readahead_offset.try_into().map_err(|e| einval!(e))?);
-            let readahead_size =
-                u32::from_le_bytes(readahead_size.try_into().map_err(|e| einval!(e))?);
-
-            let (blob_id, rest) = parse_string(rest)?;
+            let (header, rest) = input_rest.split_at(header_size);
+            let field = |i: usize| -> Result<u32> {
+                Ok(u32::from_le_bytes(
+                    header[i * 4..(i + 1) * 4]
+                        .try_into()
+                        .map_err(|e| einval!(e))?,
+                ))
+            };
+
+            let readahead_offset = field(0)?;
+            let readahead_size = field(1)?;
+            let (dict_offset, dict_size) = if extended {
+                (field(2)?, field(3)?)
+            } else {
+                (0, 0)
+            };
+
+            let (blob_id, len) = parse_id(rest)?;
+            let mut rest = &rest[len..];
+            let key_id = if keyed && !rest.is_empty() {
+                let (key_id, len) = parse_id(&rest[1..])?;
+                rest = &rest[1 + len..];
+                key_id
+            } else {
+                String::new()
+            };
 
             self.entries.push(OndiskBlobTableEntry {
-                blob_id: blob_id.to_string(),
+                blob_id,
                 readahead_offset,
                 readahead_size,
+                dict_offset,
+                dict_size,
+                key_id,
             });
 
             // Break blob id search loop, when rest bytes length is zero,
             // or not split with '\0', or not have enough data to read (ending with padding data).
-            if rest.is_empty()
-                || rest.as_bytes()[0] != b'\0'
-                || rest.as_bytes().len() <= (size_of::<u32>() * 2 + 1)
-            {
+            if rest.is_empty() || rest[0] != b'\0' || rest.len() <= (header_size + 1) {
                 break;
             }
 
             // Skip '\0' splitter for next search
-            input_rest = &rest.as_bytes()[1..];
+            input_rest = &rest[1..];
         }
 
         Ok(())
@@ -629,18 +784,28 @@ impl OndiskBlobTable {
 impl RafsStore for OndiskBlobTable {
     fn store_inner(&self, w: &mut RafsIoWriter) -> Result<usize> {
         let mut size = 0;
+        let extended = self.has_dict();
+        let keyed = self.has_key_id();
         self.entries
             .iter()
             .enumerate()
             .try_for_each::<_, Result<()>>(|(idx, entry)| {
                 w.write_all(&u32::to_le_bytes(entry.readahead_offset))?;
                 w.write_all(&u32::to_le_bytes(entry.readahead_size))?;
+                if extended {
+                    w.write_all(&u32::to_le_bytes(entry.dict_offset))?;
+                    w.write_all(&u32::to_le_bytes(entry.dict_size))?;
+                }
                 w.write_all(entry.blob_id.as_bytes())?;
+                if keyed {
+                    w.write_all(&[b'\0'])?;
+                    w.write_all(entry.key_id.as_bytes())?;
+                }
                 if idx != self.entries.len() - 1 {
-                    size += size_of::<u32>() * 2 + entry.blob_id.len() + 1;
+                    size += entry.size(extended, keyed) + 1;
                     w.write_all(&[b'\0'])?;
                 } else {
-                    size += size_of::<u32>() * 2 + entry.blob_id.len();
+                    size += entry.size(extended, keyed);
                 }
                 Ok(())
             })?;
@@ -696,6 +861,10 @@ bitflags! {
         const XATTR = 0x0000_0004;
         /// Inode chunks has holes.
         const HAS_HOLE = 0x0000_0008;
+        /// Inode chunks have variable sizes, split by content rather than at fixed offsets.
+        const VARIABLE_CHUNK = 0x0000_0010;
+        /// Inode data is stored inline in bootstrap, following xattrs, rather than in chunks.
+        const INLINE_DATA = 0x0000_0020;
    }
 }
 
@@ -760,6 +929,16 @@ impl OndiskInode {
     pub fn has_hole(&self) -> bool {
         self.i_flags.contains(RafsInodeFlags::HAS_HOLE)
     }
+
+    #[inline]
+    pub fn has_variable_chunk(&self) -> bool {
+        self.i_flags.contains(RafsInodeFlags::VARIABLE_CHUNK)
+    }
+
+    #[inline]
+    pub fn has_inline_data(&self) -> bool {
+        self.i_flags.contains(RafsInodeFlags::INLINE_DATA)
+    }
 }
 
 pub struct OndiskInodeWrapper<'a> {
@@ -799,6 +978,20 @@ impl<'a> RafsStore for OndiskInodeWrapper<'a> {
 
 impl_bootstrap_converter!(OndiskInode);
 
+/// Data of a small file stored inline in bootstrap, which is `i_size` bytes padded to
+/// alignment.
+pub struct OndiskInlineData<'a>(pub &'a [u8]);
+
+impl<'a> RafsStore for OndiskInlineData<'a> {
+    fn store_inner(&self, w: &mut RafsIoWriter) -> Result<usize> {
+        w.write_all(self.0)?;
+        let padding = align_to_rafs(self.0.len()) - self.0.len();
+        w.write_padding(padding)?;
+
+        Ok(self.0.len() + padding)
+    }
+}
+
 /// On disk Rafs data chunk information.
 #[repr(C)]
 #[derive(Default, Clone, Copy, Debug)]
@@ -821,8 +1014,10 @@ pub struct OndiskChunkInfo {
 
     /// offset in file
     pub file_offset: u64,
-    /// reserved
-    pub reserved: u64,
+    /// chunk index in blob, valid with RafsChunkFlags::INDEXED
+    pub index: u32,
+    /// offset of file data in the decompressed chunk, valid with RafsChunkFlags::BATCH
+    pub batch_offset: u32,
 }
 
 impl OndiskChunkInfo {
@@ -848,13 +1043,15 @@ impl fmt::Display for OndiskChunkInfo {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "file_offset {}, compress_offset {}, compress_size {}, decompress_offset {}, decompress_size {}, blob_index {}, block_id {}, is_compressed {}",
+            "file_offset {}, compress_offset {}, compress_size {}, decompress_offset {}, decompress_size {}, blob_index {}, index {}, batch_offset {}, block_id {}, is_compressed {}",
             self.file_offset,
             self.compress_offset,
             self.compress_size,
             self.decompress_offset,
             self.decompress_size,
             self.blob_index,
+            self.index,
+            self.batch_offset,
             self.block_id,
             self.flags.contains(RafsChunkFlags::COMPRESSED),
         )
@@ -6,7 +6,7 @@
 //! Structs and Traits for RAFS file system meta data management.
 
 use std::cmp;
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::ffi::{OsStr, OsString};
 use std::fmt;
 use std::io::{Error, Result, Seek, SeekFrom};
@@ -30,7 +30,7 @@ use crate::metadata::cached::CachedInodes;
 use storage::compress;
 use storage::device::{RafsBio, RafsBioDesc};
 // FIXME: Move this definition to metadata crate if we have it some day.
-pub use crate::storage::RAFS_DEFAULT_BLOCK_SIZE;
+pub use crate::storage::{RAFS_DEFAULT_BLOCK_SIZE, RAFS_MAX_BLOCK_SIZE, RAFS_MIN_BLOCK_SIZE};
 use crate::*;
 
 mod noop;
@@ -229,7 +229,7 @@ impl RafsSuper {
             RAFS_SUPER_VERSION_V4 => {
                 self.meta.inodes_count = std::u64::MAX;
             }
-            RAFS_SUPER_VERSION_V5 => {
+            RAFS_SUPER_VERSION_V5 | RAFS_SUPER_VERSION_V5_1 => {
                 self.meta.inodes_count = sb.inodes_count();
                 self.meta.inode_table_entries = sb.inode_table_entries();
                 self.meta.inode_table_offset = sb.inode_table_offset();
@@ -244,7 +244,7 @@ impl RafsSuper {
                 // TODO: Support Rafs v4
                 unimplemented!();
             }
-            RAFS_SUPER_VERSION_V5 => match self.mode {
+            RAFS_SUPER_VERSION_V5 | RAFS_SUPER_VERSION_V5_1 => match self.mode {
                 RafsMode::Direct => {
                     let mut inodes = DirectMapping::new(&self.meta, self.digest_validate);
                     inodes.load(r)?;
@@ -253,7 +253,7 @@ impl RafsSuper {
                 RafsMode::Cached => {
                     r.seek(SeekFrom::Start(sb.blob_table_offset()))?;
                     let mut blob_table = OndiskBlobTable::new();
-                    blob_table.load(r, sb.blob_table_size() as usize)?;
+                    blob_table.load(r, sb.blob_table_size() as usize, self.meta.flags)?;
 
                     let mut inodes = CachedInodes::new(self.meta, blob_table, self.digest_validate);
                     inodes.load(r)?;
@@ -278,7 +278,7 @@ impl RafsSuper {
 
         match self.meta.version {
             RAFS_SUPER_VERSION_V4 => {}
-            RAFS_SUPER_VERSION_V5 => {
+            RAFS_SUPER_VERSION_V5 | RAFS_SUPER_VERSION_V5_1 => {
                 sb.set_inodes_count(self.meta.inodes_count);
                 sb.set_inode_table_entries(self.meta.inode_table_entries);
                 sb.set_inode_table_offset(self.meta.inode_table_offset);
@@ -313,7 +313,7 @@ impl RafsSuper {
             // Issue a prefetch request since target is large enough.
             // As files belonging to the same directory are arranged in adjacent,
             // it should fetch a range of blob in batch.
-            if desc.bi_size >= (4 * RAFS_DEFAULT_BLOCK_SIZE) as usize {
+            if desc.bi_size >= (4 * self.meta.block_size) as usize {
                 trace!("fetching head bio size {}", desc.bi_size);
                 fetcher(desc);
                 desc.bi_size = 0;
@@ -512,6 +512,81 @@ impl RafsSuper {
 
         Ok(())
     }
+
+    /// Prefetch files or directories `files` regardless of the on-disk prefetch table.
+    pub fn prefetch_files(
+        &self,
+        files: &[Inode],
+        fetcher: &dyn Fn(&mut RafsBioDesc),
+    ) -> RafsResult<()> {
+        let mut hardlinks: HashSet<u64> = HashSet::new();
+
+        for f_ino in files {
+            let mut head_desc = RafsBioDesc::new();
+            self.build_prefetch_desc(*f_ino, &mut head_desc, &mut hardlinks, fetcher)
+                .map_err(|e| RafsError::Prefetch(e.to_string()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Build a prefetch bio desc from regular files residing in directory `parent`.
+    ///
+    /// Files belonging to the same directory are arranged adjacently in blob, so it is cheap
+    /// to fetch them together once one of them is read. Sub-directories are walked in BFS
+    /// order no deeper than `depth` levels, and collecting stops once `size_limit` bytes
+    /// are gathered. Chunks for which `skip` returns true are not put into the desc.
+    pub fn build_locality_desc(
+        &self,
+        parent: Inode,
+        depth: u32,
+        size_limit: u64,
+        skip: &dyn Fn(&dyn RafsChunkInfo) -> bool,
+    ) -> Result<RafsBioDesc> {
+        let mut desc = RafsBioDesc::new();
+        let mut hardlinks: HashSet<u64> = HashSet::new();
+        let mut dirs: VecDeque<(Inode, u32)> = VecDeque::new();
+
+        dirs.push_back((parent, 1));
+
+        'walk: while let Some((ino, level)) = dirs.pop_front() {
+            let dir = self.get_inode(ino, self.digest_validate)?;
+            if !dir.is_dir() {
+                return Err(enotdir!("is not a directory"));
+            }
+
+            for idx in 0..dir.get_child_count() {
+                let child = dir.get_child_by_index(idx as u64)?;
+                if child.is_dir() {
+                    if level < depth {
+                        dirs.push_back((child.ino(), level + 1));
+                    }
+                    continue;
+                }
+                if !child.is_reg() || child.is_empty_size() {
+                    continue;
+                }
+                // Chunks of hardlinks are the same, only fetch them once.
+                if child.is_hardlink() && !hardlinks.insert(child.ino()) {
+                    continue;
+                }
+
+                let file_desc = child.alloc_bio_desc(0, child.size() as usize)?;
+                for bio in file_desc.bi_vec {
+                    if bio.chunkinfo.is_hole() || skip(bio.chunkinfo.as_ref()) {
+                        continue;
+                    }
+                    if (desc.bi_size + bio.size) as u64 > size_limit {
+                        break 'walk;
+                    }
+                    desc.bi_size += bio.size;
+                    desc.bi_vec.push(bio);
+                }
+            }
+        }
+
+        Ok(desc)
+    }
 }
 
 /// Trait to manage all inodes of a file system.
@@ -547,6 +622,8 @@ pub trait RafsSuperInodes {
 
         if inode.is_symlink() {
             hasher.digest_update(inode.get_symlink()?.as_bytes());
+        } else if inode.has_inline_data() {
+            hasher.digest_update(&inode.get_inline_data()?);
         } else if inode.is_reg() || inode.is_dir() {
             for idx in 0..child_count {
                 if inode.is_dir() {
@@ -592,6 +669,8 @@ pub trait RafsInode {
 
     fn name(&self) -> OsString;
     fn get_symlink(&self) -> Result<OsString>;
+    /// Get file data stored inline in bootstrap, for inodes with inline data.
+    fn get_inline_data(&self) -> Result<Vec<u8>>;
     fn get_digest(&self) -> RafsDigest;
     fn get_child_by_name(&self, name: &OsStr) -> Result<Arc<dyn RafsInode>>;
     fn get_child_by_index(&self, idx: Inode) -> Result<Arc<dyn RafsInode>>;
@@ -616,6 +695,8 @@ pub trait RafsInode {
     fn is_hardlink(&self) -> bool;
     fn has_xattr(&self) -> bool;
     fn has_hole(&self) -> bool;
+    fn has_variable_chunk(&self) -> bool;
+    fn has_inline_data(&self) -> bool;
 
     fn rdev(&self) -> u32;
     fn ino(&self) -> u64;
@@ -637,15 +718,26 @@ pub trait RafsInode {
         let end = offset
             .checked_add(size as u64)
             .ok_or_else(|| einval!("invalid read size"))?;
+        // Batch chunks hold data of other files after that of this one.
+        let end = cmp::min(end, self.size());
+        if offset >= end {
+            return Ok(desc);
+        }
 
         let blksize = self.get_blocksize() as u64;
-        let (index_start, index_end) = calculate_bio_chunk_index(
-            offset,
-            end,
-            blksize,
-            self.get_child_count(),
-            self.has_hole(),
-        );
+        let (index_start, index_end) = if self.has_variable_chunk() {
+            search_bio_chunk_index(offset, end, self.get_child_count(), |idx| {
+                Ok(self.get_chunk_info(idx)?.file_offset())
+            })?
+        } else {
+            calculate_bio_chunk_index(
+                offset,
+                end,
+                blksize,
+                self.get_child_count(),
+                self.has_hole(),
+            )
+        };
 
         debug!(
             "alloc bio desc offset {} size {} i_size {} blksize {} index_start {} index_end {} i_child_count {}",
@@ -654,7 +746,12 @@ pub trait RafsInode {
 
         for idx in index_start..index_end {
             let chunk = self.get_chunk_info(idx)?;
-            let blob_id = self.get_chunk_blob_id(chunk.blob_index())?;
+            // Hole chunks are not stored in any blob.
+            let blob_id = if chunk.is_hole() {
+                String::new()
+            } else {
+                self.get_chunk_blob_id(chunk.blob_index())?
+            };
             if !add_chunk_to_bio_desc(offset, end, chunk, &mut desc, blksize as u32, blob_id) {
                 break;
             }
@@ -681,7 +778,17 @@ pub(crate) fn add_chunk_to_bio_desc(
     blksize: u32,
     blob_id: String,
 ) -> bool {
-    if offset >= (chunk.file_offset() + chunk.decompress_size() as u64) {
+    // File data in a batch chunk starts from its batch offset.
+    let (data_offset, data_size) = if chunk.is_batch() {
+        let data_offset = chunk.batch_offset() as u64;
+        (
+            data_offset,
+            (chunk.decompress_size() as u64).saturating_sub(data_offset),
+        )
+    } else {
+        (0, chunk.decompress_size() as u64)
+    };
+    if offset >= (chunk.file_offset() + data_size) {
         return true;
     }
     if end <= chunk.file_offset() {
@@ -693,16 +800,16 @@ pub(crate) fn add_chunk_to_bio_desc(
     } else {
         0
     };
-    let chunk_end = if end < (chunk.file_offset() + chunk.decompress_size() as u64) {
+    let chunk_end = if end < (chunk.file_offset() + data_size) {
         end - chunk.file_offset()
     } else {
-        chunk.decompress_size() as u64
+        data_size
     };
 
     let bio = RafsBio::new(
         chunk,
         blob_id,
-        chunk_start as u32,
+        (data_offset + chunk_start) as u32,
         (chunk_end - chunk_start) as usize,
         blksize,
     );
@@ -741,6 +848,43 @@ pub(crate) fn calculate_bio_chunk_index(
     (index_start, index_end)
 }
 
+/// Search bio chunk indices that overlaps with the provided IO range, for files whose chunks
+/// have variable sizes so that indices can't be calculated from file offsets.
+///
+/// offset: IO offset to the file start, inclusive.
+/// end: IO end to the file start, exclusive.
+/// file_offset: get file offset of the chunk at an index, chunks are sorted by it.
+pub(crate) fn search_bio_chunk_index<F>(
+    offset: u64,
+    end: u64,
+    chunk_cnt: u32,
+    file_offset: F,
+) -> Result<(u32, u32)>
+where
+    F: Fn(u32) -> Result<u64>,
+{
+    debug_assert!(offset < end);
+
+    // Index of the first chunk starting after `pos`.
+    let partition = |pos: u64| -> Result<u32> {
+        let (mut lo, mut hi) = (0, chunk_cnt);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if file_offset(mid)? <= pos {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        Ok(lo)
+    };
+    // The chunk covering `offset` is the last one starting at or before it.
+    let index_start = partition(offset)?.saturating_sub(1);
+    let index_end = partition(end - 1)?;
+
+    Ok((index_start, index_end))
+}
+
 /// Trait to store Rafs meta block and validate alignment.
 pub trait RafsStore {
     fn store_inner(&self, w: &mut RafsIoWriter) -> Result<usize>;
@@ -756,12 +900,26 @@ pub trait RafsStore {
 
 #[cfg(test)]
 mod tests {
-    use crate::metadata::{add_chunk_to_bio_desc, calculate_bio_chunk_index};
+    use crate::metadata::cached::CachedInodes;
+    use crate::metadata::layout::{
+        OndiskBlobTable, OndiskChunkInfo, OndiskInode, OndiskInodeWrapper, OndiskSuperBlock,
+        RafsSuperFlags, RAFS_ROOT_INODE, RAFS_SUPERBLOCK_SIZE, RAFS_SUPER_VERSION_V5,
+        RAFS_SUPER_VERSION_V5_1,
+    };
+    use crate::metadata::{
+        add_chunk_to_bio_desc, calculate_bio_chunk_index, search_bio_chunk_index, RafsMode,
+        RafsStore, RafsSuper, RafsSuperInodes, RafsSuperMeta, RAFS_DEFAULT_BLOCK_SIZE,
+    };
+    use crate::{RafsIoReader, RafsIoWriter};
     use nydus_utils::digest::RafsDigest;
+    use nydus_utils::ByteSize;
+    use std::ffi::OsString;
+    use std::io::{Seek, SeekFrom};
     use std::sync::Arc;
     use storage::device::RafsBioDesc;
     use storage::device::{RafsChunkFlags, RafsChunkInfo};
     use storage::impl_getter;
+    use vmm_sys_util::tempfile::TempFile;
 
     #[derive(Default, Copy, Clone)]
     struct MockChunkInfo {
@@ -773,7 +931,8 @@ mod tests {
         pub compress_offset: u64,
         pub decompress_offset: u64,
         pub file_offset: u64,
-        pub reserved: u64,
+        pub index: u32,
+        pub batch_offset: u32,
     }
 
     impl MockChunkInfo {
@@ -793,11 +952,13 @@ mod tests {
             self.flags.contains(RafsChunkFlags::HOLECHUNK)
         }
         impl_getter!(blob_index, blob_index, u32);
+        impl_getter!(index, index, u32);
         impl_getter!(compress_offset, compress_offset, u64);
         impl_getter!(compress_size, compress_size, u32);
         impl_getter!(decompress_offset, decompress_offset, u64);
         impl_getter!(decompress_size, decompress_size, u32);
         impl_getter!(file_offset, file_offset, u64);
+        impl_getter!(batch_offset, batch_offset, u32);
         impl_getter!(flags, flags, RafsChunkFlags);
     }
 
@@ -852,9 +1013,89 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_add_batch_chunk_to_bio_desc() {
+        // A file of 1000 bytes packed at offset 3000 of a chunk of 8192 bytes.
+        let mut chunk = MockChunkInfo::new();
+        chunk.flags = RafsChunkFlags::BATCH;
+        chunk.decompress_size = 8192;
+        chunk.batch_offset = 3000;
+
+        let mut desc = RafsBioDesc::new();
+        assert!(add_chunk_to_bio_desc(
+            100,
+            1000,
+            Arc::new(chunk),
+            &mut desc,
+            8192,
+            "blobid".to_string(),
+        ));
+        assert_eq!(desc.bi_size, 900);
+        assert_eq!(desc.bi_vec[0].offset, 3100);
+
+        // The rest of the chunk is covered, `alloc_bio_desc` stops reads at end of file.
+        let mut desc = RafsBioDesc::new();
+        add_chunk_to_bio_desc(
+            0,
+            u64::MAX,
+            Arc::new(chunk),
+            &mut desc,
+            8192,
+            "blobid".to_string(),
+        );
+        assert_eq!(desc.bi_vec[0].offset, 3000);
+        assert_eq!(desc.bi_size, 8192 - 3000);
+    }
+
+    #[test]
+    fn test_validate_block_size() {
+        let mut sb = OndiskSuperBlock::new();
+        sb.set_inodes_count(1);
+        sb.set_inode_table_offset(RAFS_SUPERBLOCK_SIZE as u64);
+
+        for block_size in &[0x1_0000, 0x2_0000, 0x10_0000, 0x40_0000] {
+            sb.set_block_size(*block_size);
+            assert!(sb.validate().is_ok());
+        }
+        // Not a power of two, or out of 64KB to 4MB.
+        for block_size in &[0, 0x1000, 0x8000, 0x1_1000, 0x30_0000, 0x40_1000, 0x80_0000] {
+            sb.set_block_size(*block_size);
+            assert!(sb.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn test_validate_flags() {
+        let mut sb = OndiskSuperBlock::new();
+        sb.set_inodes_count(1);
+        sb.set_inode_table_offset(RAFS_SUPERBLOCK_SIZE as u64);
+        assert_eq!(sb.version(), RAFS_SUPER_VERSION_V5);
+        assert!(sb.validate().is_ok());
+
+        sb.set_flags(1 << 63);
+        assert!(sb.validate().is_err());
+
+        // Features nydusd unaware of would read wrong bump version.
+        sb.set_flags(0);
+        sb.set_inline_data();
+        assert_eq!(sb.version(), RAFS_SUPER_VERSION_V5_1);
+        assert!(sb.validate().is_ok());
+        sb.set_version(RAFS_SUPER_VERSION_V5);
+        assert!(sb.validate().is_err());
+
+        sb.set_flags(RafsSuperFlags::HAS_XATTR.bits());
+        assert!(sb.validate().is_ok());
+    }
+
     #[test]
     fn test_calculate_bio_chunk_index() {
-        let (blksize, chunk_cnt) = (1024, 4);
+        for blksize in &[1024, 0x1_0000, 0x40_0000] {
+            check_bio_chunk_index(*blksize);
+        }
+    }
+
+    fn check_bio_chunk_index(blksize: u64) {
+        let chunk_cnt = 4;
 
         let io_range: Vec<(u64, u64, u32, u64)> = vec![
             (0, 1, 0, 1),
@@ -897,4 +1138,150 @@ mod tests {
             assert_eq!(end, *expected_end as u32);
         }
     }
+
+    #[test]
+    fn test_search_bio_chunk_index() {
+        // Chunks of sizes 100, 300, 50 and 550.
+        let offsets = [0u64, 100, 400, 450];
+        let file_offset = |idx: u32| Ok(offsets[idx as usize]);
+        let io_range: &[(u64, u64, u32, u32)] = &[
+            (0, 1, 0, 1),
+            (0, 100, 0, 1),
+            (0, 101, 0, 2),
+            (99, 2, 0, 2),
+            (100, 300, 1, 2),
+            (150, 300, 1, 3),
+            (399, 52, 1, 4),
+            (450, 1, 3, 4),
+            (0, 1000, 0, 4),
+            (2000, 10, 3, 4),
+        ];
+
+        for (io_start, io_size, expected_start, expected_end) in io_range.iter() {
+            let (start, end) =
+                search_bio_chunk_index(*io_start, *io_start + *io_size, 4, file_offset).unwrap();
+            assert_eq!(start, *expected_start);
+            assert_eq!(end, *expected_end);
+        }
+        assert_eq!(
+            search_bio_chunk_index(0, 10, 0, file_offset).unwrap(),
+            (0, 0)
+        );
+    }
+
+    /// Load a bootstrap in cached mode of the tree:
+    /// /
+    /// ├── f1 (100 bytes)
+    /// ├── h1 (200 bytes)
+    /// ├── h2 (hardlink of h1)
+    /// └── sub
+    ///     ├── f2 (300 bytes)
+    ///     └── deep
+    ///         └── f3 (400 bytes)
+    fn load_locality_super() -> RafsSuper {
+        let mut file = TempFile::new().unwrap().into_file();
+        let mut writer = Box::new(file.try_clone().unwrap()) as RafsIoWriter;
+        // (name, ino, parent, mode, size, nlink, child count)
+        let inodes: &[(&str, u64, u64, u32, u64, u32, u32)] = &[
+            ("/", 1, 0, libc::S_IFDIR, 0, 3, 4),
+            ("f1", 2, 1, libc::S_IFREG, 100, 1, 1),
+            ("h1", 3, 1, libc::S_IFREG, 200, 2, 1),
+            ("h2", 3, 1, libc::S_IFREG, 200, 2, 1),
+            ("sub", 4, 1, libc::S_IFDIR, 0, 3, 2),
+            ("f2", 5, 4, libc::S_IFREG, 300, 1, 1),
+            ("deep", 6, 4, libc::S_IFDIR, 0, 2, 1),
+            ("f3", 7, 6, libc::S_IFREG, 400, 1, 1),
+        ];
+        for (name, ino, parent, mode, size, nlink, child_count) in inodes.iter() {
+            let name = OsString::from(name);
+            let mut inode = OndiskInode::new();
+            inode.i_name_size = name.byte_size() as u16;
+            inode.i_ino = *ino;
+            inode.i_parent = *parent;
+            inode.i_mode = *mode;
+            inode.i_size = *size;
+            inode.i_nlink = *nlink;
+            inode.i_child_count = *child_count;
+            OndiskInodeWrapper {
+                name: name.as_os_str(),
+                symlink: None,
+                inode: &inode,
+            }
+            .store(&mut writer)
+            .unwrap();
+
+            if *mode == libc::S_IFREG {
+                // Chunks are told apart by their offsets in blob, hardlinks share the chunk.
+                let mut chunk = OndiskChunkInfo::new();
+                chunk.compress_offset = *ino * 1000;
+                chunk.compress_size = *size as u32;
+                chunk.decompress_offset = *ino * 1000;
+                chunk.decompress_size = *size as u32;
+                chunk.store(&mut writer).unwrap();
+            }
+        }
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let mut reader = Box::new(file) as RafsIoReader;
+
+        let mut meta = RafsSuperMeta::default();
+        meta.block_size = RAFS_DEFAULT_BLOCK_SIZE as u32;
+        let mut blob_table = OndiskBlobTable::new();
+        blob_table.add(String::from("blob"), 0, 0, 0, 0, String::new());
+        let mut inodes = CachedInodes::new(meta, blob_table, false);
+        inodes.load(&mut reader).unwrap();
+
+        RafsSuper {
+            mode: RafsMode::Cached,
+            meta,
+            inodes: Arc::new(inodes),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_build_locality_desc() {
+        let rs = load_locality_super();
+        let sizes = |desc: RafsBioDesc| {
+            let sizes = desc.bi_vec.iter().map(|b| b.size).collect::<Vec<_>>();
+            assert_eq!(desc.bi_size, sizes.iter().sum::<usize>());
+            sizes
+        };
+        let no_skip = |_: &dyn RafsChunkInfo| false;
+
+        // Sub-directories are walked in BFS order no deeper than the depth, and chunks of
+        // hardlinks are collected once.
+        for (depth, expected) in [
+            (1, vec![100, 200]),
+            (2, vec![100, 200, 300]),
+            (3, vec![100, 200, 300, 400]),
+        ]
+        .iter()
+        {
+            let desc = rs
+                .build_locality_desc(RAFS_ROOT_INODE, *depth, u64::MAX, &no_skip)
+                .unwrap();
+            assert_eq!(&sizes(desc), expected);
+        }
+        let desc = rs.build_locality_desc(4, 2, u64::MAX, &no_skip).unwrap();
+        assert_eq!(sizes(desc), vec![300, 400]);
+
+        // Collecting stops before exceeding the size limit.
+        let desc = rs
+            .build_locality_desc(RAFS_ROOT_INODE, 3, 600, &no_skip)
+            .unwrap();
+        assert_eq!(sizes(desc), vec![100, 200, 300]);
+        let desc = rs
+            .build_locality_desc(RAFS_ROOT_INODE, 3, 599, &no_skip)
+            .unwrap();
+        assert_eq!(sizes(desc), vec![100, 200]);
+
+        // Skipped chunks, like cached ones, don't count towards the size limit.
+        let skip_f1 = |c: &dyn RafsChunkInfo| c.compress_offset() == 2000;
+        let desc = rs
+            .build_locality_desc(RAFS_ROOT_INODE, 3, 600, &skip_f1)
+            .unwrap();
+        assert_eq!(sizes(desc), vec![200, 300]);
+
+        assert!(rs.build_locality_desc(2, 1, u64::MAX, &no_skip).is_err());
+    }
 }
@@ -6,7 +6,7 @@
 
 use std::collections::{BTreeMap, HashMap};
 use std::ffi::{CString, OsString};
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::io::{BufWriter, Write};
 use std::mem::size_of;
 use std::os::unix::ffi::OsStrExt;
@@ -20,20 +20,24 @@ use sha2::digest::Digest;
 use sha2::Sha256;
 
 use rafs::metadata::layout::*;
-use rafs::metadata::{Inode, RafsMode, RafsStore, RafsSuper};
+use rafs::metadata::{Inode, RafsChunkFlags, RafsMode, RafsStore, RafsSuper};
 use rafs::{RafsIoRead, RafsIoWrite};
 use vmm_sys_util::tempfile::TempFile;
 // FIXME: Must image tool depend on storage backend?
-use storage::compress;
+use storage::{compress, encrypt};
 
 use nydus_utils::digest::{self, RafsDigest};
 
+use crate::chunker::Chunker;
 use crate::node::*;
-use crate::stargz;
 use crate::tree::Tree;
 
 // TODO: select BufWriter capacity by performance testing.
 const BUF_WRITER_CAPACITY: usize = 2 << 17;
+/// Files no larger than this are samples to train zstd dictionary.
+const DICT_SAMPLE_FILE_SIZE: u64 = 64 << 10;
+/// Samples needed to train a dictionary, in times of dictionary size.
+const DICT_SAMPLES_RATIO: usize = 100;
 
 pub struct Builder {
     /// Source type: Directory | StargzIndex
@@ -50,8 +54,22 @@ pub struct Builder {
     f_bootstrap: Box<dyn RafsIoWrite>,
     /// Parent bootstrap file reader.
     f_parent_bootstrap: Option<Box<dyn RafsIoRead>>,
+    /// How files are split into chunks, chunk size is fixed by stargz for stargz source.
+    chunker: Chunker,
+    /// Size limit of files stored inline in bootstrap, 0 if no file is stored inline.
+    inline_size: u64,
+    /// Size limit of files packed into batch chunks, 0 if no file is packed.
+    batch_size: u64,
     /// Blob chunk compress flag.
     compressor: compress::Algorithm,
+    /// Compression level, the default one of the compressor if it's none.
+    compress_level: Option<i32>,
+    /// Size limit of zstd dictionary trained from small files, 0 if no dictionary is needed.
+    compress_dict_size: usize,
+    /// Size of zstd dictionary at the head of blob.
+    blob_dict_size: u32,
+    /// Chunk encryption, chunks are not encrypted if it's none.
+    encryption: Option<BlobEncryption>,
     /// Inode and chunk digest algorithm flag.
     digester: digest::Algorithm,
     /// Save host uid gid in each inode.
@@ -83,6 +101,14 @@ pub struct Builder {
     blob_size: usize,
 }
 
+/// Encryption of chunks of the blob being built.
+pub struct BlobEncryption {
+    /// Id of the key recorded in blob table, by which nydusd finds the key.
+    pub key_id: String,
+    pub algorithm: encrypt::Algorithm,
+    pub cipher: encrypt::ChunkCipher,
+}
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum PrefetchPolicy {
     None,
@@ -143,7 +169,7 @@ pub enum BlobStorage {
 }
 
 impl BlobBufferWriter {
-    fn new(blob_stor: BlobStorage) -> Result<Self> {
+    pub fn new(blob_stor: BlobStorage) -> Result<Self> {
         match blob_stor {
             BlobStorage::SingleFile(ref p) => {
                 let b = BufWriter::with_capacity(
@@ -226,7 +252,13 @@ impl Builder {
         bootstrap_path: &Path,
         parent_bootstrap_path: &Path,
         blob_id: String,
+        chunker: Chunker,
+        inline_size: u64,
+        batch_size: u64,
         compressor: compress::Algorithm,
+        compress_level: Option<i32>,
+        compress_dict_size: usize,
+        encryption: Option<BlobEncryption>,
         digester: digest::Algorithm,
         hint_readahead_files: BTreeMap<PathBuf, Option<u64>>,
         prefetch_policy: PrefetchPolicy,
@@ -275,7 +307,14 @@ impl Builder {
             blob_writer: Mutex::new(bw),
             f_bootstrap,
             f_parent_bootstrap,
+            chunker,
+            inline_size,
+            batch_size,
             compressor,
+            compress_level,
+            compress_dict_size,
+            blob_dict_size: 0,
+            encryption,
             digester,
             explicit_uidgid,
             whiteout_spec,
@@ -484,6 +523,25 @@ impl Builder {
             );
         }
 
+        // Digests of lower layer chunks are reused, they must be validated with the same digester.
+        let lower_digester = rs.meta.get_digester();
+        if self.digester != lower_digester {
+            bail!(
+                "inconsistent digester with the lower layer, current {}, lower: {}.",
+                self.digester,
+                lower_digester
+            );
+        }
+
+        // Chunk indexes of all files are calculated with the chunk size of the image.
+        if self.chunker.max_size() != rs.meta.block_size {
+            bail!(
+                "inconsistent chunk size with the lower layer, current {}, lower: {}.",
+                self.chunker.max_size(),
+                rs.meta.block_size
+            );
+        }
+
         // Reuse lower layer blob table,
         // we need to append the blob entry of upper layer to the table
         self.blob_table = rs.inodes.get_blob_table().as_ref().clone();
@@ -519,6 +577,7 @@ impl Builder {
     pub fn build_from_filesystem(&mut self, layered: bool) -> Result<()> {
         let mut tree = Tree::from_filesystem(
             &self.source_path,
+            self.chunker.max_size(),
             self.explicit_uidgid,
             layered,
             &self.whiteout_spec,
@@ -545,6 +604,55 @@ impl Builder {
         Ok(())
     }
 
+    /// Train zstd dictionary from small files of this layer, none if they are too few.
+    fn train_dict(&self) -> Result<Option<compress::ZstdDict>> {
+        let mut samples = Vec::new();
+        let mut samples_size = 0;
+        for node in &self.nodes {
+            if !node.is_reg()
+                || node.is_hardlink()
+                // Files stored inline are not compressed.
+                || node.inode.i_size <= self.inline_size
+                || node.inode.i_size > DICT_SAMPLE_FILE_SIZE
+                || (node.overlay != Overlay::UpperAddition
+                    && node.overlay != Overlay::UpperModification)
+            {
+                continue;
+            }
+            let data = fs::read(&node.path)
+                .with_context(|| format!("failed to read node file {:?}", node.path))?;
+            if ChunkCompressor::is_compressed_format(&data) {
+                continue;
+            }
+            samples_size += data.len();
+            samples.push(data);
+            if samples_size >= self.compress_dict_size * DICT_SAMPLES_RATIO {
+                break;
+            }
+        }
+
+        match compress::ZstdDict::train(&samples, self.compress_dict_size) {
+            Ok(data) => {
+                let dict = compress::ZstdDict::new(data)?;
+                info!(
+                    "trained zstd dictionary {} of {} bytes from {} files",
+                    dict.id(),
+                    dict.as_bytes().len(),
+                    samples.len()
+                );
+                Ok(Some(dict))
+            }
+            Err(e) => {
+                warn!(
+                    "build without zstd dictionary, failed to train it from {} files: {}",
+                    samples.len(),
+                    e
+                );
+                Ok(None)
+            }
+        }
+    }
+
     /// Dump blob file and generate chunks
     fn dump_blob(&mut self) -> Result<(Sha256, usize, usize)> {
         // NOTE: Don't try to sort readahead files by their sizes,  thus to keep files
@@ -562,29 +670,66 @@ impl Builder {
         let mut blob_size = 0usize;
         let mut compress_offset = 0u64;
         let mut decompress_offset = 0u64;
+        let mut chunk_index = 0u32;
         let mut blob_hash = Sha256::new();
+        // Ranges of batch chunks by digests of file data, for deduplication of small files.
+        let mut batch_cache: HashMap<RafsDigest, OndiskChunkInfo> = HashMap::new();
+        let (inline_size, batch_size) = (self.inline_size, self.batch_size);
+        let batched = |node: &Node| {
+            node.is_reg() && node.inode.i_size > inline_size && node.inode.i_size <= batch_size
+        };
+        let dict = if self.source_type == SourceType::Directory && self.compress_dict_size > 0 {
+            self.train_dict()?
+        } else {
+            None
+        };
+        let chunk_compressor = ChunkCompressor {
+            algorithm: self.compressor,
+            level: self.compress_level,
+            dict: dict.as_ref(),
+        };
 
         match self.source_type {
             SourceType::Directory => {
                 // Safe to unwrap because `Directory source` must have blob
                 let mut guard = self.blob_writer.lock().expect("Poisoned lock");
                 let blob_writer = guard.as_mut().unwrap();
+                // Dump dictionary at the head of blob, it's needed before any chunk is read.
+                if let Some(dict) = dict.as_ref() {
+                    let data = dict.as_bytes();
+                    blob_hash.update(data);
+                    blob_writer
+                        .write_all(data)
+                        .context("failed to write dictionary to blob")?;
+                    compress_offset = data.len() as u64;
+                    blob_readahead_size = data.len();
+                    self.blob_dict_size = data.len() as u32;
+                }
                 // Dump readahead nodes
+                let mut batched_files = Vec::new();
                 for index in &readahead_files {
                     let node = self.nodes.get_mut(**index as usize - 1).unwrap();
                     debug!("[{}]\treadahead {}", node.overlay, node);
                     if node.overlay == Overlay::UpperAddition
                         || node.overlay == Overlay::UpperModification
                     {
+                        if batched(node) {
+                            batched_files.push(**index as usize - 1);
+                            continue;
+                        }
                         blob_readahead_size += node
                             .dump_blob(
                                 blob_writer,
                                 &mut blob_hash,
                                 &mut compress_offset,
                                 &mut decompress_offset,
+                                &mut chunk_index,
                                 &mut self.chunk_cache,
-                                self.compressor,
+                                chunk_compressor,
+                                self.encryption.as_ref(),
                                 self.digester,
+                                self.chunker,
+                                self.inline_size,
                                 blob_index,
                                 // TODO: Introduce build context to enclose the sparse states?
                                 self.aligned_chunk,
@@ -592,11 +737,29 @@ impl Builder {
                             .context("failed to dump readahead blob chunks")?;
                     }
                 }
+                blob_readahead_size += dump_batches(
+                    &mut self.nodes,
+                    &batched_files,
+                    &mut batch_cache,
+                    blob_writer,
+                    &mut blob_hash,
+                    &mut compress_offset,
+                    &mut decompress_offset,
+                    &mut chunk_index,
+                    chunk_compressor,
+                    self.encryption.as_ref(),
+                    self.digester,
+                    self.chunker.max_size(),
+                    blob_index,
+                    self.aligned_chunk,
+                )
+                .context("failed to dump readahead batch chunks")?;
 
                 blob_size += blob_readahead_size;
 
                 // Dump other nodes
-                for node in &mut self.nodes {
+                let mut batched_files = Vec::new();
+                for (index, node) in self.nodes.iter_mut().enumerate() {
                     if self.readahead_files.get(&node.rootfs()).is_some() {
                         continue;
                     }
@@ -606,6 +769,10 @@ impl Builder {
                         && (node.overlay == Overlay::UpperAddition
                             || node.overlay == Overlay::UpperModification)
                     {
+                        if batched(node) {
+                            batched_files.push(index);
+                            continue;
+                        }
                         // Safe to unwrap because `Directory source` must have blob
                         blob_size += node
                             .dump_blob(
@@ -613,18 +780,41 @@ impl Builder {
                                 &mut blob_hash,
                                 &mut compress_offset,
                                 &mut decompress_offset,
+                                &mut chunk_index,
                                 &mut self.chunk_cache,
-                                self.compressor,
+                                chunk_compressor,
+                                self.encryption.as_ref(),
                                 self.digester,
+                                self.chunker,
+                                self.inline_size,
                                 blob_index,
                                 self.aligned_chunk,
                             )
                             .context("failed to dump remaining blob chunks")?;
                     }
                 }
+                blob_size += dump_batches(
+                    &mut self.nodes,
+                    &batched_files,
+                    &mut batch_cache,
+                    blob_writer,
+                    &mut blob_hash,
+                    &mut compress_offset,
+                    &mut decompress_offset,
+                    &mut chunk_index,
+                    chunk_compressor,
+                    self.encryption.as_ref(),
+                    self.digester,
+                    self.chunker.max_size(),
+                    blob_index,
+                    self.aligned_chunk,
+                )
+                .context("failed to dump remaining batch chunks")?;
             }
             SourceType::StargzIndex => {
-                // Set blob index and inode digest for upper nodes
+                // Set blob index, chunk index and inode digest for upper nodes. Chunks
+                // shared by hardlinks are indexed only once.
+                let mut chunk_indexes: HashMap<RafsDigest, u32> = HashMap::new();
                 for node in &mut self.nodes {
                     if node.overlay.lower_layer() {
                         continue;
@@ -633,7 +823,10 @@ impl Builder {
                     let mut inode_hasher = RafsDigest::hasher(digest::Algorithm::Sha256);
 
                     for chunk in node.chunks.iter_mut() {
+                        let next_index = chunk_indexes.len() as u32;
                         (*chunk).blob_index = blob_index;
+                        (*chunk).index = *chunk_indexes.entry(chunk.block_id).or_insert(next_index);
+                        (*chunk).flags |= RafsChunkFlags::INDEXED;
                         inode_hasher.digest_update(chunk.block_id.as_ref());
                     }
 
@@ -667,8 +860,17 @@ impl Builder {
             if self.prefetch_policy != PrefetchPolicy::Blob {
                 blob_readahead_size = 0;
             }
-            self.blob_table
-                .add(self.blob_id.clone(), 0, blob_readahead_size as u32);
+            self.blob_table.add(
+                self.blob_id.clone(),
+                0,
+                blob_readahead_size as u32,
+                0,
+                self.blob_dict_size,
+                self.encryption
+                    .as_ref()
+                    .map(|e| e.key_id.clone())
+                    .unwrap_or_default(),
+            );
         }
 
         // Set inode digest, use reverse iteration order to reduce repeated digest calculations.
@@ -718,15 +920,22 @@ impl Builder {
         if self.explicit_uidgid {
             super_block.set_explicit_uidgid();
         }
-        if self.source_type == SourceType::StargzIndex {
-            super_block.set_block_size(stargz::DEFAULT_BLOCK_SIZE);
+        if self.blob_table.has_dict() {
+            super_block.set_blob_dict();
+        }
+        if self.blob_table.has_key_id() {
+            super_block.set_blob_key_id();
         }
+        super_block.set_block_size(self.chunker.max_size());
         super_block.set_prefetch_table_entries(prefetch_table_entries);
 
         let mut inode_offset =
             (super_block_size + inode_table_size + prefetch_table_size + blob_table_size) as u32;
 
         let mut has_xattr = false;
+        let mut variable_chunk = false;
+        let mut inline_data = false;
+        let mut batch_chunk = false;
         for node in &mut self.nodes {
             inode_table.set(node.index, inode_offset)?;
             // Add inode size
@@ -739,13 +948,32 @@ impl Builder {
             }
             // Add chunks size
             if node.is_reg() {
+                if let Some(data) = node.inline_data.as_ref() {
+                    inode_offset += align_to_rafs(data.len()) as u32;
+                    inline_data = true;
+                }
                 inode_offset +=
                     (node.inode.i_child_count as usize * size_of::<OndiskChunkInfo>()) as u32;
+                // Including inodes of lower layers.
+                variable_chunk |= node.inode.has_variable_chunk();
+                batch_chunk |= node
+                    .chunks
+                    .iter()
+                    .any(|c| c.flags.contains(RafsChunkFlags::BATCH));
             }
         }
         if has_xattr {
             super_block.set_has_xattr();
         }
+        if variable_chunk {
+            super_block.set_variable_chunk();
+        }
+        if inline_data {
+            super_block.set_inline_data();
+        }
+        if batch_chunk {
+            super_block.set_batch_chunk();
+        }
 
         // Dump bootstrap
         super_block
@@ -852,3 +1080,78 @@ impl Builder {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempdir::TempDir;
+
+    /// Build an image of `source` as `<name>.boot` and `<name>.blob` in `work_dir`, upon the
+    /// lower layer `parent` if it's not empty.
+    fn build(
+        source: &Path,
+        work_dir: &Path,
+        name: &str,
+        parent: &Path,
+        chunk_size: u32,
+    ) -> Result<()> {
+        let mut builder = Builder::new(
+            SourceType::Directory,
+            source,
+            Some(BlobStorage::SingleFile(
+                work_dir.join(format!("{}.blob", name)),
+            )),
+            &work_dir.join(format!("{}.boot", name)),
+            parent,
+            String::new(),
+            Chunker::Fixed(chunk_size),
+            0,
+            0,
+            compress::Algorithm::LZ4Block,
+            None,
+            0,
+            None,
+            digest::Algorithm::Blake3,
+            BTreeMap::new(),
+            PrefetchPolicy::None,
+            true,
+            WhiteoutSpec::Oci,
+            false,
+        )?;
+        builder.build().map(|_| ())
+    }
+
+    #[test]
+    fn test_lower_layer_chunk_size() {
+        let source = TempDir::new().unwrap();
+        fs::write(source.as_path().join("file"), vec![1u8; 0x2_0000]).unwrap();
+        let work_dir = TempDir::new().unwrap();
+        build(
+            source.as_path(),
+            work_dir.as_path(),
+            "lower",
+            Path::new(""),
+            0x1_0000,
+        )
+        .unwrap();
+        let lower = work_dir.as_path().join("lower.boot");
+
+        build(
+            source.as_path(),
+            work_dir.as_path(),
+            "upper",
+            &lower,
+            0x1_0000,
+        )
+        .unwrap();
+        let err = build(
+            source.as_path(),
+            work_dir.as_path(),
+            "upper",
+            &lower,
+            0x10_0000,
+        )
+        .unwrap_err();
+        assert!(format!("{:#}", err).contains("inconsistent chunk size"));
+    }
+}
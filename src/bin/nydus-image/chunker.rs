@@ -0,0 +1,317 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Split files into chunks.
+//!
+//! Files are split at fixed offsets by default. Content-defined chunking splits them where a
+//! rolling hash of the data matches instead, so that data inserted into or removed from a file
+//! only changes chunks around it, and the following chunks are still deduplicated with those of
+//! other versions of the file. It's FastCDC, a gear hash with normalized chunking.
+//!
+//! Chunks of zeros, either in holes of sparse files or written out, are not stored in blob.
+
+use std::cmp;
+use std::fs::File;
+use std::io::{Error, Result};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+
+/// Gear hash table. It must never change, or the same data would be split differently.
+const GEAR: [u64; 256] = gear_table();
+
+/// Random numbers generated by splitmix64.
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut seed = 0u64;
+    let mut i = 0;
+    while i < 256 {
+        seed = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = seed;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// Mask of the highest `bits` bits of gear hash, which are affected by the last 64 bytes.
+fn gear_mask(bits: u32) -> u64 {
+    !0u64 << (64 - bits)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Chunker {
+    /// Split at multiples of the size.
+    Fixed(u32),
+    /// Split by content into chunks of `min` to `max` bytes, `avg` bytes on average.
+    Cdc { min: u32, avg: u32, max: u32 },
+}
+
+impl Chunker {
+    /// Size of the largest chunks, recorded as block size of the image.
+    pub fn max_size(&self) -> u32 {
+        match *self {
+            Chunker::Fixed(size) => size,
+            Chunker::Cdc { max, .. } => max,
+        }
+    }
+
+    pub fn is_variable(&self) -> bool {
+        matches!(self, Chunker::Cdc { .. })
+    }
+
+    /// Size of the chunk at the head of `data`, which is all the remaining data of a file or
+    /// at least `max_size()` bytes of it.
+    fn cut(&self, data: &[u8]) -> usize {
+        match *self {
+            Chunker::Fixed(size) => cmp::min(data.len(), size as usize),
+            Chunker::Cdc { min, avg, max } => {
+                let len = cmp::min(data.len(), max as usize);
+                if len <= min as usize {
+                    return len;
+                }
+                // Cut points are harder to match before the average size and easier after it,
+                // which keeps sizes of most chunks close to the average.
+                let bits = avg.trailing_zeros();
+                let (mask_hard, mask_easy) = (gear_mask(bits + 1), gear_mask(bits - 1));
+                let normal = cmp::max(cmp::min(avg as usize, len), min as usize);
+                let mut hash = 0u64;
+                for (i, b) in data.iter().enumerate().take(len).skip(min as usize) {
+                    hash = (hash << 1).wrapping_add(GEAR[*b as usize]);
+                    let mask = if i < normal { mask_hard } else { mask_easy };
+                    if hash & mask == 0 {
+                        return i + 1;
+                    }
+                }
+                len
+            }
+        }
+    }
+}
+
+/// A chunk of a file.
+pub enum Chunk<'a> {
+    Data(&'a [u8]),
+    /// Chunk of the size which is all zeros, so that it needn't be stored.
+    Hole(usize),
+}
+
+/// Reader splitting a file into chunks. Holes of sparse files are not read, and whole chunks
+/// in them are skipped without even being filled with zeros.
+pub struct ChunkReader {
+    chunker: Chunker,
+    file: File,
+    size: u64,
+    buf: Vec<u8>,
+    /// Data in `buf[start..end]` is read but not yet returned in chunks.
+    start: usize,
+    end: usize,
+    /// File offset of data to be read into `buf[end..]`.
+    pos: u64,
+    /// Size of chunks cut from zeros.
+    zero_cut: Option<usize>,
+}
+
+impl ChunkReader {
+    /// Create a reader of the first `size` bytes of `file`.
+    pub fn new(chunker: Chunker, file: File, size: u64) -> Self {
+        ChunkReader {
+            chunker,
+            file,
+            size,
+            buf: vec![0u8; chunker.max_size() as usize],
+            start: 0,
+            end: 0,
+            pos: 0,
+            zero_cut: None,
+        }
+    }
+
+    /// Offset of the next data or hole at or after `offset`, by `SEEK_DATA` or `SEEK_HOLE`.
+    /// There is a hole at the end of file.
+    fn seek(&self, offset: u64, whence: libc::c_int) -> Result<u64> {
+        // Safe because it only changes file offset, which is not used by `read_exact_at`.
+        let ret = unsafe { libc::lseek(self.file.as_raw_fd(), offset as libc::off_t, whence) };
+        if ret < 0 {
+            let e = Error::last_os_error();
+            // There is no more data after the offset.
+            if e.raw_os_error() == Some(libc::ENXIO) {
+                return Ok(self.size);
+            }
+            return Err(e);
+        }
+
+        Ok(cmp::min(ret as u64, self.size))
+    }
+
+    /// Fill `buf[end..]` with data following `pos`, holes are filled with zeros.
+    fn fill(&mut self) -> Result<()> {
+        let stop =
+            self.end + cmp::min((self.buf.len() - self.end) as u64, self.size - self.pos) as usize;
+        while self.end < stop {
+            let data = self.seek(self.pos, libc::SEEK_DATA)?;
+            let size = if data > self.pos {
+                let size = cmp::min(data - self.pos, (stop - self.end) as u64) as usize;
+                for b in &mut self.buf[self.end..self.end + size] {
+                    *b = 0;
+                }
+                size
+            } else {
+                let hole = self.seek(self.pos, libc::SEEK_HOLE)?;
+                let size = cmp::min(hole - self.pos, (stop - self.end) as u64) as usize;
+                self.file
+                    .read_exact_at(&mut self.buf[self.end..self.end + size], self.pos)?;
+                size
+            };
+            self.end += size;
+            self.pos += size as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Read the next chunk, none at the end of file.
+    pub fn next_chunk(&mut self) -> Result<Option<Chunk<'_>>> {
+        let max_size = self.buf.len();
+        let offset = self.pos - (self.end - self.start) as u64;
+        if offset >= self.size {
+            return Ok(None);
+        }
+
+        // Chunks in holes are the same as those cut from zeros.
+        if offset + max_size as u64 <= self.size
+            && self.seek(offset, libc::SEEK_DATA)? >= offset + max_size as u64
+        {
+            let chunker = self.chunker;
+            let size = *self
+                .zero_cut
+                .get_or_insert_with(|| chunker.cut(&vec![0u8; max_size]));
+            if size <= self.end - self.start {
+                self.start += size;
+            } else {
+                self.pos = offset + size as u64;
+                self.start = 0;
+                self.end = 0;
+            }
+            return Ok(Some(Chunk::Hole(size)));
+        }
+
+        // Chunks are cut with a full buffer ahead, so that how data is read doesn't matter.
+        if self.pos < self.size && self.end - self.start < max_size {
+            self.buf.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+            self.fill()?;
+        }
+
+        let start = self.start;
+        self.start += self.chunker.cut(&self.buf[start..self.end]);
+        let data = &self.buf[start..self.start];
+        if data.iter().all(|b| *b == 0) {
+            return Ok(Some(Chunk::Hole(data.len())));
+        }
+
+        Ok(Some(Chunk::Data(data)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempfile::TempFile;
+
+    /// Split a file into chunks, telling holes from data.
+    fn split_file(chunker: Chunker, file: File, size: u64) -> Vec<(bool, Vec<u8>)> {
+        let mut reader = ChunkReader::new(chunker, file, size);
+        let mut chunks = Vec::new();
+        while let Some(chunk) = reader.next_chunk().unwrap() {
+            chunks.push(match chunk {
+                Chunk::Data(data) => (false, data.to_vec()),
+                Chunk::Hole(size) => (true, vec![0u8; size]),
+            });
+        }
+        chunks
+    }
+
+    fn split(chunker: Chunker, data: &[u8]) -> Vec<Vec<u8>> {
+        let file = TempFile::new().unwrap().into_file();
+        file.write_all_at(data, 0).unwrap();
+        let chunks = split_file(chunker, file, data.len() as u64)
+            .into_iter()
+            .map(|(_, c)| c)
+            .collect::<Vec<_>>();
+        assert_eq!(chunks.concat(), data);
+        chunks
+    }
+
+    fn random_data(size: usize) -> Vec<u8> {
+        let mut seed = 1u64;
+        (0..size)
+            .map(|_| {
+                seed = seed.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+                (seed >> 56) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_chunker() {
+        let data = random_data(1 << 20);
+
+        let chunks = split(Chunker::Fixed(65536), &data);
+        assert_eq!(chunks.len(), 16);
+        assert!(split(Chunker::Fixed(65536), &[]).is_empty());
+
+        let cdc = Chunker::Cdc {
+            min: 2048,
+            avg: 8192,
+            max: 32768,
+        };
+        let chunks = split(cdc, &data);
+        // Only the last chunk may be smaller than the minimum size.
+        assert!(chunks[..chunks.len() - 1]
+            .iter()
+            .all(|c| c.len() >= 2048 && c.len() <= 32768));
+        assert!(chunks.len() > 64 && chunks.len() < 256);
+        assert_eq!(split(cdc, &data[..100]).len(), 1);
+
+        // Chunks following an insertion are the same as before.
+        let mut inserted = data[..100_000].to_vec();
+        inserted.extend_from_slice(b"inserted");
+        inserted.extend_from_slice(&data[100_000..]);
+        let others = split(cdc, &inserted);
+        let same = others.iter().filter(|c| chunks.contains(c)).count();
+        assert!(same >= chunks.len() - 2);
+    }
+
+    #[test]
+    fn test_chunker_holes() {
+        let size = (4 << 20) + 100;
+        let data = random_data(70000);
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(size).unwrap();
+        file.write_all_at(&data, (1 << 20) + 10).unwrap();
+        let mut content = vec![0u8; size as usize];
+        content[(1 << 20) + 10..(1 << 20) + 10 + data.len()].copy_from_slice(&data);
+
+        let cdc = Chunker::Cdc {
+            min: 4096,
+            avg: 16384,
+            max: 65536,
+        };
+        for chunker in &[Chunker::Fixed(65536), cdc] {
+            let chunks = split_file(*chunker, file.try_clone().unwrap(), size);
+            assert_eq!(
+                chunks.iter().map(|(_, c)| c.as_slice()).collect::<Vec<_>>(),
+                split(*chunker, &content)
+            );
+            // Zeros are holes whether they are written or not.
+            assert!(chunks
+                .iter()
+                .all(|(hole, c)| *hole == c.iter().all(|b| *b == 0)));
+            assert!(chunks.iter().filter(|(hole, _)| !hole).count() >= 2);
+        }
+    }
+}
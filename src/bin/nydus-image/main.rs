@@ -13,6 +13,7 @@ extern crate nydus_utils;
 mod trace;
 
 mod builder;
+mod chunker;
 mod node;
 mod stargz;
 mod tree;
@@ -25,11 +26,17 @@ extern crate serde;
 extern crate lazy_static;
 
 const BLOB_ID_MAXIMUM_LENGTH: usize = 1024;
+/// Size limits of zstd dictionary, it's not worth more than a chunk.
+const MIN_DICT_SIZE: usize = 1024;
+const MAX_DICT_SIZE: usize = 1 << 20;
+/// Chunks are not worth being split smaller, by metadata and backend requests they cost.
+const MIN_CDC_SIZE: u32 = 4096;
 
 use anyhow::{bail, Context, Result};
 use clap::{App, Arg, SubCommand};
 
 use std::collections::BTreeMap;
+use std::fs;
 use std::fs::metadata;
 use std::fs::OpenOptions;
 use std::io;
@@ -39,9 +46,13 @@ use nix::unistd::{getegid, geteuid};
 use serde::Serialize;
 
 use builder::{BlobStorage, SourceType};
+use chunker::Chunker;
 use node::WhiteoutSpec;
 use nydus_utils::{digest, setup_logging, BuildTimeInfo};
-use storage::compress;
+use rafs::metadata::layout::RAFS_MAX_INLINE_SIZE;
+use storage::{
+    compress, encrypt, RAFS_DEFAULT_BLOCK_SIZE, RAFS_MAX_BLOCK_SIZE, RAFS_MIN_BLOCK_SIZE,
+};
 use trace::{EventTracerClass, TimingTracerClass, TraceClass};
 use validator::Validator;
 
@@ -171,18 +182,103 @@ fn main() -> Result<()> {
                         .help("blob id (as object id in backend/oss)")
                         .takes_value(true),
                 )
+                .arg(
+                    Arg::with_name("chunk-size")
+                        .long("chunk-size")
+                        .help("size in bytes of chunks files are split into, a power of two from 65536 to 4194304, 1048576 (default)")
+                        .takes_value(true)
+                        .required(false),
+                )
+                .arg(
+                    Arg::with_name("chunking")
+                        .long("chunking")
+                        .help("how files are split into chunks: fixed (default) at multiples of chunk-size, cdc by content into chunks no larger than chunk-size")
+                        .takes_value(true)
+                        .required(false)
+                        .possible_values(&["fixed", "cdc"])
+                        .default_value("fixed"),
+                )
+                .arg(
+                    Arg::with_name("cdc-avg-size")
+                        .long("cdc-avg-size")
+                        .help("average size in bytes of cdc chunks, a power of two, a quarter of chunk-size (default)")
+                        .takes_value(true)
+                        .required(false),
+                )
+                .arg(
+                    Arg::with_name("cdc-min-size")
+                        .long("cdc-min-size")
+                        .help("minimal size in bytes of cdc chunks, a quarter of cdc-avg-size (default)")
+                        .takes_value(true)
+                        .required(false),
+                )
+                .arg(
+                    Arg::with_name("inline-data-size")
+                        .long("inline-data-size")
+                        .help("size limit in bytes of files stored inline in bootstrap rather than in blob, up to 65536, 0 (default) for no inline data")
+                        .takes_value(true)
+                        .required(false)
+                        .default_value("0"),
+                )
+                .arg(
+                    Arg::with_name("batch-size")
+                        .long("batch-size")
+                        .help("size limit in bytes of files packed together into shared chunks, up to chunk size, 0 (default) for a chunk per file")
+                        .takes_value(true)
+                        .required(false)
+                        .default_value("0"),
+                )
                 .arg(
                     Arg::with_name("compressor")
                         .long("compressor")
-                        .help("how blob will be compressed: none, lz4_block (default)")
+                        .help("how blob will be compressed: none, lz4_block (default), zstd")
                         .takes_value(true)
                         .required(false)
                         .default_value("lz4_block"),
                 )
+                .arg(
+                    Arg::with_name("compress-level")
+                        .long("compress-level")
+                        .help("compression level of compressor: 1 to 9 for gzip, 1 to 22 for zstd")
+                        .takes_value(true)
+                        .required(false),
+                )
+                .arg(
+                    Arg::with_name("compress-dict-size")
+                        .long("compress-dict-size")
+                        .help("size limit in bytes of zstd dictionary trained from small files, 0 (default) for no dictionary")
+                        .takes_value(true)
+                        .required(false)
+                        .default_value("0"),
+                )
+                .arg(
+                    Arg::with_name("encrypt-key-id")
+                        .long("encrypt-key-id")
+                        .help("id of the key which chunks are encrypted with, recorded in bootstrap for nydusd to find the key")
+                        .takes_value(true)
+                        .requires("encrypt-key-file")
+                        .required(false),
+                )
+                .arg(
+                    Arg::with_name("encrypt-key-file")
+                        .long("encrypt-key-file")
+                        .help("file of the key which chunks are encrypted with, 32 bytes or 64 hex digits")
+                        .takes_value(true)
+                        .requires("encrypt-key-id")
+                        .required(false),
+                )
+                .arg(
+                    Arg::with_name("encrypt-algorithm")
+                        .long("encrypt-algorithm")
+                        .help("how chunks are encrypted: aes-256-gcm (default), chacha20-poly1305")
+                        .takes_value(true)
+                        .required(false)
+                        .default_value("aes-256-gcm"),
+                )
                 .arg(
                     Arg::with_name("digester")
                         .long("digester")
-                        .help("how inode and blob chunk will be digested: blake3 (default), sha256")
+                        .help("how inode and blob chunk will be digested: blake3 (default), sha256, sha512-256, xxh3-128 (fast but not collision resistant, for trusted images only)")
                         .takes_value(true)
                         .required(false)
                         .default_value("blake3"),
@@ -310,8 +406,20 @@ fn main() -> Result<()> {
             }
         }
 
-        let mut compressor = matches.value_of("compressor").unwrap_or_default().parse()?;
+        let mut compressor: compress::Algorithm =
+            matches.value_of("compressor").unwrap_or_default().parse()?;
+        let compress_level = matches
+            .value_of("compress-level")
+            .map(|l| l.parse::<i32>())
+            .transpose()
+            .context("invalid compression level")?;
         let mut digester = matches.value_of("digester").unwrap_or_default().parse()?;
+        let mut chunk_size = matches
+            .value_of("chunk-size")
+            .map(|s| s.parse::<u32>())
+            .transpose()
+            .context("invalid chunk size")?;
+        let cdc = matches.value_of("chunking") == Some("cdc");
         let repeatable = matches.is_present("repeatable");
 
         match source_type {
@@ -331,12 +439,142 @@ fn main() -> Result<()> {
                     trace!("compressor set to {}", compress::Algorithm::GZip);
                 }
                 compressor = compress::Algorithm::GZip;
+                if compress_level.is_some() {
+                    bail!("compress-level is not supported by stargz source");
+                }
                 if digester != digest::Algorithm::Sha256 {
                     trace!("digester set to {}", digest::Algorithm::Sha256);
                 }
                 digester = digest::Algorithm::Sha256;
+                if chunk_size.is_some() {
+                    bail!("chunk-size is not supported by stargz source");
+                }
+                chunk_size = Some(stargz::DEFAULT_BLOCK_SIZE);
+                if cdc {
+                    bail!("cdc chunking is not supported by stargz source");
+                }
+            }
+        }
+        let chunk_size = chunk_size.unwrap_or(RAFS_DEFAULT_BLOCK_SIZE as u32);
+        if !chunk_size.is_power_of_two()
+            || (chunk_size as u64) < RAFS_MIN_BLOCK_SIZE
+            || (chunk_size as u64) > RAFS_MAX_BLOCK_SIZE
+        {
+            bail!(
+                "chunk-size should be a power of two from {} to {}",
+                RAFS_MIN_BLOCK_SIZE,
+                RAFS_MAX_BLOCK_SIZE
+            );
+        }
+        let chunker = if cdc {
+            let parse_size = |name: &str| {
+                matches
+                    .value_of(name)
+                    .map(|s| s.parse::<u32>())
+                    .transpose()
+                    .with_context(|| format!("invalid {}", name))
+            };
+            let avg = parse_size("cdc-avg-size")?.unwrap_or(chunk_size / 4);
+            let min = parse_size("cdc-min-size")?.unwrap_or(avg / 4);
+            if !avg.is_power_of_two() || avg >= chunk_size {
+                bail!("cdc-avg-size should be a power of two less than chunk-size");
+            }
+            if min < MIN_CDC_SIZE || min >= avg {
+                bail!(
+                    "cdc-min-size should be at least {} and less than cdc-avg-size",
+                    MIN_CDC_SIZE
+                );
+            }
+            Chunker::Cdc {
+                min,
+                avg,
+                max: chunk_size,
+            }
+        } else {
+            if matches.is_present("cdc-avg-size") || matches.is_present("cdc-min-size") {
+                bail!("cdc-avg-size and cdc-min-size are only supported by cdc chunking");
+            }
+            Chunker::Fixed(chunk_size)
+        };
+        let inline_size: u64 = matches
+            .value_of("inline-data-size")
+            .unwrap_or_default()
+            .parse()
+            .context("invalid inline data size")?;
+        if inline_size != 0 {
+            if source_type != SourceType::Directory {
+                bail!("inline-data-size is only supported by directory source");
+            }
+            if inline_size > RAFS_MAX_INLINE_SIZE {
+                bail!(
+                    "inline-data-size should be at most {}",
+                    RAFS_MAX_INLINE_SIZE
+                );
+            }
+        }
+        let batch_size: u64 = matches
+            .value_of("batch-size")
+            .unwrap_or_default()
+            .parse()
+            .context("invalid batch size")?;
+        if batch_size != 0 {
+            if source_type != SourceType::Directory {
+                bail!("batch-size is only supported by directory source");
             }
+            if batch_size > chunker.max_size() as u64 {
+                bail!("batch-size should be at most chunk-size");
+            }
+        }
+        if let Some(level) = compress_level {
+            compressor.check_level(level)?;
         }
+        let compress_dict_size: usize = matches
+            .value_of("compress-dict-size")
+            .unwrap_or_default()
+            .parse()
+            .context("invalid compression dictionary size")?;
+        if compress_dict_size != 0 {
+            if source_type != SourceType::Directory || compressor != compress::Algorithm::Zstd {
+                bail!("compress-dict-size is only supported by zstd with directory source");
+            }
+            if compress_dict_size < MIN_DICT_SIZE || compress_dict_size > MAX_DICT_SIZE {
+                bail!(
+                    "compress-dict-size should be {} to {}",
+                    MIN_DICT_SIZE,
+                    MAX_DICT_SIZE
+                );
+            }
+        }
+
+        let encryption = if let Some(key_id) = matches.value_of("encrypt-key-id") {
+            if source_type != SourceType::Directory {
+                bail!("encryption is only supported by directory source");
+            }
+            // Dictionary is trained from file data, but stored in blob unencrypted.
+            if compress_dict_size != 0 {
+                bail!("compress-dict-size is not supported with encryption");
+            }
+            // Inline data is stored in bootstrap, which is never encrypted.
+            if inline_size != 0 {
+                bail!("inline-data-size is not supported with encryption");
+            }
+            if key_id.is_empty() {
+                bail!("encrypt-key-id can't be empty");
+            }
+            let key_file = matches.value_of("encrypt-key-file").unwrap();
+            let key = fs::read(key_file)
+                .with_context(|| format!("failed to read key file {}", key_file))?;
+            Some(builder::BlobEncryption {
+                key_id: key_id.to_string(),
+                algorithm: matches
+                    .value_of("encrypt-algorithm")
+                    .unwrap_or_default()
+                    .parse()?,
+                cipher: encrypt::ChunkCipher::new(&encrypt::parse_key(&key)?),
+            })
+        } else {
+            None
+        };
 
         let bootstrap_path = Path::new(matches.value_of("bootstrap").unwrap());
 
@@ -409,7 +647,13 @@ fn main() -> Result<()> {
             bootstrap_path,
             parent_bootstrap,
             blob_id,
+            chunker,
+            inline_size,
+            batch_size,
             compressor,
+            compress_level,
+            compress_dict_size,
+            encryption,
             digester,
             hint_readahead_files,
             prefetch_policy,
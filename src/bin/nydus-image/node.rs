@@ -4,6 +4,7 @@
 
 //! File node for RAFS format
 
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
 use std::fmt;
@@ -27,7 +28,8 @@ use nydus_utils::{
     div_round_up, try_round_up_4k, ByteSize,
 };
 
-use crate::builder::BlobBufferWriter;
+use crate::builder::{BlobBufferWriter, BlobEncryption};
+use crate::chunker::{Chunk, ChunkReader, Chunker};
 
 use rafs::metadata::layout::*;
 use rafs::metadata::*;
@@ -35,6 +37,28 @@ use storage::compress;
 
 const ROOT_PATH_NAME: &[u8] = &[b'/'];
 
+/// Magic numbers of compressed formats, whose data hardly compresses any more.
+const COMPRESSED_MAGICS: &[&[u8]] = &[
+    // gzip
+    &[0x1f, 0x8b],
+    // zstd
+    &[0x28, 0xb5, 0x2f, 0xfd],
+    // xz
+    &[0xfd, b'7', b'z', b'X', b'Z', 0x00],
+    // bzip2
+    &[b'B', b'Z', b'h'],
+    // lz4 frame
+    &[0x04, 0x22, 0x4d, 0x18],
+    // zip, jar
+    &[b'P', b'K', 0x03, 0x04],
+    // 7z
+    &[b'7', b'z', 0xbc, 0xaf, 0x27, 0x1c],
+    // png
+    &[0x89, b'P', b'N', b'G'],
+    // jpeg
+    &[0xff, 0xd8, 0xff],
+];
+
 pub const OCISPEC_WHITEOUT_PREFIX: &str = ".wh.";
 pub const OCISPEC_WHITEOUT_OPAQUE: &str = ".wh..wh..opq";
 pub const OVERLAYFS_WHITEOUT_OPAQUE: &str = "trusted.overlay.opaque";
@@ -53,6 +77,43 @@ impl WhiteoutType {
     }
 }
 
+/// How chunks of files are compressed. The compressor is chosen per file and recorded in
+/// each chunk, so that nydusd decompresses chunks accordingly.
+#[derive(Clone, Copy)]
+pub struct ChunkCompressor<'a> {
+    /// Compressor of the image, recorded in superblock.
+    pub algorithm: compress::Algorithm,
+    /// Compression level, the default one of the compressor if it's none.
+    pub level: Option<i32>,
+    /// Zstd dictionary of the blob.
+    pub dict: Option<&'a compress::ZstdDict>,
+}
+
+impl<'a> ChunkCompressor<'a> {
+    /// Whether data of a file starting with `head` is in a compressed format.
+    pub fn is_compressed_format(head: &[u8]) -> bool {
+        COMPRESSED_MAGICS.iter().any(|m| head.starts_with(m))
+    }
+
+    /// Choose compressor for chunks of a file by its first chunk. Files of compressed formats
+    /// are stored raw, compressing them again takes time but hardly saves any space.
+    fn choose(&self, head: &[u8]) -> Self {
+        if Self::is_compressed_format(head) {
+            ChunkCompressor {
+                algorithm: compress::Algorithm::None,
+                level: None,
+                dict: None,
+            }
+        } else {
+            *self
+        }
+    }
+
+    fn compress<'b>(&self, data: &'b [u8]) -> std::io::Result<(Cow<'b, [u8]>, bool)> {
+        compress::compress_with_dict(data, self.algorithm, self.level, self.dict)
+    }
+}
+
 #[derive(PartialEq)]
 pub enum WhiteoutSpec {
     /// https://github.com/opencontainers/image-spec/blob/master/layer.md#whiteouts
@@ -149,6 +210,8 @@ pub struct Node {
     pub chunks: Vec<OndiskChunkInfo>,
     /// Symlink info of symlink file
     pub symlink: Option<OsString>,
+    /// Data of small regular file stored inline in bootstrap, rather than in chunks
+    pub inline_data: Option<Vec<u8>>,
     /// Xattr list of file
     pub xattrs: XAttrs,
     pub explicit_uidgid: bool,
@@ -159,6 +222,7 @@ impl Node {
         source: PathBuf,
         path: PathBuf,
         overlay: Overlay,
+        chunk_size: u32,
         explicit_uidgid: bool,
     ) -> Result<Node> {
         let mut node = Node {
@@ -172,10 +236,12 @@ impl Node {
             inode: OndiskInode::new(),
             chunks: Vec::new(),
             symlink: None,
+            inline_data: None,
             xattrs: XAttrs::default(),
             explicit_uidgid,
         };
-        node.build_inode().context("failed to build inode")?;
+        node.build_inode(chunk_size)
+            .context("failed to build inode")?;
         Ok(node)
     }
 
@@ -218,9 +284,13 @@ impl Node {
         blob_hash: &mut Sha256,
         compress_offset: &mut u64,
         decompress_offset: &mut u64,
+        chunk_index: &mut u32,
         chunk_cache: &mut HashMap<RafsDigest, OndiskChunkInfo>,
-        compressor: compress::Algorithm,
+        compressor: ChunkCompressor,
+        encryption: Option<&BlobEncryption>,
         digester: digest::Algorithm,
+        chunker: Chunker,
+        inline_size: u64,
         blob_index: u32,
         aligned_chunk: bool,
     ) -> Result<usize> {
@@ -238,34 +308,62 @@ impl Node {
         }
 
         let file_size = self.inode.i_size;
+        // Small files are stored inline in bootstrap, so reading them needs no blob at all.
+        if file_size > 0 && file_size <= inline_size {
+            let data = fs::read(&self.path)
+                .with_context(|| format!("failed to read node file {:?}", self.path))?;
+            if data.len() as u64 != file_size {
+                bail!("file {:?} is changed during build", self.path);
+            }
+            self.inode.i_digest = RafsDigest::from_buf(&data, digester);
+            self.inode.i_flags |= RafsInodeFlags::INLINE_DATA;
+            self.inode.i_child_count = 0;
+            self.chunks.clear();
+            self.inline_data = Some(data);
+            event_tracer!("inline_data_size", +file_size);
+            return Ok(0);
+        }
+
         let mut blob_size = 0usize;
         let mut inode_hasher = RafsDigest::hasher(digester);
-        let mut file = File::open(&self.path)
+        let file = File::open(&self.path)
             .with_context(|| format!("failed to open node file {:?}", self.path))?;
-
-        for i in 0..self.inode.i_child_count {
+        let mut reader = ChunkReader::new(chunker, file, file_size);
+        let mut file_compressor = None;
+        let mut hole_digests: HashMap<usize, RafsDigest> = HashMap::new();
+        let mut file_offset = 0u64;
+        self.chunks.clear();
+
+        while let Some(data) = reader
+            .next_chunk()
+            .with_context(|| format!("failed to read node file {:?}", self.path))?
+        {
             // Init chunk info
             let mut chunk = OndiskChunkInfo::new();
-            // FIXME: Should not assume that block size must be the default one.
-            // Use the configured value instead!
-            let file_offset = i as u64 * RAFS_DEFAULT_BLOCK_SIZE;
-            let chunk_size = if i == self.inode.i_child_count - 1 {
-                file_size - (RAFS_DEFAULT_BLOCK_SIZE * i as u64)
-            } else {
-                RAFS_DEFAULT_BLOCK_SIZE
+            let chunk_data = match data {
+                Chunk::Data(chunk_data) => chunk_data,
+                Chunk::Hole(size) => {
+                    // Hole chunks take no space in blob, reading them gets zeros.
+                    chunk.block_id = *hole_digests
+                        .entry(size)
+                        .or_insert_with(|| RafsDigest::from_buf(&vec![0u8; size], digester));
+                    inode_hasher.digest_update(chunk.block_id.as_ref());
+                    chunk.flags = RafsChunkFlags::HOLECHUNK;
+                    chunk.file_offset = file_offset;
+                    chunk.decompress_size = size as u32;
+                    self.chunks.push(chunk);
+                    file_offset += size as u64;
+                    event_tracer!("hole_decompressed_size", +size);
+                    trace!("\t\tbuilding hole chunk: {}", chunk);
+                    continue;
+                }
             };
-
-            // Read chunk data
-            // TODO: Hopefully, we don't have to allocate memory from heap each time.
-            // and the `usize` type restriction won't bother us anymore.
-            let mut chunk_data = vec![0; chunk_size as usize];
-            file.read_exact(&mut chunk_data)
-                .with_context(|| format!("failed to read node file {:?}", self.path))?;
+            let chunk_size = chunk_data.len() as u64;
+            let file_compressor =
+                *file_compressor.get_or_insert_with(|| compressor.choose(chunk_data));
 
             // Calculate chunk digest
-            // TODO: check for hole chunks. One possible way is to always save
-            // a global hole chunk and check for digest duplication
-            chunk.block_id = RafsDigest::from_buf(chunk_data.as_slice(), digester);
+            chunk.block_id = RafsDigest::from_buf(chunk_data, digester);
             // Calculate inode digest
             inode_hasher.digest_update(chunk.block_id.as_ref());
 
@@ -278,10 +376,11 @@ impl Node {
                     chunk.clone_from(&cached_chunk);
                     chunk.file_offset = file_offset;
                     self.chunks.push(chunk);
+                    file_offset += chunk_size;
                     trace!(
                         "\t\tbuilding duplicated chunk: {} compressor {}",
                         chunk,
-                        compressor
+                        file_compressor.algorithm
                     );
 
                     // The chunks of hardlink should be always deduplicated, so don't
@@ -295,47 +394,36 @@ impl Node {
                 }
             }
 
-            // Compress chunk data
-            let (compressed, is_compressed) = compress::compress(&chunk_data, compressor)
-                .with_context(|| format!("failed to compress node file {:?}", self.path))?;
-            let compressed_size = compressed.len();
-            if is_compressed {
-                chunk.flags |= RafsChunkFlags::COMPRESSED;
-            }
-
-            chunk.blob_index = blob_index;
             chunk.file_offset = file_offset;
-            chunk.compress_offset = *compress_offset;
-            chunk.decompress_offset = *decompress_offset;
-            chunk.compress_size = compressed_size as u32;
-            chunk.decompress_size = chunk_size as u32;
-            blob_size += compressed_size;
-
-            // Move cursor to offset of next chunk
-            *compress_offset += compressed_size as u64;
-            *decompress_offset += if aligned_chunk {
-                // Safe to unwrap since we can't have such a large chunk
-                // and conversion between u64 values is safe.
-                try_round_up_4k(chunk_size).unwrap()
-            } else {
-                chunk_size
-            };
-
-            // Calculate blob hash
-            blob_hash.update(&compressed);
-
-            // Dump compressed chunk data to blob
-            event_tracer!("blob_decompressed_size", +chunk_size);
-            event_tracer!("blob_compressed_size", +compressed_size);
-            blob_writer
-                .write_all(&compressed)
-                .context("failed to write blob")?;
+            blob_size += dump_chunk(
+                &mut chunk,
+                chunk_data,
+                blob_writer,
+                blob_hash,
+                compress_offset,
+                decompress_offset,
+                chunk_index,
+                file_compressor,
+                encryption,
+                blob_index,
+                aligned_chunk,
+            )
+            .with_context(|| format!("failed to dump node file {:?}", self.path))?;
 
             // Cache chunk digest info
             chunk_cache.insert(chunk.block_id, chunk);
             self.chunks.push(chunk);
+            file_offset += chunk_size;
 
-            trace!("\t\tbuilding chunk: {} compressor {}", chunk, compressor,);
+            trace!(
+                "\t\tbuilding chunk: {} compressor {}",
+                chunk,
+                file_compressor.algorithm
+            );
+        }
+        self.inode.i_child_count = self.chunks.len() as u32;
+        if chunker.is_variable() && !self.chunks.is_empty() {
+            self.inode.i_flags |= RafsInodeFlags::VARIABLE_CHUNK;
         }
 
         // Finish inode digest calculation
@@ -344,6 +432,15 @@ impl Node {
         Ok(blob_size)
     }
 
+    /// Refer the file to the range of its data in a batch chunk.
+    fn set_batch_chunk(&mut self, chunk: &OndiskChunkInfo, digester: digest::Algorithm) {
+        let mut inode_hasher = RafsDigest::hasher(digester);
+        inode_hasher.digest_update(chunk.block_id.as_ref());
+        self.inode.i_digest = inode_hasher.digest_finalize();
+        self.inode.i_child_count = 1;
+        self.chunks = vec![*chunk];
+    }
+
     pub fn dump_bootstrap(&mut self, f_bootstrap: &mut RafsIoWriter) -> Result<usize> {
         let mut node_size = 0;
 
@@ -368,6 +465,13 @@ impl Node {
             node_size += xattr_size;
         }
 
+        // Dump inline data
+        if let Some(data) = self.inline_data.as_ref() {
+            node_size += OndiskInlineData(data)
+                .store(f_bootstrap)
+                .context("failed to dump inline data to bootstrap")?;
+        }
+
         // Dump chunk info
         if self.is_reg() && self.inode.i_child_count as usize != self.chunks.len() {
             bail!("invalid chunks count {}: {}", self.chunks.len(), self);
@@ -415,7 +519,7 @@ impl Node {
         Ok(())
     }
 
-    fn build_inode(&mut self) -> Result<()> {
+    fn build_inode(&mut self, chunk_size: u32) -> Result<()> {
         self.inode.set_name_size(self.name().byte_size());
 
         // NOTE: Always retrieve xattr before attr so that we can know
@@ -425,7 +529,7 @@ impl Node {
             .with_context(|| format!("failed to build inode {:?}", self.path))?;
 
         if self.is_reg() {
-            self.inode.i_child_count = self.chunk_count() as u32;
+            self.inode.i_child_count = self.chunk_count(chunk_size) as u32;
         } else if self.is_symlink() {
             self.inode.i_flags |= RafsInodeFlags::SYMLINK;
             let target_path = fs::read_link(&self.path)?;
@@ -475,11 +579,11 @@ impl Node {
         self.inode.i_nlink > 1
     }
 
-    pub fn chunk_count(&self) -> usize {
+    pub fn chunk_count(&self, chunk_size: u32) -> usize {
         if !self.is_reg() {
             return 0;
         }
-        div_round_up(self.inode.i_size, RAFS_DEFAULT_BLOCK_SIZE) as usize
+        div_round_up(self.inode.i_size, chunk_size as u64) as usize
     }
 
     pub fn file_type(&self) -> &str {
@@ -589,3 +693,324 @@ impl Node {
         None
     }
 }
+
+/// Compress, encrypt and write data of `chunk` to blob, setting where the chunk is in blob.
+/// Returns size of the chunk in blob.
+#[allow(clippy::too_many_arguments)]
+fn dump_chunk(
+    chunk: &mut OndiskChunkInfo,
+    data: &[u8],
+    blob_writer: &mut BlobBufferWriter,
+    blob_hash: &mut Sha256,
+    compress_offset: &mut u64,
+    decompress_offset: &mut u64,
+    chunk_index: &mut u32,
+    compressor: ChunkCompressor,
+    encryption: Option<&BlobEncryption>,
+    blob_index: u32,
+    aligned_chunk: bool,
+) -> Result<usize> {
+    let chunk_size = data.len() as u64;
+
+    // Compress chunk data
+    let (compressed, is_compressed) = compressor
+        .compress(data)
+        .context("failed to compress chunk")?;
+    if is_compressed {
+        chunk.flags |= RafsChunkFlags::from_compressor(compressor.algorithm);
+    }
+    // Encrypt chunk data after compression, as encrypted data doesn't compress.
+    let encrypted;
+    let compressed = if let Some(encryption) = encryption {
+        encrypted = encryption
+            .cipher
+            .encrypt(encryption.algorithm, &compressed, chunk.block_id.as_ref())
+            .context("failed to encrypt chunk")?;
+        chunk.flags |= RafsChunkFlags::from_encryptor(encryption.algorithm);
+        encrypted.as_slice()
+    } else {
+        compressed.as_ref()
+    };
+    let compressed_size = compressed.len();
+
+    chunk.blob_index = blob_index;
+    chunk.index = *chunk_index;
+    chunk.flags |= RafsChunkFlags::INDEXED;
+    chunk.compress_offset = *compress_offset;
+    chunk.decompress_offset = *decompress_offset;
+    chunk.compress_size = compressed_size as u32;
+    chunk.decompress_size = chunk_size as u32;
+
+    // Move cursor to offset of next chunk
+    *chunk_index += 1;
+    *compress_offset += compressed_size as u64;
+    *decompress_offset += if aligned_chunk {
+        // Safe to unwrap since we can't have such a large chunk
+        // and conversion between u64 values is safe.
+        try_round_up_4k(chunk_size).unwrap()
+    } else {
+        chunk_size
+    };
+
+    // Calculate blob hash
+    blob_hash.update(compressed);
+
+    // Dump compressed chunk data to blob
+    event_tracer!("blob_decompressed_size", +chunk_size);
+    event_tracer!("blob_compressed_size", +compressed_size);
+    blob_writer
+        .write_all(compressed)
+        .context("failed to write blob")?;
+
+    Ok(compressed_size)
+}
+
+/// Small files being packed into a chunk.
+#[derive(Default)]
+struct ChunkBatch {
+    data: Vec<u8>,
+    /// Indexes of nodes of the files and offsets of their data in the chunk.
+    members: Vec<(usize, u32)>,
+    /// Offsets of data in the chunk by its digest, files of the same data share it.
+    offsets: HashMap<RafsDigest, u32>,
+}
+
+impl ChunkBatch {
+    fn add(&mut self, index: usize, digest: RafsDigest, data: &[u8]) {
+        let offset = match self.offsets.get(&digest) {
+            Some(offset) => *offset,
+            None => {
+                let offset = self.data.len() as u32;
+                self.data.extend_from_slice(data);
+                self.offsets.insert(digest, offset);
+                offset
+            }
+        };
+        self.members.push((index, offset));
+    }
+}
+
+/// Pack data of small files, which are `nodes` at `indexes`, into chunks of up to `chunk_size`
+/// bytes rather than a chunk per file, so that they are compressed together. Each file refers
+/// to the range of its data in a chunk, files of the same data share the range.
+/// Returns size of the chunks in blob.
+#[allow(clippy::too_many_arguments)]
+pub fn dump_batches(
+    nodes: &mut [Node],
+    indexes: &[usize],
+    batch_cache: &mut HashMap<RafsDigest, OndiskChunkInfo>,
+    blob_writer: &mut BlobBufferWriter,
+    blob_hash: &mut Sha256,
+    compress_offset: &mut u64,
+    decompress_offset: &mut u64,
+    chunk_index: &mut u32,
+    compressor: ChunkCompressor,
+    encryption: Option<&BlobEncryption>,
+    digester: digest::Algorithm,
+    chunk_size: u32,
+    blob_index: u32,
+    aligned_chunk: bool,
+) -> Result<usize> {
+    // Write the chunk of files packed so far, and refer the files to it.
+    let mut flush = |batch: &mut ChunkBatch,
+                     nodes: &mut [Node],
+                     batch_cache: &mut HashMap<RafsDigest, OndiskChunkInfo>|
+     -> Result<usize> {
+        if batch.data.is_empty() {
+            return Ok(0);
+        }
+        let mut chunk = OndiskChunkInfo::new();
+        chunk.block_id = RafsDigest::from_buf(&batch.data, digester);
+        chunk.flags = RafsChunkFlags::BATCH;
+        let size = dump_chunk(
+            &mut chunk,
+            &batch.data,
+            blob_writer,
+            blob_hash,
+            compress_offset,
+            decompress_offset,
+            chunk_index,
+            compressor,
+            encryption,
+            blob_index,
+            aligned_chunk,
+        )
+        .context("failed to dump batch chunk")?;
+        trace!("\t\tbuilding batch chunk: {}", chunk);
+        event_tracer!("batch_chunks", +1);
+
+        for (digest, offset) in batch.offsets.drain() {
+            chunk.batch_offset = offset;
+            batch_cache.insert(digest, chunk);
+        }
+        for (index, offset) in batch.members.drain(..) {
+            chunk.batch_offset = offset;
+            nodes[index].set_batch_chunk(&chunk, digester);
+        }
+        batch.data.clear();
+
+        Ok(size)
+    };
+
+    let mut blob_size = 0;
+    let mut batch = ChunkBatch::default();
+    for &index in indexes {
+        let node = &nodes[index];
+        let data = fs::read(&node.path)
+            .with_context(|| format!("failed to read node file {:?}", node.path))?;
+        if data.len() as u64 != node.inode.i_size {
+            bail!("file {:?} is changed during build", node.path);
+        }
+        let digest = RafsDigest::from_buf(&data, digester);
+        let packed = batch.offsets.contains_key(&digest);
+        // The chunks of hardlink should be always deduplicated, so don't trace them.
+        if (packed || batch_cache.contains_key(&digest)) && !node.is_hardlink() {
+            event_tracer!("dedup_decompressed_size", +data.len());
+        }
+
+        if let Some(chunk) = batch_cache.get(&digest) {
+            nodes[index].set_batch_chunk(chunk, digester);
+            continue;
+        }
+        if !packed && batch.data.len() + data.len() > chunk_size as usize {
+            blob_size += flush(&mut batch, nodes, batch_cache)?;
+        }
+        batch.add(index, digest, &data);
+    }
+    blob_size += flush(&mut batch, nodes, batch_cache)?;
+
+    Ok(blob_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempdir::TempDir;
+    use vmm_sys_util::tempfile::TempFile;
+
+    use crate::builder::BlobStorage;
+
+    const NO_COMPRESSION: ChunkCompressor = ChunkCompressor {
+        algorithm: compress::Algorithm::None,
+        level: None,
+        dict: None,
+    };
+
+    /// Dump a file of `data` into a new blob, returns the node and data of the blob.
+    fn dump_file(data: &[u8], chunker: Chunker, compressor: ChunkCompressor) -> (Node, Vec<u8>) {
+        let source = TempDir::new().unwrap();
+        let path = source.as_path().join("file");
+        fs::write(&path, data).unwrap();
+        let mut node = Node::new(
+            source.as_path().to_path_buf(),
+            path,
+            Overlay::UpperAddition,
+            chunker.max_size(),
+            true,
+        )
+        .unwrap();
+
+        let blob = TempFile::new().unwrap();
+        let mut blob_writer =
+            BlobBufferWriter::new(BlobStorage::SingleFile(blob.as_path().to_path_buf())).unwrap();
+        let (mut compress_offset, mut decompress_offset, mut chunk_index) = (0, 0, 0);
+        node.dump_blob(
+            &mut blob_writer,
+            &mut Sha256::new(),
+            &mut compress_offset,
+            &mut decompress_offset,
+            &mut chunk_index,
+            &mut HashMap::new(),
+            compressor,
+            None,
+            digest::Algorithm::Blake3,
+            chunker,
+            0,
+            0,
+            false,
+        )
+        .unwrap();
+        // Buffered data is flushed on drop.
+        drop(blob_writer);
+
+        (node, fs::read(blob.as_path()).unwrap())
+    }
+
+    #[test]
+    fn test_choose_compressor() {
+        let lz4 = ChunkCompressor {
+            algorithm: compress::Algorithm::LZ4Block,
+            level: None,
+            dict: None,
+        };
+        assert_eq!(
+            lz4.choose(b"plain text").algorithm,
+            compress::Algorithm::LZ4Block
+        );
+        assert_eq!(
+            lz4.choose(&[0x1f, 0x8b, 0x08, 0x00]).algorithm,
+            compress::Algorithm::None
+        );
+
+        // Compressible data is compressed.
+        let text = (0..0x2_0000u32)
+            .map(|i| (i % 7) as u8 + b'a')
+            .collect::<Vec<_>>();
+        let (node, blob) = dump_file(&text, Chunker::Fixed(0x1_0000), lz4);
+        assert!(blob.len() < text.len());
+        for chunk in node.chunks.iter() {
+            assert_eq!(
+                chunk.flags,
+                RafsChunkFlags::from_compressor(compress::Algorithm::LZ4Block)
+                    | RafsChunkFlags::INDEXED
+            );
+        }
+
+        // All chunks of a file of compressed format are stored raw.
+        let mut gzip = vec![0x1f, 0x8b, 0x08, 0x00];
+        gzip.extend_from_slice(&text);
+        let (node, blob) = dump_file(&gzip, Chunker::Fixed(0x1_0000), lz4);
+        assert_eq!(blob, gzip);
+        assert_eq!(node.chunks.len(), 3);
+        assert!(node
+            .chunks
+            .iter()
+            .all(|c| c.flags == RafsChunkFlags::INDEXED));
+
+        // Incompressible data is stored raw as well.
+        let mut seed = 1u64;
+        let random = (0..0x1_0000)
+            .map(|_| {
+                seed = seed.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+                (seed >> 56) as u8
+            })
+            .collect::<Vec<_>>();
+        let (node, blob) = dump_file(&random, Chunker::Fixed(0x1_0000), lz4);
+        assert_eq!(blob, random);
+        assert_eq!(node.chunks[0].flags, RafsChunkFlags::INDEXED);
+        assert_eq!(node.chunks[0].compress_size, 0x1_0000);
+    }
+
+    #[test]
+    fn test_dump_blob_chunk_size() {
+        let chunk_size = 0x1_0000usize;
+        let data = (0..chunk_size * 3 + 100)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let (node, blob) = dump_file(&data, Chunker::Fixed(chunk_size as u32), NO_COMPRESSION);
+
+        assert_eq!(node.chunk_count(chunk_size as u32), 4);
+        assert_eq!(node.chunk_count(0x10_0000), 1);
+        assert_eq!(node.inode.i_child_count, 4);
+        assert_eq!(blob, data);
+        for (i, chunk) in node.chunks.iter().enumerate() {
+            assert_eq!(chunk.index, i as u32);
+            assert_eq!(chunk.file_offset, (i * chunk_size) as u64);
+            assert_eq!(chunk.decompress_offset, (i * chunk_size) as u64);
+            assert_eq!(
+                chunk.decompress_size as usize,
+                std::cmp::min(chunk_size, data.len() - i * chunk_size)
+            );
+        }
+    }
+}
@@ -56,7 +56,8 @@ fn cast_chunk_info(cki: &dyn RafsChunkInfo) -> OndiskChunkInfo {
         compress_offset: cki.compress_offset(),
         decompress_offset: cki.decompress_offset(),
         file_offset: cki.file_offset(),
-        reserved: 0u64,
+        index: cki.index(),
+        batch_offset: cki.batch_offset(),
     }
 }
 
@@ -99,7 +100,12 @@ impl<'a> MetadataTreeBuilder<'a> {
             let child = self.parse_node(child, child_path.clone())?;
             if let Some(chunk_cache) = chunk_cache {
                 if child.is_reg() {
-                    for chunk in &child.chunks {
+                    // Files packed into batch chunks have only parts of the chunks.
+                    for chunk in child
+                        .chunks
+                        .iter()
+                        .filter(|c| !c.flags.contains(RafsChunkFlags::BATCH))
+                    {
                         chunk_cache.insert(chunk.block_id, *chunk);
                     }
                 }
@@ -135,6 +141,13 @@ impl<'a> MetadataTreeBuilder<'a> {
             None
         };
 
+        // Parse inline data
+        let inline_data = if inode.has_inline_data() {
+            Some(inode.get_inline_data()?)
+        } else {
+            None
+        };
+
         // Parse xattrs
         let mut xattrs = XAttrs::new();
         for name in inode.get_xattrs()? {
@@ -160,6 +173,7 @@ impl<'a> MetadataTreeBuilder<'a> {
             inode: ondisk_inode,
             chunks,
             symlink,
+            inline_data,
             xattrs,
         })
     }
@@ -243,7 +257,9 @@ impl StargzIndexTreeBuilder {
                     // No available data on entry
                     decompress_offset: 0,
                     file_offset: entry.chunk_offset as u64,
-                    reserved: 0u64,
+                    // Will be set later
+                    index: 0,
+                    batch_offset: 0u32,
                 };
                 if let Some((size, chunks)) = file_chunk_map.get_mut(&entry.path()?) {
                     chunks.push(chunk);
@@ -383,6 +399,7 @@ impl StargzIndexTreeBuilder {
             inode,
             chunks,
             symlink,
+            inline_data: None,
             xattrs,
         })
     }
@@ -390,12 +407,17 @@ impl StargzIndexTreeBuilder {
 
 struct FilesystemTreeBuilder {
     root_path: PathBuf,
+    chunk_size: u32,
     layered: bool,
 }
 
 impl FilesystemTreeBuilder {
-    fn new(root_path: PathBuf, layered: bool) -> Self {
-        Self { root_path, layered }
+    fn new(root_path: PathBuf, chunk_size: u32, layered: bool) -> Self {
+        Self {
+            root_path,
+            chunk_size,
+            layered,
+        }
     }
 
     /// Walk directory to build node tree by DFS,
@@ -419,6 +441,7 @@ impl FilesystemTreeBuilder {
                 self.root_path.clone(),
                 path.clone(),
                 Overlay::UpperAddition,
+                self.chunk_size,
                 parent.explicit_uidgid,
             )
             .with_context(|| format!("failed to create node {:?}", path))?;
@@ -495,16 +518,18 @@ impl Tree {
     /// Build node tree from a filesystem directory
     pub fn from_filesystem(
         root_path: &PathBuf,
+        chunk_size: u32,
         explicit_uidgid: bool,
         layered: bool,
         whiteout_spec: &WhiteoutSpec,
     ) -> Result<Self> {
-        let tree_builder = FilesystemTreeBuilder::new(root_path.clone(), layered);
+        let tree_builder = FilesystemTreeBuilder::new(root_path.clone(), chunk_size, layered);
 
         let node = Node::new(
             root_path.clone(),
             root_path.clone(),
             Overlay::UpperAddition,
+            chunk_size,
             explicit_uidgid,
         )?;
         let mut tree = Tree::new(node);
@@ -4,6 +4,7 @@
 // SPDX-License-Identifier: (Apache-2.0 AND BSD-3-Clause)
 
 use std::convert::From;
+use std::path::Path;
 use std::str::FromStr;
 use std::sync::mpsc::{Receiver, Sender};
 use std::sync::Arc;
@@ -14,8 +15,9 @@ use nix::unistd::Pid;
 use vmm_sys_util::{epoll::EventSet, eventfd::EventFd};
 
 use nydus_api::http_endpoint::{
-    ApiError, ApiMountCmd, ApiRequest, ApiResponse, ApiResponsePayload, ApiResult, DaemonConf,
-    DaemonErrorKind, MetricsErrorKind,
+    ApiAccessTraceCmd, ApiBlobcacheArchiveCmd, ApiBlobcacheEvictCmd, ApiError, ApiMountCmd,
+    ApiPrefetchCmd, ApiPrefetchConf, ApiRequest, ApiResponse, ApiResponsePayload, ApiResult,
+    DaemonConf, DaemonErrorKind, MetricsErrorKind,
 };
 use nydus_utils::metrics;
 
@@ -69,10 +71,26 @@ impl ApiServer {
             ApiRequest::ExportGlobalMetrics(id) => Self::export_global_metrics(id),
             ApiRequest::ExportFilesMetrics(id) => Self::export_files_metrics(id),
             ApiRequest::ExportAccessPatterns(id) => Self::export_access_patterns(id),
+            ApiRequest::PersistAccessTrace((id, cmd)) => Self::persist_access_trace(id, cmd),
             ApiRequest::ExportBackendMetrics(id) => Self::export_backend_metrics(id),
             ApiRequest::ExportBlobcacheMetrics(id) => Self::export_blobcache_metrics(id),
             ApiRequest::ExportInflightMetrics => self.export_inflight_metrics(),
             ApiRequest::ExportFsBackendInfo(mountpoint) => self.backend_info(&mountpoint),
+            ApiRequest::Prefetch((mountpoint, cmd)) => self.do_prefetch(&mountpoint, cmd),
+            ApiRequest::ConfigurePrefetch((mountpoint, conf)) => {
+                self.configure_prefetch(&mountpoint, conf)
+            }
+            ApiRequest::ExportPrefetchProgress(mountpoint) => self.prefetch_progress(&mountpoint),
+            ApiRequest::ScrubBlobcache(mountpoint) => self.scrub_blobcache(&mountpoint),
+            ApiRequest::ListBlobcache(mountpoint) => self.list_blobcache(&mountpoint),
+            ApiRequest::EvictBlobcache((mountpoint, cmd)) => self.evict_blobcache(&mountpoint, cmd),
+            ApiRequest::GcBlobcache => self.gc_blobcache(),
+            ApiRequest::ExportBlobcache((mountpoint, cmd)) => {
+                self.export_blobcache(&mountpoint, cmd)
+            }
+            ApiRequest::ImportBlobcache((mountpoint, cmd)) => {
+                self.import_blobcache(&mountpoint, cmd)
+            }
             ApiRequest::SendFuseFd => self.send_fuse_fd(),
             ApiRequest::Takeover => self.do_takeover(),
             ApiRequest::Exit => self.do_exit(),
@@ -110,6 +128,109 @@ impl ApiServer {
         Ok(ApiResponsePayload::FsBackendInfo(info))
     }
 
+    fn do_prefetch(&self, mountpoint: &str, cmd: ApiPrefetchCmd) -> ApiResponse {
+        self.daemon
+            .prefetch_files(mountpoint, cmd.files)
+            .map(|_| ApiResponsePayload::Empty)
+            .map_err(|e| ApiError::DaemonAbnormal(e.into()))
+    }
+
+    fn configure_prefetch(&self, mountpoint: &str, conf: ApiPrefetchConf) -> ApiResponse {
+        self.daemon
+            .configure_prefetch(
+                mountpoint,
+                conf.paused,
+                conf.threads_count,
+                conf.bandwidth_rate,
+            )
+            .map(|_| ApiResponsePayload::Empty)
+            .map_err(|e| ApiError::PrefetchFailure(e.into()))
+    }
+
+    /// Export prefetch progress of rafs at `mountpoint` in unit of Bytes,
+    /// ```json
+    /// {
+    ///   "planned": 20971520,
+    ///   "done": 8388608,
+    ///   "paused": false,
+    ///   "threads_count": 4,
+    ///   "bandwidth_rate": 10485760
+    /// }
+    /// ```
+    fn prefetch_progress(&self, mountpoint: &str) -> ApiResponse {
+        let progress = self
+            .daemon
+            .export_prefetch_progress(mountpoint)
+            .map_err(|e| ApiError::PrefetchFailure(e.into()))?;
+        Ok(ApiResponsePayload::PrefetchProgress(progress))
+    }
+
+    /// Scrubbing is done in background, check `scrub_*` fields of blobcache metrics for progress.
+    fn scrub_blobcache(&self, mountpoint: &str) -> ApiResponse {
+        self.daemon
+            .scrub_cache(mountpoint)
+            .map(|_| ApiResponsePayload::Empty)
+            .map_err(|e| ApiError::DaemonAbnormal(e.into()))
+    }
+
+    /// List blobs in blobcache directory of rafs at `mountpoint`,
+    /// ```json
+    /// [
+    ///   {
+    ///     "blob_id": "7d3f9c...",
+    ///     "cached_size": 8388608,
+    ///     "blob_size": 20971520,
+    ///     "last_access": 1609459200,
+    ///     "in_use": true
+    ///   }
+    /// ]
+    /// ```
+    fn list_blobcache(&self, mountpoint: &str) -> ApiResponse {
+        let blobs = self
+            .daemon
+            .export_cached_blobs(mountpoint)
+            .map_err(|e| ApiError::DaemonAbnormal(e.into()))?;
+        Ok(ApiResponsePayload::CachedBlobs(blobs))
+    }
+
+    fn evict_blobcache(&self, mountpoint: &str, cmd: ApiBlobcacheEvictCmd) -> ApiResponse {
+        self.daemon
+            .evict_cache(mountpoint, cmd.blob_id, cmd.files)
+            .map(|_| ApiResponsePayload::Empty)
+            .map_err(|e| ApiError::DaemonAbnormal(e.into()))
+    }
+
+    /// Respond with ids of the removed blobs.
+    fn gc_blobcache(&self) -> ApiResponse {
+        let removed = self
+            .daemon
+            .gc_cache()
+            .map_err(|e| ApiError::DaemonAbnormal(e.into()))?;
+        Ok(ApiResponsePayload::GcBlobcache(removed))
+    }
+
+    /// Respond with number of exported chunks, `{"chunks": 1024}`.
+    fn export_blobcache(&self, mountpoint: &str, cmd: ApiBlobcacheArchiveCmd) -> ApiResponse {
+        let count = self
+            .daemon
+            .export_cache(mountpoint, &cmd.path, cmd.blob_ids)
+            .map_err(|e| ApiError::DaemonAbnormal(e.into()))?;
+        Ok(ApiResponsePayload::CacheArchive(
+            serde_json::json!({ "chunks": count }).to_string(),
+        ))
+    }
+
+    /// Respond with number of imported chunks, `{"chunks": 1024}`.
+    fn import_blobcache(&self, mountpoint: &str, cmd: ApiBlobcacheArchiveCmd) -> ApiResponse {
+        let count = self
+            .daemon
+            .import_cache(mountpoint, &cmd.path)
+            .map_err(|e| ApiError::DaemonAbnormal(e.into()))?;
+        Ok(ApiResponsePayload::CacheArchive(
+            serde_json::json!({ "chunks": count }).to_string(),
+        ))
+    }
+
     fn configure_daemon(&self, conf: DaemonConf) -> ApiResponse {
         conf.log_level
             .parse::<log::LevelFilter>()
@@ -142,6 +263,12 @@ impl ApiServer {
             .map_err(|e| ApiError::Metrics(MetricsErrorKind::Stats(e)))
     }
 
+    fn persist_access_trace(id: Option<String>, cmd: ApiAccessTraceCmd) -> ApiResponse {
+        metrics::persist_access_trace(&id, Path::new(&cmd.path))
+            .map(|_| ApiResponsePayload::Empty)
+            .map_err(|e| ApiError::Metrics(MetricsErrorKind::Stats(e)))
+    }
+
     fn export_backend_metrics(id: Option<String>) -> ApiResponse {
         metrics::export_backend_metrics(&id)
             .map(ApiResponsePayload::BackendMetrics)
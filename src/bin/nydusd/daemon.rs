@@ -6,7 +6,7 @@
 
 use std::any::Any;
 use std::cmp::PartialEq;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::From;
 use std::fmt::{Display, Formatter};
 use std::io::Result;
@@ -81,7 +81,10 @@ impl From<i32> for DaemonState {
 
 impl From<RafsError> for DaemonError {
     fn from(error: RafsError) -> Self {
-        DaemonError::Rafs(error)
+        match error {
+            RafsError::Unsupported => DaemonError::Unsupported,
+            e => DaemonError::Rafs(e),
+        }
     }
 }
 
@@ -314,6 +317,171 @@ pub trait NydusDaemon: DaemonStateMachineSubscriber {
         Ok(resp)
     }
 
+    fn prefetch_files(&self, mountpoint: &str, files: Vec<String>) -> DaemonResult<()> {
+        let files = input_prefetch_files_verify(&Some(files))?.unwrap_or_default();
+        let fs = self
+            .backend_from_mountpoint(mountpoint)?
+            .ok_or(DaemonError::NotFound)?;
+        let any_fs = fs.deref().as_any();
+        let rafs = any_fs
+            .downcast_ref::<Rafs>()
+            .ok_or_else(|| DaemonError::FsTypeMismatch("to rafs".to_string()))?;
+        rafs.prefetch_files(&files)?;
+        Ok(())
+    }
+
+    fn configure_prefetch(
+        &self,
+        mountpoint: &str,
+        paused: Option<bool>,
+        threads_count: Option<usize>,
+        bandwidth_rate: Option<u32>,
+    ) -> DaemonResult<()> {
+        let fs = self
+            .backend_from_mountpoint(mountpoint)?
+            .ok_or(DaemonError::NotFound)?;
+        let any_fs = fs.deref().as_any();
+        let rafs = any_fs
+            .downcast_ref::<Rafs>()
+            .ok_or_else(|| DaemonError::FsTypeMismatch("to rafs".to_string()))?;
+        rafs.tune_prefetch(threads_count, bandwidth_rate)?;
+        if let Some(paused) = paused {
+            rafs.pause_prefetch(paused)?;
+        }
+        Ok(())
+    }
+
+    fn export_prefetch_progress(&self, mountpoint: &str) -> DaemonResult<String> {
+        let fs = self
+            .backend_from_mountpoint(mountpoint)?
+            .ok_or(DaemonError::NotFound)?;
+        let any_fs = fs.deref().as_any();
+        let rafs = any_fs
+            .downcast_ref::<Rafs>()
+            .ok_or_else(|| DaemonError::FsTypeMismatch("to rafs".to_string()))?;
+        let progress = rafs.prefetch_progress()?;
+        serde_json::to_string(&progress).map_err(DaemonError::Serde)
+    }
+
+    fn scrub_cache(&self, mountpoint: &str) -> DaemonResult<()> {
+        let fs = self
+            .backend_from_mountpoint(mountpoint)?
+            .ok_or(DaemonError::NotFound)?;
+        let any_fs = fs.deref().as_any();
+        let rafs = any_fs
+            .downcast_ref::<Rafs>()
+            .ok_or_else(|| DaemonError::FsTypeMismatch("to rafs".to_string()))?;
+        rafs.scrub_cache()?;
+        Ok(())
+    }
+
+    fn export_cached_blobs(&self, mountpoint: &str) -> DaemonResult<String> {
+        let fs = self
+            .backend_from_mountpoint(mountpoint)?
+            .ok_or(DaemonError::NotFound)?;
+        let any_fs = fs.deref().as_any();
+        let rafs = any_fs
+            .downcast_ref::<Rafs>()
+            .ok_or_else(|| DaemonError::FsTypeMismatch("to rafs".to_string()))?;
+        let blobs = rafs.list_cached_blobs()?;
+        serde_json::to_string(&blobs).map_err(DaemonError::Serde)
+    }
+
+    fn evict_cache(
+        &self,
+        mountpoint: &str,
+        blob_id: Option<String>,
+        files: Option<Vec<String>>,
+    ) -> DaemonResult<()> {
+        if blob_id.is_none() && files.is_none() {
+            return Err(DaemonError::InvalidArguments(
+                "Either blob id or files should be specified".to_string(),
+            ));
+        }
+        let files = input_prefetch_files_verify(&files)?;
+        let fs = self
+            .backend_from_mountpoint(mountpoint)?
+            .ok_or(DaemonError::NotFound)?;
+        let any_fs = fs.deref().as_any();
+        let rafs = any_fs
+            .downcast_ref::<Rafs>()
+            .ok_or_else(|| DaemonError::FsTypeMismatch("to rafs".to_string()))?;
+        if let Some(blob_id) = blob_id {
+            rafs.evict_cached_blob(&blob_id)?;
+        }
+        if let Some(files) = files {
+            rafs.evict_cached_files(&files)?;
+        }
+        Ok(())
+    }
+
+    fn export_cache(
+        &self,
+        mountpoint: &str,
+        path: &str,
+        blob_ids: Option<Vec<String>>,
+    ) -> DaemonResult<usize> {
+        let fs = self
+            .backend_from_mountpoint(mountpoint)?
+            .ok_or(DaemonError::NotFound)?;
+        let any_fs = fs.deref().as_any();
+        let rafs = any_fs
+            .downcast_ref::<Rafs>()
+            .ok_or_else(|| DaemonError::FsTypeMismatch("to rafs".to_string()))?;
+        let count = rafs.export_cache(&blob_ids.unwrap_or_default(), Path::new(path))?;
+        Ok(count)
+    }
+
+    fn import_cache(&self, mountpoint: &str, path: &str) -> DaemonResult<usize> {
+        let fs = self
+            .backend_from_mountpoint(mountpoint)?
+            .ok_or(DaemonError::NotFound)?;
+        let any_fs = fs.deref().as_any();
+        let rafs = any_fs
+            .downcast_ref::<Rafs>()
+            .ok_or_else(|| DaemonError::FsTypeMismatch("to rafs".to_string()))?;
+        let count = rafs.import_cache(Path::new(path))?;
+        Ok(count)
+    }
+
+    /// Remove cached blobs not referred by any rafs mount, from cache directories of all
+    /// rafs mounts.
+    fn gc_cache(&self) -> DaemonResult<String> {
+        let mountpoints = self
+            .backend_collection()
+            .0
+            .values()
+            .filter(|d| d.backend_type == FsBackendType::Rafs)
+            .map(|d| d.mountpoint.clone())
+            .collect::<Vec<String>>();
+        let mut backends = Vec::new();
+        for mp in mountpoints {
+            if let Some(fs) = self.backend_from_mountpoint(&mp)? {
+                backends.push(fs);
+            }
+        }
+
+        let mounts = backends
+            .iter()
+            .filter_map(|fs| fs.deref().as_any().downcast_ref::<Rafs>())
+            .collect::<Vec<&Rafs>>();
+        let referenced = mounts
+            .iter()
+            .flat_map(|rafs| rafs.blob_ids())
+            .collect::<HashSet<String>>();
+        let mut removed = Vec::new();
+        for rafs in mounts {
+            match rafs.gc_cache(&referenced) {
+                Ok(ids) => removed.extend(ids),
+                // Rafs without blobcache.
+                Err(RafsError::Unsupported) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        serde_json::to_string(&removed).map_err(DaemonError::Serde)
+    }
+
     fn backend_from_mountpoint(&self, mp: &str) -> DaemonResult<Option<Arc<BackFileSystem>>> {
         let r = self.get_vfs().get_rootfs(mp)?;
         Ok(r)
@@ -0,0 +1,162 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! A portable archive of blobcache chunks, to seed blobcache on hosts without access to
+//! storage backend.
+//!
+//! All integers are in little endian. The archive begins with a header:
+//! - magic `NYDUSCAR`
+//! - version, u32
+//! - flags, u32
+//!
+//! Followed by chunk records till the end of file:
+//! - length of blob id, u16
+//! - blob id
+//! - offset of the chunk in cache file, u64
+//! - size of the chunk in cache file, u32
+//! - chunk data as stored in cache file
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+const ARCHIVE_MAGIC: &[u8; 8] = b"NYDUSCAR";
+const ARCHIVE_VERSION: u32 = 1;
+const ARCHIVE_HEADER_SIZE: u64 = 16;
+/// Chunks are stored compressed, as blobcache does with `compressed` enabled.
+const ARCHIVE_FLAG_COMPRESSED: u32 = 0x1;
+
+pub(crate) struct CacheArchiveWriter {
+    writer: BufWriter<File>,
+}
+
+impl CacheArchiveWriter {
+    pub fn create(path: &Path, compressed: bool) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+        let mut writer = BufWriter::new(file);
+        let flags = if compressed {
+            ARCHIVE_FLAG_COMPRESSED
+        } else {
+            0
+        };
+
+        writer.write_all(ARCHIVE_MAGIC)?;
+        writer.write_all(&ARCHIVE_VERSION.to_le_bytes())?;
+        writer.write_all(&flags.to_le_bytes())?;
+
+        Ok(Self { writer })
+    }
+
+    pub fn append(&mut self, blob_id: &str, offset: u64, data: &[u8]) -> Result<()> {
+        if blob_id.len() > u16::MAX as usize {
+            return Err(einval!(format!("blob id {} is too long", blob_id)));
+        }
+
+        self.writer
+            .write_all(&(blob_id.len() as u16).to_le_bytes())?;
+        self.writer.write_all(blob_id.as_bytes())?;
+        self.writer.write_all(&offset.to_le_bytes())?;
+        self.writer.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.writer.write_all(data)
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()
+    }
+}
+
+pub(crate) struct CacheArchiveReader {
+    file: File,
+    compressed: bool,
+    // Maps blob id and offset in cache file to position and size of chunk data in archive.
+    index: HashMap<(String, u64), (u64, u32)>,
+}
+
+fn read_u16(r: &mut dyn Read) -> Result<u16> {
+    let mut buf = [0u8; 2];
+    r.read_exact(&mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn read_u32(r: &mut dyn Read) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(r: &mut dyn Read) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+impl CacheArchiveReader {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path)?;
+        let mut reader = BufReader::new(file.try_clone()?);
+
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != ARCHIVE_MAGIC {
+            return Err(einval!(format!("{:?} is not a cache archive", path)));
+        }
+        let version = read_u32(&mut reader)?;
+        if version != ARCHIVE_VERSION {
+            return Err(einval!(format!(
+                "unsupported cache archive version {}",
+                version
+            )));
+        }
+        let flags = read_u32(&mut reader)?;
+
+        let mut index = HashMap::new();
+        let mut pos = ARCHIVE_HEADER_SIZE;
+        loop {
+            let id_len = match read_u16(&mut reader) {
+                Ok(len) => len as usize,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            };
+            let mut blob_id = vec![0u8; id_len];
+            reader.read_exact(&mut blob_id)?;
+            let blob_id = String::from_utf8(blob_id).map_err(|e| einval!(e))?;
+            let offset = read_u64(&mut reader)?;
+            let size = read_u32(&mut reader)?;
+
+            pos += 2 + id_len as u64 + 8 + 4;
+            index.insert((blob_id, offset), (pos, size));
+            pos += size as u64;
+            reader.seek(SeekFrom::Start(pos))?;
+        }
+
+        Ok(Self {
+            file,
+            compressed: flags & ARCHIVE_FLAG_COMPRESSED != 0,
+            index,
+        })
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.compressed
+    }
+
+    /// Get data of chunk at `offset` of blob `blob_id`, which must be `size` bytes.
+    pub fn get(&self, blob_id: &str, offset: u64, size: usize) -> Result<Option<Vec<u8>>> {
+        match self.index.get(&(blob_id.to_string(), offset)) {
+            Some((pos, len)) if *len as usize == size => {
+                let mut data = vec![0u8; size];
+                self.file.read_exact_at(&mut data, *pos)?;
+                Ok(Some(data))
+            }
+            _ => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,263 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Batched IO to blobcache files, which are submitted via io_uring if it's enabled and
+//! supported by this build, or issued one by one otherwise.
+
+#[cfg(feature = "io-uring")]
+use std::io::Error;
+use std::io::{ErrorKind, Result};
+use std::os::unix::io::RawFd;
+#[cfg(feature = "io-uring")]
+use std::sync::Mutex;
+#[cfg(feature = "io-uring")]
+use std::thread;
+#[cfg(feature = "io-uring")]
+use std::time::Duration;
+
+use nix::sys::uio;
+
+#[cfg(feature = "io-uring")]
+use io_uring::{opcode, types, IoUring};
+
+/// Number of IOs submitted to io_uring at a time.
+#[cfg(feature = "io-uring")]
+const URING_QUEUE_DEPTH: u32 = 64;
+/// Flag of `io_uring_enter` to wait for completions, which is not exported by `io_uring`.
+#[cfg(feature = "io-uring")]
+const IORING_ENTER_GETEVENTS: u32 = 1;
+/// How long to back off when waiting for completions keeps failing.
+#[cfg(feature = "io-uring")]
+const URING_REAP_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A read or write of a region of file, the buffer must be valid till the IO is done.
+pub(crate) struct BatchIoOp {
+    pub fd: RawFd,
+    pub buf: *mut u8,
+    pub len: usize,
+    pub offset: u64,
+    pub write: bool,
+}
+
+impl BatchIoOp {
+    pub fn read(fd: RawFd, buf: &mut [u8], offset: u64) -> Self {
+        BatchIoOp {
+            fd,
+            buf: buf.as_mut_ptr(),
+            len: buf.len(),
+            offset,
+            write: false,
+        }
+    }
+
+    pub fn write(fd: RawFd, buf: &[u8], offset: u64) -> Self {
+        BatchIoOp {
+            fd,
+            buf: buf.as_ptr() as *mut u8,
+            len: buf.len(),
+            offset,
+            write: true,
+        }
+    }
+
+    pub fn issue(&self) -> Result<usize> {
+        loop {
+            let ret = if self.write {
+                let buf = unsafe { std::slice::from_raw_parts(self.buf, self.len) };
+                uio::pwrite(self.fd, buf, self.offset as i64)
+            } else {
+                let buf = unsafe { std::slice::from_raw_parts_mut(self.buf, self.len) };
+                uio::pread(self.fd, buf, self.offset as i64)
+            };
+            match ret.map_err(|_| last_error!()) {
+                Ok(size) => return Ok(size),
+                // Retry if the IO is interrupted by signal.
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+pub(crate) enum BatchIo {
+    Sync,
+    #[cfg(feature = "io-uring")]
+    Uring(UringPool),
+}
+
+impl BatchIo {
+    /// Set up io_uring if `uring` is true, falling back to synchronous IO if it's
+    /// unavailable.
+    pub fn new(uring: bool) -> Self {
+        if !uring {
+            return BatchIo::Sync;
+        }
+
+        #[cfg(feature = "io-uring")]
+        match IoUring::new(URING_QUEUE_DEPTH) {
+            Ok(ring) => {
+                return BatchIo::Uring(UringPool {
+                    rings: Mutex::new(vec![ring]),
+                })
+            }
+            Err(e) => warn!(
+                "Failed to set up io_uring, fall back to synchronous IO, {}",
+                e
+            ),
+        }
+        #[cfg(not(feature = "io-uring"))]
+        warn!("io_uring is not supported by this build, fall back to synchronous IO");
+
+        BatchIo::Sync
+    }
+
+    pub fn is_uring(&self) -> bool {
+        !matches!(self, BatchIo::Sync)
+    }
+
+    /// Issue all `ops` and wait for them, returning bytes transferred by each.
+    pub fn submit(&self, ops: &[BatchIoOp]) -> Vec<Result<usize>> {
+        match self {
+            BatchIo::Sync => ops.iter().map(|op| op.issue()).collect(),
+            #[cfg(feature = "io-uring")]
+            BatchIo::Uring(pool) => pool.submit(ops),
+        }
+    }
+}
+
+/// Rings shared by prefetch workers, each worker takes a ring of its own for a submission
+/// so that workers never wait for each other.
+#[cfg(feature = "io-uring")]
+pub(crate) struct UringPool {
+    rings: Mutex<Vec<IoUring>>,
+}
+
+#[cfg(feature = "io-uring")]
+impl UringPool {
+    fn submit(&self, ops: &[BatchIoOp]) -> Vec<Result<usize>> {
+        let ring = self.rings.lock().unwrap().pop();
+        let mut ring = match ring.map_or_else(|| IoUring::new(URING_QUEUE_DEPTH), Ok) {
+            Ok(ring) => ring,
+            Err(e) => {
+                warn!("Failed to set up io_uring, issue IO synchronously, {}", e);
+                return ops.iter().map(|op| op.issue()).collect();
+            }
+        };
+
+        let mut results = Vec::with_capacity(ops.len());
+        let mut broken = false;
+        for batch in ops.chunks(URING_QUEUE_DEPTH as usize) {
+            let base = results.len();
+            results.extend(batch.iter().map(|_| Err(Error::from(ErrorKind::Other))));
+            if broken {
+                for (i, op) in batch.iter().enumerate() {
+                    results[base + i] = op.issue();
+                }
+            } else if let Err(e) = Self::submit_batch(&mut ring, batch, &mut results[base..]) {
+                // The ring can't be trusted any more, so issue the rest by ourselves.
+                warn!("io_uring submission failed, {}", e);
+                broken = true;
+                for (i, op) in batch.iter().enumerate() {
+                    if results[base + i].is_err() {
+                        results[base + i] = op.issue();
+                    }
+                }
+            }
+        }
+
+        // Entries never taken by kernel may be left in a broken ring, so it's dropped
+        // rather than reused.
+        if !broken {
+            self.rings.lock().unwrap().push(ring);
+        }
+
+        results
+    }
+
+    fn submit_batch(
+        ring: &mut IoUring,
+        batch: &[BatchIoOp],
+        results: &mut [Result<usize>],
+    ) -> Result<()> {
+        for (i, op) in batch.iter().enumerate() {
+            let entry = if op.write {
+                opcode::Write::new(types::Fd(op.fd), op.buf as *const u8, op.len as u32)
+                    .offset(op.offset as i64)
+                    .build()
+            } else {
+                opcode::Read::new(types::Fd(op.fd), op.buf, op.len as u32)
+                    .offset(op.offset as i64)
+                    .build()
+            };
+            // The queue is drained before each batch, so it never overflows.
+            unsafe { ring.submission().push(&entry.user_data(i as u64)) }
+                .map_err(|_| eio!("io_uring submission queue is full"))?;
+        }
+
+        let mut completed = 0;
+        while completed < batch.len() {
+            match ring.submit_and_wait(batch.len() - completed) {
+                Ok(_) => {}
+                // Completion queue may be congested, just wait for it again.
+                Err(e) if Self::is_transient(&e) => {}
+                Err(e) => {
+                    // Buffers of entries taken by kernel are still in use, they must be
+                    // completed before the caller reclaims them.
+                    let inflight = batch.len() - ring.submission().len() - completed;
+                    Self::reap(ring, inflight, results);
+                    return Err(e);
+                }
+            }
+            completed += Self::complete(ring, results);
+        }
+
+        Ok(())
+    }
+
+    /// Record results of completed entries, returning how many are completed.
+    fn complete(ring: &mut IoUring, results: &mut [Result<usize>]) -> usize {
+        let mut completed = 0;
+        for cqe in ring.completion() {
+            let res = cqe.result();
+            results[cqe.user_data() as usize] = if res < 0 {
+                Err(Error::from_raw_os_error(-res))
+            } else {
+                Ok(res as usize)
+            };
+            completed += 1;
+        }
+        completed
+    }
+
+    /// Wait for `inflight` entries to complete without submitting any more.
+    fn reap(ring: &mut IoUring, mut inflight: usize, results: &mut [Result<usize>]) {
+        loop {
+            inflight -= Self::complete(ring, results);
+            if inflight == 0 {
+                return;
+            }
+            let ret = unsafe {
+                ring.submitter().enter::<libc::sigset_t>(
+                    0,
+                    inflight as u32,
+                    IORING_ENTER_GETEVENTS,
+                    None,
+                )
+            };
+            if let Err(e) = ret {
+                // Kernel still owns the buffers, so there is no way out but waiting.
+                if !Self::is_transient(&e) {
+                    error!("Failed to wait for io_uring completions, {}", e);
+                    thread::sleep(URING_REAP_INTERVAL);
+                }
+            }
+        }
+    }
+
+    fn is_transient(e: &Error) -> bool {
+        e.kind() == ErrorKind::Interrupted
+            || e.raw_os_error() == Some(libc::EAGAIN)
+            || e.raw_os_error() == Some(libc::EBUSY)
+    }
+}
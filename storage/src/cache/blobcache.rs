@@ -2,19 +2,22 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::{self, File, OpenOptions};
-use std::io::{ErrorKind, Result, Seek, SeekFrom};
+use std::io::Result;
 use std::num::NonZeroU32;
-use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
 use std::sync::{
-    atomic::{AtomicU64, Ordering},
-    Arc, Mutex, RwLock,
+    atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+    Arc, Condvar, Mutex, RwLock, Weak,
 };
 use std::thread;
+use std::time::{Duration, Instant};
 
+use nix::fcntl::{fallocate, FallocateFlags};
 use nix::sys::uio;
-use nix::unistd::dup;
 
 use futures::executor::block_on;
 use governor::{
@@ -24,99 +27,528 @@ use governor::{
 use vm_memory::VolatileSlice;
 
 use crate::backend::BlobBackend;
+use crate::cache::archive::{CacheArchiveReader, CacheArchiveWriter};
+use crate::cache::batchio::{BatchIo, BatchIoOp};
+use crate::cache::encryption::{seal_journal_path, CacheCipher, CacheSeal};
 use crate::cache::RafsCache;
 use crate::cache::*;
-use crate::device::{BlobPrefetchControl, RafsBio};
+use crate::device::{BlobPrefetchControl, RafsBio, RafsChunkFlags};
 use crate::factory::CacheConfig;
-use crate::utils::{alloc_buf, copyv, readv};
-use crate::RAFS_DEFAULT_BLOCK_SIZE;
+use crate::utils::{align_down, align_up, alloc_buf, copyv, digest_check, readv, AlignedBuf};
+use crate::{StorageError, RAFS_DEFAULT_BLOCK_SIZE};
 
 use nydus_utils::{
-    digest::RafsDigest,
     einval, enoent, enosys, last_error,
     metrics::{BlobcacheMetrics, Metric},
 };
 
-#[derive(Clone, Eq, PartialEq)]
-enum CacheStatus {
-    Ready,
-    NotReady,
+/// Chunk data in cache file is trusted.
+const CHUNK_READY: u32 = 0x1;
+/// Chunk is being filled or evicted by someone, others have to wait for it.
+const CHUNK_PENDING: u32 = 0x2;
+/// Chunk is written by this blobcache and charged against its quota.
+const CHUNK_CHARGED: u32 = 0x4;
+/// Bits above flags count how many times the chunk gets ready, so that readers not
+/// waiting for pending chunks can tell if the chunk is evicted meanwhile.
+const CHUNK_GENERATION: u32 = 0x100;
+
+/// State of a chunk in cache file, which is accessed without locking.
+#[derive(Default)]
+struct ChunkSlot {
+    state: AtomicU32,
+    // Region of cache file charged against quota, valid with `CHUNK_CHARGED`.
+    charged_size: AtomicU32,
+    charged_offset: AtomicU64,
+    // Logical time of the latest access, for LRU eviction.
+    last_access: AtomicU64,
 }
 
-struct BlobCacheEntry {
-    status: CacheStatus,
-    chunk: Arc<dyn RafsChunkInfo>,
-    fd: RawFd,
+/// Cache file of a blob, along with states of its chunks indexed by their positions in blob.
+/// Memory taken by the index scales with blob size rather than number of accessed chunks.
+struct BlobCacheFile {
+    blob_id: String,
+    file: File,
+    // The same file opened with O_DIRECT, if direct IO is enabled and supported.
+    direct: Option<File>,
+    // Chunks are encrypted in cache file if it's present.
+    seal: Option<CacheSeal>,
+    // Blob size from backend, which is only known in advance for stargz.
+    size: u64,
+    // Grown on demand as chunk count of blob is unknown.
+    chunks: RwLock<Vec<ChunkSlot>>,
+    // Chunks of images built without chunk index are assigned positions on first access,
+    // keyed by their offsets in cache file.
+    legacy_positions: Mutex<HashMap<u64, u32>>,
+    // Chunks are no longer charged once the blob is evicted.
+    evicted: AtomicBool,
+    // Waiters of pending chunks are woken up when any chunk of the blob is released.
+    pending_lock: Mutex<()>,
+    pending_cond: Condvar,
+    metrics: Arc<BlobcacheMetrics>,
 }
 
-impl BlobCacheEntry {
-    fn new(chunk: Arc<dyn RafsChunkInfo>, fd: RawFd) -> BlobCacheEntry {
-        BlobCacheEntry {
-            status: CacheStatus::NotReady,
-            chunk,
-            fd,
+/// A chunk made pending by the holder, it's released on drop.
+struct ChunkGuard<'a> {
+    blob: &'a BlobCacheFile,
+    pos: usize,
+}
+
+impl<'a> ChunkGuard<'a> {
+    fn is_ready(&self) -> bool {
+        self.blob.state(self.pos) & CHUNK_READY != 0
+    }
+
+    fn set_ready(&self) {
+        self.blob.with_slot(self.pos, |slot| {
+            // Only the holder changes readiness, so adding doesn't carry over.
+            if slot.state.load(Ordering::Acquire) & CHUNK_READY == 0 {
+                slot.state
+                    .fetch_add(CHUNK_GENERATION | CHUNK_READY, Ordering::AcqRel);
+            }
+        })
+    }
+
+    fn set_not_ready(&self) {
+        self.blob.with_slot(self.pos, |slot| {
+            slot.state.fetch_and(!CHUNK_READY, Ordering::AcqRel);
+        })
+    }
+}
+
+impl<'a> Drop for ChunkGuard<'a> {
+    fn drop(&mut self) {
+        self.blob.with_slot(self.pos, |slot| {
+            slot.state.fetch_and(!CHUNK_PENDING, Ordering::AcqRel);
+        });
+        // Take the lock so that a waiter never misses the wakeup between checking state
+        // and sleeping.
+        let _guard = self.blob.pending_lock.lock().unwrap();
+        self.blob.pending_cond.notify_all();
+    }
+}
+
+/// Scrubbing is marked running in metrics until it's dropped, even if the scrubber never
+/// starts.
+struct ScrubRunning(Arc<BlobcacheMetrics>);
+
+impl Drop for ScrubRunning {
+    fn drop(&mut self) {
+        self.0.scrub_running.store(false, Ordering::Release);
+    }
+}
+
+/// Data of a region of cache file, which may be read within a larger aligned buffer.
+struct RegionBuf {
+    buf: AlignedBuf,
+    skip: usize,
+    len: usize,
+}
+
+impl RegionBuf {
+    fn data(&self) -> &[u8] {
+        &self.buf[self.skip..self.skip + self.len]
+    }
+
+    fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[self.skip..self.skip + self.len]
+    }
+}
+
+/// Aligned region of cache file covering `size` bytes at `offset`, for direct IO.
+fn direct_window(offset: u64, size: usize) -> (u64, usize) {
+    let start = align_down(offset);
+    (start, (align_up(offset + size as u64) - start) as usize)
+}
+
+impl BlobCacheFile {
+    fn new(
+        blob_id: &str,
+        file: File,
+        direct: Option<File>,
+        seal: Option<CacheSeal>,
+        size: u64,
+        metrics: &Arc<BlobcacheMetrics>,
+    ) -> Self {
+        BlobCacheFile {
+            blob_id: blob_id.to_string(),
+            file,
+            direct,
+            seal,
+            size,
+            chunks: RwLock::new(Vec::new()),
+            legacy_positions: Mutex::new(HashMap::new()),
+            evicted: AtomicBool::new(false),
+            pending_lock: Mutex::new(()),
+            pending_cond: Condvar::new(),
+            metrics: metrics.clone(),
         }
     }
 
-    fn is_ready(&self) -> bool {
-        self.status == CacheStatus::Ready
+    fn fd(&self) -> RawFd {
+        self.file.as_raw_fd()
     }
 
-    fn set_ready(&mut self) {
-        self.status = CacheStatus::Ready
+    /// Position of chunk `cki` whose data is at `offset` of cache file, it's assigned if the
+    /// chunk has no index.
+    fn position(&self, cki: &dyn RafsChunkInfo, offset: u64) -> usize {
+        if cki.flags().contains(RafsChunkFlags::INDEXED) {
+            return cki.index() as usize;
+        }
+
+        let mut positions = self.legacy_positions.lock().unwrap();
+        let next = positions.len() as u32;
+        *positions.entry(offset).or_insert(next) as usize
     }
 
-    fn read_partial_chunk(
-        &self,
-        bufs: &[VolatileSlice],
-        offset: u64,
-        max_size: usize,
-    ) -> Result<usize> {
-        readv(self.fd, bufs, offset, max_size)
+    /// Like `position` but never assigns one, so `None` means the chunk is never accessed.
+    fn lookup(&self, cki: &dyn RafsChunkInfo, offset: u64) -> Option<usize> {
+        if cki.flags().contains(RafsChunkFlags::INDEXED) {
+            Some(cki.index() as usize)
+        } else {
+            self.legacy_positions
+                .lock()
+                .unwrap()
+                .get(&offset)
+                .map(|pos| *pos as usize)
+        }
     }
 
-    /// Persist a single chunk into local blob cache file. We have to write to the cache
-    /// file in unit of chunk size
-    fn cache(&mut self, buf: &[u8], offset: u64) -> Result<()> {
+    /// Position of a chunk evicted by another blobcache sharing the cache file.
+    fn locate(&self, evicted: &EvictedChunk) -> Option<usize> {
+        let positions = self.legacy_positions.lock().unwrap();
+        if !positions.is_empty() {
+            positions.get(&evicted.offset).map(|pos| *pos as usize)
+        } else if evicted.indexed {
+            Some(evicted.position)
+        } else {
+            None
+        }
+    }
+
+    /// Run `f` on slot of chunk at `pos`, the index is extended if it's not large enough.
+    fn with_slot<F, R>(&self, pos: usize, f: F) -> R
+    where
+        F: FnOnce(&ChunkSlot) -> R,
+    {
+        {
+            let chunks = self.chunks.read().unwrap();
+            if let Some(slot) = chunks.get(pos) {
+                return f(slot);
+            }
+        }
+
+        let mut chunks = self.chunks.write().unwrap();
+        let len = chunks.len();
+        if len <= pos {
+            let new_len = std::cmp::max(pos + 1, len * 2);
+            chunks.resize_with(new_len, ChunkSlot::default);
+            self.metrics
+                .entries_count
+                .fetch_add(new_len - len, Ordering::Relaxed);
+        }
+        f(&chunks[pos])
+    }
+
+    fn state(&self, pos: usize) -> u32 {
+        self.chunks
+            .read()
+            .unwrap()
+            .get(pos)
+            .map(|slot| slot.state.load(Ordering::Acquire))
+            .unwrap_or(0)
+    }
+
+    fn is_ready(&self, pos: usize) -> bool {
+        self.state(pos) & CHUNK_READY != 0
+    }
+
+    fn touch(&self, pos: usize, now: u64) {
+        self.with_slot(pos, |slot| slot.last_access.store(now, Ordering::Relaxed))
+    }
+
+    fn try_lock_chunk(&self, pos: usize) -> Option<ChunkGuard> {
+        let prev = self.with_slot(pos, |slot| {
+            slot.state.fetch_or(CHUNK_PENDING, Ordering::AcqRel)
+        });
+        if prev & CHUNK_PENDING == 0 {
+            Some(ChunkGuard { blob: self, pos })
+        } else {
+            None
+        }
+    }
+
+    /// Make chunk at `pos` pending, waiting for the current holder if there is.
+    fn lock_chunk(&self, pos: usize) -> ChunkGuard {
         loop {
-            let ret = uio::pwrite(self.fd, buf, offset as i64).map_err(|_| last_error!());
+            if let Some(guard) = self.try_lock_chunk(pos) {
+                return guard;
+            }
+            let mut guard = self.pending_lock.lock().unwrap();
+            while self.state(pos) & CHUNK_PENDING != 0 {
+                guard = self.pending_cond.wait(guard).unwrap();
+            }
+        }
+    }
 
-            match ret {
-                Ok(nr_write) => {
-                    trace!("write {}(offset={}) bytes to cache file", nr_write, offset);
-                    break;
+    fn direct_fd(&self) -> Option<RawFd> {
+        self.direct.as_ref().map(|f| f.as_raw_fd())
+    }
+
+    fn account_io(&self, direct: usize, buffered: usize, begin: Instant) {
+        self.metrics.direct_io_amount.add(direct);
+        self.metrics.buffered_io_amount.add(buffered);
+        self.metrics
+            .cache_io_latency_us
+            .add(begin.elapsed().as_micros() as usize);
+    }
+
+    fn submit(&self, io: &BatchIo, ops: &[BatchIoOp]) -> Vec<Result<usize>> {
+        if io.is_uring() {
+            self.metrics.uring_batches.inc();
+            self.metrics.uring_ios.add(ops.len());
+        }
+        io.submit(ops)
+    }
+
+    /// Read data at `offset` of cache file. With direct IO, it's read in aligned window
+    /// through a bounce buffer.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        let begin = Instant::now();
+        if let Some(fd) = self.direct_fd() {
+            let (start, len) = direct_window(offset, buf.len());
+            let mut bounce = AlignedBuf::new(len);
+            let nr_read = BatchIoOp::read(fd, &mut bounce[..len], start).issue()?;
+            let skip = (offset - start) as usize;
+            let size = std::cmp::min(nr_read.saturating_sub(skip), buf.len());
+            buf[..size].copy_from_slice(&bounce[skip..skip + size]);
+            self.account_io(nr_read, 0, begin);
+            return Ok(size);
+        }
+
+        let nr_read = BatchIoOp::read(self.fd(), buf, offset).issue()?;
+        self.account_io(0, nr_read, begin);
+        Ok(nr_read)
+    }
+
+    /// Read a whole chunk stored at `offset` of cache file, it's decrypted if the cache
+    /// file is encrypted.
+    fn read_chunk(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        let nr_read = self.read_at(buf, offset)?;
+        if let Some(ref seal) = self.seal {
+            if nr_read != buf.len() {
+                return Err(einval!("short read from cache file"));
+            }
+            seal.open(offset, buf)?;
+        }
+        Ok(nr_read)
+    }
+
+    /// Whether the chunk at `region` of cache file is trusted without validation, which is
+    /// only true for chunks sealed by this or previous runs.
+    fn is_sealed(&self, region: (u64, usize)) -> bool {
+        self.seal
+            .as_ref()
+            .map(|seal| seal.is_sealed(region.0, region.1))
+            .unwrap_or(false)
+    }
+
+    /// Like `read_at` but read into volatile slices of user IO.
+    fn readv_at(&self, bufs: &[VolatileSlice], offset: u64, size: usize) -> Result<usize> {
+        let begin = Instant::now();
+        if let Some(fd) = self.direct_fd() {
+            let (start, len) = direct_window(offset, size);
+            let mut bounce = AlignedBuf::new(len);
+            let nr_read = BatchIoOp::read(fd, &mut bounce[..len], start).issue()?;
+            let skip = std::cmp::min((offset - start) as usize, nr_read);
+            let nr_read_v = copyv(&bounce[skip..nr_read], bufs, 0, size)?;
+            self.account_io(nr_read, 0, begin);
+            return Ok(nr_read_v);
+        }
+
+        let nr_read = readv(self.fd(), bufs, offset, size)?;
+        self.account_io(0, nr_read, begin);
+        Ok(nr_read)
+    }
+
+    /// Read regions of cache file in batch, they are considered failed if not fully read.
+    fn read_batch(&self, io: &BatchIo, regions: &[(u64, usize)]) -> Vec<Result<RegionBuf>> {
+        let begin = Instant::now();
+        let mut ops = Vec::with_capacity(regions.len());
+        let mut bufs = Vec::with_capacity(regions.len());
+        for &(offset, size) in regions {
+            let (fd, start, len) = match self.direct_fd() {
+                Some(fd) => {
+                    let (start, len) = direct_window(offset, size);
+                    (fd, start, len)
+                }
+                None => (self.fd(), offset, size),
+            };
+            // Moving the buffer doesn't move its data, which is what the IO refers to.
+            let mut buf = AlignedBuf::new(len);
+            ops.push(BatchIoOp::read(fd, &mut buf[..len], start));
+            bufs.push(RegionBuf {
+                buf,
+                skip: (offset - start) as usize,
+                len: size,
+            });
+        }
+
+        let mut amount = 0;
+        let results = self
+            .submit(io, &ops)
+            .into_iter()
+            .zip(bufs)
+            .zip(regions)
+            .map(|((res, mut buf), (offset, _))| {
+                let nr_read = res?;
+                amount += nr_read;
+                if nr_read < buf.skip + buf.len {
+                    return Err(einval!("short read from cache file"));
+                }
+                if let Some(ref seal) = self.seal {
+                    seal.open(*offset, buf.data_mut())?;
+                }
+                Ok(buf)
+            })
+            .collect();
+        if self.direct.is_some() {
+            self.account_io(amount, 0, begin);
+        } else {
+            self.account_io(0, amount, begin);
+        }
+
+        results
+    }
+
+    /// Plan IOs to write `buf` at `offset` of cache file. With direct IO, the aligned middle
+    /// part is written via a bounce buffer, which is returned along with IOs, and the rest
+    /// is written through page cache. Partial blocks at both ends may be shared with
+    /// neighbouring chunks, so they can't be written in unit of block.
+    fn plan_write(&self, buf: &[u8], offset: u64) -> (Vec<BatchIoOp>, Option<AlignedBuf>) {
+        if let Some(fd) = self.direct_fd() {
+            let start = align_up(offset);
+            let end = align_down(offset + buf.len() as u64);
+            if start < end {
+                let head = (start - offset) as usize;
+                let body = (end - start) as usize;
+                let mut bounce = AlignedBuf::new(body);
+                bounce[..body].copy_from_slice(&buf[head..head + body]);
+
+                let mut ops = vec![BatchIoOp::write(fd, &bounce[..body], start)];
+                if head > 0 {
+                    ops.push(BatchIoOp::write(self.fd(), &buf[..head], offset));
                 }
-                Err(err) => {
-                    // Retry if the IO is interrupted by signal.
-                    if err.kind() != ErrorKind::Interrupted {
-                        return Err(err);
+                if head + body < buf.len() {
+                    ops.push(BatchIoOp::write(self.fd(), &buf[head + body..], end));
+                }
+                return (ops, Some(bounce));
+            }
+        }
+
+        (vec![BatchIoOp::write(self.fd(), buf, offset)], None)
+    }
+
+    /// Persist chunks into cache file in batch, each is given by its offset in cache file
+    /// and data. Chunks are encrypted if the cache file is encrypted, and they are trusted
+    /// only after the data is written.
+    fn write_batch(&self, io: &BatchIo, chunks: &[(u64, &[u8])]) -> Vec<Result<()>> {
+        let seal = match self.seal {
+            Some(ref seal) => seal,
+            None => return self.write_raw_batch(io, chunks),
+        };
+
+        let mut sealed = Vec::with_capacity(chunks.len());
+        let mut tags = Vec::with_capacity(chunks.len());
+        for (offset, buf) in chunks {
+            let mut data = buf.to_vec();
+            tags.push(seal.seal(*offset, &mut data));
+            sealed.push((*offset, data));
+        }
+        let writes = sealed
+            .iter()
+            .map(|(offset, data)| (*offset, data.as_slice()))
+            .collect::<Vec<_>>();
+
+        self.write_raw_batch(io, &writes)
+            .into_iter()
+            .zip(tags)
+            .zip(chunks)
+            .map(|((res, tag), (offset, buf))| {
+                res?;
+                seal.commit(*offset, buf.len(), tag?)
+            })
+            .collect()
+    }
+
+    fn write_raw_batch(&self, io: &BatchIo, chunks: &[(u64, &[u8])]) -> Vec<Result<()>> {
+        let begin = Instant::now();
+        let mut ops = Vec::new();
+        let mut owners = Vec::new();
+        let mut bounces = Vec::new();
+        for (i, (offset, buf)) in chunks.iter().enumerate() {
+            let (chunk_ops, bounce) = self.plan_write(buf, *offset);
+            owners.extend(std::iter::repeat(i).take(chunk_ops.len()));
+            ops.extend(chunk_ops);
+            bounces.extend(bounce);
+        }
+
+        let mut results: Vec<Result<()>> = chunks.iter().map(|_| Ok(())).collect();
+        let (mut direct, mut buffered) = (0, 0);
+        for ((op, res), i) in ops.iter().zip(self.submit(io, &ops)).zip(owners) {
+            match res {
+                Ok(nr_write) if nr_write == op.len => {
+                    if Some(op.fd) == self.direct_fd() {
+                        direct += nr_write;
+                    } else {
+                        buffered += nr_write;
                     }
                 }
+                Ok(_) => results[i] = Err(eio!("short write to cache file")),
+                Err(e) => results[i] = Err(e),
             }
         }
+        self.account_io(direct, buffered, begin);
+        trace!("write {} chunks to cache file", chunks.len());
 
-        self.set_ready();
-        Ok(())
+        results
+    }
+
+    /// Persist a single chunk into local blob cache file. We have to write to the cache
+    /// file in unit of chunk size
+    fn write_chunk(&self, buf: &[u8], offset: u64) -> Result<()> {
+        self.write_batch(&BatchIo::Sync, &[(offset, buf)])
+            .pop()
+            .unwrap()
+    }
+}
+
+impl Drop for BlobCacheFile {
+    fn drop(&mut self) {
+        let len = self.chunks.read().unwrap().len();
+        self.metrics.entries_count.fetch_sub(len, Ordering::Relaxed);
     }
 }
 
 #[derive(Default)]
 struct BlobCacheState {
-    chunk_map: HashMap<RafsDigest, Arc<Mutex<BlobCacheEntry>>>,
-    file_map: HashMap<String, (File, u64)>,
+    blobs: HashMap<String, Arc<BlobCacheFile>>,
+    // Blobs by their indexes in blob table of rafs, to look up chunks without blob id.
+    blob_indexes: HashMap<u32, Arc<BlobCacheFile>>,
     work_dir: String,
     backend_size_valid: bool,
+    direct_io: bool,
+    cipher: Option<Arc<CacheCipher>>,
 }
 
 impl BlobCacheState {
-    fn get_blob_fd(
+    fn get_blob(
         &mut self,
         blob_id: &str,
         backend: &(dyn BlobBackend + Sync + Send),
         metrics: &Arc<BlobcacheMetrics>,
-    ) -> Result<(RawFd, u64)> {
-        if let Some((file, size)) = self.file_map.get(blob_id) {
-            return Ok((file.as_raw_fd(), *size));
+    ) -> Result<Arc<BlobCacheFile>> {
+        if let Some(blob) = self.blobs.get(blob_id) {
+            return Ok(blob.clone());
         }
 
         let blob_file_path = format!("{}/{}", self.work_dir, blob_id);
@@ -124,8 +556,24 @@ impl BlobCacheState {
             .create(true)
             .write(true)
             .read(true)
-            .open(blob_file_path)?;
-        let fd = file.as_raw_fd();
+            .open(&blob_file_path)?;
+        // Some file systems like tmpfs don't support O_DIRECT.
+        let direct = if self.direct_io {
+            OpenOptions::new()
+                .write(true)
+                .read(true)
+                .custom_flags(libc::O_DIRECT)
+                .open(&blob_file_path)
+                .map_err(|e| warn!("Blobcache falls back to buffered IO, {}", e))
+                .ok()
+        } else {
+            None
+        };
+
+        let seal = match self.cipher {
+            Some(ref cipher) => Some(CacheSeal::new(&self.work_dir, blob_id, cipher)?),
+            None => None,
+        };
 
         let size = if self.backend_size_valid {
             backend.blob_size(blob_id).map_err(|e| einval!(e))?
@@ -133,42 +581,23 @@ impl BlobCacheState {
             0
         };
 
-        self.file_map.insert(blob_id.to_string(), (file, size));
+        let blob = Arc::new(BlobCacheFile::new(
+            blob_id, file, direct, seal, size, metrics,
+        ));
+        self.blobs.insert(blob_id.to_string(), blob.clone());
         metrics
             .underlying_files
             .lock()
             .unwrap()
             .insert(blob_id.to_string());
 
-        Ok((fd, size))
-    }
-
-    fn get(&self, blk: &dyn RafsChunkInfo) -> Option<Arc<Mutex<BlobCacheEntry>>> {
-        // Do not expect poisoned lock here.
-        self.chunk_map.get(&blk.block_id()).cloned()
+        Ok(blob)
     }
 
-    fn set(
-        &mut self,
-        blob_id: &str,
-        cki: Arc<dyn RafsChunkInfo>,
-        backend: &(dyn BlobBackend + Sync + Send),
-        metrics: &Arc<BlobcacheMetrics>,
-    ) -> Result<Arc<Mutex<BlobCacheEntry>>> {
-        let block_id = *cki.block_id();
-        // Double check if someone else has inserted the blob chunk concurrently.
-        if let Some(entry) = self.chunk_map.get(&block_id) {
-            Ok(entry.clone())
-        } else {
-            let (fd, _) = self.get_blob_fd(blob_id, backend, metrics)?;
-            let entry = Arc::new(Mutex::new(BlobCacheEntry::new(cki, fd)));
-            let r = self.chunk_map.insert(block_id, entry.clone());
-            if r.is_some() {
-                warn!("Entry(block_id={}) is inserted again", block_id);
-            }
-            metrics.entries_count.inc();
-            Ok(entry)
-        }
+    fn remove_blob(&mut self, blob_id: &str) -> Option<Arc<BlobCacheFile>> {
+        let blob = self.blobs.remove(blob_id)?;
+        self.blob_indexes.retain(|_, b| !Arc::ptr_eq(b, &blob));
+        Some(blob)
     }
 }
 
@@ -180,41 +609,207 @@ pub struct BlobCache {
     is_compressed: bool,
     compressor: compress::Algorithm,
     digester: digest::Algorithm,
+    chunk_size: u32,
     // TODO: Directly using Governor RateLimiter makes code a little hard to read as
     // some concepts come from GCRA like "cells". GCRA is a sort of improved "Leaky Bucket"
     // firstly invented from ATM network technology. Wrap the limiter into Throttle!
-    limiter: Option<Arc<RateLimiter<NotKeyed, InMemoryState, QuantaClock>>>,
-    mr_sender: Arc<Mutex<Option<spmc::Sender<MergedBackendRequest>>>>,
-    mr_receiver: Option<spmc::Receiver<MergedBackendRequest>>,
+    limiter: RwLock<Option<Arc<RateLimiter<NotKeyed, InMemoryState, QuantaClock>>>>,
+    bandwidth_rate: AtomicU32,
+    scrub_limiter: Option<Arc<RateLimiter<NotKeyed, InMemoryState, QuantaClock>>>,
+    prefetch_queue: PrefetchQueue,
     prefetch_seq: AtomicU64,
+    prefetch_planned: AtomicU64,
+    prefetch_done: AtomicU64,
+    on_demand_latency: BackendLatency,
     metrics: Arc<BlobcacheMetrics>,
+    // Usage of all blobcaches sharing work_dir with this one.
+    shared_usage: Arc<AtomicUsize>,
+    work_dir_capacity: usize,
+    reclaiming: AtomicBool,
+    // Logical clock ticking on each chunk access.
+    access_clock: AtomicU64,
+    // Cache file IO of merged prefetch requests is done in batch.
+    batch_io: BatchIo,
+    // Decompressor checkpoints shared by stargz chunks read from backend and cache files.
+    stargz_streams: StargzStreams,
+    blob_dicts: BlobDicts,
+    blob_keys: BlobKeys,
+    // Prefetch workers may be spawned after the cache is created, they need an owned handle.
+    myself: Mutex<Weak<BlobCache>>,
+}
+
+/// Blobcaches sharing the same work_dir in the process, along with their total usage.
+#[derive(Default)]
+struct SharedWorkDir {
+    caches: Vec<Weak<BlobCache>>,
+    usage: Arc<AtomicUsize>,
+}
+
+lazy_static! {
+    static ref SHARED_WORK_DIRS: Mutex<HashMap<String, SharedWorkDir>> = Mutex::new(HashMap::new());
+}
+
+/// A chunk evicted from blobcache whose data is to be dropped from cache file.
+struct EvictedChunk {
+    blob: Arc<BlobCacheFile>,
+    position: usize,
+    // Whether the position is a chunk index rather than assigned by blobcache.
+    indexed: bool,
+    offset: u64,
+    size: usize,
+}
+
+// Evict a bit more than needed, so that reclaiming is not triggered by every cached chunk.
+fn reclaim_target(limit: usize) -> usize {
+    limit - limit / 10
+}
+
+fn register_shared_cache(work_dir: &str, cache: &Arc<BlobCache>) {
+    let mut dirs = SHARED_WORK_DIRS.lock().unwrap();
+    let dir = dirs.entry(work_dir.to_string()).or_default();
+    dir.caches.retain(|c| c.strong_count() > 0);
+    dir.caches.push(Arc::downgrade(cache));
+}
+
+fn shared_caches(work_dir: &str) -> Vec<Arc<BlobCache>> {
+    SHARED_WORK_DIRS
+        .lock()
+        .unwrap()
+        .get(work_dir)
+        .map(|dir| dir.caches.iter().filter_map(|c| c.upgrade()).collect())
+        .unwrap_or_default()
+}
+
+fn shared_usage(work_dir: &str) -> Arc<AtomicUsize> {
+    SHARED_WORK_DIRS
+        .lock()
+        .unwrap()
+        .entry(work_dir.to_string())
+        .or_default()
+        .usage
+        .clone()
+}
+
+// If the given value is less than blob chunk size, it exceeds burst size of the limiter ending
+// up with throttling all throughput.
+fn new_limiter(
+    bandwidth_rate: u32,
+    chunk_size: u32,
+    usage: &str,
+) -> Option<Arc<RateLimiter<NotKeyed, InMemoryState, QuantaClock>>> {
+    let tweaked_bw_limit = if bandwidth_rate != 0 {
+        std::cmp::max(chunk_size, bandwidth_rate)
+    } else {
+        0
+    };
+
+    NonZeroU32::new(tweaked_bw_limit).map(|v| {
+        info!("{} bandwidth will be limited at {}Bytes/S", usage, v);
+        Arc::new(RateLimiter::direct(Quota::per_second(v)))
+    })
+}
+
+fn throttle(limiter: &Option<Arc<RateLimiter<NotKeyed, InMemoryState, QuantaClock>>>, size: u32) {
+    if let Some(ref limiter) = limiter {
+        let cells = NonZeroU32::new(size).unwrap();
+        if let Err(e) = limiter
+            .check_n(cells)
+            .or_else(|_| block_on(limiter.until_n_ready(cells)))
+        {
+            // `InsufficientCapacity` is the only possible error
+            // Have to give up to avoid dead-loop
+            error!("{}: give up rate-limiting", e);
+        }
+    }
+}
+
+fn punch_hole(fd: RawFd, offset: u64, size: usize) -> Result<()> {
+    fallocate(
+        fd,
+        FallocateFlags::FALLOC_FL_PUNCH_HOLE | FallocateFlags::FALLOC_FL_KEEP_SIZE,
+        offset as i64,
+        size as i64,
+    )
+    .map_err(|_| last_error!())
+}
+
+// Tags of encrypted chunks go along with the cache file.
+fn remove_seal_journal(work_dir: &str, blob_id: &str) {
+    if let Err(e) = fs::remove_file(seal_journal_path(work_dir, blob_id)) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            warn!("Failed to remove seal journal of blob {}, {}", blob_id, e);
+        }
+    }
+}
+
+// Blob ids are sha256 digests in hex. Management operations leave alone other files in
+// `work_dir` unless they are opened by the cache.
+fn is_blob_file_name(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|c| c.is_ascii_hexdigit())
 }
 
 impl BlobCache {
     fn entry_read(
         &self,
         blob_id: &str,
-        entry: &Mutex<BlobCacheEntry>,
+        blob: &BlobCacheFile,
+        chunk: &dyn RafsChunkInfo,
         bufs: &[VolatileSlice],
         offset: u64,
         size: usize,
     ) -> Result<usize> {
-        let mut cache_entry = entry.lock().unwrap();
-        let chunk = cache_entry.chunk.clone();
+        let pos = self.chunk_position(blob, chunk);
         let mut reuse = false;
+        let mut need_reclaim = false;
+
+        blob.touch(pos, self.access_clock.fetch_add(1, Ordering::Relaxed));
 
-        // Hit cache if cache ready
-        if !self.is_compressed && !self.need_validate() && cache_entry.is_ready() {
+        // Hit cache if cache ready, encrypted chunks have to be decrypted in whole.
+        let state = blob.state(pos);
+        if !self.is_compressed
+            && !self.need_validate()
+            && blob.seal.is_none()
+            && state & CHUNK_READY != 0
+        {
             trace!(
                 "hit blob cache {} {}",
                 chunk.block_id().to_string(),
                 chunk.compress_size()
             );
-            self.metrics.partial_hits.inc();
-            return cache_entry.read_partial_chunk(bufs, offset + chunk.decompress_offset(), size);
+            let nr_read = blob.readv_at(bufs, offset + chunk.decompress_offset(), size)?;
+            // The chunk is not locked, so it may be evicted and punched meanwhile.
+            let stable = !(CHUNK_PENDING | CHUNK_CHARGED);
+            if blob.state(pos) & stable == state & stable {
+                self.metrics.partial_hits.inc();
+                return Ok(nr_read);
+            }
         }
 
+        let guard = blob.lock_chunk(pos);
         let d_size = chunk.decompress_size() as usize;
+
+        // Only the wanted range of cached stargz chunks is inflated, resuming from the nearest
+        // checkpoint of the gzip member.
+        if self.is_compressed
+            && self.compressor() == compress::Algorithm::GZip
+            && !self.need_validate()
+            && guard.is_ready()
+            && offset < d_size as u64
+        {
+            let size = std::cmp::min(size, d_size - offset as usize);
+            let mut d = alloc_buf(size);
+            if self
+                .stargz_streams
+                .read(&blob.blob_id, chunk, offset, &mut d, None, |buf, offset| {
+                    blob.read_at(buf, offset)
+                })
+                .is_ok()
+            {
+                drop(guard);
+                self.metrics.partial_hits.inc();
+                return copyv(&d, bufs, 0, size);
+            }
+        }
         let mut d;
         // one_chunk_buf is the decompressed data buffer
         let one_chunk_buf =
@@ -231,16 +826,17 @@ impl BlobCache {
         // Try to recover cache from blobcache first
         // For gzip, we can only trust ready blobcache because we cannot validate chunks due to
         // stargz format limitations (missing chunk level digest)
-        if (self.compressor() != compress::Algorithm::GZip || cache_entry.is_ready())
+        // Encrypted chunks cached by previous runs are trusted as well once decrypted.
+        let sealed = !guard.is_ready() && blob.is_sealed(self.cache_region(chunk));
+        let trusted = guard.is_ready() || sealed;
+        if (self.compressor() != compress::Algorithm::GZip || trusted)
             && self
-                .read_blobcache_chunk(
-                    cache_entry.fd,
-                    chunk.as_ref(),
-                    one_chunk_buf,
-                    !cache_entry.is_ready() || self.need_validate(),
-                )
+                .read_blobcache_chunk(blob, chunk, one_chunk_buf, !trusted || self.need_validate())
                 .is_ok()
         {
+            if sealed {
+                guard.set_ready();
+            }
             self.metrics.whole_hits.inc();
             trace!(
                 "recover blob cache {} {} reuse {} offset {} size {}",
@@ -251,16 +847,27 @@ impl BlobCache {
                 size,
             );
         } else {
-            self.read_backend_chunk(blob_id, chunk.as_ref(), one_chunk_buf, |c1, c2| {
-                let (chunk, c_offset) = if self.is_compressed {
-                    (c1, cache_entry.chunk.compress_offset())
+            let begin = Instant::now();
+            self.read_backend_chunk(blob_id, chunk, one_chunk_buf, |c1, c2| {
+                let (data, c_offset) = if self.is_compressed {
+                    (c1, chunk.compress_offset())
                 } else {
-                    (c2, cache_entry.chunk.decompress_offset())
+                    (c2, chunk.decompress_offset())
                 };
                 // TODO: Try to make this as a following asynchronous step writing cache
                 // This should be help to reduce read latency.
-                cache_entry.cache(chunk, c_offset)
+                blob.write_chunk(data, c_offset)?;
+                guard.set_ready();
+                Ok(())
             })?;
+            self.on_demand_latency.record(begin.elapsed());
+            need_reclaim = guard.is_ready() && self.charge(&guard, chunk);
+        }
+
+        // Reclaiming makes chunks of this and other blobcaches pending.
+        drop(guard);
+        if need_reclaim {
+            self.reclaim();
         }
 
         if reuse {
@@ -275,7 +882,7 @@ impl BlobCache {
 
     fn read_blobcache_chunk(
         &self,
-        fd: RawFd,
+        blob: &BlobCacheFile,
         cki: &dyn RafsChunkInfo,
         chunk: &mut [u8],
         need_validate: bool,
@@ -286,12 +893,28 @@ impl BlobCache {
             cki.decompress_offset()
         };
 
+        // gzip chunks stored compressed are inflated from cache file, resuming from the
+        // nearest checkpoint of the member.
+        if self.is_compressed && self.compressor() == compress::Algorithm::GZip {
+            debug!(
+                "inflating blobcache file fd {} offset {} size {}",
+                blob.fd(),
+                offset,
+                chunk.len()
+            );
+            self.stargz_streams
+                .read(&blob.blob_id, cki, 0, chunk, None, |buf, offset| {
+                    blob.read_at(buf, offset)
+                })?;
+            if need_validate && !digest_check(chunk, cki.block_id(), self.digester()) {
+                return Err(eio!("digest mismatch"));
+            }
+            return Ok(());
+        }
+
         let mut d;
-        let raw_chunk = if self.is_compressed && self.compressor() != compress::Algorithm::GZip {
+        let raw_chunk = if self.is_compressed {
             // Need to put compressed data into a temporary buffer so as to perform decompression.
-            //
-            // gzip is special that it doesn't carry compress_size, instead, we make an IO stream out
-            // of the blobcache file. So no need for an internal buffer here.
             let c_size = cki.compress_size() as usize;
             d = alloc_buf(c_size);
             d.as_mut_slice()
@@ -300,35 +923,22 @@ impl BlobCache {
             unsafe { slice::from_raw_parts_mut(chunk.as_mut_ptr(), chunk.len()) }
         };
 
-        let mut raw_stream = None;
-        if self.compressor() != compress::Algorithm::GZip {
-            debug!(
-                "reading blobcache file fd {} offset {} size {}",
-                fd,
-                offset,
-                raw_chunk.len()
-            );
-            let nr_read = uio::pread(fd, raw_chunk, offset as i64).map_err(|_| last_error!())?;
-            if nr_read == 0 || nr_read != raw_chunk.len() {
-                return Err(einval!());
-            }
-        } else {
-            debug!(
-                "using blobcache file fd {} offset {} as data stream",
-                fd, offset,
-            );
-            // FIXME: In case of multiple threads duplicating the same fd, they still share the same file offset.
-            let fd = dup(fd).map_err(|_| last_error!())?;
-            let mut f = unsafe { File::from_raw_fd(fd) };
-            f.seek(SeekFrom::Start(offset)).map_err(|_| last_error!())?;
-            raw_stream = Some(f)
+        debug!(
+            "reading blobcache file fd {} offset {} size {}",
+            blob.fd(),
+            offset,
+            raw_chunk.len()
+        );
+        let nr_read = blob.read_chunk(raw_chunk, offset)?;
+        if nr_read == 0 || nr_read != raw_chunk.len() {
+            return Err(einval!());
         }
 
         // Try to validate data just fetched from backend inside.
         self.process_raw_chunk(
             cki,
             raw_chunk,
-            raw_stream,
+            None,
             chunk,
             self.is_compressed,
             need_validate,
@@ -347,26 +957,10 @@ impl BlobCache {
         }
         false
     }
-    fn generate_merged_requests(
-        &self,
-        bios: &mut [RafsBio],
-        tx: &mut spmc::Sender<MergedBackendRequest>,
-        merging_size: usize,
-        seq: u64,
-    ) {
-        let limiter = |merged_size: u32| {
-            if let Some(ref limiter) = self.limiter {
-                let cells = NonZeroU32::new(merged_size).unwrap();
-                if let Err(e) = limiter
-                    .check_n(cells)
-                    .or_else(|_| block_on(limiter.until_n_ready(cells)))
-                {
-                    // `InsufficientCapacity` is the only possible error
-                    // Have to give up to avoid dead-loop
-                    error!("{}: give up rate-limiting", e);
-                }
-            }
-        };
+    fn generate_merged_requests(&self, bios: &mut [RafsBio], merging_size: usize, seq: u64) {
+        // Bandwidth limit may be changed meanwhile, which applies to the next round.
+        let limiter = self.limiter.read().unwrap().clone();
+        let limiter = |merged_size: u32| throttle(&limiter, merged_size);
 
         bios.sort_by_key(|entry| entry.chunkinfo.compress_offset());
         let mut index: usize = 1;
@@ -379,7 +973,7 @@ impl BlobCache {
 
         if bios.len() == 1 {
             limiter(mr.blob_size);
-            tx.send(mr).unwrap();
+            self.push_merged_request(mr);
             return;
         }
 
@@ -395,183 +989,563 @@ impl BlobCache {
             } else {
                 // New a MR if a non-continuous chunk is met.
                 limiter(mr.blob_size);
-                tx.send(mr.clone()).unwrap();
+                self.push_merged_request(mr.clone());
                 mr.reset();
                 mr.merge_begin(Arc::clone(&cki), &cur_bio.blob_id);
             }
             index += 1;
             if index >= bios.len() {
                 limiter(mr.blob_size);
-                tx.send(mr).unwrap();
+                self.push_merged_request(mr);
                 break;
             }
         }
     }
-}
-
-// TODO: This function is too long... :-(
-fn kick_prefetch_workers(cache: &Arc<BlobCache>) {
-    for num in 0..cache.prefetch_worker.threads_count {
-        let blobcache = cache.clone();
-        let rx = blobcache.mr_receiver.clone();
-        // TODO: We now don't define prefetch policy. Prefetch works according to hints coming
-        // from on-disk prefetch table or input arguments while nydusd starts. So better
-        // we can have method to kill prefetch threads. But hopefully, we can add
-        // another new prefetch policy triggering prefetch files belonging to the same
-        // directory while one of them is read. We can easily get a continuous region on blob
-        // that way.
-        let _thread = thread::Builder::new()
-            .name(format!("prefetch_thread_{}", num))
-            .spawn(move || {
-                blobcache
-                    .metrics
-                    .prefetch_workers
-                    .fetch_add(1, Ordering::Relaxed);
-                // Safe because channel must be established before prefetch workers
-                'wait_mr: while let Ok(mr) = rx.as_ref().unwrap().recv() {
-                    let blob_offset = mr.blob_offset;
-                    let blob_size = mr.blob_size;
-                    let continuous_chunks = &mr.chunks;
-                    let blob_id = &mr.blob_id;
-                    let mut issue_batch: bool;
-
-                    trace!(
-                        "Merged req id {} seq {} req offset {} size {}",
-                        blob_id,
-                        &mr.seq,
-                        blob_offset,
-                        blob_size
-                    );
-
-                    if blob_size == 0 {
-                        continue;
-                    }
-
-                    if continuous_chunks.len() > 2 {
-                        blobcache
-                            .metrics
-                            .prefetch_total_size
-                            .add(blob_size as usize);
-                        blobcache.metrics.prefetch_mr_count.inc();
-                    }
-
-                    blobcache
-                        .metrics
-                        .prefetch_data_amount
-                        .add(blob_size as usize);
-
-                    issue_batch = false;
-                    // An immature trick here to detect if chunk already resides in
-                    // blob cache file. Hopefully, we can have a more clever and agile
-                    // way in the future. Principe is that if all chunks are Ready,
-                    // abort this Merged Request. It might involve extra stress
-                    // to local file system.
-                    for c in continuous_chunks {
-                        let d_size = c.decompress_size() as usize;
-                        let entry = blobcache
-                            .cache
-                            .write()
-                            .expect("Expect cache lock not poisoned")
-                            .set(blob_id, c.clone(), blobcache.backend(), &blobcache.metrics);
-                        if let Ok(entry) = entry {
-                            let mut entry = entry.lock().unwrap();
-                            if entry.is_ready() {
-                                continue;
-                            }
-                            let fd = entry.fd;
-                            let chunk = entry.chunk.clone();
-                            // Always validate if chunk's hash is equal to `block_id` by which
-                            // blobcache judges if the data is up-to-date.
-                            if blobcache
-                                .read_blobcache_chunk(
-                                    fd,
-                                    chunk.as_ref(),
-                                    alloc_buf(d_size).as_mut_slice(),
-                                    true,
-                                )
-                                .is_err()
-                            {
-                                // Aha, we have a not integrated chunk here. Issue the entire
-                                // merged request from backend to boost.
-                                issue_batch = true;
-                                break;
-                            } else {
-                                entry.set_ready();
-                            }
-                        }
-                    }
 
-                    if !issue_batch {
-                        continue 'wait_mr;
-                    }
+    fn push_merged_request(&self, mr: MergedBackendRequest) {
+        let size = mr.chunks_size();
+        if !self.prefetch_queue.push(mr) {
+            // Prefetch is stopped meanwhile, so the request will never be done.
+            self.prefetch_planned.fetch_sub(size, Ordering::Relaxed);
+        }
+    }
 
-                    if let Ok(chunks) = blobcache.read_chunks(
-                        blob_id,
-                        blob_offset,
-                        blob_size as usize,
-                        &continuous_chunks,
-                    ) {
-                        for (i, c) in continuous_chunks.iter().enumerate() {
-                            let mut cache_guard = blobcache
-                                .cache
-                                .write()
-                                .expect("Expect cache lock not poisoned");
-
-                            if let Ok(entry) = cache_guard
-                                .set(blob_id, c.clone(), blobcache.backend(), &blobcache.metrics)
-                                .map_err(|_| error!("Set cache index error!"))
-                            {
-                                let mut entry = entry.lock().unwrap();
-                                if !entry.is_ready() {
-                                    let offset = if blobcache.is_compressed {
-                                        entry.chunk.compress_offset()
-                                    } else {
-                                        entry.chunk.decompress_offset()
-                                    };
-                                    if let Err(err) = entry.cache(chunks[i].as_slice(), offset) {
-                                        error!("Failed to cache chunk: {}", err);
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-                blobcache
-                    .metrics
-                    .prefetch_workers
-                    .fetch_sub(1, Ordering::Relaxed);
-                info!("Prefetch thread exits.")
-            });
+    /// Whether ordinary prefetch requests should step back for user IO.
+    fn prefetch_should_pause(&self) -> bool {
+        self.prefetch_worker.pause_latency_ms != 0
+            && self
+                .on_demand_latency
+                .exceeds(Duration::from_millis(self.prefetch_worker.pause_latency_ms))
     }
-}
 
-impl RafsCache for BlobCache {
-    fn init(&self, blobs: &[BlobPrefetchControl]) -> Result<()> {
-        // Backend may be capable to prefetch a range of blob bypass upper file system
-        // to blobcache. This should be asynchronous, so filesystem read cache hit
-        // should validate data integrity.
-        for b in blobs {
-            let _ = self.backend.prefetch_blob(&b.blob_id, b.offset, b.len);
+    /// Chunk is going to be fetched from backend for user IO. It may have been queued or
+    /// is being fetched by prefetch workers, so don't fetch it twice.
+    fn wait_for_prefetch(&self, cki: &dyn RafsChunkInfo) {
+        let (promoted, waited) = self.prefetch_queue.promote_and_wait(cki);
+        if promoted {
+            self.metrics.prefetch_promoted.inc();
+        }
+        if waited {
+            self.metrics.prefetch_waited.inc();
         }
-        Ok(())
     }
 
-    fn backend(&self) -> &(dyn BlobBackend + Sync + Send) {
-        self.backend.as_ref()
-    }
+    fn prefetch_merged_request(&self, mr: &MergedBackendRequest) {
+        let blob_offset = mr.blob_offset;
+        let blob_size = mr.blob_size;
+        let continuous_chunks = &mr.chunks;
+        let blob_id = &mr.blob_id;
+        let mut issue_batch: bool;
+
+        trace!(
+            "Merged req id {} seq {} req offset {} size {}",
+            blob_id,
+            &mr.seq,
+            blob_offset,
+            blob_size
+        );
 
-    fn has(&self, cki: &dyn RafsChunkInfo) -> bool {
-        // Doesn't expected poisoned lock here.
-        self.cache
+        if blob_size == 0 {
+            return;
+        }
+
+        if continuous_chunks.len() > 2 {
+            self.metrics.prefetch_total_size.add(blob_size as usize);
+            self.metrics.prefetch_mr_count.inc();
+        }
+
+        self.metrics.prefetch_data_amount.add(blob_size as usize);
+
+        issue_batch = false;
+        let blob = match self.blob_file(blob_id, continuous_chunks[0].as_ref()) {
+            Ok(blob) => blob,
+            Err(e) => {
+                error!("Failed to open cache file of blob {}, {}", blob_id, e);
+                return;
+            }
+        };
+        // An immature trick here to detect if chunk already resides in
+        // blob cache file. Hopefully, we can have a more clever and agile
+        // way in the future. Principe is that if all chunks are Ready,
+        // abort this Merged Request. It might involve extra stress
+        // to local file system.
+        // Chunks being filled by others are skipped, as they will be ready anyway.
+        let pending = continuous_chunks
+            .iter()
+            .filter_map(|c| {
+                blob.try_lock_chunk(self.chunk_position(&blob, c.as_ref()))
+                    .filter(|guard| !guard.is_ready())
+                    .map(|guard| (guard, c))
+            })
+            .collect::<Vec<_>>();
+        if self.compressor() == compress::Algorithm::GZip {
+            for (guard, c) in pending {
+                let d_size = c.decompress_size() as usize;
+                if self
+                    .read_blobcache_chunk(&blob, c.as_ref(), alloc_buf(d_size).as_mut_slice(), true)
+                    .is_err()
+                {
+                    // Aha, we have a not integrated chunk here. Issue the entire
+                    // merged request from backend to boost.
+                    issue_batch = true;
+                    break;
+                } else {
+                    guard.set_ready();
+                }
+            }
+        } else {
+            let regions = pending
+                .iter()
+                .map(|(_, c)| self.cache_region(c.as_ref()))
+                .collect::<Vec<_>>();
+            let raws = blob.read_batch(&self.batch_io, &regions);
+            for ((guard, c), raw) in pending.into_iter().zip(raws) {
+                let mut chunk = alloc_buf(c.decompress_size() as usize);
+                // Always validate if chunk's hash is equal to `block_id` by which
+                // blobcache judges if the data is up-to-date.
+                if raw
+                    .and_then(|raw| {
+                        self.process_raw_chunk(
+                            c.as_ref(),
+                            raw.data(),
+                            None,
+                            &mut chunk,
+                            self.is_compressed,
+                            true,
+                        )
+                    })
+                    .is_ok()
+                {
+                    guard.set_ready();
+                } else {
+                    issue_batch = true;
+                }
+            }
+        }
+
+        if !issue_batch {
+            return;
+        }
+
+        let mut need_reclaim = false;
+        if let Ok(chunks) =
+            self.read_chunks(blob_id, blob_offset, blob_size as usize, &continuous_chunks)
+        {
+            let pending = continuous_chunks
+                .iter()
+                .zip(chunks.iter())
+                .filter_map(|(c, data)| {
+                    blob.try_lock_chunk(self.chunk_position(&blob, c.as_ref()))
+                        .filter(|guard| !guard.is_ready())
+                        .map(|guard| (guard, c, data))
+                })
+                .collect::<Vec<_>>();
+            let writes = pending
+                .iter()
+                .map(|(_, c, data)| (self.cache_region(c.as_ref()).0, data.as_slice()))
+                .collect::<Vec<_>>();
+            let results = blob.write_batch(&self.batch_io, &writes);
+            for ((guard, c, _), res) in pending.iter().zip(results) {
+                match res {
+                    Ok(_) => {
+                        guard.set_ready();
+                        need_reclaim |= self.charge(guard, c.as_ref());
+                    }
+                    Err(err) => error!("Failed to cache chunk: {}", err),
+                }
+            }
+        }
+
+        if need_reclaim {
+            self.reclaim();
+        }
+    }
+
+    /// Verify ready chunks of `bios` against their digests. Corrupted ones are evicted so
+    /// that they are fetched from backend again on next access.
+    fn scrub_chunks(&self, bios: &[RafsBio]) {
+        // Only chunks of opened blobs may be ready.
+        let blobs = self.cache.read().unwrap().blobs.clone();
+        let mut scrubbed = HashSet::new();
+        let mut chunks = Vec::new();
+        for bio in bios {
+            if let Some(blob) = blobs.get(&bio.blob_id) {
+                if let Some(pos) = self.find_chunk(blob, bio.chunkinfo.as_ref()) {
+                    if blob.is_ready(pos) && scrubbed.insert((bio.blob_id.as_str(), pos)) {
+                        chunks.push((blob, pos, bio.chunkinfo.as_ref()));
+                    }
+                }
+            }
+        }
+
+        self.metrics
+            .scrub_total_chunks
+            .store(chunks.len(), Ordering::Relaxed);
+        self.metrics
+            .scrub_checked_chunks
+            .store(0, Ordering::Relaxed);
+
+        for (blob, pos, chunk) in chunks {
+            let size = chunk.decompress_size();
+            // Don't hold the chunk while being throttled, user IO may need it.
+            throttle(&self.scrub_limiter, size);
+
+            let guard = blob.lock_chunk(pos);
+            if guard.is_ready()
+                && self
+                    .read_blobcache_chunk(
+                        blob,
+                        chunk,
+                        alloc_buf(size as usize).as_mut_slice(),
+                        true,
+                    )
+                    .is_err()
+            {
+                warn!("Scrubber found corrupted chunk {}", chunk.block_id());
+                guard.set_not_ready();
+                self.uncharge(blob, pos);
+                self.metrics.scrub_corrupted_chunks.inc();
+            }
+            drop(guard);
+
+            self.metrics
+                .scrub_checked_chunks
+                .fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.metrics.scrub_rounds.inc();
+    }
+
+    /// Cache file of blob `blob_id` which chunk `cki` belongs to, it's opened if not yet.
+    fn blob_file(&self, blob_id: &str, cki: &dyn RafsChunkInfo) -> Result<Arc<BlobCacheFile>> {
+        if let Some(blob) = self.indexed_blob(cki.blob_index()) {
+            if blob.blob_id == blob_id {
+                return Ok(blob);
+            }
+        }
+
+        let mut cache = self.cache.write().unwrap();
+        let blob = cache.get_blob(blob_id, self.backend(), &self.metrics)?;
+        cache.blob_indexes.insert(cki.blob_index(), blob.clone());
+
+        Ok(blob)
+    }
+
+    fn indexed_blob(&self, blob_index: u32) -> Option<Arc<BlobCacheFile>> {
+        // Doesn't expect poisoned lock here.
+        self.cache
             .read()
             .unwrap()
-            .chunk_map
-            .contains_key(cki.block_id())
+            .blob_indexes
+            .get(&blob_index)
+            .cloned()
+    }
+
+    fn is_encrypted(&self) -> bool {
+        self.cache.read().unwrap().cipher.is_some()
+    }
+
+    fn chunk_position(&self, blob: &BlobCacheFile, cki: &dyn RafsChunkInfo) -> usize {
+        blob.position(cki, self.cache_region(cki).0)
+    }
+
+    fn find_chunk(&self, blob: &BlobCacheFile, cki: &dyn RafsChunkInfo) -> Option<usize> {
+        blob.lookup(cki, self.cache_region(cki).0)
+    }
+
+    /// Offset and size of a chunk in cache file.
+    fn cache_region(&self, cki: &dyn RafsChunkInfo) -> (u64, usize) {
+        if self.is_compressed {
+            (cki.compress_offset(), cki.compress_size() as usize)
+        } else {
+            (cki.decompress_offset(), cki.decompress_size() as usize)
+        }
+    }
+
+    /// Release disk space of a chunk in cache file.
+    fn punch_chunk(&self, fd: RawFd, cki: &dyn RafsChunkInfo) -> Result<()> {
+        let (offset, size) = self.cache_region(cki);
+        punch_hole(fd, offset, size)
+    }
+
+    /// Charge a chunk just written to cache file against quota, returning whether usage
+    /// exceeds quota so that reclaiming is needed.
+    fn charge(&self, guard: &ChunkGuard, cki: &dyn RafsChunkInfo) -> bool {
+        // Chunks written to an evicted blob are dropped along with the file.
+        if guard.blob.evicted.load(Ordering::Acquire) {
+            return false;
+        }
+
+        let (offset, size) = self.cache_region(cki);
+        let charged = guard.blob.with_slot(guard.pos, |slot| {
+            if slot.state.load(Ordering::Acquire) & CHUNK_CHARGED != 0 {
+                return false;
+            }
+            slot.charged_offset.store(offset, Ordering::Relaxed);
+            slot.charged_size.store(size as u32, Ordering::Relaxed);
+            slot.state.fetch_or(CHUNK_CHARGED, Ordering::AcqRel);
+            true
+        });
+        if charged {
+            self.metrics.quota_usage.fetch_add(size, Ordering::Relaxed);
+            self.shared_usage.fetch_add(size, Ordering::Relaxed);
+        }
+
+        let hard_limit = self.metrics.quota_hard_limit.load(Ordering::Relaxed);
+        (hard_limit != 0 && self.metrics.quota_usage.load(Ordering::Relaxed) > hard_limit)
+            || (self.work_dir_capacity != 0
+                && self.shared_usage.load(Ordering::Relaxed) > self.work_dir_capacity)
+    }
+
+    /// Returning the charged region of cache file if the chunk is charged.
+    fn uncharge(&self, blob: &BlobCacheFile, pos: usize) -> Option<(u64, usize)> {
+        let region = blob.with_slot(pos, |slot| {
+            if slot.state.fetch_and(!CHUNK_CHARGED, Ordering::AcqRel) & CHUNK_CHARGED != 0 {
+                Some((
+                    slot.charged_offset.load(Ordering::Relaxed),
+                    slot.charged_size.load(Ordering::Relaxed) as usize,
+                ))
+            } else {
+                None
+            }
+        });
+        if let Some((_, size)) = region {
+            self.metrics.quota_usage.fetch_sub(size, Ordering::Relaxed);
+            self.shared_usage.fetch_sub(size, Ordering::Relaxed);
+        }
+
+        region
+    }
+
+    /// Evict least recently used chunks charged to this blobcache till its usage drops to
+    /// `target`. Data of evicted chunks is still in cache files.
+    fn evict_lru(&self, target: usize) -> Vec<EvictedChunk> {
+        let blobs = self
+            .cache
+            .read()
+            .unwrap()
+            .blobs
+            .values()
+            .cloned()
+            .collect::<Vec<Arc<BlobCacheFile>>>();
+        let mut charged = Vec::new();
+        for (i, blob) in blobs.iter().enumerate() {
+            for (pos, slot) in blob.chunks.read().unwrap().iter().enumerate() {
+                if slot.state.load(Ordering::Acquire) & CHUNK_CHARGED != 0 {
+                    charged.push((slot.last_access.load(Ordering::Relaxed), i, pos));
+                }
+            }
+        }
+        charged.sort_unstable();
+
+        let mut evicted = Vec::new();
+        for (_, i, pos) in charged {
+            if self.metrics.quota_usage.load(Ordering::Relaxed) <= target {
+                break;
+            }
+            let blob = &blobs[i];
+            // Chunks being used are not cold anyway.
+            let guard = match blob.try_lock_chunk(pos) {
+                Some(guard) => guard,
+                None => continue,
+            };
+            guard.set_not_ready();
+            if let Some((offset, size)) = self.uncharge(blob, pos) {
+                evicted.push(EvictedChunk {
+                    blob: blob.clone(),
+                    position: pos,
+                    indexed: blob.legacy_positions.lock().unwrap().is_empty(),
+                    offset,
+                    size,
+                });
+            }
+        }
+        self.metrics.quota_evicted_chunks.add(evicted.len());
+
+        evicted
+    }
+
+    /// Stop trusting a chunk whose data is to be dropped from cache file by another blobcache.
+    fn invalidate_chunk(&self, evicted: &EvictedChunk) {
+        let blob = match self.cache.read().unwrap().blobs.get(&evicted.blob.blob_id) {
+            Some(blob) => blob.clone(),
+            None => return,
+        };
+        if let Some(pos) = blob.locate(evicted) {
+            let guard = blob.lock_chunk(pos);
+            guard.set_not_ready();
+            self.uncharge(&blob, pos);
+        }
+    }
+
+    /// Bring usage back within hard limit and capacity of work_dir. Capacity is reclaimed
+    /// from blobcaches exceeding soft limit at first, in order of how much they exceed, and
+    /// then from this blobcache. So blobcaches within soft limit keep their data.
+    fn reclaim(&self) {
+        if self
+            .reclaiming
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            return;
+        }
+
+        let caches = shared_caches(&self.cache.read().unwrap().work_dir);
+        let mut evicted: Vec<(&BlobCache, EvictedChunk)> = Vec::new();
+
+        let hard_limit = self.metrics.quota_hard_limit.load(Ordering::Relaxed);
+        if hard_limit != 0 && self.metrics.quota_usage.load(Ordering::Relaxed) > hard_limit {
+            let soft_limit = self.metrics.quota_soft_limit.load(Ordering::Relaxed);
+            let target = if soft_limit != 0 {
+                soft_limit
+            } else {
+                reclaim_target(hard_limit)
+            };
+            evicted.extend(self.evict_lru(target).into_iter().map(|c| (self, c)));
+        }
+
+        let capacity = self.work_dir_capacity;
+        if capacity != 0 && self.shared_usage.load(Ordering::Relaxed) > capacity {
+            let mut excess = self
+                .shared_usage
+                .load(Ordering::Relaxed)
+                .saturating_sub(reclaim_target(capacity));
+            let mut over_soft = caches
+                .iter()
+                .filter_map(|c| {
+                    let soft_limit = c.metrics.quota_soft_limit.load(Ordering::Relaxed);
+                    let usage = c.metrics.quota_usage.load(Ordering::Relaxed);
+                    if soft_limit != 0 && usage > soft_limit {
+                        Some((usage - soft_limit, soft_limit, c.as_ref()))
+                    } else {
+                        None
+                    }
+                })
+                .collect::<Vec<(usize, usize, &BlobCache)>>();
+            over_soft.sort_by_key(|(over, _, _)| std::cmp::Reverse(*over));
+
+            for (_, soft_limit, c) in over_soft {
+                if excess == 0 {
+                    break;
+                }
+                let usage = c.metrics.quota_usage.load(Ordering::Relaxed);
+                let target = std::cmp::max(soft_limit, usage.saturating_sub(excess));
+                evicted.extend(c.evict_lru(target).into_iter().map(|e| (c, e)));
+                excess = excess.saturating_sub(
+                    usage.saturating_sub(c.metrics.quota_usage.load(Ordering::Relaxed)),
+                );
+            }
+            if excess != 0 {
+                let usage = self.metrics.quota_usage.load(Ordering::Relaxed);
+                let target = usage.saturating_sub(excess);
+                evicted.extend(self.evict_lru(target).into_iter().map(|c| (self, c)));
+            }
+        }
+
+        for (owner, chunk) in evicted {
+            for c in caches.iter() {
+                if !std::ptr::eq(c.as_ref(), owner) {
+                    c.invalidate_chunk(&chunk);
+                }
+            }
+            // The chunk may be cached again meanwhile.
+            let guard = chunk.blob.lock_chunk(chunk.position);
+            if guard.is_ready() {
+                continue;
+            }
+            if let Err(e) = punch_hole(chunk.blob.fd(), chunk.offset, chunk.size) {
+                warn!(
+                    "Failed to drop chunk at offset {} of blob {} from cache file, {}",
+                    chunk.offset, chunk.blob.blob_id, e
+                );
+            }
+        }
+
+        self.reclaiming.store(false, Ordering::Release);
+    }
+
+    fn kick_prefetch_workers(&self, count: usize) {
+        if let Some(cache) = self.myself.lock().unwrap().upgrade() {
+            kick_prefetch_workers(&cache, count);
+        }
+    }
+}
+
+fn kick_prefetch_workers(cache: &Arc<BlobCache>, count: usize) {
+    for num in 0..count {
+        let blobcache = cache.clone();
+        // Prefetch works according to hints coming from on-disk prefetch table or input
+        // arguments while nydusd starts, optionally as per `PrefetchPolicy` while user
+        // IO is served, and requests from API at any time. Workers exit once `stop_prefetch`
+        // closes the queue or threads count is reduced.
+        let spawned = thread::Builder::new()
+            .name(format!("prefetch_thread_{}", num))
+            .spawn(move || {
+                blobcache
+                    .metrics
+                    .prefetch_workers
+                    .fetch_add(1, Ordering::Relaxed);
+                while let Some((id, mr)) = blobcache
+                    .prefetch_queue
+                    .pop(&|| blobcache.prefetch_should_pause())
+                {
+                    blobcache.prefetch_merged_request(&mr);
+                    blobcache.prefetch_queue.complete(id, &mr);
+                    blobcache
+                        .prefetch_done
+                        .fetch_add(mr.chunks_size(), Ordering::Relaxed);
+                }
+                blobcache
+                    .metrics
+                    .prefetch_workers
+                    .fetch_sub(1, Ordering::Relaxed);
+                info!("Prefetch thread exits.")
+            });
+        if let Err(e) = spawned {
+            error!("Create prefetch worker failed, {:?}", e);
+            cache.prefetch_queue.worker_failed();
+        }
+    }
+}
+
+impl RafsCache for BlobCache {
+    fn init(&self, blobs: &[BlobPrefetchControl]) -> Result<()> {
+        self.blob_dicts.register(blobs);
+        self.blob_keys.register(blobs)?;
+        // Backend may be capable to prefetch a range of blob bypass upper file system
+        // to blobcache. This should be asynchronous, so filesystem read cache hit
+        // should validate data integrity.
+        for b in blobs {
+            let _ = self.backend.prefetch_blob(&b.blob_id, b.offset, b.len);
+        }
+        Ok(())
+    }
+
+    fn backend(&self) -> &(dyn BlobBackend + Sync + Send) {
+        self.backend.as_ref()
+    }
+
+    fn has(&self, cki: &dyn RafsChunkInfo) -> bool {
+        let blob = match self.indexed_blob(cki.blob_index()) {
+            Some(blob) => blob,
+            None => return false,
+        };
+        // A chunk is only a placeholder until its data is persisted into cache file.
+        self.find_chunk(&blob, cki)
+            .map(|pos| blob.is_ready(pos))
+            .unwrap_or(false)
     }
 
     fn evict(&self, cki: &dyn RafsChunkInfo) -> Result<()> {
-        // Doesn't expect poisoned lock here.
-        self.cache.write().unwrap().chunk_map.remove(cki.block_id());
+        let blob = match self.indexed_blob(cki.blob_index()) {
+            Some(blob) => blob,
+            None => return Ok(()),
+        };
+        if let Some(pos) = self.find_chunk(&blob, cki) {
+            let guard = blob.lock_chunk(pos);
+            guard.set_not_ready();
+            self.uncharge(&blob, pos);
+        }
         Ok(())
     }
 
@@ -581,21 +1555,16 @@ impl RafsCache for BlobCache {
 
     fn read(&self, bio: &RafsBio, bufs: &[VolatileSlice], offset: u64) -> Result<usize> {
         let blob_id = &bio.blob_id;
+        let chunk = bio.chunkinfo.as_ref();
 
         self.metrics.total.inc();
 
-        let mut entry = self.cache.read().unwrap().get(bio.chunkinfo.as_ref());
-        if entry.is_none() {
-            let en = self.cache.write().unwrap().set(
-                blob_id,
-                bio.chunkinfo.clone(),
-                self.backend(),
-                &self.metrics,
-            )?;
-            entry = Some(en);
-        };
+        let blob = self.blob_file(blob_id, chunk)?;
+        if self.prefetch_worker.enable && !blob.is_ready(self.chunk_position(&blob, chunk)) {
+            self.wait_for_prefetch(chunk);
+        }
 
-        self.entry_read(blob_id, &entry.unwrap(), bufs, offset, bio.size)
+        self.entry_read(blob_id, &blob, chunk, bufs, offset, bio.size)
     }
 
     fn write(&self, _blob_id: &str, _blk: &dyn RafsChunkInfo, _buf: &[u8]) -> Result<usize> {
@@ -603,12 +1572,12 @@ impl RafsCache for BlobCache {
     }
 
     fn blob_size(&self, blob_id: &str) -> Result<u64> {
-        let (_, size) =
-            self.cache
-                .write()
-                .unwrap()
-                .get_blob_fd(blob_id, self.backend(), &self.metrics)?;
-        Ok(size)
+        let blob = self
+            .cache
+            .write()
+            .unwrap()
+            .get_blob(blob_id, self.backend(), &self.metrics)?;
+        Ok(blob.size)
     }
 
     fn release(&self) {
@@ -621,20 +1590,355 @@ impl RafsCache for BlobCache {
         let merging_size = self.prefetch_worker.merging_size;
         let seq = self.prefetch_seq.fetch_add(1, Ordering::Relaxed);
 
-        self.metrics.prefetch_unmerged_chunks.add(bios.len());
-
-        if let Some(mr_sender) = self.mr_sender.lock().unwrap().as_mut() {
-            self.generate_merged_requests(bios, mr_sender, merging_size, seq);
+        if self.prefetch_queue.is_closed() {
+            return Ok(0);
         }
 
+        let planned: u64 = bios
+            .iter()
+            .map(|b| b.chunkinfo.compress_size() as u64)
+            .sum();
+        self.prefetch_planned.fetch_add(planned, Ordering::Relaxed);
+        self.metrics.prefetch_unmerged_chunks.add(bios.len());
+        self.generate_merged_requests(bios, merging_size, seq);
+
         Ok(0)
     }
 
     fn stop_prefetch(&self) -> StorageResult<()> {
-        drop(self.mr_sender.lock().unwrap().take().unwrap());
+        self.prefetch_queue.close();
+        Ok(())
+    }
+
+    fn start_prefetch(&self) -> StorageResult<()> {
+        let count = self.prefetch_queue.open();
+        self.kick_prefetch_workers(count);
+        Ok(())
+    }
+
+    fn pause_prefetch(&self, paused: bool) -> StorageResult<()> {
+        self.prefetch_queue.set_paused(paused);
+        Ok(())
+    }
+
+    fn tune_prefetch(
+        &self,
+        threads_count: Option<usize>,
+        bandwidth_rate: Option<u32>,
+    ) -> StorageResult<()> {
+        if let Some(rate) = bandwidth_rate {
+            *self.limiter.write().unwrap() = new_limiter(rate, self.chunk_size, "Prefetch");
+            self.bandwidth_rate.store(rate, Ordering::Relaxed);
+        }
+        if let Some(threads) = threads_count {
+            // At least one worker is kept to serve chunks waited by user IO.
+            let count = self
+                .prefetch_queue
+                .set_max_workers(std::cmp::max(threads, 1));
+            self.kick_prefetch_workers(count);
+        }
+        Ok(())
+    }
+
+    fn scrub(&self, bios: Vec<RafsBio>) -> StorageResult<()> {
+        // There is no chunk level digest for stargz to verify against.
+        if self.compressor() == compress::Algorithm::GZip {
+            return Err(StorageError::Unsupported);
+        }
+
+        if self
+            .metrics
+            .scrub_running
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            info!("Blobcache scrubber is already running");
+            return Ok(());
+        }
+        let running = ScrubRunning(self.metrics.clone());
+
+        if let Some(cache) = self.myself.lock().unwrap().upgrade() {
+            let r = thread::Builder::new()
+                .name("blobcache_scrubber".to_string())
+                .spawn(move || {
+                    let _running = running;
+                    cache.scrub_chunks(&bios);
+                    info!("Blobcache scrubber exits.")
+                });
+            if let Err(e) = r {
+                error!("Failed to start blobcache scrubber, {}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn list_blobs(&self) -> StorageResult<Vec<CachedBlobInfo>> {
+        let (work_dir, opened) = {
+            let cache = self.cache.read().unwrap();
+            let opened = cache
+                .blobs
+                .iter()
+                .map(|(id, blob)| (id.clone(), blob.size))
+                .collect::<HashMap<String, u64>>();
+            (cache.work_dir.clone(), opened)
+        };
+
+        let mut blobs = Vec::new();
+        for entry in fs::read_dir(&work_dir).map_err(StorageError::Io)? {
+            let entry = entry.map_err(StorageError::Io)?;
+            let blob_id = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            if !opened.contains_key(&blob_id) && !is_blob_file_name(&blob_id) {
+                continue;
+            }
+            // The file may be removed concurrently.
+            let md = match entry.metadata() {
+                Ok(md) if md.is_file() => md,
+                _ => continue,
+            };
+            // Backend size is only known in advance for stargz, otherwise ask backend.
+            let blob_size = match opened.get(&blob_id) {
+                Some(0) => self.backend.blob_size(&blob_id).unwrap_or(0),
+                Some(size) => *size,
+                None => 0,
+            };
+
+            blobs.push(CachedBlobInfo {
+                cached_size: md.blocks() * 512,
+                blob_size,
+                last_access: std::cmp::max(md.atime(), md.mtime()) as u64,
+                in_use: opened.contains_key(&blob_id),
+                blob_id,
+            });
+        }
+
+        Ok(blobs)
+    }
+
+    fn evict_blob(&self, blob_id: &str) -> StorageResult<()> {
+        // Hold cache lock till the file is removed, so the blob is not opened again
+        // before that.
+        let mut cache = self.cache.write().unwrap();
+
+        if !cache.blobs.contains_key(blob_id) && !is_blob_file_name(blob_id) {
+            return Err(StorageError::Io(einval!(format!(
+                "invalid blob id {}",
+                blob_id
+            ))));
+        }
+
+        if let Some(blob) = cache.remove_blob(blob_id) {
+            blob.evicted.store(true, Ordering::Release);
+            let count = blob.chunks.read().unwrap().len();
+            for pos in 0..count {
+                // Readers still holding the blob must not trust the removed file.
+                let guard = blob.lock_chunk(pos);
+                guard.set_not_ready();
+                self.uncharge(&blob, pos);
+            }
+            self.metrics
+                .underlying_files
+                .lock()
+                .unwrap()
+                .remove(blob_id);
+        }
+
+        // Caches of other mounts sharing `work_dir` keep reading the removed file with their
+        // own fd, which is unlinked only.
+        fs::remove_file(Path::new(&cache.work_dir).join(blob_id)).map_err(StorageError::Io)?;
+        remove_seal_journal(&cache.work_dir, blob_id);
+
+        Ok(())
+    }
+
+    fn evict_chunks(&self, bios: &[RafsBio]) -> StorageResult<()> {
+        let (work_dir, blobs) = {
+            let cache = self.cache.read().unwrap();
+            (cache.work_dir.clone(), cache.blobs.clone())
+        };
+        // Blobs not opened by this cache may still have been cached by previous runs.
+        let mut unopened: HashMap<String, Option<File>> = HashMap::new();
+
+        for bio in bios {
+            let cki = bio.chunkinfo.as_ref();
+            if let Some(blob) = blobs.get(&bio.blob_id) {
+                // Keep the chunk pending so that it's not cached again while punching.
+                let guard = self.find_chunk(blob, cki).map(|pos| blob.lock_chunk(pos));
+                if let Some(ref guard) = guard {
+                    guard.set_not_ready();
+                    self.uncharge(blob, guard.pos);
+                }
+                self.punch_chunk(blob.fd(), cki).map_err(StorageError::Io)?;
+            } else {
+                let file = unopened.entry(bio.blob_id.clone()).or_insert_with(|| {
+                    OpenOptions::new()
+                        .write(true)
+                        .open(Path::new(&work_dir).join(&bio.blob_id))
+                        .ok()
+                });
+                if let Some(file) = file {
+                    self.punch_chunk(file.as_raw_fd(), cki)
+                        .map_err(StorageError::Io)?;
+                }
+            }
+        }
+
         Ok(())
     }
 
+    fn gc(&self, referenced: &HashSet<String>) -> StorageResult<Vec<String>> {
+        // Blobs can't be opened while holding cache lock.
+        let cache = self.cache.read().unwrap();
+        let mut removed = Vec::new();
+
+        for entry in fs::read_dir(&cache.work_dir).map_err(StorageError::Io)? {
+            let entry = entry.map_err(StorageError::Io)?;
+            let blob_id = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            if !is_blob_file_name(&blob_id)
+                || referenced.contains(&blob_id)
+                || cache.blobs.contains_key(&blob_id)
+                || !entry.file_type().map(|t| t.is_file()).unwrap_or(false)
+            {
+                continue;
+            }
+
+            match fs::remove_file(entry.path()) {
+                Ok(_) => {
+                    remove_seal_journal(&cache.work_dir, &blob_id);
+                    info!("Blobcache gc removed blob {}", blob_id);
+                    removed.push(blob_id);
+                }
+                Err(e) => warn!("Blobcache gc failed to remove blob {}, {}", blob_id, e),
+            }
+        }
+
+        Ok(removed)
+    }
+
+    fn export_chunks(&self, bios: &[RafsBio], path: &Path) -> StorageResult<usize> {
+        // Stargz chunks can't be validated when imported as there is no chunk level digest.
+        // Encrypted chunks are not supposed to leave the host in plaintext.
+        if self.compressor() == compress::Algorithm::GZip || self.is_encrypted() {
+            return Err(StorageError::Unsupported);
+        }
+
+        let work_dir = self.cache.read().unwrap().work_dir.clone();
+        let mut archive =
+            CacheArchiveWriter::create(path, self.is_compressed).map_err(StorageError::Io)?;
+        let mut files: HashMap<String, Option<File>> = HashMap::new();
+        let mut exported = HashSet::new();
+
+        for bio in bios {
+            let cki = bio.chunkinfo.as_ref();
+            if !exported.insert(*cki.block_id()) {
+                continue;
+            }
+            // Chunks may be cached by previous runs, so check cache files rather than entries.
+            let file = files
+                .entry(bio.blob_id.clone())
+                .or_insert_with(|| File::open(Path::new(&work_dir).join(&bio.blob_id)).ok());
+            let fd = match file {
+                Some(file) => file.as_raw_fd(),
+                None => continue,
+            };
+
+            let (offset, size) = self.cache_region(cki);
+            let mut raw = alloc_buf(size);
+            let mut chunk = alloc_buf(cki.decompress_size() as usize);
+            if uio::pread(fd, &mut raw, offset as i64).ok() != Some(size)
+                || self
+                    .process_raw_chunk(cki, &raw, None, &mut chunk, self.is_compressed, true)
+                    .is_err()
+            {
+                exported.remove(cki.block_id());
+                continue;
+            }
+
+            archive
+                .append(&bio.blob_id, offset, &raw)
+                .map_err(StorageError::Io)?;
+        }
+
+        archive.finish().map_err(StorageError::Io)?;
+
+        Ok(exported.len())
+    }
+
+    fn import_chunks(&self, bios: &[RafsBio], path: &Path) -> StorageResult<usize> {
+        if self.compressor() == compress::Algorithm::GZip || self.is_encrypted() {
+            return Err(StorageError::Unsupported);
+        }
+
+        let archive = CacheArchiveReader::open(path).map_err(StorageError::Io)?;
+        if archive.is_compressed() != self.is_compressed {
+            return Err(StorageError::Io(einval!(
+                "layout of cache archive mismatches blobcache"
+            )));
+        }
+
+        let mut imported = HashSet::new();
+        let mut need_reclaim = false;
+        for bio in bios {
+            let cki = bio.chunkinfo.as_ref();
+            let (offset, size) = self.cache_region(cki);
+            if imported.contains(&(bio.blob_id.as_str(), offset)) {
+                continue;
+            }
+
+            let raw = match archive
+                .get(&bio.blob_id, offset, size)
+                .map_err(StorageError::Io)?
+            {
+                Some(raw) => raw,
+                None => continue,
+            };
+            let mut chunk = alloc_buf(cki.decompress_size() as usize);
+            if let Err(e) =
+                self.process_raw_chunk(cki, &raw, None, &mut chunk, self.is_compressed, true)
+            {
+                warn!(
+                    "Drop corrupted chunk {} from archive, {}",
+                    cki.block_id(),
+                    e
+                );
+                continue;
+            }
+
+            let blob = self
+                .blob_file(&bio.blob_id, cki)
+                .map_err(StorageError::Io)?;
+            let guard = blob.lock_chunk(self.chunk_position(&blob, cki));
+            if !guard.is_ready() {
+                blob.write_chunk(&raw, offset).map_err(StorageError::Io)?;
+                guard.set_ready();
+                need_reclaim |= self.charge(&guard, cki);
+            }
+            imported.insert((bio.blob_id.as_str(), offset));
+        }
+
+        if need_reclaim {
+            self.reclaim();
+        }
+
+        Ok(imported.len())
+    }
+
+    fn prefetch_progress(&self) -> StorageResult<PrefetchProgress> {
+        Ok(PrefetchProgress {
+            planned: self.prefetch_planned.load(Ordering::Relaxed),
+            done: self.prefetch_done.load(Ordering::Relaxed),
+            paused: self.prefetch_queue.is_paused(),
+            threads_count: self.prefetch_queue.max_workers(),
+            bandwidth_rate: self.bandwidth_rate.load(Ordering::Relaxed),
+        })
+    }
+
     #[inline]
     fn digester(&self) -> digest::Algorithm {
         self.digester
@@ -649,18 +1953,71 @@ impl RafsCache for BlobCache {
     fn need_validate(&self) -> bool {
         self.validate
     }
+
+    fn stargz_streams(&self) -> &StargzStreams {
+        &self.stargz_streams
+    }
+
+    fn blob_dicts(&self) -> &BlobDicts {
+        &self.blob_dicts
+    }
+
+    fn blob_keys(&self) -> &BlobKeys {
+        &self.blob_keys
+    }
+}
+
+impl Drop for BlobCache {
+    fn drop(&mut self) {
+        // Space taken by this blobcache is free to be reclaimed by others.
+        self.shared_usage.fetch_sub(
+            self.metrics.quota_usage.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+    }
 }
 
 #[derive(Clone, Deserialize)]
 struct BlobCacheConfig {
     #[serde(default = "default_work_dir")]
     work_dir: String,
+    // In unit of Bytes and Zero means no rate limit is set.
+    #[serde(default = "default_scrub_bandwidth_rate")]
+    scrub_bandwidth_rate: u32,
+    // Quota of cache space charged to this blobcache, in unit of Bytes and Zero means unlimited.
+    // Usage beyond soft limit is reclaimed first once work_dir runs out of capacity, and usage
+    // beyond hard limit is reclaimed right away.
+    #[serde(default)]
+    quota_soft_limit: u64,
+    #[serde(default)]
+    quota_hard_limit: u64,
+    // Capacity of work_dir shared by all blobcaches using it, in unit of Bytes and Zero
+    // means unlimited. It's supposed to be the same for all of them.
+    #[serde(default)]
+    work_dir_capacity: u64,
+    // Access cache files with O_DIRECT, so that cached chunks don't take page cache.
+    #[serde(default)]
+    direct_io: bool,
+    // Submit cache file IO of merged prefetch requests via io_uring, which requires nydusd
+    // built with feature `io-uring`.
+    #[serde(default)]
+    io_uring: bool,
+    // Encrypt chunks in cache files with the key in this file, or printed by this command if
+    // there is no key file. The key is loaded once per nydusd process.
+    #[serde(default)]
+    encryption_key_file: String,
+    #[serde(default)]
+    encryption_key_command: String,
 }
 
 fn default_work_dir() -> String {
     ".".to_string()
 }
 
+fn default_scrub_bandwidth_rate() -> u32 {
+    10 * 1024 * 1024
+}
+
 pub fn new(
     config: CacheConfig,
     backend: Arc<dyn BlobBackend + Sync + Send>,
@@ -692,39 +2049,56 @@ pub fn new(
         }
     }?;
 
-    // If the given value is less than blob chunk size, it exceeds burst size of the limiter ending
-    // up with throttling all throughput.
-    // TODO: We get the chunk size by a constant which is the default value and it's not
-    // easy to get real value now. Perhaps we should have a configuration center?
-    let tweaked_bw_limit = if config.prefetch_worker.bandwidth_rate != 0 {
-        std::cmp::max(
-            RAFS_DEFAULT_BLOCK_SIZE as u32,
-            config.prefetch_worker.bandwidth_rate,
-        )
+    if blob_config.quota_hard_limit != 0
+        && blob_config.quota_soft_limit > blob_config.quota_hard_limit
+    {
+        return Err(einval!(
+            "blobcache quota soft limit is larger than hard limit"
+        ));
+    }
+    let cipher = if !blob_config.encryption_key_file.is_empty()
+        || !blob_config.encryption_key_command.is_empty()
+    {
+        // Compressed gzip chunks are read as a stream directly from cache file.
+        if config.cache_compressed && compressor == compress::Algorithm::GZip {
+            return Err(einval!(
+                "blobcache encryption doesn't support compressed stargz cache"
+            ));
+        }
+        Some(CacheCipher::load(
+            &blob_config.encryption_key_file,
+            &blob_config.encryption_key_command,
+        )?)
     } else {
-        0
+        None
     };
+    // Blobcaches using the same work_dir via different paths are told by the real path.
+    let shared_dir = fs::canonicalize(work_dir)?.to_string_lossy().to_string();
 
-    let limiter = NonZeroU32::new(tweaked_bw_limit).map(|v| {
-        info!("Prefetch bandwidth will be limited at {}Bytes/S", v);
-        Arc::new(RateLimiter::direct(Quota::per_second(v)))
-    });
-
-    let mut enabled = false;
-    let (tx, rx) = if config.prefetch_worker.enable {
-        let (send, recv) = spmc::channel::<MergedBackendRequest>();
-        enabled = true;
-        (Some(send), Some(recv))
+    // Caches of rafs are given chunk size of the image, the default one is assumed otherwise.
+    let chunk_size = if config.chunk_size != 0 {
+        config.chunk_size
     } else {
-        (None, None)
+        RAFS_DEFAULT_BLOCK_SIZE as u32
     };
+    let limiter = new_limiter(
+        config.prefetch_worker.bandwidth_rate,
+        chunk_size,
+        "Prefetch",
+    );
+    let scrub_limiter = new_limiter(blob_config.scrub_bandwidth_rate, chunk_size, "Scrub");
+    let bandwidth_rate = config.prefetch_worker.bandwidth_rate;
+    let threads_count = config.prefetch_worker.threads_count;
+    let enabled = config.prefetch_worker.enable;
 
     let cache = Arc::new(BlobCache {
         cache: Arc::new(RwLock::new(BlobCacheState {
-            chunk_map: HashMap::new(),
-            file_map: HashMap::new(),
-            work_dir: work_dir.to_string(),
+            blobs: HashMap::new(),
+            blob_indexes: HashMap::new(),
+            work_dir: shared_dir.clone(),
             backend_size_valid: compressor == compress::Algorithm::GZip,
+            direct_io: blob_config.direct_io,
+            cipher,
         })),
         validate: config.cache_validate,
         is_compressed: config.cache_compressed,
@@ -732,12 +2106,37 @@ pub fn new(
         prefetch_worker: config.prefetch_worker,
         compressor,
         digester,
-        limiter,
-        mr_sender: Arc::new(Mutex::new(tx)),
-        mr_receiver: rx,
+        chunk_size,
+        limiter: RwLock::new(limiter),
+        bandwidth_rate: AtomicU32::new(bandwidth_rate),
+        scrub_limiter,
+        prefetch_queue: PrefetchQueue::new(threads_count),
         prefetch_seq: AtomicU64::new(0),
+        prefetch_planned: AtomicU64::new(0),
+        prefetch_done: AtomicU64::new(0),
+        on_demand_latency: BackendLatency::default(),
         metrics: BlobcacheMetrics::new(id, work_dir),
+        shared_usage: shared_usage(&shared_dir),
+        work_dir_capacity: blob_config.work_dir_capacity as usize,
+        reclaiming: AtomicBool::new(false),
+        access_clock: AtomicU64::new(0),
+        batch_io: BatchIo::new(blob_config.io_uring),
+        stargz_streams: StargzStreams::default(),
+        blob_dicts: BlobDicts::default(),
+        blob_keys: BlobKeys::new(config.encryption),
+        myself: Mutex::new(Weak::new()),
     });
+    *cache.myself.lock().unwrap() = Arc::downgrade(&cache);
+    register_shared_cache(&shared_dir, &cache);
+
+    cache
+        .metrics
+        .quota_soft_limit
+        .store(blob_config.quota_soft_limit as usize, Ordering::Relaxed);
+    cache
+        .metrics
+        .quota_hard_limit
+        .store(blob_config.quota_hard_limit as usize, Ordering::Relaxed);
 
     cache
         .metrics
@@ -745,9 +2144,18 @@ pub fn new(
         .lock()
         .unwrap()
         .insert("hinted".to_string());
+    if cache.prefetch_worker.policy != PrefetchPolicy::None {
+        cache
+            .metrics
+            .prefetch_policy
+            .lock()
+            .unwrap()
+            .insert(cache.prefetch_worker.policy.to_string());
+    }
 
     if enabled {
-        kick_prefetch_workers(&cache);
+        let count = cache.prefetch_queue.open();
+        kick_prefetch_workers(&cache, count);
     }
 
     Ok(cache)
@@ -756,8 +2164,15 @@ pub fn new(
 #[cfg(test)]
 mod blob_cache_tests {
     use std::alloc::{alloc, Layout};
+    use std::collections::HashSet;
+    use std::fs::OpenOptions;
+    use std::io::Result;
+    use std::os::unix::fs::FileExt;
+    use std::path::Path;
     use std::slice::from_raw_parts;
+    use std::sync::atomic::Ordering;
     use std::sync::Arc;
+    use std::thread;
 
     use vm_memory::{VolatileMemory, VolatileSlice};
     use vmm_sys_util::tempdir::TempDir;
@@ -766,15 +2181,16 @@ mod blob_cache_tests {
     use crate::cache::blobcache;
     use crate::cache::PrefetchWorker;
     use crate::cache::RafsCache;
+    use crate::cache::{MergedBackendRequest, PrefetchQueue};
     use crate::compress;
     use crate::device::{RafsBio, RafsChunkFlags, RafsChunkInfo};
     use crate::factory::CacheConfig;
-    use crate::impl_getter;
+    use crate::test::MockChunkInfo;
     use crate::RAFS_DEFAULT_BLOCK_SIZE;
 
     use nydus_utils::{
         digest::{self, RafsDigest},
-        metrics::BackendMetrics,
+        metrics::{BackendMetrics, Metric},
     };
 
     struct MockBackend {
@@ -817,72 +2233,42 @@ mod blob_cache_tests {
         }
     }
 
-    #[derive(Default, Clone)]
-    struct MockChunkInfo {
-        pub block_id: RafsDigest,
-        pub blob_index: u32,
-        pub flags: RafsChunkFlags,
-        pub compress_size: u32,
-        pub decompress_size: u32,
-        pub compress_offset: u64,
-        pub decompress_offset: u64,
-        pub file_offset: u64,
-        pub reserved: u64,
-    }
-
-    impl MockChunkInfo {
-        fn new() -> Self {
-            MockChunkInfo::default()
-        }
-    }
-
-    impl RafsChunkInfo for MockChunkInfo {
-        fn block_id(&self) -> &RafsDigest {
-            &self.block_id
-        }
-        fn is_compressed(&self) -> bool {
-            self.flags.contains(RafsChunkFlags::COMPRESSED)
-        }
-        fn is_hole(&self) -> bool {
-            self.flags.contains(RafsChunkFlags::HOLECHUNK)
-        }
-        impl_getter!(blob_index, blob_index, u32);
-        impl_getter!(compress_offset, compress_offset, u64);
-        impl_getter!(compress_size, compress_size, u32);
-        impl_getter!(decompress_offset, decompress_offset, u64);
-        impl_getter!(decompress_size, decompress_size, u32);
-        impl_getter!(file_offset, file_offset, u64);
-        impl_getter!(flags, flags, RafsChunkFlags);
+    /// Blobcache named `id` over the mock backend, with `work_dir` and other options of
+    /// blobcache in `options`, the rest of configuration is taken from `config`.
+    fn new_cache(
+        id: &str,
+        work_dir: &Path,
+        mut options: serde_json::Value,
+        config: CacheConfig,
+    ) -> Result<Arc<blobcache::BlobCache>> {
+        options["work_dir"] = serde_json::json!(work_dir);
+        blobcache::new(
+            CacheConfig {
+                cache_type: String::from("blobcache"),
+                cache_config: options,
+                ..config
+            },
+            Arc::new(MockBackend {
+                metrics: BackendMetrics::new(id, "mock"),
+            }) as Arc<dyn BlobBackend + Send + Sync>,
+            compress::Algorithm::LZ4Block,
+            digest::Algorithm::Blake3,
+            id,
+        )
     }
 
     #[test]
     fn test_add() {
         // new blob cache
         let tmp_dir = TempDir::new().unwrap();
-        let s = format!(
-            r###"
-        {{
-            "work_dir": {:?}
-        }}
-        "###,
-            tmp_dir.as_path().to_path_buf().join("cache"),
-        );
-
-        let cache_config = CacheConfig {
-            cache_validate: true,
-            cache_compressed: false,
-            cache_type: String::from("blobcache"),
-            cache_config: serde_json::from_str(&s).unwrap(),
-            prefetch_worker: PrefetchWorker::default(),
-        };
-        let blob_cache = blobcache::new(
-            cache_config,
-            Arc::new(MockBackend {
-                metrics: BackendMetrics::new("id", "mock"),
-            }) as Arc<dyn BlobBackend + Send + Sync>,
-            compress::Algorithm::LZ4Block,
-            digest::Algorithm::Blake3,
+        let blob_cache = new_cache(
             "id",
+            &tmp_dir.as_path().join("cache"),
+            serde_json::json!({}),
+            CacheConfig {
+                cache_validate: true,
+                ..Default::default()
+            },
         )
         .unwrap();
 
@@ -936,4 +2322,657 @@ mod blob_cache_tests {
         assert_eq!(r1, &expect[50..]);
         assert_eq!(r2, &expect[50..]);
     }
+
+    #[test]
+    fn test_chunk_compressor() {
+        let tmp_dir = TempDir::new().unwrap();
+        let blob_cache = new_cache(
+            "compressor",
+            &tmp_dir.as_path().join("cache"),
+            serde_json::json!({}),
+            CacheConfig {
+                cache_validate: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let data = (0..4096u32).map(|i| (i % 7) as u8).collect::<Vec<_>>();
+        let mut chunk = MockChunkInfo::new();
+        chunk.block_id = RafsDigest::from_buf(&data, digest::Algorithm::Blake3);
+        chunk.decompress_size = data.len() as u32;
+        let mut buf = vec![0u8; data.len()];
+
+        // Chunk compressed with zstd in an image whose compressor is lz4_block.
+        let (zstd, _) = compress::compress(&data, compress::Algorithm::Zstd).unwrap();
+        chunk.flags = RafsChunkFlags::from_compressor(compress::Algorithm::Zstd);
+        chunk.compress_size = zstd.len() as u32;
+        assert_eq!(
+            blob_cache.chunk_compressor(&chunk),
+            compress::Algorithm::Zstd
+        );
+        blob_cache
+            .process_raw_chunk(&chunk, &zstd, None, &mut buf, true, true)
+            .unwrap();
+        assert_eq!(buf, data);
+
+        // Chunks without compressor flags are compressed with the one of superblock.
+        chunk.flags = RafsChunkFlags::COMPRESSED;
+        assert_eq!(
+            blob_cache.chunk_compressor(&chunk),
+            compress::Algorithm::LZ4Block
+        );
+        assert!(blob_cache
+            .process_raw_chunk(&chunk, &zstd, None, &mut buf, true, true)
+            .is_err());
+        let (lz4, _) = compress::compress(&data, compress::Algorithm::LZ4Block).unwrap();
+        chunk.compress_size = lz4.len() as u32;
+        buf.iter_mut().for_each(|b| *b = 0);
+        blob_cache
+            .process_raw_chunk(&chunk, &lz4, None, &mut buf, true, true)
+            .unwrap();
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn test_scrub() {
+        let tmp_dir = TempDir::new().unwrap();
+        let blob_cache = new_cache(
+            "scrub",
+            &tmp_dir.as_path().join("cache"),
+            serde_json::json!({ "scrub_bandwidth_rate": 0 }),
+            CacheConfig::default(),
+        )
+        .unwrap();
+
+        let mut expect = vec![1u8; 100];
+        let blob_id = "blobcache";
+        blob_cache
+            .backend
+            .read(blob_id, expect.as_mut(), 0)
+            .unwrap();
+        let mut chunk = MockChunkInfo::new();
+        chunk.block_id = RafsDigest::from_buf(&expect, digest::Algorithm::Blake3);
+        chunk.compress_size = 100;
+        chunk.decompress_size = 100;
+        let chunk = Arc::new(chunk);
+        let bio = RafsBio::new(
+            chunk.clone(),
+            blob_id.to_string(),
+            0,
+            100,
+            RAFS_DEFAULT_BLOCK_SIZE as u32,
+        );
+
+        let mut buf = vec![0u8; 100];
+        let vs = unsafe { VolatileSlice::new(buf.as_mut_ptr(), buf.len()) };
+        blob_cache.read(&bio, &[vs], 0).unwrap();
+        assert!(blob_cache.has(chunk.as_ref()));
+
+        // Nothing is wrong yet.
+        blob_cache.scrub_chunks(&[bio.clone()]);
+        assert_eq!(blob_cache.metrics.scrub_corrupted_chunks.count(), 0);
+        assert!(blob_cache.has(chunk.as_ref()));
+
+        // Corrupt the cache file behind blobcache.
+        let file = OpenOptions::new()
+            .write(true)
+            .open(tmp_dir.as_path().join("cache").join(blob_id))
+            .unwrap();
+        file.write_all_at(&[0xffu8; 10], 20).unwrap();
+
+        blob_cache.scrub_chunks(&[bio.clone()]);
+        assert_eq!(blob_cache.metrics.scrub_corrupted_chunks.count(), 1);
+        assert_eq!(blob_cache.metrics.scrub_rounds.count(), 2);
+        assert_eq!(
+            blob_cache
+                .metrics
+                .scrub_checked_chunks
+                .load(Ordering::Relaxed),
+            1
+        );
+        assert!(!blob_cache.has(chunk.as_ref()));
+
+        // Chunk is fetched from backend again.
+        blob_cache.read(&bio, &[vs], 0).unwrap();
+        assert_eq!(buf, expect);
+    }
+
+    #[test]
+    fn test_cache_management() {
+        let tmp_dir = TempDir::new().unwrap();
+        let work_dir = tmp_dir.as_path().join("cache");
+        let blob_cache = new_cache(
+            "manage",
+            &work_dir,
+            serde_json::json!({}),
+            CacheConfig::default(),
+        )
+        .unwrap();
+
+        let blob_id = "a".repeat(64);
+        let stale_id = "b".repeat(64);
+        std::fs::write(work_dir.join(&stale_id), &[1u8; 4096]).unwrap();
+        std::fs::write(work_dir.join("not_a_blob"), &[1u8; 4096]).unwrap();
+
+        let mut expect = vec![0u8; 8192];
+        blob_cache
+            .backend
+            .read(&blob_id, expect.as_mut(), 0)
+            .unwrap();
+        let mut chunk = MockChunkInfo::new();
+        chunk.block_id = RafsDigest::from_buf(&expect, digest::Algorithm::Blake3);
+        chunk.compress_size = 8192;
+        chunk.decompress_size = 8192;
+        let chunk = Arc::new(chunk);
+        let bio = RafsBio::new(
+            chunk.clone(),
+            blob_id.clone(),
+            0,
+            8192,
+            RAFS_DEFAULT_BLOCK_SIZE as u32,
+        );
+
+        let mut buf = vec![0u8; 8192];
+        let vs = unsafe { VolatileSlice::new(buf.as_mut_ptr(), buf.len()) };
+        blob_cache.read(&bio, &[vs], 0).unwrap();
+
+        let mut blobs = blob_cache.list_blobs().unwrap();
+        blobs.sort_by(|a, b| a.blob_id.cmp(&b.blob_id));
+        assert_eq!(blobs.len(), 2);
+        assert_eq!(blobs[0].blob_id, blob_id);
+        assert!(blobs[0].in_use);
+        assert!(blobs[0].cached_size >= 8192);
+        assert_eq!(blobs[1].blob_id, stale_id);
+        assert!(!blobs[1].in_use);
+
+        // Data is fetched from backend again after chunks are evicted.
+        blob_cache.evict_chunks(&[bio.clone()]).unwrap();
+        assert!(!blob_cache.has(chunk.as_ref()));
+        let blobs = blob_cache.list_blobs().unwrap();
+        let blob = blobs.iter().find(|b| b.blob_id == blob_id).unwrap();
+        assert!(blob.cached_size < 8192);
+        blob_cache.read(&bio, &[vs], 0).unwrap();
+        assert_eq!(buf, expect);
+        assert!(blob_cache.has(chunk.as_ref()));
+
+        // Only unreferenced blobs not used by the cache are collected.
+        let removed = blob_cache.gc(&HashSet::new()).unwrap();
+        assert_eq!(removed, vec![stale_id.clone()]);
+        assert!(!work_dir.join(&stale_id).exists());
+        assert!(work_dir.join("not_a_blob").exists());
+        assert!(blob_cache.evict_blob("../not_a_blob").is_err());
+
+        blob_cache.evict_blob(&blob_id).unwrap();
+        assert!(!blob_cache.has(chunk.as_ref()));
+        assert!(blob_cache.list_blobs().unwrap().is_empty());
+        blob_cache.read(&bio, &[vs], 0).unwrap();
+        assert_eq!(buf, expect);
+    }
+
+    #[test]
+    fn test_cache_archive() {
+        let tmp_dir = TempDir::new().unwrap();
+        let archive_cache = |name: &str, compressed: bool| {
+            new_cache(
+                name,
+                &tmp_dir.as_path().join(name),
+                serde_json::json!({}),
+                CacheConfig {
+                    cache_compressed: compressed,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+
+        let blob_id = "blobcache";
+        let mut expect = vec![0u8; 100];
+        let source = archive_cache("source", false);
+        source.backend.read(blob_id, expect.as_mut(), 0).unwrap();
+        let mut chunk = MockChunkInfo::new();
+        chunk.block_id = RafsDigest::from_buf(&expect, digest::Algorithm::Blake3);
+        chunk.compress_size = 100;
+        chunk.decompress_size = 100;
+        let chunk = Arc::new(chunk);
+        let bio = RafsBio::new(
+            chunk.clone(),
+            blob_id.to_string(),
+            0,
+            100,
+            RAFS_DEFAULT_BLOCK_SIZE as u32,
+        );
+        let bios = vec![bio.clone(), bio];
+
+        // Nothing is cached yet.
+        let archive = tmp_dir.as_path().join("archive");
+        assert_eq!(source.export_chunks(&bios, &archive).unwrap(), 0);
+
+        let mut buf = vec![0u8; 100];
+        let vs = unsafe { VolatileSlice::new(buf.as_mut_ptr(), buf.len()) };
+        source.read(&bios[0], &[vs], 0).unwrap();
+        assert_eq!(source.export_chunks(&bios, &archive).unwrap(), 1);
+
+        let target = archive_cache("target", false);
+        assert!(!target.has(chunk.as_ref()));
+        assert_eq!(target.import_chunks(&bios, &archive).unwrap(), 1);
+        assert!(target.has(chunk.as_ref()));
+
+        // Archive of plain layout can't be imported by compressed blobcache.
+        assert!(archive_cache("compressed", true)
+            .import_chunks(&bios, &archive)
+            .is_err());
+
+        // Corrupted chunks are not imported.
+        let file = OpenOptions::new().write(true).open(&archive).unwrap();
+        let len = file.metadata().unwrap().len();
+        file.write_all_at(&[0xffu8; 10], len - 10).unwrap();
+        let target = archive_cache("corrupted", false);
+        assert_eq!(target.import_chunks(&bios, &archive).unwrap(), 0);
+        assert!(!target.has(chunk.as_ref()));
+    }
+
+    #[test]
+    fn test_quota() {
+        let tmp_dir = TempDir::new().unwrap();
+        let work_dir = tmp_dir.as_path().join("cache");
+        let new_quota_cache = |id: &str, soft_limit: u64, hard_limit: u64, capacity: u64| {
+            let options = serde_json::json!({
+                "quota_soft_limit": soft_limit,
+                "quota_hard_limit": hard_limit,
+                "work_dir_capacity": capacity,
+            });
+            new_cache(id, &work_dir, options, CacheConfig::default())
+        };
+        // Mock backend returns the same data at any offset, so make chunks different in size.
+        let read_chunk = |cache: &blobcache::BlobCache, blob_id: &str, offset: u64, size: u32| {
+            let mut data = vec![0u8; size as usize];
+            cache.backend.read(blob_id, &mut data, 0).unwrap();
+            let mut chunk = MockChunkInfo::new();
+            chunk.block_id = RafsDigest::from_buf(&data, digest::Algorithm::Blake3);
+            chunk.decompress_offset = offset;
+            chunk.compress_size = size;
+            chunk.decompress_size = size;
+            let chunk: Arc<dyn RafsChunkInfo> = Arc::new(chunk);
+            let bio = RafsBio::new(
+                chunk.clone(),
+                blob_id.to_string(),
+                0,
+                size,
+                RAFS_DEFAULT_BLOCK_SIZE as u32,
+            );
+            let vs = unsafe { VolatileSlice::new(data.as_mut_ptr(), data.len()) };
+            cache.read(&bio, &[vs], 0).unwrap();
+            chunk
+        };
+
+        assert!(new_quota_cache("invalid_quota", 2, 1, 0).is_err());
+
+        // Least recently used chunks are evicted down to soft limit once exceeding hard limit.
+        let cache = new_quota_cache("hard_limit", 120, 250, 0).unwrap();
+        let chunks = [(0, 100), (1000, 110), (2000, 120)]
+            .iter()
+            .map(|(offset, size)| read_chunk(&cache, "hard_limit", *offset, *size))
+            .collect::<Vec<Arc<dyn RafsChunkInfo>>>();
+        assert_eq!(cache.metrics.quota_usage.load(Ordering::Relaxed), 120);
+        assert_eq!(cache.metrics.quota_evicted_chunks.count(), 2);
+        assert!(!cache.has(chunks[0].as_ref()));
+        assert!(!cache.has(chunks[1].as_ref()));
+        assert!(cache.has(chunks[2].as_ref()));
+        drop(cache);
+
+        // Capacity of work_dir is reclaimed from the blobcache exceeding soft limit.
+        let noisy = new_quota_cache("noisy", 100, 0, 300).unwrap();
+        let quiet = new_quota_cache("quiet", 1000, 0, 300).unwrap();
+        let quiet_chunk = read_chunk(&quiet, "quiet", 0, 120);
+        for (offset, size) in [(0, 100), (1000, 110), (2000, 120)].iter() {
+            read_chunk(&noisy, "noisy", *offset, *size);
+        }
+        assert!(noisy.metrics.quota_usage.load(Ordering::Relaxed) <= 130);
+        assert_eq!(quiet.metrics.quota_usage.load(Ordering::Relaxed), 120);
+        assert!(quiet.has(quiet_chunk.as_ref()));
+    }
+
+    #[test]
+    fn test_chunk_index() {
+        let tmp_dir = TempDir::new().unwrap();
+        let blob_cache = new_cache(
+            "index",
+            &tmp_dir.as_path().join("cache"),
+            serde_json::json!({}),
+            CacheConfig::default(),
+        )
+        .unwrap();
+
+        let blob_id = "blobcache";
+        let mut expect = vec![0u8; 100];
+        blob_cache
+            .backend
+            .read(blob_id, expect.as_mut(), 0)
+            .unwrap();
+        // Chunks of the same data at different positions are cached separately.
+        let chunks = (0..2)
+            .map(|i| {
+                let mut chunk = MockChunkInfo::new();
+                chunk.block_id = RafsDigest::from_buf(&expect, digest::Algorithm::Blake3);
+                chunk.flags = RafsChunkFlags::INDEXED;
+                chunk.index = 7 + i;
+                chunk.decompress_offset = 100 * i as u64;
+                chunk.compress_size = 100;
+                chunk.decompress_size = 100;
+                Arc::new(chunk) as Arc<dyn RafsChunkInfo>
+            })
+            .collect::<Vec<Arc<dyn RafsChunkInfo>>>();
+
+        let mut buf = vec![0u8; 100];
+        let vs = unsafe { VolatileSlice::new(buf.as_mut_ptr(), buf.len()) };
+        let bio = RafsBio::new(
+            chunks[0].clone(),
+            blob_id.to_string(),
+            0,
+            100,
+            RAFS_DEFAULT_BLOCK_SIZE as u32,
+        );
+        blob_cache.read(&bio, &[vs], 0).unwrap();
+        assert_eq!(buf, expect);
+        assert!(blob_cache.has(chunks[0].as_ref()));
+        assert!(!blob_cache.has(chunks[1].as_ref()));
+        // Index scales with position of chunks rather than number of accessed chunks.
+        assert_eq!(blob_cache.metrics.entries_count.load(Ordering::Relaxed), 8);
+
+        blob_cache.evict(chunks[0].as_ref()).unwrap();
+        assert!(!blob_cache.has(chunks[0].as_ref()));
+        assert_eq!(blob_cache.metrics.quota_usage.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_chunk_legacy_position() {
+        let tmp_dir = TempDir::new().unwrap();
+        let blob_cache = new_cache(
+            "legacy",
+            &tmp_dir.as_path().join("cache"),
+            serde_json::json!({}),
+            CacheConfig::default(),
+        )
+        .unwrap();
+
+        let blob_id = "blobcache";
+        let mut expect = vec![0u8; 100];
+        blob_cache
+            .backend
+            .read(blob_id, expect.as_mut(), 0)
+            .unwrap();
+        // Chunks from bootstraps built before chunk index was recorded.
+        let chunks = (0..2)
+            .map(|i| {
+                let mut chunk = MockChunkInfo::new();
+                chunk.block_id = RafsDigest::from_buf(&expect, digest::Algorithm::Blake3);
+                chunk.flags = RafsChunkFlags::empty();
+                chunk.decompress_offset = 100 * i as u64;
+                chunk.compress_size = 100;
+                chunk.decompress_size = 100;
+                Arc::new(chunk) as Arc<dyn RafsChunkInfo>
+            })
+            .collect::<Vec<Arc<dyn RafsChunkInfo>>>();
+
+        // Positions are assigned in order of access, keyed by offset in cache file.
+        for (n, chunk) in chunks.iter().rev().enumerate() {
+            let mut buf = vec![0u8; 100];
+            let vs = unsafe { VolatileSlice::new(buf.as_mut_ptr(), buf.len()) };
+            let bio = RafsBio::new(
+                chunk.clone(),
+                blob_id.to_string(),
+                0,
+                100,
+                RAFS_DEFAULT_BLOCK_SIZE as u32,
+            );
+            blob_cache.read(&bio, &[vs], 0).unwrap();
+            assert_eq!(buf, expect);
+            assert!(blob_cache.has(chunk.as_ref()));
+            assert_eq!(
+                blob_cache.metrics.entries_count.load(Ordering::Relaxed),
+                n + 1
+            );
+        }
+
+        let blob = blob_cache.blob_file(blob_id, chunks[0].as_ref()).unwrap();
+        assert_eq!(blob_cache.find_chunk(&blob, chunks[1].as_ref()), Some(0));
+        assert_eq!(blob_cache.find_chunk(&blob, chunks[0].as_ref()), Some(1));
+        assert_eq!(blob.lookup(chunks[0].as_ref(), 200), None);
+
+        blob_cache.evict(chunks[1].as_ref()).unwrap();
+        assert!(!blob_cache.has(chunks[1].as_ref()));
+        assert!(blob_cache.has(chunks[0].as_ref()));
+    }
+
+    #[test]
+    fn test_direct_io() {
+        let tmp_dir = TempDir::new().unwrap();
+        let blob_cache = new_cache(
+            "direct",
+            &tmp_dir.as_path().join("cache"),
+            serde_json::json!({ "direct_io": true, "io_uring": true }),
+            CacheConfig::default(),
+        )
+        .unwrap();
+
+        let blob_id = "blobcache";
+        // Neither chunk is aligned, the second one spans a whole aligned block.
+        let chunks = [(0u64, 5000usize), (5000, 9000)]
+            .iter()
+            .map(|&(offset, size)| {
+                let mut expect = vec![0u8; size];
+                blob_cache
+                    .backend
+                    .read(blob_id, expect.as_mut(), 0)
+                    .unwrap();
+                let mut chunk = MockChunkInfo::new();
+                chunk.block_id = RafsDigest::from_buf(&expect, digest::Algorithm::Blake3);
+                chunk.decompress_offset = offset;
+                chunk.compress_size = size as u32;
+                chunk.decompress_size = size as u32;
+                (Arc::new(chunk) as Arc<dyn RafsChunkInfo>, expect)
+            })
+            .collect::<Vec<_>>();
+
+        // The first round fills cache file and the second one hits it.
+        for _ in 0..2 {
+            for (chunk, expect) in chunks.iter() {
+                let size = expect.len() - 100;
+                let mut buf = vec![0u8; size];
+                let vs = unsafe { VolatileSlice::new(buf.as_mut_ptr(), buf.len()) };
+                let bio = RafsBio::new(
+                    chunk.clone(),
+                    blob_id.to_string(),
+                    100,
+                    size,
+                    RAFS_DEFAULT_BLOCK_SIZE as u32,
+                );
+                assert_eq!(blob_cache.read(&bio, &[vs], 100).unwrap(), size);
+                assert_eq!(buf, &expect[100..]);
+            }
+        }
+        assert_eq!(blob_cache.metrics.partial_hits.count(), 2);
+        // Chunks are written in whole and read without the first 100 bytes. File systems
+        // without O_DIRECT support fall back to buffered IO.
+        assert!(
+            blob_cache.metrics.direct_io_amount.count()
+                + blob_cache.metrics.buffered_io_amount.count()
+                >= 14000 + 13800
+        );
+    }
+
+    #[test]
+    fn test_encryption() {
+        let tmp_dir = TempDir::new().unwrap();
+        let work_dir = tmp_dir.as_path().join("cache");
+        let key_file = tmp_dir.as_path().join("key");
+        std::fs::write(&key_file, [3u8; 32]).unwrap();
+
+        let blob_id = "blobcache";
+        let mut expect = vec![0u8; 100];
+        let mut chunk = MockChunkInfo::new();
+        chunk.decompress_offset = 100;
+        chunk.compress_size = 100;
+        chunk.decompress_size = 100;
+        let mut chunk = Arc::new(chunk);
+
+        // The second round runs as if nydusd is restarted.
+        for round in 0..2 {
+            let blob_cache = new_cache(
+                "encryption",
+                &work_dir,
+                serde_json::json!({ "encryption_key_file": key_file }),
+                CacheConfig::default(),
+            )
+            .unwrap();
+            if round == 0 {
+                blob_cache
+                    .backend
+                    .read(blob_id, expect.as_mut(), 0)
+                    .unwrap();
+                Arc::get_mut(&mut chunk).unwrap().block_id =
+                    RafsDigest::from_buf(&expect, digest::Algorithm::Blake3);
+            }
+
+            let mut buf = vec![0u8; 50];
+            let vs = unsafe { VolatileSlice::new(buf.as_mut_ptr(), buf.len()) };
+            let bio = RafsBio::new(
+                chunk.clone(),
+                blob_id.to_string(),
+                50,
+                50,
+                RAFS_DEFAULT_BLOCK_SIZE as u32,
+            );
+            blob_cache.read(&bio, &[vs], 50).unwrap();
+            assert_eq!(buf, &expect[50..]);
+            // Chunks sealed by previous runs are trusted without fetching from backend.
+            assert_eq!(blob_cache.metrics.whole_hits.count(), round);
+            assert!(blob_cache.has(chunk.as_ref()));
+        }
+
+        let data = std::fs::read(work_dir.join(blob_id)).unwrap();
+        assert_ne!(&data[100..200], expect.as_slice());
+        assert!(work_dir.join(format!("{}.seal", blob_id)).exists());
+    }
+
+    #[test]
+    fn test_prefetch_queue_promote() {
+        let queue = Arc::new(PrefetchQueue::new(1));
+        assert_eq!(queue.open(), 1);
+        let mut chunks: Vec<Arc<dyn RafsChunkInfo>> = Vec::new();
+
+        for i in 0..3u8 {
+            let mut chunk = MockChunkInfo::new();
+            chunk.block_id = RafsDigest::from_buf(&[i], digest::Algorithm::Blake3);
+            chunk.compress_offset = i as u64 * 100;
+            chunk.compress_size = 100;
+            let chunk: Arc<dyn RafsChunkInfo> = Arc::new(chunk);
+            let mut mr = MergedBackendRequest::new(i as u64);
+            mr.merge_begin(chunk.clone(), "blobcache");
+            assert!(queue.push(mr));
+            chunks.push(chunk);
+        }
+
+        // User IO touches chunk of the last request.
+        let q = queue.clone();
+        let chunk = chunks[2].clone();
+        let waiter = thread::spawn(move || q.promote_and_wait(chunk.as_ref()));
+
+        // Ordinary requests are held while paused, only the promoted one comes out.
+        let (id, mr) = queue.pop(&|| true).unwrap();
+        assert_eq!(mr.seq, 2);
+        queue.complete(id, &mr);
+        assert_eq!(waiter.join().unwrap(), (true, true));
+
+        queue.close();
+        assert_eq!(queue.pop(&|| false).unwrap().1.seq, 0);
+        assert_eq!(queue.pop(&|| false).unwrap().1.seq, 1);
+        assert!(queue.pop(&|| false).is_none());
+        assert!(!queue.push(MergedBackendRequest::new(3)));
+        assert_eq!(queue.promote_and_wait(chunks[0].as_ref()), (false, false));
+    }
+
+    #[test]
+    fn test_prefetch_progress() {
+        let tmp_dir = TempDir::new().unwrap();
+        let blob_cache = new_cache(
+            "progress",
+            &tmp_dir.as_path().join("cache"),
+            serde_json::json!({}),
+            CacheConfig {
+                prefetch_worker: PrefetchWorker {
+                    merging_size: 1024,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // Two continuous chunks and a distant one make up two merged requests.
+        let mut bios = [0u64, 100, 1000]
+            .iter()
+            .enumerate()
+            .map(|(i, offset)| {
+                let mut chunk = MockChunkInfo::new();
+                chunk.block_id = RafsDigest::from_buf(&[i as u8], digest::Algorithm::Blake3);
+                chunk.compress_offset = *offset;
+                chunk.compress_size = 100;
+                chunk.decompress_size = 100;
+                RafsBio::new(
+                    Arc::new(chunk),
+                    "blobcache".to_string(),
+                    0,
+                    100,
+                    RAFS_DEFAULT_BLOCK_SIZE as u32,
+                )
+            })
+            .collect::<Vec<RafsBio>>();
+
+        // No worker is kicked, requests stay queued.
+        blob_cache.prefetch_queue.open();
+        blob_cache.prefetch(&mut bios).unwrap();
+        assert_eq!(blob_cache.prefetch_planned.load(Ordering::Relaxed), 300);
+
+        let mut done = 0;
+        let mut mrs = Vec::new();
+        for _ in 0..2 {
+            let (id, mr) = blob_cache.prefetch_queue.pop(&|| false).unwrap();
+            blob_cache.prefetch_queue.complete(id, &mr);
+            done += mr.chunks_size();
+            mrs.push(mr);
+        }
+        assert_eq!(done, 300);
+        assert_eq!(mrs[0].chunks.len(), 2);
+
+        // Requests never queued are not planned any more.
+        blob_cache.prefetch_queue.close();
+        blob_cache.push_merged_request(mrs.pop().unwrap());
+        assert_eq!(blob_cache.prefetch_planned.load(Ordering::Relaxed), 200);
+    }
+
+    #[test]
+    fn test_prefetch_queue_control() {
+        let queue = PrefetchQueue::new(2);
+        assert!(!queue.push(MergedBackendRequest::new(0)));
+        assert_eq!(queue.open(), 2);
+        assert!(queue.push(MergedBackendRequest::new(0)));
+
+        // A redundant worker gives up even if there are requests queued.
+        assert_eq!(queue.set_max_workers(1), 0);
+        assert!(queue.pop(&|| false).is_none());
+        assert_eq!(queue.set_max_workers(3), 2);
+        assert_eq!(queue.max_workers(), 3);
+
+        queue.set_paused(true);
+        assert!(queue.is_paused());
+        queue.set_paused(false);
+        assert_eq!(queue.pop(&|| false).unwrap().1.seq, 0);
+
+        queue.close();
+        assert!(queue.is_closed());
+        assert!(queue.pop(&|| false).is_none());
+        assert_eq!(queue.open(), 1);
+    }
 }
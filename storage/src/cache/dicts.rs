@@ -0,0 +1,152 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Compression dictionaries of blobs.
+//!
+//! A dictionary is stored once at the head of its blob, and referenced from the blob table.
+//! It's loaded from backend on the first read of a chunk compressed with it, and kept in
+//! memory until the cache is released.
+
+use std::collections::HashMap;
+use std::io::Result;
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::compress::ZstdDict;
+use crate::device::BlobPrefetchControl;
+
+struct BlobDict {
+    blob_id: String,
+    offset: u32,
+    size: u32,
+    loaded: Mutex<Option<Arc<ZstdDict>>>,
+}
+
+/// Dictionaries of blobs, indexed by positions of blobs in blob table.
+#[derive(Default)]
+pub struct BlobDicts {
+    dicts: RwLock<HashMap<u32, Arc<BlobDict>>>,
+}
+
+impl BlobDicts {
+    /// Register dictionaries of `blobs`, which are all blobs in blob table in order.
+    pub fn register(&self, blobs: &[BlobPrefetchControl]) {
+        let mut dicts = self.dicts.write().unwrap();
+        for (index, b) in blobs.iter().enumerate() {
+            if b.dict_size != 0 {
+                dicts.insert(
+                    index as u32,
+                    Arc::new(BlobDict {
+                        blob_id: b.blob_id.clone(),
+                        offset: b.dict_offset,
+                        size: b.dict_size,
+                        loaded: Mutex::new(None),
+                    }),
+                );
+            }
+        }
+    }
+
+    /// Take over dictionaries of the cache being replaced, loaded ones are not loaded again.
+    pub fn inherit(&self, other: &BlobDicts) {
+        let other = other.dicts.read().unwrap();
+        self.dicts
+            .write()
+            .unwrap()
+            .extend(other.iter().map(|(k, v)| (*k, v.clone())));
+    }
+
+    /// Dictionary of the blob at `blob_index` of blob table, it's read by `read_at` given blob
+    /// id, buffer and offset in blob if it's not loaded yet.
+    pub fn get<F>(&self, blob_index: u32, read_at: F) -> Result<Arc<ZstdDict>>
+    where
+        F: FnOnce(&str, &mut [u8], u64) -> Result<usize>,
+    {
+        let dict = self
+            .dicts
+            .read()
+            .unwrap()
+            .get(&blob_index)
+            .cloned()
+            .ok_or_else(|| enoent!(format!("blob {} has no dictionary", blob_index)))?;
+
+        // Concurrent readers wait for the dictionary being loaded rather than load it again.
+        let mut loaded = dict.loaded.lock().unwrap();
+        if let Some(d) = loaded.as_ref() {
+            return Ok(d.clone());
+        }
+        let mut data = vec![0u8; dict.size as usize];
+        let size = read_at(&dict.blob_id, &mut data, dict.offset as u64)?;
+        if size != data.len() {
+            return Err(eio!(format!(
+                "dictionary of blob {} is truncated",
+                dict.blob_id
+            )));
+        }
+        let d = Arc::new(ZstdDict::new(data)?);
+        *loaded = Some(d.clone());
+
+        Ok(d)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_blob_dicts() {
+        let samples = (0..1000)
+            .map(|i| {
+                format!(
+                    "key_{} = \"value of key {}\"\nenabled = {}\n",
+                    i,
+                    i * 3,
+                    i % 2
+                )
+            })
+            .collect::<Vec<_>>();
+        let data = ZstdDict::train(&samples, 2048).unwrap();
+        // The dictionary is at the head of the second blob.
+        let mut blob = data.clone();
+        blob.extend_from_slice(&[0u8; 100]);
+
+        let dicts = BlobDicts::default();
+        dicts.register(&[
+            BlobPrefetchControl {
+                blob_id: "blob0".to_string(),
+                offset: 0,
+                len: 0,
+                dict_offset: 0,
+                dict_size: 0,
+                key_id: String::new(),
+            },
+            BlobPrefetchControl {
+                blob_id: "blob1".to_string(),
+                offset: 0,
+                len: 0,
+                dict_offset: 0,
+                dict_size: data.len() as u32,
+                key_id: String::new(),
+            },
+        ]);
+
+        let reads = Cell::new(0);
+        let read_at = |blob_id: &str, buf: &mut [u8], offset: u64| {
+            assert_eq!(blob_id, "blob1");
+            reads.set(reads.get() + 1);
+            let offset = offset as usize;
+            buf.copy_from_slice(&blob[offset..offset + buf.len()]);
+            Ok(buf.len())
+        };
+        assert!(dicts.get(0, read_at).is_err());
+        let dict = dicts.get(1, read_at).unwrap();
+        assert_eq!(dict.as_bytes(), data.as_slice());
+        // Loaded only once, even by the cache taking over.
+        let other = BlobDicts::default();
+        other.inherit(&dicts);
+        assert_eq!(other.get(1, read_at).unwrap().id(), dict.id());
+        assert_eq!(reads.get(), 1);
+    }
+}
@@ -2,8 +2,10 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use std::collections::{HashSet, VecDeque};
 use std::io::Result;
-use std::sync::Arc;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 
 use vm_memory::VolatileSlice;
 
@@ -16,11 +18,72 @@ use crate::{compress, StorageError, StorageResult};
 
 use nydus_utils::{digest, eother};
 
+/// Count of batch chunks kept decompressed when the chunk buffer is disabled, so that small
+/// files packed into the same chunk don't fetch it from backend one after another.
+const BATCH_BUFFERED_CHUNKS: usize = 8;
+
+#[derive(Clone, Default, Deserialize)]
+struct DummyCacheConfig {
+    // Count of the last chunks read which are kept decompressed in memory, so that small
+    // reads of the same chunk don't fetch it from backend again. Zero disables it.
+    #[serde(default)]
+    buffered_chunks: usize,
+}
+
+/// The last chunks read, kept decompressed. It works like a last chunk buffer per file, as
+/// long as files being read at the same time are not more than chunks it holds. It's shared
+/// by all files rather than one per file, since the cache is never told which file a chunk is
+/// read for, or when a file is closed, and chunks are deduplicated across files anyway. So
+/// memory it takes is bounded by its capacity however many files are open.
+struct ChunkBuffer {
+    capacity: usize,
+    // The most recently used chunk is at the back.
+    chunks: Mutex<VecDeque<(String, u64, Arc<Vec<u8>>)>>,
+}
+
+impl ChunkBuffer {
+    fn new(capacity: usize) -> Self {
+        ChunkBuffer {
+            capacity,
+            chunks: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    fn get(&self, blob_id: &str, cki: &dyn RafsChunkInfo) -> Option<Arc<Vec<u8>>> {
+        let mut chunks = self.chunks.lock().unwrap();
+        let pos = chunks
+            .iter()
+            .position(|(id, offset, _)| id == blob_id && *offset == cki.compress_offset())?;
+        let entry = chunks.remove(pos).unwrap();
+        let data = entry.2.clone();
+        chunks.push_back(entry);
+        Some(data)
+    }
+
+    fn insert(&self, blob_id: &str, cki: &dyn RafsChunkInfo, data: Vec<u8>) -> Arc<Vec<u8>> {
+        let data = Arc::new(data);
+        let mut chunks = self.chunks.lock().unwrap();
+        chunks.retain(|(id, offset, _)| id != blob_id || *offset != cki.compress_offset());
+        if chunks.len() >= self.capacity {
+            chunks.pop_front();
+        }
+        chunks.push_back((blob_id.to_string(), cki.compress_offset(), data.clone()));
+        data
+    }
+}
+
 pub struct DummyCache {
     pub backend: Arc<dyn BlobBackend + Sync + Send>,
     validate: bool,
     compressor: compress::Algorithm,
     digester: digest::Algorithm,
+    stargz_streams: StargzStreams,
+    inflight_fetches: InflightFetches,
+    blob_dicts: BlobDicts,
+    blob_keys: BlobKeys,
+    buffer: Option<ChunkBuffer>,
+    /// Buffer of batch chunks, used only if `buffer` is disabled.
+    batch_buffer: ChunkBuffer,
 }
 
 impl RafsCache for DummyCache {
@@ -33,6 +96,8 @@ impl RafsCache for DummyCache {
     }
 
     fn init(&self, prefetch_vec: &[BlobPrefetchControl]) -> Result<()> {
+        self.blob_dicts.register(prefetch_vec);
+        self.blob_keys.register(prefetch_vec)?;
         for b in prefetch_vec {
             let _ = self.backend.prefetch_blob(&b.blob_id, b.offset, b.len);
         }
@@ -54,6 +119,44 @@ impl RafsCache for DummyCache {
 
         let d_size = chunk.decompress_size() as usize;
 
+        let buffer = match self.buffer {
+            Some(ref buffer) => Some(buffer),
+            None if chunk.is_batch() => Some(&self.batch_buffer),
+            None => None,
+        };
+        if let Some(buffer) = buffer {
+            let data = match buffer.get(blob_id, chunk.as_ref()) {
+                Some(data) => data,
+                None => {
+                    let mut d = alloc_buf(d_size);
+                    self.read_backend_chunk(blob_id, chunk.as_ref(), &mut d, |_, _| Ok(()))?;
+                    buffer.insert(blob_id, chunk.as_ref(), d)
+                }
+            };
+            return copyv(&data, bufs, offset, bio.size);
+        }
+
+        // Only the wanted range of stargz chunks is inflated, resuming from the nearest
+        // checkpoint of the gzip member, unless the whole chunk has to be validated.
+        if chunk.is_compressed()
+            && self.compressor == compress::Algorithm::GZip
+            && !self.validate
+            && offset < d_size as u64
+        {
+            let size = cmp::min(bio.size, d_size - offset as usize);
+            let mut d = alloc_buf(size);
+            let blob_size = self.blob_size(blob_id)?;
+            self.stargz_streams.read(
+                blob_id,
+                chunk.as_ref(),
+                offset,
+                &mut d,
+                None,
+                |buf, offset| read_backend_range(self.backend(), blob_id, blob_size, buf, offset),
+            )?;
+            return copyv(&d, bufs, 0, size);
+        }
+
         let mut d;
         let one_chunk_buf = if bufs.len() == 1 && offset == 0 && bufs[0].len() >= d_size {
             // Use the destination buffer to received the decompressed data.
@@ -73,6 +176,64 @@ impl RafsCache for DummyCache {
         }
     }
 
+    /// Fetch chunks of a read which are adjacent in blob at once into the chunk buffer.
+    fn prepare_read(&self, bios: &[RafsBio]) -> usize {
+        let buffer = match self.buffer {
+            Some(ref buffer) if bios.len() > 1 => buffer,
+            _ => return bios.len(),
+        };
+        // Chunks prepared must stay in the buffer till they're read, so no more than it
+        // holds are prepared at a time.
+        let bios = &bios[..cmp::min(bios.len(), buffer.capacity)];
+
+        let mut runs: Vec<Vec<&RafsBio>> = Vec::new();
+        for bio in bios {
+            let cki = bio.chunkinfo.as_ref();
+            // Size of gzip members is unknown, so they can't be merged.
+            if cki.is_hole()
+                || (cki.is_compressed() && self.compressor == compress::Algorithm::GZip)
+                || buffer.get(&bio.blob_id, cki).is_some()
+            {
+                continue;
+            }
+            match runs.last_mut() {
+                Some(run)
+                    if run.last().map_or(false, |prior| {
+                        prior.blob_id == bio.blob_id
+                            && prior.chunkinfo.compress_offset()
+                                + prior.chunkinfo.compress_size() as u64
+                                == cki.compress_offset()
+                    }) =>
+                {
+                    run.push(bio)
+                }
+                _ => runs.push(vec![bio]),
+            }
+        }
+
+        // Single chunks are fetched on read.
+        for run in runs.iter().filter(|r| r.len() > 1) {
+            let first = run[0].chunkinfo.compress_offset();
+            let last = &run[run.len() - 1].chunkinfo;
+            let size = (last.compress_offset() + last.compress_size() as u64 - first) as usize;
+            let ckis = run
+                .iter()
+                .map(|bio| bio.chunkinfo.clone())
+                .collect::<Vec<_>>();
+            match self.read_chunks(&run[0].blob_id, first, size, &ckis) {
+                Ok(chunks) => {
+                    for (cki, data) in ckis.iter().zip(chunks) {
+                        buffer.insert(&run[0].blob_id, cki.as_ref(), data);
+                    }
+                }
+                // Chunks are fetched one by one on read then.
+                Err(e) => debug!("failed to fetch {} adjacent chunks, {}", run.len(), e),
+            }
+        }
+
+        bios.len()
+    }
+
     fn blob_size(&self, blob_id: &str) -> Result<u64> {
         self.backend().blob_size(blob_id).map_err(|e| eother!(e))
     }
@@ -89,6 +250,26 @@ impl RafsCache for DummyCache {
         self.validate
     }
 
+    fn stargz_streams(&self) -> &StargzStreams {
+        &self.stargz_streams
+    }
+
+    fn inflight_fetches(&self) -> Option<&InflightFetches> {
+        Some(&self.inflight_fetches)
+    }
+
+    fn blob_dicts(&self) -> &BlobDicts {
+        &self.blob_dicts
+    }
+
+    fn blob_keys(&self) -> &BlobKeys {
+        &self.blob_keys
+    }
+
+    fn prefetch_supported(&self) -> bool {
+        false
+    }
+
     /// Prefetch works when blobcache is enabled
     fn prefetch(&self, _bios: &mut [RafsBio]) -> StorageResult<usize> {
         Err(StorageError::Unsupported)
@@ -98,10 +279,61 @@ impl RafsCache for DummyCache {
         Err(StorageError::Unsupported)
     }
 
+    fn start_prefetch(&self) -> StorageResult<()> {
+        Err(StorageError::Unsupported)
+    }
+
+    fn pause_prefetch(&self, _paused: bool) -> StorageResult<()> {
+        Err(StorageError::Unsupported)
+    }
+
+    fn tune_prefetch(
+        &self,
+        _threads_count: Option<usize>,
+        _bandwidth_rate: Option<u32>,
+    ) -> StorageResult<()> {
+        Err(StorageError::Unsupported)
+    }
+
+    fn prefetch_progress(&self) -> StorageResult<PrefetchProgress> {
+        Err(StorageError::Unsupported)
+    }
+
+    fn scrub(&self, _bios: Vec<RafsBio>) -> StorageResult<()> {
+        Err(StorageError::Unsupported)
+    }
+
+    fn list_blobs(&self) -> StorageResult<Vec<CachedBlobInfo>> {
+        Err(StorageError::Unsupported)
+    }
+
+    fn evict_blob(&self, _blob_id: &str) -> StorageResult<()> {
+        Err(StorageError::Unsupported)
+    }
+
+    fn evict_chunks(&self, _bios: &[RafsBio]) -> StorageResult<()> {
+        Err(StorageError::Unsupported)
+    }
+
+    fn gc(&self, _referenced: &HashSet<String>) -> StorageResult<Vec<String>> {
+        Err(StorageError::Unsupported)
+    }
+
+    fn export_chunks(&self, _bios: &[RafsBio], _path: &Path) -> StorageResult<usize> {
+        Err(StorageError::Unsupported)
+    }
+
+    fn import_chunks(&self, _bios: &[RafsBio], _path: &Path) -> StorageResult<usize> {
+        Err(StorageError::Unsupported)
+    }
+
     fn write(&self, blob_id: &str, blk: &dyn RafsChunkInfo, buf: &[u8]) -> Result<usize> {
+        if blk.flags().encryptor().is_some() {
+            return Err(enosys!("writing encrypted chunk is not supported"));
+        }
         let out;
         let wbuf = if blk.is_compressed() {
-            out = compress::compress(buf, self.compressor())?;
+            out = compress::compress(buf, self.chunk_compressor(blk))?;
             out.0.as_ref()
         } else {
             unsafe { slice::from_raw_parts(buf.as_ptr(), buf.len()) }
@@ -123,10 +355,144 @@ pub fn new(
     compressor: compress::Algorithm,
     digester: digest::Algorithm,
 ) -> Result<DummyCache> {
+    // Dummy cache needs no config at all by default.
+    let dummy_config: DummyCacheConfig = if config.cache_config.is_null() {
+        DummyCacheConfig::default()
+    } else {
+        serde_json::from_value(config.cache_config).map_err(|e| einval!(e))?
+    };
+
     Ok(DummyCache {
         backend,
         validate: config.cache_validate,
         compressor,
         digester,
+        stargz_streams: StargzStreams::default(),
+        inflight_fetches: InflightFetches::default(),
+        blob_dicts: BlobDicts::default(),
+        blob_keys: BlobKeys::new(config.encryption),
+        buffer: if dummy_config.buffered_chunks > 0 {
+            Some(ChunkBuffer::new(dummy_config.buffered_chunks))
+        } else {
+            None
+        },
+        batch_buffer: ChunkBuffer::new(BATCH_BUFFERED_CHUNKS),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::backend::BackendResult;
+    use crate::test::MockChunkInfo;
+    use nydus_utils::metrics::BackendMetrics;
+
+    struct MockBackend {
+        metrics: Arc<BackendMetrics>,
+        reads: AtomicUsize,
+    }
+
+    impl BlobBackend for MockBackend {
+        fn try_read(&self, _blob_id: &str, buf: &mut [u8], _offset: u64) -> BackendResult<usize> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            Ok(buf.len())
+        }
+
+        fn write(&self, _blob_id: &str, _buf: &[u8], _offset: u64) -> BackendResult<usize> {
+            Ok(0)
+        }
+
+        fn blob_size(&self, _blob_id: &str) -> BackendResult<u64> {
+            Ok(0)
+        }
+
+        fn release(&self) {}
+
+        fn prefetch_blob(
+            &self,
+            _blob_id: &str,
+            _blob_readahead_offset: u32,
+            _blob_readahead_size: u32,
+        ) -> BackendResult<()> {
+            Ok(())
+        }
+
+        fn metrics(&self) -> &BackendMetrics {
+            &self.metrics
+        }
+    }
+
+    #[test]
+    fn test_chunk_buffer() {
+        let buffer = ChunkBuffer::new(2);
+        let chunk = |offset| MockChunkInfo {
+            compress_offset: offset,
+            compress_size: 4096,
+            decompress_size: 4096,
+            ..Default::default()
+        };
+
+        buffer.insert("blob", &chunk(0), vec![0u8; 4096]);
+        buffer.insert("blob", &chunk(4096), vec![1u8; 4096]);
+        assert!(buffer.get("other", &chunk(0)).is_none());
+        // The least recently used chunk is dropped.
+        assert_eq!(buffer.get("blob", &chunk(0)).unwrap()[0], 0);
+        buffer.insert("blob", &chunk(8192), vec![2u8; 4096]);
+        assert!(buffer.get("blob", &chunk(4096)).is_none());
+        assert_eq!(buffer.get("blob", &chunk(0)).unwrap()[0], 0);
+        assert_eq!(buffer.get("blob", &chunk(8192)).unwrap()[0], 2);
+
+        // Chunk inserted again replaces the old one.
+        buffer.insert("blob", &chunk(0), vec![3u8; 4096]);
+        assert_eq!(buffer.chunks.lock().unwrap().len(), 2);
+        assert_eq!(buffer.get("blob", &chunk(0)).unwrap()[0], 3);
+    }
+
+    #[test]
+    fn test_prepare_read() {
+        let backend = Arc::new(MockBackend {
+            metrics: BackendMetrics::new("dummy", "mock"),
+            reads: AtomicUsize::new(0),
+        });
+        let config = CacheConfig {
+            cache_type: String::from("dummycache"),
+            cache_config: serde_json::json!({ "buffered_chunks": 2 }),
+            ..Default::default()
+        };
+        let cache = new(
+            config,
+            backend.clone(),
+            compress::Algorithm::None,
+            digest::Algorithm::Blake3,
+        )
+        .unwrap();
+
+        let bios = (0..5)
+            .map(|i| {
+                let chunk = MockChunkInfo {
+                    compress_offset: i * 4096,
+                    compress_size: 4096,
+                    decompress_size: 4096,
+                    ..Default::default()
+                };
+                RafsBio::new(Arc::new(chunk), "blob".to_string(), 0, 4096, 4096)
+            })
+            .collect::<Vec<RafsBio>>();
+
+        // Adjacent chunks are fetched no more than the buffer holds at a time, so none of
+        // them is dropped before it's read.
+        let mut prepared = 0;
+        for (idx, bio) in bios.iter().enumerate() {
+            if idx == prepared {
+                prepared += cache.prepare_read(&bios[idx..]);
+            }
+            let mut buf = vec![0u8; 4096];
+            let vs = unsafe { VolatileSlice::new(buf.as_mut_ptr(), buf.len()) };
+            assert_eq!(cache.read(bio, &[vs], 0).unwrap(), 4096);
+        }
+        assert_eq!(prepared, 5);
+        assert_eq!(backend.reads.load(Ordering::Relaxed), 3);
+    }
+}
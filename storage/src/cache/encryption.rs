@@ -0,0 +1,338 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Encryption of blobcache files at rest.
+//!
+//! Chunks are encrypted one by one with AES-256-GCM as they are stored in cache file, so that
+//! they can still be accessed randomly. Each cache file is encrypted with its own key derived
+//! from the configured key and blob id, and the nonce is offset of the chunk in cache file,
+//! which is authenticated along with chunk size. Tags are persisted in a journal next to the
+//! cache file, named `<blob_id>.seal`, so that chunks whose tags verify are trusted across
+//! restarts.
+//!
+//! Each journal record is 28 bytes in little endian, the latest one of an offset wins:
+//! - offset of the chunk in cache file, u64
+//! - size of the chunk, u32
+//! - tag, 16 bytes
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Result, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex, RwLock};
+
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{AeadInPlace, NewAead};
+use aes_gcm::Aes256Gcm;
+
+use crate::encrypt::{parse_key, KEY_SIZE};
+
+const KEY_CONTEXT: &str = "nydus blobcache";
+const TAG_SIZE: usize = 16;
+const RECORD_SIZE: usize = 12 + TAG_SIZE;
+
+pub(crate) type ChunkTag = [u8; TAG_SIZE];
+
+lazy_static! {
+    // Keys are loaded once per daemon, so that the key command is not run for each mount.
+    static ref CIPHERS: Mutex<HashMap<String, Arc<CacheCipher>>> = Mutex::new(HashMap::new());
+}
+
+pub(crate) struct CacheCipher {
+    key: [u8; KEY_SIZE],
+}
+
+impl CacheCipher {
+    /// Load key from `key_file`, or stdout of `key_command` if there is no key file. The key
+    /// is 32 bytes of raw data or 64 hex digits.
+    pub fn load(key_file: &str, key_command: &str) -> Result<Arc<CacheCipher>> {
+        let source = if !key_file.is_empty() {
+            format!("file:{}", key_file)
+        } else {
+            format!("command:{}", key_command)
+        };
+        let mut ciphers = CIPHERS.lock().unwrap();
+        if let Some(cipher) = ciphers.get(&source) {
+            return Ok(cipher.clone());
+        }
+
+        let raw = if !key_file.is_empty() {
+            let mut raw = Vec::new();
+            File::open(key_file)
+                .and_then(|mut f| f.read_to_end(&mut raw))
+                .map_err(|e| einval!(format!("failed to read key file {}, {}", key_file, e)))?;
+            raw
+        } else {
+            let output = Command::new("sh")
+                .arg("-c")
+                .arg(key_command)
+                .output()
+                .map_err(|e| einval!(format!("failed to run key command, {}", e)))?;
+            if !output.status.success() {
+                return Err(einval!(format!("key command exits with {}", output.status)));
+            }
+            output.stdout
+        };
+
+        let cipher = Arc::new(CacheCipher {
+            key: parse_key(&raw)?,
+        });
+        ciphers.insert(source, cipher.clone());
+
+        Ok(cipher)
+    }
+
+    /// Cipher of the cache file of `blob_id`. Its key is derived from the key and blob id, so
+    /// that chunks at the same offset of different cache files never share a key and nonce.
+    pub fn blob_cipher(&self, blob_id: &str) -> BlobCipher {
+        let mut material = Vec::with_capacity(KEY_SIZE + blob_id.len());
+        material.extend_from_slice(&self.key);
+        material.extend_from_slice(blob_id.as_bytes());
+        let mut key = [0u8; KEY_SIZE];
+        blake3::derive_key(KEY_CONTEXT, &material, &mut key);
+
+        BlobCipher {
+            cipher: Aes256Gcm::new(GenericArray::from_slice(&key)),
+        }
+    }
+}
+
+pub(crate) struct BlobCipher {
+    cipher: Aes256Gcm,
+}
+
+impl BlobCipher {
+    fn nonce(offset: u64) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&offset.to_le_bytes());
+        nonce
+    }
+
+    fn aad(offset: u64, size: usize) -> [u8; 12] {
+        let mut aad = [0u8; 12];
+        aad[..8].copy_from_slice(&offset.to_le_bytes());
+        aad[8..].copy_from_slice(&(size as u32).to_le_bytes());
+        aad
+    }
+
+    /// Encrypt chunk data to be stored at `offset` of cache file in place.
+    pub fn seal(&self, offset: u64, buf: &mut [u8]) -> Result<ChunkTag> {
+        let nonce = Self::nonce(offset);
+        let aad = Self::aad(offset, buf.len());
+        self.cipher
+            .encrypt_in_place_detached(GenericArray::from_slice(&nonce), &aad, buf)
+            .map(|tag| tag.into())
+            .map_err(|_| eio!("failed to encrypt chunk"))
+    }
+
+    /// Decrypt chunk data read from `offset` of cache file in place.
+    pub fn open(&self, offset: u64, buf: &mut [u8], tag: &ChunkTag) -> Result<()> {
+        let nonce = Self::nonce(offset);
+        let aad = Self::aad(offset, buf.len());
+        self.cipher
+            .decrypt_in_place_detached(
+                GenericArray::from_slice(&nonce),
+                &aad,
+                buf,
+                GenericArray::from_slice(tag),
+            )
+            .map_err(|_| eio!("failed to decrypt chunk"))
+    }
+}
+
+pub(crate) fn seal_journal_path(work_dir: &str, blob_id: &str) -> PathBuf {
+    Path::new(work_dir).join(format!("{}.seal", blob_id))
+}
+
+/// Journal of tags of chunks in a cache file.
+pub(crate) struct SealJournal {
+    file: Mutex<File>,
+    tags: RwLock<HashMap<u64, (u32, ChunkTag)>>,
+}
+
+impl SealJournal {
+    /// Load the journal at `path`, it's compacted if it has stale records.
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut tags = HashMap::new();
+        let mut stale = false;
+        if let Ok(mut file) = File::open(path) {
+            let mut data = Vec::new();
+            file.read_to_end(&mut data)?;
+            // A record torn by crash is dropped, the chunk is just not trusted.
+            stale = data.len() % RECORD_SIZE != 0;
+            for record in data.chunks_exact(RECORD_SIZE) {
+                let offset = u64::from_le_bytes(record[..8].try_into().unwrap());
+                let size = u32::from_le_bytes(record[8..12].try_into().unwrap());
+                stale |= tags
+                    .insert(offset, (size, record[12..].try_into().unwrap()))
+                    .is_some();
+            }
+        }
+
+        let file = if stale {
+            let tmp = path.with_extension("seal.tmp");
+            let mut file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(&tmp)?;
+            let mut data = Vec::with_capacity(tags.len() * RECORD_SIZE);
+            for (offset, (size, tag)) in tags.iter() {
+                data.extend_from_slice(&Self::record(*offset, *size, tag));
+            }
+            file.write_all(&data)?;
+            fs::rename(&tmp, path)?;
+            OpenOptions::new().append(true).open(path)?
+        } else {
+            OpenOptions::new().create(true).append(true).open(path)?
+        };
+
+        Ok(SealJournal {
+            file: Mutex::new(file),
+            tags: RwLock::new(tags),
+        })
+    }
+
+    fn record(offset: u64, size: u32, tag: &ChunkTag) -> [u8; RECORD_SIZE] {
+        let mut record = [0u8; RECORD_SIZE];
+        record[..8].copy_from_slice(&offset.to_le_bytes());
+        record[8..12].copy_from_slice(&size.to_le_bytes());
+        record[12..].copy_from_slice(tag);
+        record
+    }
+
+    /// Tag of the chunk of `size` at `offset` of cache file, if it's ever sealed.
+    pub fn get(&self, offset: u64, size: usize) -> Option<ChunkTag> {
+        match self.tags.read().unwrap().get(&offset) {
+            Some((s, tag)) if *s as usize == size => Some(*tag),
+            _ => None,
+        }
+    }
+
+    /// Persist tag of a chunk just written to cache file.
+    pub fn put(&self, offset: u64, size: usize, tag: ChunkTag) -> Result<()> {
+        let record = Self::record(offset, size as u32, &tag);
+        // Write a record at once so that records of concurrent writers don't interleave.
+        self.file.lock().unwrap().write_all(&record)?;
+        self.tags
+            .write()
+            .unwrap()
+            .insert(offset, (size as u32, tag));
+        Ok(())
+    }
+}
+
+/// Encryption state of a cache file.
+pub(crate) struct CacheSeal {
+    cipher: BlobCipher,
+    journal: SealJournal,
+}
+
+impl CacheSeal {
+    pub fn new(work_dir: &str, blob_id: &str, cipher: &CacheCipher) -> Result<Self> {
+        Ok(CacheSeal {
+            cipher: cipher.blob_cipher(blob_id),
+            journal: SealJournal::open(&seal_journal_path(work_dir, blob_id))?,
+        })
+    }
+
+    /// Whether the chunk of `size` at `offset` of cache file is ever sealed, its data is
+    /// trusted once decrypted.
+    pub fn is_sealed(&self, offset: u64, size: usize) -> bool {
+        self.journal.get(offset, size).is_some()
+    }
+
+    pub fn seal(&self, offset: u64, buf: &mut [u8]) -> Result<ChunkTag> {
+        self.cipher.seal(offset, buf)
+    }
+
+    /// Persist tag of a chunk after it's written to cache file.
+    pub fn commit(&self, offset: u64, size: usize, tag: ChunkTag) -> Result<()> {
+        self.journal.put(offset, size, tag)
+    }
+
+    pub fn open(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let tag = self
+            .journal
+            .get(offset, buf.len())
+            .ok_or_else(|| enoent!("chunk is not sealed"))?;
+        self.cipher.open(offset, buf, &tag)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_seal_journal() {
+        let tmp_dir = TempDir::new().unwrap();
+        let key_file = tmp_dir.as_path().join("key");
+        fs::write(
+            &key_file,
+            "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff\n",
+        )
+        .unwrap();
+        let cipher = CacheCipher::load(key_file.to_str().unwrap(), "")
+            .unwrap()
+            .blob_cipher("blob");
+
+        let mut buf = vec![7u8; 100];
+        let tag = cipher.seal(4096, &mut buf).unwrap();
+        assert_ne!(buf, vec![7u8; 100]);
+        // Chunk moved to another offset must not be trusted.
+        let mut moved = buf.clone();
+        assert!(cipher.open(0, &mut moved, &tag).is_err());
+
+        let path = seal_journal_path(tmp_dir.as_path().to_str().unwrap(), "blob");
+        let journal = SealJournal::open(&path).unwrap();
+        journal.put(4096, 100, [0u8; TAG_SIZE]).unwrap();
+        journal.put(4096, 100, tag).unwrap();
+        drop(journal);
+
+        // Tags survive reopening, and stale records are compacted.
+        let journal = SealJournal::open(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), RECORD_SIZE as u64);
+        assert!(journal.get(4096, 99).is_none());
+        let tag = journal.get(4096, 100).unwrap();
+        cipher.open(4096, &mut buf, &tag).unwrap();
+        assert_eq!(buf, vec![7u8; 100]);
+    }
+
+    #[test]
+    fn test_blob_cipher() {
+        let tmp_dir = TempDir::new().unwrap();
+        let key_file = tmp_dir.as_path().join("key");
+        fs::write(&key_file, [0x5au8; KEY_SIZE]).unwrap();
+        let cipher = CacheCipher::load(key_file.to_str().unwrap(), "").unwrap();
+        let blob1 = cipher.blob_cipher("blob1");
+        let blob2 = cipher.blob_cipher("blob2");
+
+        // The same data at the same offset of different cache files never encrypts the same.
+        for offset in [0u64, 4096, 1 << 32].iter() {
+            let mut buf1 = vec![0u8; 4096];
+            let mut buf2 = vec![0u8; 4096];
+            let tag1 = blob1.seal(*offset, &mut buf1).unwrap();
+            let tag2 = blob2.seal(*offset, &mut buf2).unwrap();
+            assert_ne!(buf1, buf2);
+            assert_ne!(tag1, tag2);
+
+            // Chunk copied from another cache file must not be trusted.
+            assert!(blob2.open(*offset, &mut buf1.clone(), &tag1).is_err());
+            blob1.open(*offset, &mut buf1, &tag1).unwrap();
+            assert_eq!(buf1, vec![0u8; 4096]);
+        }
+
+        // Key of a blob is stable across loads.
+        let mut buf = vec![0u8; 4096];
+        let tag = blob1.seal(0, &mut buf).unwrap();
+        let reloaded = CacheCipher::load(key_file.to_str().unwrap(), "")
+            .unwrap()
+            .blob_cipher("blob1");
+        reloaded.open(0, &mut buf, &tag).unwrap();
+    }
+}
@@ -0,0 +1,215 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Deduplication of concurrent backend reads of the same chunk.
+//!
+//! The first reader of a chunk becomes the leader of the fetch, which is tracked by blob and
+//! compressed range of the chunk until it's done. Readers coming meanwhile wait for the leader
+//! and take a copy of the fetched data, rather than reading backend by themselves. They read
+//! backend by themselves only if the leader fails.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::device::RafsChunkInfo;
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+struct FetchKey {
+    blob_id: String,
+    offset: u64,
+    size: u32,
+}
+
+/// Chunk data fetched by the leader, shared by all followers.
+pub struct FetchedChunk {
+    // Empty if raw data is the chunk itself.
+    raw: Vec<u8>,
+    pub chunk: Vec<u8>,
+}
+
+impl FetchedChunk {
+    /// Data read from backend, which may be compressed.
+    pub fn raw(&self) -> &[u8] {
+        if self.raw.is_empty() {
+            &self.chunk
+        } else {
+            &self.raw
+        }
+    }
+}
+
+#[derive(Default)]
+struct FetchState {
+    done: bool,
+    followers: usize,
+    fetched: Option<Arc<FetchedChunk>>,
+}
+
+#[derive(Default)]
+struct InflightFetch {
+    state: Mutex<FetchState>,
+    cond: Condvar,
+}
+
+pub enum Flight<'a> {
+    Leader(FlightLeader<'a>),
+    /// Data fetched by the leader, or none if the leader fails.
+    Follower(Option<Arc<FetchedChunk>>),
+}
+
+/// The fetch is over once its leader is dropped, followers are woken up then.
+pub struct FlightLeader<'a> {
+    fetches: &'a InflightFetches,
+    key: FetchKey,
+    fetch: Arc<InflightFetch>,
+    retired: bool,
+}
+
+impl<'a> FlightLeader<'a> {
+    fn retire(&mut self, fetched: Option<(&[u8], &[u8])>) {
+        // No more followers can join once the fetch is removed.
+        self.fetches.fetches.lock().unwrap().remove(&self.key);
+        let mut state = self.fetch.state.lock().unwrap();
+        if state.followers > 0 {
+            state.fetched = fetched.map(|(raw, chunk)| {
+                Arc::new(FetchedChunk {
+                    raw: if raw.as_ptr() == chunk.as_ptr() {
+                        Vec::new()
+                    } else {
+                        raw.to_vec()
+                    },
+                    chunk: chunk.to_vec(),
+                })
+            });
+        }
+        state.done = true;
+        self.fetch.cond.notify_all();
+        self.retired = true;
+    }
+
+    /// Hand data fetched from backend over to followers, if there are any.
+    pub fn complete(mut self, raw: &[u8], chunk: &[u8]) {
+        self.retire(Some((raw, chunk)));
+    }
+}
+
+impl<'a> Drop for FlightLeader<'a> {
+    fn drop(&mut self) {
+        if !self.retired {
+            self.retire(None);
+        }
+    }
+}
+
+/// In-flight backend reads of a cache, keyed by blob and compressed range of chunks.
+#[derive(Default)]
+pub struct InflightFetches {
+    fetches: Mutex<HashMap<FetchKey, Arc<InflightFetch>>>,
+}
+
+impl InflightFetches {
+    /// Join the in-flight fetch of the chunk, waiting for it to be done, or lead a new one
+    /// if there is none.
+    pub fn join(&self, blob_id: &str, cki: &dyn RafsChunkInfo) -> Flight<'_> {
+        let key = FetchKey {
+            blob_id: blob_id.to_string(),
+            offset: cki.compress_offset(),
+            size: cki.compress_size(),
+        };
+
+        let mut fetches = self.fetches.lock().unwrap();
+        if let Some(fetch) = fetches.get(&key) {
+            let fetch = fetch.clone();
+            fetch.state.lock().unwrap().followers += 1;
+            drop(fetches);
+
+            let mut state = fetch.state.lock().unwrap();
+            while !state.done {
+                state = fetch.cond.wait(state).unwrap();
+            }
+            return Flight::Follower(state.fetched.clone());
+        }
+
+        let fetch = Arc::new(InflightFetch::default());
+        fetches.insert(key.clone(), fetch.clone());
+        Flight::Leader(FlightLeader {
+            fetches: self,
+            key,
+            fetch,
+            retired: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::MockChunkInfo;
+    use std::thread;
+
+    #[test]
+    fn test_inflight_fetches() {
+        let fetches = Arc::new(InflightFetches::default());
+        let cki = MockChunkInfo::default();
+
+        // The leader fails, so the follower has to fetch by itself.
+        let leader = match fetches.join("blob", &cki) {
+            Flight::Leader(leader) => leader,
+            Flight::Follower(_) => panic!("no fetch is in flight"),
+        };
+        let f = fetches.clone();
+        let follower = thread::spawn(move || {
+            matches!(
+                f.join("blob", &MockChunkInfo::default()),
+                Flight::Follower(None)
+            )
+        });
+        while fetches.fetches.lock().unwrap()[&leader.key]
+            .state
+            .lock()
+            .unwrap()
+            .followers
+            == 0
+        {
+            thread::yield_now();
+        }
+        drop(leader);
+        assert!(follower.join().unwrap());
+
+        let leader = match fetches.join("blob", &cki) {
+            Flight::Leader(leader) => leader,
+            Flight::Follower(_) => panic!("the last fetch is over"),
+        };
+        let followers = (0..4)
+            .map(|_| {
+                let f = fetches.clone();
+                thread::spawn(move || match f.join("blob", &MockChunkInfo::default()) {
+                    Flight::Follower(Some(fetched)) => fetched.chunk.clone(),
+                    _ => Vec::new(),
+                })
+            })
+            .collect::<Vec<_>>();
+        while fetches.fetches.lock().unwrap()[&leader.key]
+            .state
+            .lock()
+            .unwrap()
+            .followers
+            < 4
+        {
+            thread::yield_now();
+        }
+        leader.complete(&[1u8; 100], &[2u8; 200]);
+        for f in followers {
+            assert_eq!(f.join().unwrap(), vec![2u8; 200]);
+        }
+
+        // Raw data of uncompressed chunks is not copied twice.
+        let fetched = FetchedChunk {
+            raw: Vec::new(),
+            chunk: vec![3u8; 10],
+        };
+        assert_eq!(fetched.raw(), &[3u8; 10]);
+        assert!(fetches.fetches.lock().unwrap().is_empty());
+    }
+}
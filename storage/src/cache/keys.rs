@@ -0,0 +1,159 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Keys of encrypted blobs.
+//!
+//! Blob table refers to the key of a blob by its id, and keys are provided at mount time, in
+//! configuration or by a key command. They are loaded when the cache is initialized, so that
+//! a mount lacking keys fails at once rather than on reading data.
+
+use std::collections::HashMap;
+use std::io::Result;
+use std::process::Command;
+use std::sync::{Arc, RwLock};
+
+use crate::device::BlobPrefetchControl;
+use crate::encrypt::{parse_key, ChunkCipher};
+use crate::factory::EncryptionConfig;
+
+/// Keys of blobs, indexed by positions of blobs in blob table.
+#[derive(Default)]
+pub struct BlobKeys {
+    config: EncryptionConfig,
+    keys: RwLock<HashMap<u32, Arc<ChunkCipher>>>,
+}
+
+impl BlobKeys {
+    pub fn new(config: EncryptionConfig) -> Self {
+        BlobKeys {
+            config,
+            keys: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Load the key of `key_id` from configuration, or stdout of the key command which gets
+    /// the key id in env `NYDUS_KEY_ID`.
+    fn load(&self, key_id: &str) -> Result<ChunkCipher> {
+        let raw = if let Some(key) = self.config.keys.get(key_id) {
+            key.as_bytes().to_vec()
+        } else if !self.config.key_command.is_empty() {
+            let output = Command::new("sh")
+                .arg("-c")
+                .arg(&self.config.key_command)
+                .env("NYDUS_KEY_ID", key_id)
+                .output()
+                .map_err(|e| einval!(format!("failed to run key command, {}", e)))?;
+            if !output.status.success() {
+                return Err(einval!(format!(
+                    "key command exits with {} for key {}",
+                    output.status, key_id
+                )));
+            }
+            output.stdout
+        } else {
+            return Err(enoent!(format!("key {} is not provided", key_id)));
+        };
+
+        parse_key(&raw)
+            .map(|key| ChunkCipher::new(&key))
+            .map_err(|e| einval!(format!("invalid key {}, {}", key_id, e)))
+    }
+
+    /// Load keys of `blobs`, which are all blobs in blob table in order. Blobs sharing a key
+    /// share the loaded key as well.
+    pub fn register(&self, blobs: &[BlobPrefetchControl]) -> Result<()> {
+        let mut loaded: HashMap<&str, Arc<ChunkCipher>> = HashMap::new();
+        let mut keys = self.keys.write().unwrap();
+        for (index, b) in blobs.iter().enumerate() {
+            if b.key_id.is_empty() {
+                continue;
+            }
+            let key = match loaded.get(b.key_id.as_str()) {
+                Some(key) => key.clone(),
+                None => {
+                    let key = Arc::new(self.load(&b.key_id)?);
+                    loaded.insert(&b.key_id, key.clone());
+                    key
+                }
+            };
+            keys.insert(index as u32, key);
+        }
+
+        Ok(())
+    }
+
+    /// Take over keys of the cache being replaced.
+    pub fn inherit(&self, other: &BlobKeys) {
+        let other = other.keys.read().unwrap();
+        self.keys
+            .write()
+            .unwrap()
+            .extend(other.iter().map(|(k, v)| (*k, v.clone())));
+    }
+
+    /// Key of the blob at `blob_index` of blob table.
+    pub fn get(&self, blob_index: u32) -> Result<Arc<ChunkCipher>> {
+        self.keys
+            .read()
+            .unwrap()
+            .get(&blob_index)
+            .cloned()
+            .ok_or_else(|| enoent!(format!("blob {} has no key", blob_index)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encrypt::Algorithm;
+
+    fn blob(blob_id: &str, key_id: &str) -> BlobPrefetchControl {
+        BlobPrefetchControl {
+            blob_id: blob_id.to_string(),
+            offset: 0,
+            len: 0,
+            dict_offset: 0,
+            dict_size: 0,
+            key_id: key_id.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_blob_keys() {
+        let hex = "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff";
+        let mut config = EncryptionConfig::default();
+        config.keys.insert("k1".to_string(), hex.to_string());
+        config.key_command = format!(
+            "if [ \"$NYDUS_KEY_ID\" = k2 ]; then echo {}; else exit 1; fi",
+            hex
+        );
+
+        let keys = BlobKeys::new(config);
+        keys.register(&[blob("blob0", ""), blob("blob1", "k1"), blob("blob2", "k2")])
+            .unwrap();
+        assert!(keys.get(0).is_err());
+        // The same key is configured as k1 and printed by the key command as k2.
+        let data = keys
+            .get(1)
+            .unwrap()
+            .encrypt(Algorithm::Aes256Gcm, b"data", b"")
+            .unwrap();
+        assert_eq!(
+            keys.get(2)
+                .unwrap()
+                .decrypt(Algorithm::Aes256Gcm, &data, b"")
+                .unwrap(),
+            b"data"
+        );
+
+        let other = BlobKeys::default();
+        other.inherit(&keys);
+        assert!(other.get(1).is_ok());
+        // Keys unknown to both configuration and key command.
+        assert!(keys.register(&[blob("blob0", "k3")]).is_err());
+        assert!(BlobKeys::default()
+            .register(&[blob("blob0", "k1")])
+            .is_err());
+    }
+}
@@ -3,10 +3,14 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
 use std::fs::File;
 use std::io::Result;
+use std::path::Path;
 use std::slice;
-use std::sync::Arc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
 use vm_memory::VolatileSlice;
 
@@ -15,10 +19,23 @@ use crate::device::{BlobPrefetchControl, RafsBio, RafsChunkInfo};
 use crate::utils::{alloc_buf, digest_check};
 use crate::{compress, StorageResult};
 
-use nydus_utils::digest;
+use nydus_utils::digest::{self, RafsDigest};
 
+mod archive;
+mod batchio;
 pub mod blobcache;
+mod dicts;
 pub mod dummycache;
+mod encryption;
+mod inflight;
+mod keys;
+mod stargz;
+
+pub use self::dicts::BlobDicts;
+use self::inflight::Flight;
+pub use self::inflight::InflightFetches;
+pub use self::keys::BlobKeys;
+pub use self::stargz::StargzStreams;
 
 #[derive(Default, Clone)]
 struct MergedBackendRequest {
@@ -56,6 +73,305 @@ impl MergedBackendRequest {
         self.blob_size += cki.compress_size();
         self.chunks.push(cki);
     }
+
+    /// Compressed size of all merged chunks, which is how prefetch progress is counted.
+    fn chunks_size(&self) -> u64 {
+        self.chunks.iter().map(|c| c.compress_size() as u64).sum()
+    }
+}
+
+/// How long a paused prefetch worker sleeps before checking backend latency again.
+const PREFETCH_PAUSE_INTERVAL: Duration = Duration::from_millis(10);
+/// Latency samples older than this are stale, which means user IO is quiet.
+const LATENCY_SAMPLE_EXPIRY: Duration = Duration::from_secs(1);
+
+struct PrefetchQueueState {
+    // Merged requests waiting for prefetch workers, in FIFO order of their ids.
+    normal: BTreeMap<u64, MergedBackendRequest>,
+    // Merged requests promoted by user IO, served before `normal` ones.
+    urgent: VecDeque<(u64, MergedBackendRequest)>,
+    // Chunks queued or being fetched by prefetch workers, mapped to id of the request.
+    pending: HashMap<RafsDigest, u64>,
+    next_id: u64,
+    closed: bool,
+    // Ordinary requests are held back while prefetch is paused by users.
+    paused: bool,
+    // Number of workers serving the queue and the number it should be.
+    workers: usize,
+    max_workers: usize,
+}
+
+/// Queue of merged prefetch requests shared by prefetch workers.
+///
+/// Unlike a FIFO channel, a request containing chunks that user IO is waiting for
+/// can be promoted ahead of others, and ordinary requests can be held back while
+/// user IO is suffering from high backend latency.
+struct PrefetchQueue {
+    state: Mutex<PrefetchQueueState>,
+    // Signaled when a request is queued or the queue is closed.
+    work_cond: Condvar,
+    // Signaled when prefetch workers complete a request.
+    done_cond: Condvar,
+}
+
+impl PrefetchQueue {
+    /// Create a closed queue, which should be opened before accepting requests.
+    fn new(max_workers: usize) -> Self {
+        PrefetchQueue {
+            state: Mutex::new(PrefetchQueueState {
+                normal: BTreeMap::new(),
+                urgent: VecDeque::new(),
+                pending: HashMap::new(),
+                next_id: 0,
+                closed: true,
+                paused: false,
+                workers: 0,
+                max_workers,
+            }),
+            work_cond: Condvar::new(),
+            done_cond: Condvar::new(),
+        }
+    }
+
+    /// Queue a merged request. Returns false if prefetch is already stopped.
+    fn push(&self, mr: MergedBackendRequest) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return false;
+        }
+
+        let id = state.next_id;
+        state.next_id += 1;
+        for c in mr.chunks.iter() {
+            state.pending.insert(*c.block_id(), id);
+        }
+        state.normal.insert(id, mr);
+        self.work_cond.notify_one();
+
+        true
+    }
+
+    /// Take the next request to be served, promoted ones go first. Ordinary requests are
+    /// held while prefetch is paused or `pause` returns true. Return None once the queue is
+    /// closed and drained or the calling worker is redundant, then the worker should exit.
+    fn pop(&self, pause: &dyn Fn() -> bool) -> Option<(u64, MergedBackendRequest)> {
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            if state.workers > state.max_workers {
+                state.workers -= 1;
+                return None;
+            }
+
+            if let Some(r) = state.urgent.pop_front() {
+                return Some(r);
+            }
+
+            if let Some(id) = state.normal.keys().next().copied() {
+                // Queued requests are still drained once the queue is closed.
+                if state.paused && !state.closed {
+                    state = self.work_cond.wait(state).unwrap();
+                    continue;
+                }
+                if !pause() {
+                    // Safe because the key is just got from the map.
+                    let mr = state.normal.remove(&id).unwrap();
+                    return Some((id, mr));
+                }
+                state = self
+                    .work_cond
+                    .wait_timeout(state, PREFETCH_PAUSE_INTERVAL)
+                    .unwrap()
+                    .0;
+            } else if state.closed {
+                state.workers -= 1;
+                return None;
+            } else {
+                state = self.work_cond.wait(state).unwrap();
+            }
+        }
+    }
+
+    /// Prefetch workers have done with request `id`, wake up user IO waiting for it.
+    fn complete(&self, id: u64, mr: &MergedBackendRequest) {
+        let mut state = self.state.lock().unwrap();
+
+        for c in mr.chunks.iter() {
+            if state.pending.get(c.block_id()) == Some(&id) {
+                state.pending.remove(c.block_id());
+            }
+        }
+        self.done_cond.notify_all();
+    }
+
+    /// User IO is about to fetch chunk `cki` from backend. If the chunk is queued for
+    /// prefetch, promote the request containing it. Then wait for prefetch workers to
+    /// finish fetching the chunk so that it will not be fetched from backend twice.
+    /// Return whether the request is promoted and whether user IO ever waited.
+    fn promote_and_wait(&self, cki: &dyn RafsChunkInfo) -> (bool, bool) {
+        let mut state = self.state.lock().unwrap();
+        // Nobody would serve the queue.
+        if state.workers == 0 {
+            return (false, false);
+        }
+        let id = match state.pending.get(cki.block_id()) {
+            Some(id) => *id,
+            None => return (false, false),
+        };
+
+        let mut promoted = false;
+        if let Some(mr) = state.normal.remove(&id) {
+            state.urgent.push_back((id, mr));
+            self.work_cond.notify_one();
+            promoted = true;
+        }
+
+        while state.pending.contains_key(cki.block_id()) {
+            state = self.done_cond.wait(state).unwrap();
+        }
+
+        (promoted, true)
+    }
+
+    /// No more requests are accepted, workers exit after queued ones are served.
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.work_cond.notify_all();
+    }
+
+    /// Accept requests again. Return the number of workers to be spawned.
+    fn open(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state.closed = false;
+        Self::lack_workers(&mut state)
+    }
+
+    fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+
+    fn set_paused(&self, paused: bool) {
+        self.state.lock().unwrap().paused = paused;
+        self.work_cond.notify_all();
+    }
+
+    fn is_paused(&self) -> bool {
+        self.state.lock().unwrap().paused
+    }
+
+    /// Change the number of workers serving the queue. Redundant workers exit once they
+    /// are done with their current requests. Return the number of workers to be spawned.
+    fn set_max_workers(&self, max_workers: usize) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state.max_workers = max_workers;
+        self.work_cond.notify_all();
+        if state.closed {
+            0
+        } else {
+            Self::lack_workers(&mut state)
+        }
+    }
+
+    /// A worker accounted by `lack_workers` is never spawned.
+    fn worker_failed(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.workers -= 1;
+    }
+
+    fn max_workers(&self) -> usize {
+        self.state.lock().unwrap().max_workers
+    }
+
+    // Caller must spawn the returned number of workers as they are accounted here.
+    fn lack_workers(state: &mut PrefetchQueueState) -> usize {
+        let n = state.max_workers.saturating_sub(state.workers);
+        state.workers += n;
+        n
+    }
+}
+
+/// Moving average of backend read latency experienced by user IO.
+#[derive(Default)]
+struct BackendLatency {
+    // (average in unit of micro-seconds, time of the latest sample)
+    inner: Mutex<(u64, Option<Instant>)>,
+}
+
+impl BackendLatency {
+    fn record(&self, elapsed: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        let sample = elapsed.as_micros() as u64;
+        inner.0 = if inner.1.is_none() {
+            sample
+        } else {
+            (inner.0 * 7 + sample) / 8
+        };
+        inner.1 = Some(Instant::now());
+    }
+
+    /// Whether user IO recently suffers from backend latency higher than `threshold`.
+    fn exceeds(&self, threshold: Duration) -> bool {
+        let inner = self.inner.lock().unwrap();
+        match inner.1 {
+            Some(t) if t.elapsed() < LATENCY_SAMPLE_EXPIRY => {
+                inner.0 > threshold.as_micros() as u64
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Prefetch policy applied while the filesystem is serving user IO, in addition to
+/// the hinted prefetch performed once when rafs is imported.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PrefetchPolicy {
+    /// Only prefetch as per hints from bootstrap or nydusd arguments.
+    None,
+    /// Prefetch files belonging to the same directory once one of them is read.
+    Directory,
+}
+
+impl Default for PrefetchPolicy {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+impl fmt::Display for PrefetchPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::None => write!(f, "none"),
+            Self::Directory => write!(f, "directory"),
+        }
+    }
+}
+
+/// Progress of prefetch in unit of Bytes, along with settings that can be changed at runtime.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct PrefetchProgress {
+    /// Data requested to be prefetched.
+    pub planned: u64,
+    /// Data prefetched or found already in cache.
+    pub done: u64,
+    pub paused: bool,
+    pub threads_count: usize,
+    pub bandwidth_rate: u32,
+}
+
+/// A blob stored in cache directory.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct CachedBlobInfo {
+    pub blob_id: String,
+    /// Disk space taken by cached chunks, cache files are sparse as chunks are cached on demand.
+    pub cached_size: u64,
+    /// Size of blob in backend, 0 if unknown or the blob is not used by this cache.
+    pub blob_size: u64,
+    /// Seconds since epoch when the cache file was last read or written, as recorded by
+    /// the filesystem, so it's subject to its atime update policy.
+    pub last_access: u64,
+    /// Whether the blob is opened by this cache.
+    pub in_use: bool,
 }
 
 #[derive(Clone, Default)]
@@ -65,6 +381,30 @@ pub struct PrefetchWorker {
     pub merging_size: usize,
     // In unit of Bytes and Zero means no rate limit is set.
     pub bandwidth_rate: u32,
+    pub policy: PrefetchPolicy,
+    // In unit of milli-seconds and Zero means prefetch never pauses for user IO.
+    pub pause_latency_ms: u64,
+    // In unit of Bytes and Zero means files read sequentially are not read ahead.
+    pub readahead_max_window: u64,
+    // In unit of Bytes, data read ahead but not yet read by user IO of all files.
+    pub readahead_budget: u64,
+}
+
+/// Read data at `offset` of blob from backend, not going beyond the end of blob.
+fn read_backend_range(
+    backend: &(dyn BlobBackend + Sync + Send),
+    blob_id: &str,
+    blob_size: u64,
+    buf: &mut [u8],
+    offset: u64,
+) -> Result<usize> {
+    if offset >= blob_size {
+        return Ok(0);
+    }
+    let size = cmp::min(buf.len() as u64, blob_size - offset) as usize;
+    backend
+        .read(blob_id, &mut buf[..size], offset)
+        .map_err(|e| eio!(e))
 }
 
 pub trait RafsCache {
@@ -82,14 +422,57 @@ pub trait RafsCache {
     /// offset is relative to chunk start
     fn read(&self, bio: &RafsBio, bufs: &[VolatileSlice], offset: u64) -> Result<usize>;
 
+    /// Called before bios of a read are served one by one, so that the cache may fetch
+    /// adjacent chunks from backend at once. Returns how many of the bios are prepared, the
+    /// rest are passed in again once those are served.
+    fn prepare_read(&self, bios: &[RafsBio]) -> usize {
+        bios.len()
+    }
+
     /// Write a chunk data through cache
     fn write(&self, blob_id: &str, blk: &dyn RafsChunkInfo, buf: &[u8]) -> Result<usize>;
 
     /// Get the size of a blob
     fn blob_size(&self, blob_id: &str) -> Result<u64>;
 
+    /// Whether `prefetch` works, prefetch triggered by user IO like readahead is disabled
+    /// if it doesn't.
+    fn prefetch_supported(&self) -> bool {
+        true
+    }
     fn prefetch(&self, bio: &mut [RafsBio]) -> StorageResult<usize>;
     fn stop_prefetch(&self) -> StorageResult<()>;
+    /// Serve prefetch requests again after `stop_prefetch`, or even if prefetch is not
+    /// enabled by configuration.
+    fn start_prefetch(&self) -> StorageResult<()>;
+    /// Hold back or release queued prefetch requests. Chunks waited by user IO are still
+    /// prefetched while paused.
+    fn pause_prefetch(&self, paused: bool) -> StorageResult<()>;
+    /// Change prefetch threads count or bandwidth limit while prefetch is running.
+    fn tune_prefetch(
+        &self,
+        threads_count: Option<usize>,
+        bandwidth_rate: Option<u32>,
+    ) -> StorageResult<()>;
+    fn prefetch_progress(&self) -> StorageResult<PrefetchProgress>;
+    /// Verify cached chunks of `bios` in background and evict corrupted ones.
+    fn scrub(&self, bios: Vec<RafsBio>) -> StorageResult<()>;
+
+    /// List blobs stored in cache directory.
+    fn list_blobs(&self) -> StorageResult<Vec<CachedBlobInfo>>;
+    /// Drop all cached data of a blob.
+    fn evict_blob(&self, blob_id: &str) -> StorageResult<()>;
+    /// Drop cached data of chunks, they are fetched from backend again on next access.
+    fn evict_chunks(&self, bios: &[RafsBio]) -> StorageResult<()>;
+    /// Remove cached blobs neither in `referenced` nor used by this cache, returning ids
+    /// of the removed ones.
+    fn gc(&self, referenced: &HashSet<String>) -> StorageResult<Vec<String>>;
+    /// Export chunks of `bios` which are in cache to an archive, returning number of
+    /// exported chunks.
+    fn export_chunks(&self, bios: &[RafsBio], path: &Path) -> StorageResult<usize>;
+    /// Import chunks of `bios` from an archive, only chunks matching their digests are
+    /// cached. Returning number of imported chunks.
+    fn import_chunks(&self, bios: &[RafsBio], path: &Path) -> StorageResult<usize>;
 
     /// Release cache
     fn release(&self);
@@ -98,7 +481,45 @@ pub trait RafsCache {
 
     fn digester(&self) -> digest::Algorithm;
     fn compressor(&self) -> compress::Algorithm;
+    /// Compressor of the chunk, which falls back to the one of superblock if the chunk
+    /// doesn't record it.
+    fn chunk_compressor(&self, cki: &dyn RafsChunkInfo) -> compress::Algorithm {
+        cki.flags()
+            .compressor()
+            .unwrap_or_else(|| self.compressor())
+    }
     fn need_validate(&self) -> bool;
+    /// Decompressor checkpoints of gzip members of stargz blobs.
+    fn stargz_streams(&self) -> &StargzStreams;
+    /// Backend reads in flight, which are shared by concurrent readers of the same chunk.
+    /// Caches serializing readers of a chunk by themselves, like blobcache, have none.
+    fn inflight_fetches(&self) -> Option<&InflightFetches> {
+        None
+    }
+    /// Compression dictionaries of blobs.
+    fn blob_dicts(&self) -> &BlobDicts;
+    /// Keys of encrypted blobs.
+    fn blob_keys(&self) -> &BlobKeys;
+
+    /// Dictionary which the compressed chunk refers to, if any.
+    fn chunk_dict(
+        &self,
+        cki: &dyn RafsChunkInfo,
+        raw_chunk: &[u8],
+    ) -> Result<Option<Arc<compress::ZstdDict>>> {
+        if self.chunk_compressor(cki) != compress::Algorithm::Zstd
+            || compress::zstd_dict_id(raw_chunk) == 0
+        {
+            return Ok(None);
+        }
+        self.blob_dicts()
+            .get(cki.blob_index(), |blob_id, buf, offset| {
+                self.backend()
+                    .read(blob_id, buf, offset)
+                    .map_err(|e| eio!(e))
+            })
+            .map(Some)
+    }
 
     /// Read a whole chunk directly from *backend*.
     /// The fetched chunk could be compressed or not by different compressors.
@@ -116,63 +537,50 @@ pub trait RafsCache {
         F: FnOnce(&[u8], &[u8]) -> Result<()>,
         Self: Sized,
     {
+        let leader = match self.inflight_fetches().map(|f| f.join(blob_id, cki)) {
+            Some(Flight::Follower(Some(fetched))) if fetched.chunk.len() == chunk.len() => {
+                chunk.copy_from_slice(&fetched.chunk);
+                self.backend().metrics().dedup(fetched.raw().len());
+                cacher(fetched.raw(), chunk)?;
+                return Ok(chunk.len());
+            }
+            // Read by ourselves if the leader fails.
+            Some(Flight::Follower(_)) | None => None,
+            Some(Flight::Leader(leader)) => Some(leader),
+        };
         let offset = cki.compress_offset();
         let mut d;
 
-        let raw_chunk = if cki.is_compressed() {
+        // gzip is special that it doesn't carry compress_size. The gzip member is read until
+        // its end of stream, and the raw data is exactly the member.
+        if cki.is_compressed() && self.compressor() == compress::Algorithm::GZip {
+            let blob_size = self.blob_size(blob_id)?;
+            let mut raw_chunk = Vec::new();
+            self.stargz_streams()
+                .read(
+                    blob_id,
+                    cki,
+                    0,
+                    chunk,
+                    Some(&mut raw_chunk),
+                    |buf, offset| {
+                        read_backend_range(self.backend(), blob_id, blob_size, buf, offset)
+                    },
+                )
+                .map_err(|e| eio!(format!("fail to read from backend: {}", e)))?;
+            if self.need_validate() && !digest_check(chunk, cki.block_id(), self.digester()) {
+                return Err(eio!("fail to read from backend: digest mismatch"));
+            }
+            if let Some(leader) = leader {
+                leader.complete(&raw_chunk, chunk);
+            }
+            cacher(&raw_chunk, chunk)?;
+            return Ok(chunk.len());
+        }
+
+        let raw_chunk = if cki.is_encoded() {
             // Need to put compressed data into a temporary buffer so as to perform decompression.
-            //
-            // gzip is special that it doesn't carry compress_size, instead, we can read as much
-            // as chunk_decompress_size compressed data per chunk, decompress as much as necessary to fill in chunk
-            // that has the original uncompressed data size.
-            let c_size = if self.compressor() != compress::Algorithm::GZip {
-                cki.compress_size() as usize
-            } else {
-                // Per man(1) gzip
-                // The worst case expansion is a few bytes for the gzip file header,
-                // plus 5 bytes every 32K block, or an expansion ratio of 0.015% for
-                // large files.
-                //
-                // Per http://www.zlib.org/rfc-gzip.html#header-trailer
-                // Each member has the following structure:
-                // +---+---+---+---+---+---+---+---+---+---+
-                // |ID1|ID2|CM |FLG|     MTIME     |XFL|OS | (more-->)
-                // +---+---+---+---+---+---+---+---+---+---+
-                // (if FLG.FEXTRA set)
-                // +---+---+=================================+
-                // | XLEN  |...XLEN bytes of "extra field"...| (more-->)
-                // +---+---+=================================+
-                // (if FLG.FNAME set)
-                // +=========================================+
-                // |...original file name, zero-terminated...| (more-->)
-                // +=========================================+
-                // (if FLG.FCOMMENT set)
-                // +===================================+
-                // |...file comment, zero-terminated...| (more-->)
-                // +===================================+
-                // (if FLG.FHCRC set)
-                // +---+---+
-                // | CRC16 |
-                // +---+---+
-                // +=======================+
-                // |...compressed blocks...| (more-->)
-                // +=======================+
-                //   0   1   2   3   4   5   6   7
-                // +---+---+---+---+---+---+---+---+
-                // |     CRC32     |     ISIZE     |
-                // +---+---+---+---+---+---+---+---+
-                // gzip head+footer is at least 10+8 bytes, stargz header doesn't include any flags
-                // so it's 18 bytes. Let's read at least 128 bytes more, to allow the decompressor to
-                // find out end of the gzip stream.
-                //
-                // Ideally we should introduce a streaming cache for stargz that maintains internal
-                // chunks and expose stream APIs.
-                let size = chunk.len() + 10 + 8 + 5 + (chunk.len() / (16 << 10)) * 5 + 128;
-                cmp::min(
-                    size as u64,
-                    self.blob_size(blob_id)? - cki.compress_offset(),
-                ) as usize
-            };
+            let c_size = cki.compress_size() as usize;
             d = alloc_buf(c_size);
             d.as_mut_slice()
         } else {
@@ -189,10 +597,13 @@ pub trait RafsCache {
             raw_chunk,
             None,
             chunk,
-            cki.is_compressed(),
+            cki.is_encoded(),
             self.need_validate(),
         )
         .map_err(|e| eio!(format!("fail to read from backend: {}", e)))?;
+        if let Some(leader) = leader {
+            leader.complete(raw_chunk, chunk);
+        }
         cacher(raw_chunk, chunk)?;
         Ok(chunk.len())
     }
@@ -201,6 +612,8 @@ pub trait RafsCache {
     /// backend a bit as per the chunk description as blob cache always saves plain data
     /// into cache file rather than compressed.
     /// An inside trick is that it tries to directly save data into caller's buffer.
+    /// With `need_decompress`, `raw_chunk` is data of the chunk as it's in blob, which is
+    /// decrypted and decompressed as per the chunk flags.
     fn process_raw_chunk(
         &self,
         cki: &dyn RafsChunkInfo,
@@ -211,10 +624,41 @@ pub trait RafsCache {
         need_validate: bool,
     ) -> Result<usize> {
         if need_decompress {
-            compress::decompress(raw_chunk, raw_stream, chunk, self.compressor()).map_err(|e| {
-                error!("failed to decompress chunk: {}", e);
-                e
-            })?;
+            // Chunks are encrypted after being compressed, digest of the chunk is authenticated
+            // along with it.
+            let decrypted;
+            let raw_chunk = match cki.flags().encryptor() {
+                Some(encryptor) => {
+                    decrypted = self
+                        .blob_keys()
+                        .get(cki.blob_index())?
+                        .decrypt(encryptor, raw_chunk, cki.block_id().as_ref())
+                        .map_err(|e| {
+                            error!("failed to decrypt chunk: {}", e);
+                            e
+                        })?;
+                    decrypted.as_slice()
+                }
+                None => raw_chunk,
+            };
+            if cki.is_compressed() {
+                let dict = self.chunk_dict(cki, raw_chunk)?;
+                compress::decompress_with_dict(
+                    raw_chunk,
+                    raw_stream,
+                    chunk,
+                    self.chunk_compressor(cki),
+                    dict.as_deref(),
+                )
+                .map_err(|e| {
+                    error!("failed to decompress chunk: {}", e);
+                    e
+                })?;
+            } else if raw_chunk.len() != chunk.len() {
+                return Err(eio!("size of uncompressed chunk mismatches"));
+            } else if raw_chunk.as_ptr() != chunk.as_ptr() {
+                chunk.copy_from_slice(raw_chunk);
+            }
         } else if raw_chunk.as_ptr() != chunk.as_ptr() {
             // Sometimes, caller directly put data into consumer provided buffer.
             // Then we don't have to copy data between slices.
@@ -269,7 +713,7 @@ pub trait RafsCache {
                 &c_buf[offset_merged..(offset_merged + size_merged)],
                 None,
                 &mut chunk,
-                cki.is_compressed(),
+                cki.is_encoded(),
                 self.need_validate(),
             )?;
             chunks.push(chunk);
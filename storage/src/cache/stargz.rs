@@ -0,0 +1,203 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Gzip stream aware reading of stargz chunks.
+//!
+//! Each chunk of a stargz image is a gzip member, whose size in compressed data is not
+//! recorded. Rather than over-reading the member by a heuristic size and inflating it from the
+//! beginning on each read, members are read in growing pieces until the end of stream, and
+//! decompressor checkpoints are remembered per member. Checkpoints are shared by reads from
+//! backend and from blobcache file, as both hold the same compressed data.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::io::Result;
+use std::sync::{Arc, Mutex};
+
+use crate::compress::GzipIndex;
+use crate::device::RafsChunkInfo;
+
+/// Uncompressed data between decompressor checkpoints.
+const CHECKPOINT_SPAN: u64 = 1 << 20;
+/// Memory taken by checkpoints of all members.
+const CHECKPOINT_CAPACITY: usize = 64 << 20;
+
+struct StargzMember {
+    index: Arc<GzipIndex>,
+    footprint: usize,
+    access: u64,
+}
+
+#[derive(Default)]
+struct StargzStreamsState {
+    members: HashMap<(String, u64), StargzMember>,
+    footprint: usize,
+    clock: u64,
+}
+
+/// Decompressor checkpoints of stargz members, the least recently used ones are dropped
+/// once they take too much memory.
+#[derive(Default)]
+pub struct StargzStreams {
+    state: Mutex<StargzStreamsState>,
+}
+
+impl StargzStreams {
+    fn index(&self, blob_id: &str, cki: &dyn RafsChunkInfo) -> Arc<GzipIndex> {
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let clock = state.clock;
+        let member = state
+            .members
+            .entry((blob_id.to_string(), cki.compress_offset()))
+            .or_insert_with(|| StargzMember {
+                index: Arc::new(GzipIndex::new(
+                    cki.decompress_size() as u64,
+                    CHECKPOINT_SPAN,
+                )),
+                footprint: 0,
+                access: clock,
+            });
+        member.access = clock;
+        member.index.clone()
+    }
+
+    fn update(&self, blob_id: &str, cki: &dyn RafsChunkInfo, footprint: usize) {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let key = (blob_id.to_string(), cki.compress_offset());
+        let old = match state.members.get_mut(&key) {
+            // Members without checkpoints are not worth remembering.
+            Some(m) if footprint == 0 => {
+                let old = m.footprint;
+                state.members.remove(&key);
+                old
+            }
+            Some(m) => std::mem::replace(&mut m.footprint, footprint),
+            None => return,
+        };
+        state.footprint = state.footprint + footprint - old;
+
+        while state.footprint > CHECKPOINT_CAPACITY {
+            let victim = state
+                .members
+                .iter()
+                .filter(|(k, _)| **k != key)
+                .min_by_key(|(_, m)| m.access)
+                .map(|(k, _)| k.clone());
+            match victim {
+                Some(victim) => {
+                    let m = state.members.remove(&victim).unwrap();
+                    state.footprint -= m.footprint;
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Read uncompressed data at `offset` of the stargz chunk `cki` into `out`. Compressed
+    /// data of the chunk is read by `read_at`, given offset in blob. If `raw` is given, the
+    /// whole chunk must be read, and its compressed data is returned there.
+    pub fn read<F>(
+        &self,
+        blob_id: &str,
+        cki: &dyn RafsChunkInfo,
+        offset: u64,
+        out: &mut [u8],
+        mut raw: Option<&mut Vec<u8>>,
+        mut read_at: F,
+    ) -> Result<()>
+    where
+        F: FnMut(&mut [u8], u64) -> Result<usize>,
+    {
+        // Concurrent readers of the member share checkpoints but not reads from backend.
+        let index = self.index(blob_id, cki);
+        if let Some(raw) = raw.as_mut() {
+            raw.clear();
+        }
+
+        let c_offset = cki.compress_offset();
+        index.inflate(
+            |pos, buf| {
+                let nr_read = read_at(buf, c_offset + pos)?;
+                if let Some(raw) = raw.as_mut() {
+                    // The whole chunk is inflated from its beginning, so compressed data is
+                    // read in sequence.
+                    let end = pos as usize + nr_read;
+                    raw.resize(cmp::max(raw.len(), end), 0);
+                    raw[pos as usize..end].copy_from_slice(&buf[..nr_read]);
+                }
+                Ok(nr_read)
+            },
+            offset,
+            out,
+        )?;
+
+        if let Some(raw) = raw {
+            // Data of the next member may be read along with the tail of this one.
+            let size = index
+                .compressed_size()
+                .ok_or_else(|| eio!("size of gzip member is unknown"))?;
+            raw.truncate(size as usize);
+        }
+        self.update(blob_id, cki, index.footprint());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::RafsChunkFlags;
+    use crate::test::MockChunkInfo;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    #[test]
+    fn test_stargz_streams() {
+        let mut seed = 7u32;
+        let data = (0..3u32 << 20)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                b'0' + (seed >> 16) as u8 % 10
+            })
+            .collect::<Vec<u8>>();
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(&data).unwrap();
+        let member = gz.finish().unwrap();
+        // The blob holds the member at offset 100, followed by another member.
+        let mut blob = vec![0u8; 100];
+        blob.extend_from_slice(&member);
+        blob.extend_from_slice(&member);
+        let read_at = |buf: &mut [u8], offset: u64| {
+            let end = cmp::min(offset as usize + buf.len(), blob.len());
+            buf[..end - offset as usize].copy_from_slice(&blob[offset as usize..end]);
+            Ok(end - offset as usize)
+        };
+
+        let cki = MockChunkInfo {
+            compress_offset: 100,
+            decompress_size: data.len() as u32,
+            flags: RafsChunkFlags::COMPRESSED,
+            ..Default::default()
+        };
+        let streams = StargzStreams::default();
+        let mut out = vec![0u8; data.len()];
+        let mut raw = Vec::new();
+        streams
+            .read("blob", &cki, 0, &mut out, Some(&mut raw), read_at)
+            .unwrap();
+        assert_eq!(out, data);
+        assert_eq!(raw, member);
+        assert_eq!(streams.state.lock().unwrap().members.len(), 1);
+
+        let mut out = vec![0u8; 4096];
+        streams
+            .read("blob", &cki, 2 << 20, &mut out, None, read_at)
+            .unwrap();
+        assert_eq!(out, &data[2 << 20..(2 << 20) + 4096]);
+    }
+}
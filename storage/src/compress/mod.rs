@@ -14,14 +14,21 @@ use flate2::Compression;
 
 mod lz4_standard;
 use self::lz4_standard::*;
+mod zran;
+pub use self::zran::GzipIndex;
+mod zstd_dict;
+pub use self::zstd_dict::{zstd_dict_id, ZstdDict};
 
 const COMPRESSION_MINIMUM_RATIO: usize = 100;
+/// Compression level of zstd, 0 stands for the default level of libzstd.
+const ZSTD_DEFAULT_LEVEL: i32 = 0;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Algorithm {
     None,
     LZ4Block,
     GZip,
+    Zstd,
 }
 
 impl fmt::Display for Algorithm {
@@ -38,7 +45,10 @@ impl FromStr for Algorithm {
             "none" => Ok(Self::None),
             "lz4_block" => Ok(Self::LZ4Block),
             "gzip" => Ok(Self::GZip),
-            _ => Err(einval!("compression algorithm should be none or lz4_block")),
+            "zstd" => Ok(Self::Zstd),
+            _ => Err(einval!(
+                "compression algorithm should be none, lz4_block, gzip or zstd"
+            )),
         }
     }
 }
@@ -47,6 +57,26 @@ impl Algorithm {
     pub fn is_none(self) -> bool {
         self == Self::None
     }
+
+    /// Range of compression levels the algorithm accepts, none if it has no levels.
+    pub fn level_range(self) -> Option<(i32, i32)> {
+        match self {
+            Self::GZip => Some((1, 9)),
+            Self::Zstd => Some((1, 22)),
+            Self::None | Self::LZ4Block => None,
+        }
+    }
+
+    pub fn check_level(self, level: i32) -> Result<()> {
+        match self.level_range() {
+            Some((min, max)) if level >= min && level <= max => Ok(()),
+            Some((min, max)) => Err(einval!(format!(
+                "compression level of {} should be {} to {}",
+                self, min, max
+            ))),
+            None => Err(einval!(format!("{} has no compression level", self))),
+        }
+    }
 }
 
 // Algorithm::LZ4Block:
@@ -57,6 +87,31 @@ impl Algorithm {
 // with data blocks so that we don't really care about lz4 header magic numbers like
 // as being done with all these rust lz4 implementations
 pub fn compress(src: &[u8], algorithm: Algorithm) -> Result<(Cow<[u8]>, bool)> {
+    compress_with_level(src, algorithm, None)
+}
+
+/// Compress with the given level, or the default level of the algorithm if it's none.
+pub fn compress_with_level(
+    src: &[u8],
+    algorithm: Algorithm,
+    level: Option<i32>,
+) -> Result<(Cow<'_, [u8]>, bool)> {
+    compress_with_dict(src, algorithm, level, None)
+}
+
+/// Compress with the given level and dictionary, which only works with zstd.
+pub fn compress_with_dict<'a>(
+    src: &'a [u8],
+    algorithm: Algorithm,
+    level: Option<i32>,
+    dict: Option<&ZstdDict>,
+) -> Result<(Cow<'a, [u8]>, bool)> {
+    if let Some(level) = level {
+        algorithm.check_level(level)?;
+    }
+    if dict.is_some() && algorithm != Algorithm::Zstd {
+        return Err(einval!(format!("{} doesn't support dictionary", algorithm)));
+    }
     let src_size = src.len();
     if src_size == 0 {
         return Ok((Cow::Borrowed(src), false));
@@ -67,10 +122,17 @@ pub fn compress(src: &[u8], algorithm: Algorithm) -> Result<(Cow<[u8]>, bool)> {
         Algorithm::LZ4Block => lz4_compress(src)?,
         Algorithm::GZip => {
             let dst: Vec<u8> = Vec::new();
-            let mut gz = GzEncoder::new(dst, Compression::default());
+            let compression = level
+                .map(|l| Compression::new(l as u32))
+                .unwrap_or_default();
+            let mut gz = GzEncoder::new(dst, compression);
             gz.write_all(src)?;
             gz.finish()?
         }
+        Algorithm::Zstd => match dict {
+            Some(dict) => dict.compress(src, level)?,
+            None => zstd::block::compress(src, level.unwrap_or(ZSTD_DEFAULT_LEVEL))?,
+        },
     };
 
     // Abandon compressed data when compression ratio greater than COMPRESSION_MINIMUM_RATIO
@@ -89,6 +151,17 @@ pub fn decompress(
     src_file: Option<File>,
     dst: &mut [u8],
     algorithm: Algorithm,
+) -> Result<usize> {
+    decompress_with_dict(src, src_file, dst, algorithm, None)
+}
+
+/// Decompress with the dictionary which the source is compressed with, if any.
+pub fn decompress_with_dict(
+    src: &[u8],
+    src_file: Option<File>,
+    dst: &mut [u8],
+    algorithm: Algorithm,
+    dict: Option<&ZstdDict>,
 ) -> Result<usize> {
     match algorithm {
         Algorithm::None => Ok(dst.len()),
@@ -103,6 +176,10 @@ pub fn decompress(
             };
             Ok(dst.len())
         }
+        Algorithm::Zstd => match dict {
+            Some(dict) => dict.decompress(src, dst),
+            None => zstd::block::decompress_to_buffer(src, dst),
+        },
     }
 }
 
@@ -147,6 +224,118 @@ mod tests {
         assert_eq!(buf, decompressed);
     }
 
+    #[test]
+    fn test_compress_algorithm_zstd() {
+        let buf = vec![0x2u8; 4097];
+        let (compressed, is_compressed) = compress(&buf, Algorithm::Zstd).unwrap();
+        assert!(is_compressed);
+        assert!(compressed.len() < buf.len());
+
+        let mut decompressed = vec![0; buf.len()];
+        let sz = decompress(
+            &compressed,
+            None,
+            decompressed.as_mut_slice(),
+            Algorithm::Zstd,
+        )
+        .unwrap();
+        assert_eq!(sz, 4097);
+        assert_eq!(buf, decompressed);
+
+        // Destination buffer too small for the chunk.
+        let mut decompressed = vec![0; buf.len() - 1];
+        assert!(decompress(&compressed, None, &mut decompressed, Algorithm::Zstd).is_err());
+
+        // Higher level compresses better.
+        let text = (0..64 << 10)
+            .map(|i: u32| format!("line {} of {}\n", i % 997, i % 13))
+            .collect::<String>();
+        let (fast, _) = compress_with_level(text.as_bytes(), Algorithm::Zstd, Some(1)).unwrap();
+        let (best, _) = compress_with_level(text.as_bytes(), Algorithm::Zstd, Some(19)).unwrap();
+        assert!(best.len() < fast.len());
+        let mut decompressed = vec![0; text.len()];
+        decompress(&best, None, &mut decompressed, Algorithm::Zstd).unwrap();
+        assert_eq!(text.as_bytes(), decompressed.as_slice());
+        assert!(compress_with_level(&buf, Algorithm::Zstd, Some(23)).is_err());
+        assert!(compress_with_level(&buf, Algorithm::LZ4Block, Some(1)).is_err());
+
+        assert_eq!("zstd".parse::<Algorithm>().unwrap(), Algorithm::Zstd);
+        assert!("zstd2".parse::<Algorithm>().is_err());
+    }
+
+    #[test]
+    fn test_check_level() {
+        assert!(Algorithm::GZip.check_level(1).is_ok());
+        assert!(Algorithm::GZip.check_level(9).is_ok());
+        assert!(Algorithm::GZip.check_level(0).is_err());
+        assert!(Algorithm::GZip.check_level(10).is_err());
+
+        assert!(Algorithm::Zstd.check_level(1).is_ok());
+        assert!(Algorithm::Zstd.check_level(22).is_ok());
+        assert!(Algorithm::Zstd.check_level(-1).is_err());
+        assert!(Algorithm::Zstd.check_level(0).is_err());
+        assert!(Algorithm::Zstd.check_level(23).is_err());
+
+        // Algorithms without levels reject any of them.
+        for level in &[0, 1, 9] {
+            assert!(Algorithm::None.check_level(*level).is_err());
+            assert!(Algorithm::LZ4Block.check_level(*level).is_err());
+        }
+    }
+
+    #[test]
+    fn test_compress_zstd_dict() {
+        // Small config files sharing most of their content.
+        let samples = (0..1000)
+            .map(|i| {
+                format!(
+                    "[server]\nname = \"node-{}\"\nport = {}\nlog_level = \"info\"\n\
+                     [storage]\npath = \"/var/lib/app/{}\"\ncache_size = {}\n",
+                    i,
+                    8000 + i,
+                    i * 7,
+                    i * 4096
+                )
+                .into_bytes()
+            })
+            .collect::<Vec<_>>();
+        let dict = ZstdDict::new(ZstdDict::train(&samples, 4096).unwrap()).unwrap();
+        assert_ne!(dict.id(), 0);
+        assert!(ZstdDict::new(vec![1u8; 100]).is_err());
+
+        let buf = &samples[500];
+        let (plain, _) = compress(buf, Algorithm::Zstd).unwrap();
+        let (compressed, is_compressed) =
+            compress_with_dict(buf, Algorithm::Zstd, None, Some(&dict)).unwrap();
+        assert!(is_compressed);
+        assert!(compressed.len() < plain.len());
+        assert_eq!(zstd_dict_id(&compressed), dict.id());
+        assert_eq!(zstd_dict_id(&plain), 0);
+
+        let mut decompressed = vec![0; buf.len()];
+        decompress_with_dict(
+            &compressed,
+            None,
+            &mut decompressed,
+            Algorithm::Zstd,
+            Some(&dict),
+        )
+        .unwrap();
+        assert_eq!(buf, &decompressed);
+        // Frames compressed without dictionary are decompressed with it as well.
+        decompress_with_dict(
+            &plain,
+            None,
+            &mut decompressed,
+            Algorithm::Zstd,
+            Some(&dict),
+        )
+        .unwrap();
+        assert_eq!(buf, &decompressed);
+        assert!(decompress(&compressed, None, &mut decompressed, Algorithm::Zstd).is_err());
+        assert!(compress_with_dict(buf, Algorithm::LZ4Block, None, Some(&dict)).is_err());
+    }
+
     #[test]
     fn test_compress_algorithm_none() {
         let buf = [
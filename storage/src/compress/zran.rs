@@ -0,0 +1,357 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Random access to uncompressed data of a gzip member, in the way of zran.c from zlib.
+//!
+//! Reaching data in the middle of a member needs to inflate it from the beginning, which is
+//! costly for large members. While inflating a member, access points are recorded at deflate
+//! block boundaries every `span` bytes of uncompressed data. Each access point keeps its bit
+//! position in compressed data along with the 32KB window of uncompressed data before it, so
+//! that inflating can resume from the nearest access point before the wanted data.
+
+use std::cmp;
+use std::io::Result;
+use std::os::raw::{c_int, c_uint};
+use std::ptr;
+use std::sync::{Arc, Mutex};
+
+use libz_sys::{
+    inflate, inflateEnd, inflateInit2_, inflatePrime, inflateSetDictionary, uInt, voidpf, z_stream,
+    zlibVersion, Z_BLOCK, Z_BUF_ERROR, Z_OK, Z_STREAM_END,
+};
+
+/// Size of deflate window, which is all history needed to inflate from a block boundary.
+const WINDOW_SIZE: usize = 32 << 10;
+/// Compressed data is fetched in pieces growing from this size.
+const MIN_PIECE_SIZE: usize = 64 << 10;
+const MAX_PIECE_SIZE: usize = 1 << 20;
+const GZIP_HEADER_SIZE: u64 = 10;
+/// Size of gzip trailer, which is not consumed when inflating raw deflate data.
+const GZIP_TRAILER_SIZE: u64 = 8;
+/// Parse gzip header automatically.
+const GZIP_WINDOW_BITS: c_int = 47;
+/// Raw deflate data without header.
+const RAW_WINDOW_BITS: c_int = -15;
+
+unsafe extern "C" fn zalloc(_opaque: voidpf, items: uInt, size: uInt) -> voidpf {
+    libc::calloc(items as usize, size as usize)
+}
+
+unsafe extern "C" fn zfree(_opaque: voidpf, address: voidpf) {
+    libc::free(address)
+}
+
+struct AccessPoint {
+    // Offset of the next byte to read in compressed data of the member.
+    in_offset: u64,
+    // Bits of the byte before `in_offset` yet to be consumed, 0 to 7.
+    bits: u32,
+    out_offset: u64,
+    window: Vec<u8>,
+}
+
+/// Inflater which is freed on drop. zlib refers to the stream by address, so it's boxed.
+struct Inflater(Box<z_stream>);
+
+impl Inflater {
+    fn new(window_bits: c_int) -> Result<Self> {
+        let mut strm = Box::new(z_stream {
+            next_in: ptr::null_mut(),
+            avail_in: 0,
+            total_in: 0,
+            next_out: ptr::null_mut(),
+            avail_out: 0,
+            total_out: 0,
+            msg: ptr::null_mut(),
+            state: ptr::null_mut(),
+            zalloc,
+            zfree,
+            opaque: ptr::null_mut(),
+            data_type: 0,
+            adler: 0,
+            reserved: 0,
+        });
+        let ret = unsafe {
+            inflateInit2_(
+                strm.as_mut(),
+                window_bits,
+                zlibVersion(),
+                std::mem::size_of::<z_stream>() as c_int,
+            )
+        };
+        if ret != Z_OK {
+            return Err(eio!(format!("failed to init inflater, {}", ret)));
+        }
+
+        Ok(Inflater(strm))
+    }
+}
+
+impl Drop for Inflater {
+    fn drop(&mut self) {
+        unsafe { inflateEnd(self.0.as_mut()) };
+    }
+}
+
+#[derive(Default)]
+struct GzipIndexState {
+    // In order of `out_offset`.
+    points: Vec<Arc<AccessPoint>>,
+    compressed_size: Option<u64>,
+}
+
+/// Access points of a gzip member, which grow as the member is inflated. The index is shared
+/// by concurrent readers of the member, its lock is held to look up or record access points
+/// but never while compressed data is read.
+pub struct GzipIndex {
+    span: u64,
+    size: u64,
+    state: Mutex<GzipIndexState>,
+}
+
+impl GzipIndex {
+    /// New index of a member of `size` bytes uncompressed, with access points every `span`
+    /// bytes of uncompressed data.
+    pub fn new(size: u64, span: u64) -> Self {
+        GzipIndex {
+            span: cmp::max(span, WINDOW_SIZE as u64),
+            size,
+            state: Mutex::new(GzipIndexState::default()),
+        }
+    }
+
+    /// Size of the member in compressed data including gzip header and trailer, which is
+    /// known once the member is inflated to its end.
+    pub fn compressed_size(&self) -> Option<u64> {
+        self.state.lock().unwrap().compressed_size
+    }
+
+    /// Upper bound of the member size in compressed data. Per man(1) gzip, the worst case
+    /// expansion is a few bytes for the gzip header, plus 5 bytes every 16K stored block.
+    /// Allow some more bytes for optional fields of gzip header.
+    fn max_compressed_size(&self) -> u64 {
+        self.size + (self.size / (16 << 10) + 1) * 5 + GZIP_HEADER_SIZE + GZIP_TRAILER_SIZE + 128
+    }
+
+    /// Memory taken by windows of access points.
+    pub fn footprint(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.points.iter().map(|p| p.window.len()).sum()
+    }
+
+    /// Record an access point unless a concurrent reader has recorded one less than `span`
+    /// before it. Return uncompressed offset of the access point in effect.
+    fn record(&self, point: AccessPoint) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let pos = state
+            .points
+            .iter()
+            .position(|p| p.out_offset > point.out_offset)
+            .unwrap_or_else(|| state.points.len());
+        if pos > 0 && state.points[pos - 1].out_offset + self.span > point.out_offset {
+            return state.points[pos - 1].out_offset;
+        }
+        let out_offset = point.out_offset;
+        state.points.insert(pos, Arc::new(point));
+        out_offset
+    }
+
+    /// Inflate uncompressed data at `offset` of the member into `out`, resuming from the
+    /// nearest access point. Compressed data of the member is read by `read_at`, given offset
+    /// in the member, in pieces growing from 64KB. Pieces are clamped to the end of the
+    /// member, or its upper bound if the size is unknown yet, so that data beyond the member
+    /// is hardly read.
+    pub fn inflate<F>(&self, mut read_at: F, offset: u64, out: &mut [u8]) -> Result<()>
+    where
+        F: FnMut(u64, &mut [u8]) -> Result<usize>,
+    {
+        let end = offset + out.len() as u64;
+        if end > self.size {
+            return Err(einval!("read beyond end of gzip member"));
+        }
+        let (point, mut compressed_size) = {
+            let state = self.state.lock().unwrap();
+            let point = state.points.iter().rev().find(|p| p.out_offset <= offset);
+            (point.cloned(), state.compressed_size)
+        };
+        let raw = point.is_some();
+        let mut inflater = Inflater::new(if raw {
+            RAW_WINDOW_BITS
+        } else {
+            GZIP_WINDOW_BITS
+        })?;
+        let strm = inflater.0.as_mut();
+
+        let (mut in_offset, mut out_offset) = match point.as_ref() {
+            Some(p) => {
+                if p.bits != 0 {
+                    let mut byte = [0u8];
+                    if read_at(p.in_offset - 1, &mut byte)? != 1 {
+                        return Err(eio!("gzip member is truncated"));
+                    }
+                    let value = (byte[0] >> (8 - p.bits)) as c_int;
+                    if unsafe { inflatePrime(strm, p.bits as c_int, value) } != Z_OK {
+                        return Err(eio!("failed to resume inflating"));
+                    }
+                }
+                let ret = unsafe {
+                    inflateSetDictionary(strm, p.window.as_ptr(), p.window.len() as c_uint)
+                };
+                if ret != Z_OK {
+                    return Err(eio!("failed to resume inflating"));
+                }
+                (p.in_offset, p.out_offset)
+            }
+            None => (0, 0),
+        };
+
+        let mut input = vec![0u8; MIN_PIECE_SIZE];
+        let mut piece = MIN_PIECE_SIZE;
+        let mut output = vec![0u8; WINDOW_SIZE];
+        let mut window: Vec<u8> = Vec::with_capacity(WINDOW_SIZE * 2);
+        if let Some(p) = point.as_ref() {
+            window.extend_from_slice(&p.window);
+        }
+        let mut last_point = point.as_ref().map(|p| p.out_offset).unwrap_or(0);
+
+        // Go on to the end of stream when reading the tail, to learn size of the member.
+        while out_offset < end || (end == self.size && compressed_size.is_none()) {
+            if strm.avail_in == 0 {
+                let limit = compressed_size.unwrap_or_else(|| self.max_compressed_size());
+                piece = cmp::min(piece as u64, limit.saturating_sub(in_offset)) as usize;
+                if piece == 0 {
+                    return Err(eio!("gzip member is truncated"));
+                }
+                if input.len() < piece {
+                    input.resize(piece, 0);
+                }
+                let count = read_at(in_offset, &mut input[..piece])?;
+                if count == 0 {
+                    return Err(eio!("gzip member is truncated"));
+                }
+                in_offset += count as u64;
+                strm.next_in = input.as_mut_ptr();
+                strm.avail_in = count as c_uint;
+                piece = cmp::min(piece * 2, MAX_PIECE_SIZE);
+            }
+
+            strm.next_out = output.as_mut_ptr();
+            strm.avail_out = output.len() as c_uint;
+            let ret = unsafe { inflate(strm, Z_BLOCK) };
+            if ret != Z_OK && ret != Z_STREAM_END && ret != Z_BUF_ERROR {
+                return Err(eio!(format!("failed to inflate gzip member, {}", ret)));
+            }
+
+            let produced = output.len() - strm.avail_out as usize;
+            // Copy the part overlapping with the wanted range.
+            let from = cmp::max(out_offset, offset);
+            let to = cmp::min(out_offset + produced as u64, end);
+            if from < to {
+                out[(from - offset) as usize..(to - offset) as usize].copy_from_slice(
+                    &output[(from - out_offset) as usize..(to - out_offset) as usize],
+                );
+            }
+            out_offset += produced as u64;
+            window.extend_from_slice(&output[..produced]);
+            if window.len() > WINDOW_SIZE {
+                window.drain(..window.len() - WINDOW_SIZE);
+            }
+
+            let consumed = in_offset - strm.avail_in as u64;
+            if ret == Z_STREAM_END {
+                // Gzip trailer is only consumed if the member is inflated from its beginning.
+                compressed_size = Some(if raw {
+                    consumed + GZIP_TRAILER_SIZE
+                } else {
+                    consumed
+                });
+                self.state.lock().unwrap().compressed_size = compressed_size;
+                break;
+            }
+            // Record an access point at the end of a block header, which is not the last one.
+            if strm.data_type & 128 != 0
+                && strm.data_type & 64 == 0
+                && out_offset >= last_point + self.span
+            {
+                last_point = self.record(AccessPoint {
+                    in_offset: consumed,
+                    bits: (strm.data_type & 7) as u32,
+                    out_offset,
+                    window: window.clone(),
+                });
+            }
+        }
+
+        if out_offset < end {
+            return Err(eio!("gzip member is shorter than expected"));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::cell::Cell;
+    use std::io::Write;
+
+    #[test]
+    fn test_gzip_index() {
+        // Pseudo random data of small alphabet, so that there are many deflate blocks.
+        let mut seed = 1u32;
+        let data = (0..4u32 << 20)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                b'a' + (seed >> 16) as u8 % 16
+            })
+            .collect::<Vec<u8>>();
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(&data).unwrap();
+        let mut member = gz.finish().unwrap();
+        // Data of the next member must not be read.
+        member.extend_from_slice(&[0xffu8; 4096]);
+        let member_size = member.len() as u64 - 4096;
+
+        let fetched = Cell::new(0);
+        let index = GzipIndex::new(data.len() as u64, 256 << 10);
+        let read_at = |offset: u64, buf: &mut [u8]| {
+            // Other readers of the member are not blocked by reads of compressed data.
+            assert!(index.state.try_lock().is_ok());
+            let end = cmp::min(offset as usize + buf.len(), member.len());
+            buf[..end - offset as usize].copy_from_slice(&member[offset as usize..end]);
+            fetched.set(fetched.get() + end - offset as usize);
+            Ok(end - offset as usize)
+        };
+
+        let mut out = vec![0u8; 100];
+        index.inflate(read_at, 1 << 20, &mut out).unwrap();
+        assert_eq!(out, &data[1 << 20..(1 << 20) + 100]);
+        assert!(index.compressed_size().is_none());
+
+        let mut out = vec![0u8; 4096];
+        index
+            .inflate(read_at, data.len() as u64 - 4096, &mut out)
+            .unwrap();
+        assert_eq!(out, &data[data.len() - 4096..]);
+        assert_eq!(index.compressed_size(), Some(member_size));
+        assert!(index.footprint() > 0);
+
+        // Random reads resume from access points rather than the member beginning.
+        let before = fetched.get();
+        let mut out = vec![0u8; 4096];
+        index.inflate(read_at, (3 << 20) + 5, &mut out).unwrap();
+        assert_eq!(out, &data[(3 << 20) + 5..(3 << 20) + 5 + 4096]);
+        assert!(((fetched.get() - before) as u64) < member_size / 2);
+
+        let mut out = vec![0u8; data.len()];
+        index.inflate(read_at, 0, &mut out).unwrap();
+        assert_eq!(out, data);
+        assert!(index
+            .inflate(read_at, data.len() as u64, &mut [0u8])
+            .is_err());
+    }
+}
@@ -0,0 +1,76 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Zstd dictionary trained from data of an image.
+//!
+//! Small chunks hardly compress on their own, since there is little history to match. A
+//! dictionary trained from samples of similar data gives each chunk such history up front.
+//! Frames compressed with a dictionary carry its id, so that frames compressed without it can
+//! still be told apart and decompressed alone.
+
+use std::io::Result;
+
+use zstd::dict::DecoderDictionary;
+
+use super::ZSTD_DEFAULT_LEVEL;
+
+fn zstd_error(code: usize) -> std::io::Error {
+    eio!(format!("zstd error, {}", zstd_safe::get_error_name(code)))
+}
+
+pub struct ZstdDict {
+    id: u32,
+    data: Vec<u8>,
+    ddict: DecoderDictionary<'static>,
+}
+
+impl ZstdDict {
+    /// Train a dictionary of at most `max_size` bytes from `samples`. Training needs samples
+    /// of about a hundred times of the dictionary size to do well, and fails if they are too
+    /// few or too small.
+    pub fn train<S: AsRef<[u8]>>(samples: &[S], max_size: usize) -> Result<Vec<u8>> {
+        zstd::dict::from_samples(samples, max_size)
+    }
+
+    pub fn new(data: Vec<u8>) -> Result<Self> {
+        let id = zstd_safe::get_dict_id(&data).ok_or_else(|| einval!("invalid zstd dictionary"))?;
+        let ddict = DecoderDictionary::copy(&data);
+
+        Ok(ZstdDict { id, data, ddict })
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn compress(&self, src: &[u8], level: Option<i32>) -> Result<Vec<u8>> {
+        let mut dst = vec![0u8; zstd_safe::compress_bound(src.len())];
+        let size = zstd_safe::CCtx::create()
+            .compress_using_dict(
+                &mut dst,
+                src,
+                &self.data,
+                level.unwrap_or(ZSTD_DEFAULT_LEVEL),
+            )
+            .map_err(zstd_error)?;
+        dst.truncate(size);
+
+        Ok(dst)
+    }
+
+    pub fn decompress(&self, src: &[u8], dst: &mut [u8]) -> Result<usize> {
+        zstd_safe::DCtx::create()
+            .decompress_using_ddict(dst, src, self.ddict.as_ddict())
+            .map_err(zstd_error)
+    }
+}
+
+/// Id of the dictionary a zstd frame is compressed with, 0 if it's compressed without one.
+pub fn zstd_dict_id(src: &[u8]) -> u32 {
+    zstd_safe::get_dict_id_from_frame(src)
+}
@@ -4,16 +4,19 @@
 
 use arc_swap::ArcSwap;
 use std::cmp;
+use std::collections::HashSet;
 use std::io;
 use std::io::Error;
+use std::path::Path;
 use std::sync::Arc;
 
 use fuse_rs::api::filesystem::{ZeroCopyReader, ZeroCopyWriter};
 use fuse_rs::transport::FileReadWriteVolatile;
 use vm_memory::{Bytes, VolatileSlice};
 
-use crate::cache::RafsCache;
-use crate::{compress, factory, StorageResult};
+use crate::cache::{CachedBlobInfo, PrefetchProgress, RafsCache};
+use crate::readahead::Readahead;
+use crate::{compress, encrypt, factory, StorageResult};
 
 use nydus_utils::digest::{self, RafsDigest};
 
@@ -23,6 +26,7 @@ static ZEROS: &[u8] = &[0u8; 4096]; // why 4096? volatile slice default size, un
 #[derive(Clone)]
 pub struct RafsDevice {
     rw_layer: ArcSwap<Arc<dyn RafsCache + Send + Sync>>,
+    readahead: Arc<Readahead>,
 }
 
 bitflags! {
@@ -30,6 +34,67 @@ bitflags! {
         /// chunk is compressed
         const COMPRESSED = 0x0000_0001;
         const HOLECHUNK = 0x0000_0002;
+        /// chunk has a valid index of its position in blob
+        const INDEXED = 0x0000_0004;
+        /// chunk is shared by small files packed into it, each of them refers to a range of
+        /// the decompressed chunk from its batch offset
+        const BATCH = 0x0000_0008;
+        /// compressed chunk is compressed with lz4_block, gzip or zstd respectively, rather
+        /// than the compressor recorded in superblock
+        const COMPRESS_LZ4_BLOCK = 0x0000_0100;
+        const COMPRESS_GZIP = 0x0000_0200;
+        const COMPRESS_ZSTD = 0x0000_0400;
+        /// chunk is encrypted with aes-256-gcm or chacha20-poly1305 respectively, with the
+        /// key of its blob
+        const ENCRYPT_AES_256_GCM = 0x0000_1000;
+        const ENCRYPT_CHACHA20_POLY1305 = 0x0000_2000;
+    }
+}
+
+impl RafsChunkFlags {
+    /// Compressor recorded in chunk flags, if any. Images built by older builders rely on
+    /// the compressor of superblock.
+    pub fn compressor(self) -> Option<compress::Algorithm> {
+        if self.contains(RafsChunkFlags::COMPRESS_ZSTD) {
+            Some(compress::Algorithm::Zstd)
+        } else if self.contains(RafsChunkFlags::COMPRESS_GZIP) {
+            Some(compress::Algorithm::GZip)
+        } else if self.contains(RafsChunkFlags::COMPRESS_LZ4_BLOCK) {
+            Some(compress::Algorithm::LZ4Block)
+        } else {
+            None
+        }
+    }
+
+    /// Flags recording that the chunk is compressed with `compressor`.
+    pub fn from_compressor(compressor: compress::Algorithm) -> Self {
+        match compressor {
+            compress::Algorithm::None => RafsChunkFlags::empty(),
+            compress::Algorithm::LZ4Block => {
+                RafsChunkFlags::COMPRESSED | RafsChunkFlags::COMPRESS_LZ4_BLOCK
+            }
+            compress::Algorithm::GZip => RafsChunkFlags::COMPRESSED | RafsChunkFlags::COMPRESS_GZIP,
+            compress::Algorithm::Zstd => RafsChunkFlags::COMPRESSED | RafsChunkFlags::COMPRESS_ZSTD,
+        }
+    }
+
+    /// Algorithm the chunk is encrypted with, if it's encrypted.
+    pub fn encryptor(self) -> Option<encrypt::Algorithm> {
+        if self.contains(RafsChunkFlags::ENCRYPT_AES_256_GCM) {
+            Some(encrypt::Algorithm::Aes256Gcm)
+        } else if self.contains(RafsChunkFlags::ENCRYPT_CHACHA20_POLY1305) {
+            Some(encrypt::Algorithm::ChaCha20Poly1305)
+        } else {
+            None
+        }
+    }
+
+    /// Flags recording that the chunk is encrypted with `encryptor`.
+    pub fn from_encryptor(encryptor: encrypt::Algorithm) -> Self {
+        match encryptor {
+            encrypt::Algorithm::Aes256Gcm => RafsChunkFlags::ENCRYPT_AES_256_GCM,
+            encrypt::Algorithm::ChaCha20Poly1305 => RafsChunkFlags::ENCRYPT_CHACHA20_POLY1305,
+        }
     }
 }
 
@@ -44,14 +109,26 @@ bitflags! {
 pub trait RafsChunkInfo: Sync + Send {
     fn block_id(&self) -> &RafsDigest;
     fn blob_index(&self) -> u32;
+    /// Position of the chunk in blob, valid only with `RafsChunkFlags::INDEXED`.
+    fn index(&self) -> u32;
     fn compress_offset(&self) -> u64;
     fn compress_size(&self) -> u32;
     fn decompress_offset(&self) -> u64;
     fn decompress_size(&self) -> u32;
     fn file_offset(&self) -> u64;
+    /// Offset of file data in the decompressed chunk, valid only with `RafsChunkFlags::BATCH`.
+    fn batch_offset(&self) -> u32;
     fn is_compressed(&self) -> bool;
     fn is_hole(&self) -> bool;
     fn flags(&self) -> RafsChunkFlags;
+    /// Whether data of the chunk in blob is not its plain data, as it's compressed or
+    /// encrypted, so that it has to be processed as a whole.
+    fn is_encoded(&self) -> bool {
+        self.is_compressed() || self.flags().encryptor().is_some()
+    }
+    fn is_batch(&self) -> bool {
+        self.flags().contains(RafsChunkFlags::BATCH)
+    }
 }
 
 impl Default for RafsChunkFlags {
@@ -67,6 +144,11 @@ pub struct BlobPrefetchControl {
     pub blob_id: String,
     pub offset: u32,
     pub len: u32,
+    /// Compression dictionary of the blob, 0 size if there is none.
+    pub dict_offset: u32,
+    pub dict_size: u32,
+    /// Id of the key which chunks of the blob are encrypted with, empty if they are not.
+    pub key_id: String,
 }
 
 impl RafsDevice {
@@ -76,10 +158,18 @@ impl RafsDevice {
         digester: digest::Algorithm,
         id: &str,
     ) -> io::Result<RafsDevice> {
+        let worker = &config.cache.prefetch_worker;
+        let (max_window, budget) = (worker.readahead_max_window, worker.readahead_budget);
+        let rw_layer = factory::new_rw_layer(config, compressor, digester, id)?;
+        // Data is read ahead through prefetch, which the cache may not be capable of.
+        let readahead = if rw_layer.prefetch_supported() {
+            Readahead::new(max_window, budget)
+        } else {
+            Readahead::new(0, budget)
+        };
         Ok(RafsDevice {
-            rw_layer: ArcSwap::new(Arc::new(factory::new_rw_layer(
-                config, compressor, digester, id,
-            )?)),
+            rw_layer: ArcSwap::new(Arc::new(rw_layer)),
+            readahead: Arc::new(readahead),
         })
     }
 
@@ -90,9 +180,12 @@ impl RafsDevice {
         digester: digest::Algorithm,
         id: &str,
     ) -> io::Result<()> {
-        self.rw_layer.store(Arc::new(factory::new_rw_layer(
-            config, compressor, digester, id,
-        )?));
+        let rw_layer = factory::new_rw_layer(config, compressor, digester, id)?;
+        // Blobs are not changed by update, nor their dictionaries and keys.
+        let old = self.rw_layer.load();
+        rw_layer.blob_dicts().inherit(old.blob_dicts());
+        rw_layer.blob_keys().inherit(old.blob_keys());
+        self.rw_layer.store(Arc::new(rw_layer));
         Ok(())
     }
 
@@ -108,7 +201,11 @@ impl RafsDevice {
     /// Read a range of data from blob into the provided writer
     pub fn read_to(&self, w: &mut dyn ZeroCopyWriter, desc: RafsBioDesc) -> io::Result<usize> {
         let mut count: usize = 0;
-        for bio in desc.bi_vec.iter() {
+        let mut prepared = 0;
+        for (idx, bio) in desc.bi_vec.iter().enumerate() {
+            if idx == prepared {
+                prepared += cmp::max(self.rw_layer.load().prepare_read(&desc.bi_vec[idx..]), 1);
+            }
             let mut f = RafsBioDevice::new(bio, &self);
             count += w.write_from(&mut f, bio.size, bio.offset as u64)?;
         }
@@ -127,6 +224,18 @@ impl RafsDevice {
     }
 
     pub fn prefetch(&self, desc: &mut RafsBioDesc) -> StorageResult<usize> {
+        // Hole chunks are read as zeros, there is nothing to fetch. Batch chunks shared by
+        // files are fetched only once.
+        let mut batches = HashSet::new();
+        desc.bi_vec.retain(|bio| {
+            let cki = &bio.chunkinfo;
+            !cki.is_hole()
+                && (!cki.is_batch() || batches.insert((bio.blob_id.clone(), cki.compress_offset())))
+        });
+        desc.bi_size = desc.bi_vec.iter().map(|bio| bio.size).sum();
+        if desc.bi_vec.is_empty() {
+            return Ok(0);
+        }
         self.rw_layer.load().prefetch(desc.bi_vec.as_mut_slice())?;
 
         Ok(desc.bi_size)
@@ -135,6 +244,86 @@ impl RafsDevice {
     pub fn stop_prefetch(&self) -> StorageResult<()> {
         self.rw_layer.load().stop_prefetch()
     }
+
+    pub fn start_prefetch(&self) -> StorageResult<()> {
+        self.rw_layer.load().start_prefetch()
+    }
+
+    pub fn pause_prefetch(&self, paused: bool) -> StorageResult<()> {
+        self.rw_layer.load().pause_prefetch(paused)
+    }
+
+    pub fn tune_prefetch(
+        &self,
+        threads_count: Option<usize>,
+        bandwidth_rate: Option<u32>,
+    ) -> StorageResult<()> {
+        self.rw_layer
+            .load()
+            .tune_prefetch(threads_count, bandwidth_rate)
+    }
+
+    pub fn prefetch_progress(&self) -> StorageResult<PrefetchProgress> {
+        self.rw_layer.load().prefetch_progress()
+    }
+
+    pub fn scrub(&self, mut bios: Vec<RafsBio>) -> StorageResult<()> {
+        bios.retain(|bio| !bio.chunkinfo.is_hole());
+        self.rw_layer.load().scrub(bios)
+    }
+
+    pub fn list_blobs(&self) -> StorageResult<Vec<CachedBlobInfo>> {
+        self.rw_layer.load().list_blobs()
+    }
+
+    pub fn evict_blob(&self, blob_id: &str) -> StorageResult<()> {
+        self.rw_layer.load().evict_blob(blob_id)
+    }
+
+    pub fn evict_chunks(&self, desc: &RafsBioDesc) -> StorageResult<()> {
+        self.rw_layer.load().evict_chunks(&data_bios(&desc.bi_vec))
+    }
+
+    pub fn gc(&self, referenced: &HashSet<String>) -> StorageResult<Vec<String>> {
+        self.rw_layer.load().gc(referenced)
+    }
+
+    pub fn export_chunks(&self, bios: &[RafsBio], path: &Path) -> StorageResult<usize> {
+        self.rw_layer.load().export_chunks(&data_bios(bios), path)
+    }
+
+    pub fn import_chunks(&self, bios: &[RafsBio], path: &Path) -> StorageResult<usize> {
+        self.rw_layer.load().import_chunks(&data_bios(bios), path)
+    }
+
+    /// Check whether chunk data is already available from cache layer.
+    pub fn has(&self, cki: &dyn RafsChunkInfo) -> bool {
+        cki.is_hole() || self.rw_layer.load().has(cki)
+    }
+
+    /// Track reads of file `ino` for sequential access, returning the range of the file to
+    /// read ahead, if any.
+    pub fn readahead_enabled(&self) -> bool {
+        self.readahead.is_enabled()
+    }
+
+    pub fn readahead(
+        &self,
+        ino: u64,
+        offset: u64,
+        size: usize,
+        file_size: u64,
+    ) -> Option<(u64, u64)> {
+        self.readahead.on_read(ino, offset, size, file_size)
+    }
+}
+
+/// Bios of chunks stored in blobs, that is, without hole chunks.
+fn data_bios(bios: &[RafsBio]) -> Vec<RafsBio> {
+    bios.iter()
+        .filter(|bio| !bio.chunkinfo.is_hole())
+        .cloned()
+        .collect()
 }
 
 struct RafsBioDevice<'a> {
@@ -262,3 +451,34 @@ impl RafsBio {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_flags_compressor() {
+        let algorithms = [
+            compress::Algorithm::LZ4Block,
+            compress::Algorithm::GZip,
+            compress::Algorithm::Zstd,
+        ];
+        for algorithm in algorithms.iter() {
+            let flags = RafsChunkFlags::from_compressor(*algorithm);
+            assert!(flags.contains(RafsChunkFlags::COMPRESSED));
+            assert_eq!(flags.compressor(), Some(*algorithm));
+
+            // Flags read back from bootstrap, along with other ones.
+            let flags = RafsChunkFlags::from_bits(
+                (flags | RafsChunkFlags::INDEXED | RafsChunkFlags::ENCRYPT_AES_256_GCM).bits(),
+            )
+            .unwrap();
+            assert_eq!(flags.compressor(), Some(*algorithm));
+        }
+
+        assert!(RafsChunkFlags::from_compressor(compress::Algorithm::None).is_empty());
+        // Chunks built by older builders don't record compressor.
+        assert_eq!(RafsChunkFlags::COMPRESSED.compressor(), None);
+        assert_eq!(RafsChunkFlags::empty().compressor(), None);
+    }
+}
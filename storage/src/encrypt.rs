@@ -0,0 +1,192 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Authenticated encryption of chunks of confidential images.
+//!
+//! Chunks are encrypted one by one after compression, so that they can still be read randomly.
+//! An encrypted chunk in blob is `nonce (12 bytes) | ciphertext | tag (16 bytes)`, and its
+//! digest is authenticated as associated data, so that chunks can't be swapped with each other.
+//!
+//! The nonce is derived from the key, the digest and the data to encrypt, rather than picked
+//! randomly. The same chunk is always encrypted into the same data, which keeps images
+//! reproducible and chunks deduplicated, while nonces are never reused with different data.
+
+use std::fmt;
+use std::io::{Error, Result};
+use std::str::FromStr;
+
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{AeadInPlace, NewAead};
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::ChaCha20Poly1305;
+
+pub const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+/// Size an encrypted chunk grows by.
+pub const ENCRYPTION_OVERHEAD: usize = NONCE_SIZE + TAG_SIZE;
+
+const NONCE_KEY_CONTEXT: &str = "nydus 2020 chunk encryption nonce";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Aes256Gcm => write!(f, "aes-256-gcm"),
+            Self::ChaCha20Poly1305 => write!(f, "chacha20-poly1305"),
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "aes-256-gcm" => Ok(Self::Aes256Gcm),
+            "chacha20-poly1305" => Ok(Self::ChaCha20Poly1305),
+            _ => Err(einval!(
+                "encryption algorithm should be aes-256-gcm or chacha20-poly1305"
+            )),
+        }
+    }
+}
+
+/// Parse a key of 32 bytes of raw data or 64 hex digits.
+pub fn parse_key(raw: &[u8]) -> Result<[u8; KEY_SIZE]> {
+    let mut key = [0u8; KEY_SIZE];
+    if raw.len() == KEY_SIZE {
+        key.copy_from_slice(raw);
+        return Ok(key);
+    }
+
+    let hex = std::str::from_utf8(raw)
+        .map_err(|_| einval!("invalid encryption key"))?
+        .trim();
+    if hex.len() != KEY_SIZE * 2 {
+        return Err(einval!("encryption key must be 32 bytes or 64 hex digits"));
+    }
+    for (i, k) in key.iter_mut().enumerate() {
+        *k = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| einval!("invalid encryption key"))?;
+    }
+
+    Ok(key)
+}
+
+/// Key of encrypted chunks, which works with any of the algorithms.
+pub struct ChunkCipher {
+    aes: Aes256Gcm,
+    chacha: ChaCha20Poly1305,
+    nonce_key: [u8; KEY_SIZE],
+}
+
+impl ChunkCipher {
+    pub fn new(key: &[u8; KEY_SIZE]) -> Self {
+        // Nonces are derived with a key of their own rather than the encryption key.
+        let mut nonce_key = [0u8; KEY_SIZE];
+        blake3::derive_key(NONCE_KEY_CONTEXT, key, &mut nonce_key);
+        ChunkCipher {
+            aes: Aes256Gcm::new(GenericArray::from_slice(key)),
+            chacha: ChaCha20Poly1305::new(GenericArray::from_slice(key)),
+            nonce_key,
+        }
+    }
+
+    fn nonce(&self, data: &[u8], aad: &[u8]) -> [u8; NONCE_SIZE] {
+        let mut hasher = blake3::Hasher::new_keyed(&self.nonce_key);
+        hasher.update(&(aad.len() as u32).to_le_bytes());
+        hasher.update(aad);
+        hasher.update(data);
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce.copy_from_slice(&hasher.finalize().as_bytes()[..NONCE_SIZE]);
+        nonce
+    }
+
+    /// Encrypt `data` of a chunk, `aad` is authenticated along with it.
+    pub fn encrypt(&self, algorithm: Algorithm, data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let nonce = self.nonce(data, aad);
+        let mut buf = Vec::with_capacity(data.len() + ENCRYPTION_OVERHEAD);
+        buf.extend_from_slice(&nonce);
+        buf.extend_from_slice(data);
+        let nonce = GenericArray::from_slice(&nonce);
+        let tag = match algorithm {
+            Algorithm::Aes256Gcm => {
+                self.aes
+                    .encrypt_in_place_detached(nonce, aad, &mut buf[NONCE_SIZE..])
+            }
+            Algorithm::ChaCha20Poly1305 => {
+                self.chacha
+                    .encrypt_in_place_detached(nonce, aad, &mut buf[NONCE_SIZE..])
+            }
+        }
+        .map_err(|_| eio!("failed to encrypt chunk"))?;
+        buf.extend_from_slice(&tag);
+
+        Ok(buf)
+    }
+
+    /// Decrypt `data` of a chunk encrypted along with `aad`.
+    pub fn decrypt(&self, algorithm: Algorithm, data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        if data.len() < ENCRYPTION_OVERHEAD {
+            return Err(eio!("encrypted chunk is truncated"));
+        }
+        let (nonce, rest) = data.split_at(NONCE_SIZE);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_SIZE);
+        let mut buf = ciphertext.to_vec();
+        let nonce = GenericArray::from_slice(nonce);
+        let tag = GenericArray::from_slice(tag);
+        match algorithm {
+            Algorithm::Aes256Gcm => self
+                .aes
+                .decrypt_in_place_detached(nonce, aad, &mut buf, tag),
+            Algorithm::ChaCha20Poly1305 => self
+                .chacha
+                .decrypt_in_place_detached(nonce, aad, &mut buf, tag),
+        }
+        .map_err(|_| eio!("failed to decrypt chunk, wrong key or corrupted data"))?;
+
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_cipher() {
+        let key = parse_key(b"00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff\n")
+            .unwrap();
+        assert_eq!(key[1], 0x11);
+        assert!(parse_key(b"0011").is_err());
+        let cipher = ChunkCipher::new(&key);
+        let data = vec![7u8; 1000];
+
+        for alg in &["aes-256-gcm", "chacha20-poly1305"] {
+            let alg: Algorithm = alg.parse().unwrap();
+            let encrypted = cipher.encrypt(alg, &data, b"digest").unwrap();
+            assert_eq!(encrypted.len(), data.len() + ENCRYPTION_OVERHEAD);
+            // Deterministic, so that the same chunk is deduplicated.
+            assert_eq!(cipher.encrypt(alg, &data, b"digest").unwrap(), encrypted);
+            assert_ne!(cipher.encrypt(alg, &data, b"other").unwrap(), encrypted);
+            assert_eq!(cipher.decrypt(alg, &encrypted, b"digest").unwrap(), data);
+
+            // Swapped chunks, tampered data or wrong key must be rejected.
+            assert!(cipher.decrypt(alg, &encrypted, b"other").is_err());
+            let mut tampered = encrypted.clone();
+            tampered[NONCE_SIZE] ^= 1;
+            assert!(cipher.decrypt(alg, &tampered, b"digest").is_err());
+            let other = ChunkCipher::new(&[1u8; KEY_SIZE]);
+            assert!(other.decrypt(alg, &encrypted, b"digest").is_err());
+            assert!(cipher.decrypt(alg, &encrypted[..20], b"digest").is_err());
+        }
+        assert!("aes-128-gcm".parse::<Algorithm>().is_err());
+    }
+}
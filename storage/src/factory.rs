@@ -2,6 +2,7 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::Result as IOResult;
 use std::sync::Arc;
@@ -22,6 +23,8 @@ pub struct Config {
     pub backend: BackendConfig,
     #[serde(default)]
     pub cache: CacheConfig,
+    #[serde(default)]
+    pub encryption: EncryptionConfig,
 }
 
 #[derive(Default, Clone, Deserialize)]
@@ -65,6 +68,22 @@ pub struct CacheConfig {
     pub cache_config: Value,
     #[serde(skip_serializing, skip_deserializing)]
     pub prefetch_worker: PrefetchWorker,
+    #[serde(skip_serializing, skip_deserializing)]
+    pub encryption: EncryptionConfig,
+    /// Chunk size of the image, which is from bootstrap rather than configuration.
+    #[serde(skip_serializing, skip_deserializing)]
+    pub chunk_size: u32,
+}
+
+/// Keys of encrypted images, referred by key ids in blob table.
+#[derive(Default, Clone, Deserialize)]
+pub struct EncryptionConfig {
+    /// Keys indexed by key id, each is 64 hex digits.
+    #[serde(default)]
+    pub keys: HashMap<String, String>,
+    /// Command printing the key of the key id in env `NYDUS_KEY_ID`, for keys not in `keys`.
+    #[serde(default)]
+    pub key_command: String,
 }
 
 pub fn new_backend(
@@ -92,16 +111,18 @@ pub fn new_rw_layer(
     id: &str,
 ) -> IOResult<Arc<dyn RafsCache + Send + Sync>> {
     let backend = new_backend(config.backend, id)?;
-    match config.cache.cache_type.as_str() {
+    let mut cache_config = config.cache;
+    cache_config.encryption = config.encryption;
+    match cache_config.cache_type.as_str() {
         "blobcache" => Ok(blobcache::new(
-            config.cache,
+            cache_config,
             backend,
             compressor,
             digester,
             id,
         )?),
         _ => Ok(Arc::new(dummycache::new(
-            config.cache,
+            cache_config,
             backend,
             compressor,
             digester,
@@ -2,6 +2,8 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+#[macro_use]
+extern crate lazy_static;
 #[macro_use]
 extern crate log;
 #[macro_use]
@@ -15,7 +17,9 @@ pub mod backend;
 pub mod cache;
 pub mod compress;
 pub mod device;
+pub mod encrypt;
 pub mod factory;
+pub mod readahead;
 pub mod utils;
 
 // A helper to impl RafsChunkInfo for upper layers like Rafs different metadata mode.
@@ -28,12 +32,19 @@ macro_rules! impl_getter {
     };
 }
 
+#[cfg(test)]
+mod test;
+
 // FIXME: u64 for this constant is extremely large, which is unnecessary as `u32` can represent block size 4GB.
 pub const RAFS_DEFAULT_BLOCK_SIZE: u64 = 1024 * 1024;
+/// Range of chunk size of images, which must also be a power of two.
+pub const RAFS_MIN_BLOCK_SIZE: u64 = 64 * 1024;
+pub const RAFS_MAX_BLOCK_SIZE: u64 = 4 * 1024 * 1024;
 
 #[derive(Debug)]
 pub enum StorageError {
     Unsupported,
+    Io(std::io::Error),
 }
 
 pub type StorageResult<T> = std::result::Result<T, StorageError>;
@@ -0,0 +1,276 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Readahead of files read sequentially, in the way of kernel page cache readahead.
+//!
+//! Each file read sequentially has a readahead window, which starts from 128KB. Once the
+//! reader consumes half of the data read ahead, the next window is read ahead and the window
+//! doubles, up to the maximum. A random read resets the window. Data read ahead but not yet
+//! consumed by all files is bounded by a budget. Once it's exhausted, files idle for a while
+//! give their readahead data back to the budget, and the window halves if it's still short.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// Initial size and lower limit of readahead window.
+const MIN_WINDOW_SIZE: u64 = 128 << 10;
+/// Files tracked at most, the least recently read one is forgotten beyond that.
+const MAX_TRACKED_FILES: usize = 1024;
+/// Data read ahead for files not read for this long is taken as wasted.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(1);
+
+struct FileReadahead {
+    // End of the last read, where the next sequential read starts.
+    next: u64,
+    window: u64,
+    // End of data read ahead.
+    ra_end: u64,
+    last_read: Instant,
+    // No longer tracked, the reader holding it should look up the file again.
+    forgotten: bool,
+}
+
+impl FileReadahead {
+    fn new(now: Instant) -> Self {
+        FileReadahead {
+            next: 0,
+            window: 0,
+            ra_end: 0,
+            last_read: now,
+            forgotten: false,
+        }
+    }
+
+    /// Data read ahead but not yet consumed.
+    fn ahead(&self) -> u64 {
+        self.ra_end.saturating_sub(self.next)
+    }
+}
+
+/// Readahead state is kept per file, so reads of different files only share the lock of
+/// the file map for reading, which is locked for writing only to track or forget files.
+pub struct Readahead {
+    max_window: u64,
+    budget: u64,
+    files: RwLock<HashMap<u64, Arc<Mutex<FileReadahead>>>>,
+    // Data read ahead but not yet consumed by all files.
+    outstanding: AtomicU64,
+}
+
+impl Readahead {
+    /// Readahead is disabled if `max_window` is 0.
+    pub fn new(max_window: u64, budget: u64) -> Self {
+        Readahead {
+            max_window: if max_window == 0 {
+                0
+            } else {
+                cmp::max(max_window, MIN_WINDOW_SIZE)
+            },
+            budget,
+            files: RwLock::new(HashMap::new()),
+            outstanding: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_window != 0
+    }
+
+    fn file(&self, ino: u64, now: Instant) -> Arc<Mutex<FileReadahead>> {
+        if let Some(file) = self.files.read().unwrap().get(&ino) {
+            return file.clone();
+        }
+
+        let mut files = self.files.write().unwrap();
+        if !files.contains_key(&ino) && files.len() >= MAX_TRACKED_FILES {
+            self.forget_lru(&mut files);
+        }
+        files
+            .entry(ino)
+            .or_insert_with(|| Arc::new(Mutex::new(FileReadahead::new(now))))
+            .clone()
+    }
+
+    /// Stop tracking `file` and give its readahead data back to the budget.
+    fn forget(&self, file: &mut FileReadahead) {
+        self.outstanding.fetch_sub(file.ahead(), Ordering::Relaxed);
+        file.forgotten = true;
+    }
+
+    // Files being read are locked, they are neither the least recently read nor idle.
+    fn forget_lru(&self, files: &mut HashMap<u64, Arc<Mutex<FileReadahead>>>) {
+        if let Some(ino) = files
+            .iter()
+            .filter_map(|(ino, f)| f.try_lock().ok().map(|f| (*ino, f.last_read)))
+            .min_by_key(|(_, last_read)| *last_read)
+            .map(|(ino, _)| ino)
+        {
+            let forgotten = match files[&ino].try_lock() {
+                Ok(mut file) => {
+                    self.forget(&mut file);
+                    true
+                }
+                Err(_) => false,
+            };
+            if forgotten {
+                files.remove(&ino);
+            }
+        }
+    }
+
+    fn forget_idle(&self, now: Instant) {
+        self.files
+            .write()
+            .unwrap()
+            .retain(|_, f| match f.try_lock() {
+                Ok(mut f) if now.duration_since(f.last_read) > IDLE_TIMEOUT => {
+                    self.forget(&mut f);
+                    false
+                }
+                _ => true,
+            });
+    }
+
+    /// Record a read of `size` bytes at `offset` of file `ino` whose size is `file_size`.
+    /// Returns the range to read ahead, if any.
+    pub fn on_read(
+        &self,
+        ino: u64,
+        offset: u64,
+        size: usize,
+        file_size: u64,
+    ) -> Option<(u64, u64)> {
+        if !self.is_enabled() || size == 0 {
+            return None;
+        }
+
+        let now = Instant::now();
+        loop {
+            let file = self.file(ino, now);
+            let mut file = file.lock().unwrap();
+            if !file.forgotten {
+                return self.file_read(&mut file, offset, size, file_size, now);
+            }
+        }
+    }
+
+    fn file_read(
+        &self,
+        file: &mut FileReadahead,
+        offset: u64,
+        size: usize,
+        file_size: u64,
+        now: Instant,
+    ) -> Option<(u64, u64)> {
+        file.last_read = now;
+
+        let end = cmp::min(offset + size as u64, file_size);
+        let old_ahead = file.ahead();
+        // A file read from its beginning is taken as sequential read as well.
+        let sequential = offset == file.next;
+        file.next = end;
+        if !sequential {
+            file.window = 0;
+            file.ra_end = 0;
+            self.outstanding.fetch_sub(old_ahead, Ordering::Relaxed);
+            return None;
+        }
+        self.outstanding
+            .fetch_sub(old_ahead.saturating_sub(file.ahead()), Ordering::Relaxed);
+
+        if file.window == 0 {
+            file.window = cmp::min(cmp::max(size as u64 * 4, MIN_WINDOW_SIZE), self.max_window);
+        } else if file.ahead() >= file.window / 2 {
+            // Less than half of data read ahead is consumed.
+            return None;
+        } else if file.ra_end != 0 {
+            // The reader catches up with data read ahead, so read ahead more.
+            file.window = cmp::min(file.window * 2, self.max_window);
+        }
+
+        let start = cmp::max(file.ra_end, end);
+        let stop = cmp::min(end + file.window, file_size);
+        if start >= stop {
+            return None;
+        }
+        let mut len = stop - start;
+        if self.outstanding.load(Ordering::Relaxed) + len > self.budget {
+            // The file itself is locked, so it's still tracked.
+            self.forget_idle(now);
+        }
+        // Concurrent readers may exceed the budget a bit, which is fine for readahead.
+        let outstanding = self.outstanding.load(Ordering::Relaxed);
+        if outstanding + len > self.budget {
+            file.window = cmp::max(file.window / 2, MIN_WINDOW_SIZE);
+            len = cmp::min(len, self.budget.saturating_sub(outstanding));
+            if len == 0 {
+                return None;
+            }
+        }
+        file.ra_end = start + len;
+        self.outstanding.fetch_add(len, Ordering::Relaxed);
+
+        Some((start, len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_readahead_window() {
+        let ra = Readahead::new(1 << 20, 4 << 20);
+        let size = 16 << 20;
+
+        // Initial window starts from the end of the first read.
+        assert_eq!(ra.on_read(1, 0, 4096, size), Some((4096, 128 << 10)));
+        // Nothing is read ahead until half of the window is consumed.
+        assert_eq!(ra.on_read(1, 4096, 32 << 10, size), None);
+        let (start, len) = ra.on_read(1, 36 << 10, 64 << 10, size).unwrap();
+        assert_eq!(start, (128 << 10) + 4096);
+        // The window doubles.
+        assert_eq!(start + len, (100 << 10) + (256 << 10));
+
+        // The window grows up to the maximum.
+        let mut offset = 100 << 10;
+        let mut window = 0;
+        while offset < (8 << 20) {
+            if let Some((start, len)) = ra.on_read(1, offset, 128 << 10, size) {
+                window = start + len - offset - (128 << 10);
+            }
+            offset += 128 << 10;
+        }
+        assert_eq!(window, 1 << 20);
+
+        // Random read resets the window.
+        assert_eq!(ra.on_read(1, 0, 4096, size), None);
+        assert_eq!(ra.outstanding.load(Ordering::Relaxed), 0);
+        assert_eq!(ra.on_read(1, 4096, 4096, size), Some((8192, 128 << 10)));
+
+        // Readahead doesn't go beyond end of file.
+        assert_eq!(ra.on_read(2, 0, 4096, 8192), Some((4096, 4096)));
+        assert_eq!(ra.on_read(2, 4096, 4096, 8192), None);
+        assert_eq!(ra.outstanding.load(Ordering::Relaxed), 128 << 10);
+
+        // Budget is shared by all files.
+        let mut total = 128 << 10;
+        for ino in 3..100 {
+            let mut offset = 0;
+            while offset < (4 << 20) {
+                if let Some((_, len)) = ra.on_read(ino, offset, 128 << 10, size) {
+                    total += len;
+                }
+                offset += 128 << 10;
+            }
+        }
+        assert!(ra.outstanding.load(Ordering::Relaxed) <= 4 << 20);
+        assert!(total > 4 << 20);
+
+        assert!(!Readahead::new(0, 4 << 20).is_enabled());
+    }
+}
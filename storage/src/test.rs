@@ -0,0 +1,50 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Helpers shared by unit tests of storage.
+
+use nydus_utils::digest::RafsDigest;
+
+use crate::device::{RafsChunkFlags, RafsChunkInfo};
+
+#[derive(Default, Copy, Clone)]
+pub(crate) struct MockChunkInfo {
+    pub block_id: RafsDigest,
+    pub blob_index: u32,
+    pub flags: RafsChunkFlags,
+    pub compress_size: u32,
+    pub decompress_size: u32,
+    pub compress_offset: u64,
+    pub decompress_offset: u64,
+    pub file_offset: u64,
+    pub index: u32,
+    pub batch_offset: u32,
+}
+
+impl MockChunkInfo {
+    pub fn new() -> Self {
+        MockChunkInfo::default()
+    }
+}
+
+impl RafsChunkInfo for MockChunkInfo {
+    fn block_id(&self) -> &RafsDigest {
+        &self.block_id
+    }
+    fn is_compressed(&self) -> bool {
+        self.flags.contains(RafsChunkFlags::COMPRESSED)
+    }
+    fn is_hole(&self) -> bool {
+        self.flags.contains(RafsChunkFlags::HOLECHUNK)
+    }
+    impl_getter!(blob_index, blob_index, u32);
+    impl_getter!(index, index, u32);
+    impl_getter!(compress_offset, compress_offset, u64);
+    impl_getter!(compress_size, compress_size, u32);
+    impl_getter!(decompress_offset, decompress_offset, u64);
+    impl_getter!(decompress_size, decompress_size, u32);
+    impl_getter!(file_offset, file_offset, u64);
+    impl_getter!(batch_offset, batch_offset, u32);
+    impl_getter!(flags, flags, RafsChunkFlags);
+}
@@ -2,9 +2,12 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use std::alloc::{alloc, dealloc, Layout};
 use std::io::{ErrorKind, Result};
+use std::ops::{Deref, DerefMut};
 use std::os::unix::io::RawFd;
-use std::slice::from_raw_parts_mut;
+use std::ptr::NonNull;
+use std::slice::{from_raw_parts, from_raw_parts_mut};
 
 use libc::off64_t;
 use nix::sys::uio::{preadv, IoVec};
@@ -108,6 +111,63 @@ pub fn alloc_buf(size: usize) -> Vec<u8> {
     buf
 }
 
+/// Alignment of buffers, file offsets and sizes of direct IO.
+pub const DIRECT_IO_ALIGNMENT: usize = 4096;
+
+/// A buffer aligned for direct IO, which is not zeroed either.
+pub struct AlignedBuf {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// It owns the memory exclusively, just like Vec<u8>.
+unsafe impl Send for AlignedBuf {}
+unsafe impl Sync for AlignedBuf {}
+
+impl AlignedBuf {
+    /// Allocate a buffer of `size` rounded up to `DIRECT_IO_ALIGNMENT`.
+    pub fn new(size: usize) -> Self {
+        let len = std::cmp::max(align_up(size as u64) as usize, DIRECT_IO_ALIGNMENT);
+        let ptr = unsafe { alloc(Self::layout(len)) };
+        AlignedBuf {
+            ptr: NonNull::new(ptr).expect("failed to allocate aligned buffer"),
+            len,
+        }
+    }
+
+    fn layout(len: usize) -> Layout {
+        Layout::from_size_align(len, DIRECT_IO_ALIGNMENT).unwrap()
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr.as_ptr(), Self::layout(self.len)) }
+    }
+}
+
+pub fn align_down(offset: u64) -> u64 {
+    offset & !(DIRECT_IO_ALIGNMENT as u64 - 1)
+}
+
+pub fn align_up(offset: u64) -> u64 {
+    align_down(offset + DIRECT_IO_ALIGNMENT as u64 - 1)
+}
+
 /// Check hash of data matches provided one
 pub fn digest_check(data: &[u8], digest: &RafsDigest, digester: digest::Algorithm) -> bool {
     digest == &RafsDigest::from_buf(data, digester)
@@ -8,9 +8,10 @@
 use std::fmt;
 
 use sha2::digest::Digest;
-use sha2::Sha256;
+use sha2::{Sha256, Sha512Trunc256};
 use std::io::Error;
 use std::str::FromStr;
+use xxhash_rust::xxh3::{xxh3_128, Xxh3};
 
 pub const RAFS_DIGEST_LENGTH: usize = 32;
 type DigestData = [u8; RAFS_DIGEST_LENGTH];
@@ -19,6 +20,11 @@ type DigestData = [u8; RAFS_DIGEST_LENGTH];
 pub enum Algorithm {
     Blake3,
     Sha256,
+    /// SHA-512 truncated to 256 bits, as required by some compliance environments.
+    Sha512_256,
+    /// Fast non-cryptographic checksum, only for trusted images where collision resistance
+    /// doesn't matter. The 128-bit hash is padded with zeros to fill a digest.
+    Xxh3_128,
 }
 
 impl fmt::Display for Algorithm {
@@ -34,7 +40,11 @@ impl FromStr for Algorithm {
         match s {
             "blake3" => Ok(Self::Blake3),
             "sha256" => Ok(Self::Sha256),
-            _ => Err(einval!("digest algorithm should be blake3 or sha256")),
+            "sha512-256" => Ok(Self::Sha512_256),
+            "xxh3-128" => Ok(Self::Xxh3_128),
+            _ => Err(einval!(
+                "digest algorithm should be blake3, sha256, sha512-256 or xxh3-128"
+            )),
         }
     }
 }
@@ -66,6 +76,26 @@ impl DigestHasher for Sha256 {
     }
 }
 
+impl DigestHasher for Sha512Trunc256 {
+    fn digest_update(&mut self, buf: &[u8]) {
+        self.update(buf);
+    }
+    fn digest_finalize(&mut self) -> RafsDigest {
+        RafsDigest {
+            data: self.clone().finalize().into(),
+        }
+    }
+}
+
+impl DigestHasher for Xxh3 {
+    fn digest_update(&mut self, buf: &[u8]) {
+        self.update(buf);
+    }
+    fn digest_finalize(&mut self) -> RafsDigest {
+        RafsDigest::from_xxh3_128(self.digest128())
+    }
+}
+
 #[derive(Clone, Copy, Hash, PartialEq, Eq, Debug)]
 pub struct RafsDigest {
     pub data: DigestData,
@@ -80,6 +110,12 @@ impl RafsDigest {
                 hasher.update(buf);
                 hasher.finalize().into()
             }
+            Algorithm::Sha512_256 => {
+                let mut hasher = Sha512Trunc256::new();
+                hasher.update(buf);
+                hasher.finalize().into()
+            }
+            Algorithm::Xxh3_128 => return Self::from_xxh3_128(xxh3_128(buf)),
         };
 
         RafsDigest { data }
@@ -88,8 +124,16 @@ impl RafsDigest {
         match algorithm {
             Algorithm::Blake3 => Box::new(blake3::Hasher::new()) as Box<dyn DigestHasher>,
             Algorithm::Sha256 => Box::new(Sha256::new()) as Box<dyn DigestHasher>,
+            Algorithm::Sha512_256 => Box::new(Sha512Trunc256::new()) as Box<dyn DigestHasher>,
+            Algorithm::Xxh3_128 => Box::new(Xxh3::new()) as Box<dyn DigestHasher>,
         }
     }
+
+    fn from_xxh3_128(hash: u128) -> Self {
+        let mut data = [0u8; RAFS_DIGEST_LENGTH];
+        data[..16].copy_from_slice(&hash.to_be_bytes());
+        RafsDigest { data }
+    }
 }
 
 impl Default for RafsDigest {
@@ -126,3 +170,31 @@ impl Into<String> for RafsDigest {
         format!("{}", self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_digest_algorithms() {
+        let buf = b"nydus digest test data";
+        for alg in &["blake3", "sha256", "sha512-256", "xxh3-128"] {
+            let alg: Algorithm = alg.parse().unwrap();
+            let digest = RafsDigest::from_buf(buf, alg);
+            let mut hasher = RafsDigest::hasher(alg);
+            hasher.digest_update(&buf[..5]);
+            hasher.digest_update(&buf[5..]);
+            assert_eq!(hasher.digest_finalize(), digest);
+            assert_ne!(RafsDigest::from_buf(b"other data", alg), digest);
+        }
+        assert!("md5".parse::<Algorithm>().is_err());
+
+        assert_eq!(
+            format!("{}", RafsDigest::from_buf(b"abc", Algorithm::Sha512_256)),
+            "53048e2681941ef99b2e29b76b4c7dabe4c2d0c634fc6d46e0e2f13107e7af23"
+        );
+        let digest = RafsDigest::from_buf(b"abc", Algorithm::Xxh3_128);
+        assert_eq!(&digest.data[..16], &xxh3_128(b"abc").to_be_bytes());
+        assert_eq!(&digest.data[16..], &[0u8; 16]);
+    }
+}
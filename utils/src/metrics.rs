@@ -5,8 +5,11 @@
 // Rafs fop stats accounting and exporting.
 
 use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io;
 use std::ops::{Deref, Drop};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process;
 use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
 use std::time::SystemTime;
@@ -41,6 +44,7 @@ pub enum StatsFop {
 pub enum IoStatsError {
     NoCounter,
     Serialize(SerdeError),
+    Persist(io::Error),
 }
 
 type IoStatsResult<T> = Result<T, IoStatsError>;
@@ -139,6 +143,9 @@ pub struct GlobalIOStats {
     file_counters: RwLock<HashMap<Inode, Arc<InodeIOStats>>>,
     #[serde(skip_serializing, skip_deserializing)]
     access_patterns: RwLock<HashMap<Inode, Arc<AccessPattern>>>,
+    // Sequence number of the latest file accessed for the first time.
+    #[serde(skip_serializing, skip_deserializing)]
+    access_seq: AtomicUsize,
 }
 
 #[derive(Default, Debug, Serialize, Deserialize)]
@@ -172,6 +179,41 @@ pub struct AccessPattern {
     nr_read: AtomicUsize,
     /// In unit of seconds.
     first_access_time: AtomicUsize,
+    /// Order of the first access among all files, starting from 1.
+    #[serde(skip_serializing)]
+    first_access_seq: AtomicUsize,
+}
+
+/// Files in order of their first access during a run. It can be persisted and replayed
+/// as prefetch hints by a later run of the same image, regardless of storage backend.
+#[derive(Default, Debug, Serialize, Deserialize)]
+pub struct AccessTrace {
+    pub files: Vec<PathBuf>,
+}
+
+impl AccessTrace {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        serde_json::from_reader(file).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Write the trace to a temporary file first, so a trace being replayed is never
+    /// seen half written. Each persist has a temporary file of its own, so concurrent
+    /// ones to the same path don't write into the same file.
+    pub fn persist(&self, path: &Path) -> io::Result<()> {
+        static PERSIST_SEQ: AtomicUsize = AtomicUsize::new(0);
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(format!(
+            ".{}.{}.tmp",
+            process::id(),
+            PERSIST_SEQ.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = File::create(&tmp)?;
+        serde_json::to_writer(&file, self)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    }
 }
 
 pub trait InodeStatsCounter {
@@ -302,6 +344,17 @@ impl GlobalIOStats {
             match records.get(&ino) {
                 Some(r) => {
                     r.nr_read.fetch_add(1, Ordering::Relaxed);
+                    if r.first_access_seq.load(Ordering::Relaxed) == 0 {
+                        // Concurrent first reads of the file may both get here, only the
+                        // first one settles its order, leaving a gap in the sequence.
+                        let seq = self.access_seq.fetch_add(1, Ordering::Relaxed) + 1;
+                        let _ = r.first_access_seq.compare_exchange(
+                            0,
+                            seq,
+                            Ordering::Relaxed,
+                            Ordering::Relaxed,
+                        );
+                    }
                     if r.first_access_time.load(Ordering::Relaxed) == 0 {
                         // FIXME: Conversion from `u64` to `usize` on 32-bit platform
                         // is not reliable. Fix this by using AtomicU64 instead.
@@ -406,6 +459,21 @@ impl GlobalIOStats {
     fn export_global_stats(&self) -> Result<String, IoStatsError> {
         serde_json::to_string(self).map_err(IoStatsError::Serialize)
     }
+
+    /// Get files ever read in order of their first access.
+    pub fn access_trace(&self) -> AccessTrace {
+        let records = self.access_patterns.read().expect("Not poisoned lock");
+        let mut accessed = records
+            .values()
+            .filter(|r| r.nr_read.load(Ordering::Relaxed) != 0)
+            .map(|r| (r.first_access_seq.load(Ordering::Relaxed), &r.file_path))
+            .collect::<Vec<(usize, &PathBuf)>>();
+        accessed.sort_by_key(|(seq, _)| *seq);
+
+        AccessTrace {
+            files: accessed.into_iter().map(|(_, p)| p.clone()).collect(),
+        }
+    }
 }
 
 /// If you need FOP recorder count file system operations.
@@ -487,6 +555,26 @@ pub fn export_files_access_pattern(name: &Option<String>) -> Result<String, IoSt
     }
 }
 
+/// Persist access trace of rafs `name` to file `path`, access pattern recording must be
+/// enabled for the rafs.
+pub fn persist_access_trace(name: &Option<String>, path: &Path) -> Result<(), IoStatsError> {
+    let ios_set = IOS_SET.read().unwrap();
+    let ios = match name {
+        Some(k) => ios_set.get(k).ok_or(IoStatsError::NoCounter)?,
+        None => {
+            if ios_set.len() == 1 {
+                ios_set.values().next().ok_or(IoStatsError::NoCounter)?
+            } else {
+                return Err(IoStatsError::NoCounter);
+            }
+        }
+    };
+
+    ios.access_trace()
+        .persist(path)
+        .map_err(IoStatsError::Persist)
+}
+
 pub fn export_global_stats(name: &Option<String>) -> Result<String, IoStatsError> {
     // With only one rafs instance, we allow caller to ask for an unknown ios name.
     let ios_set = IOS_SET.read().unwrap();
@@ -593,6 +681,10 @@ pub struct BackendMetrics {
     read_cumulative_latency_total: BasicMetric,
     // Categorize metrics as per their latency and request size
     read_latency_dist: [[BasicMetric; READ_LATENCY_RANGE_MAX]; BLOCK_READ_COUNT_MAX],
+    // Cumulative count and amount of reads saved by waiting for in-flight reads of the
+    // same chunk.
+    read_dedup_count: BasicMetric,
+    read_dedup_amount: BasicMetric,
 }
 
 impl Metric for BasicMetric {
@@ -653,6 +745,12 @@ impl BackendMetrics {
         }
     }
 
+    /// A read of `size` bytes is saved as the data is fetched by another reader.
+    pub fn dedup(&self, size: usize) {
+        self.read_dedup_count.inc();
+        self.read_dedup_amount.add(size);
+    }
+
     fn export_metrics(&self) -> IoStatsResult<String> {
         serde_json::to_string(self).map_err(IoStatsError::Serialize)
     }
@@ -670,8 +768,9 @@ pub struct BlobcacheMetrics {
     pub partial_hits: BasicMetric,
     pub whole_hits: BasicMetric,
     pub total: BasicMetric,
-    // Scale of blobcache. Blobcache does not evict entries.
-    pub entries_count: BasicMetric,
+    // Scale of blobcache index in unit of chunks, which grows with size of cached blobs
+    // rather than number of accessed chunks.
+    pub entries_count: AtomicUsize,
     // In unit of Bytes
     pub prefetch_data_amount: BasicMetric,
     pub prefetch_workers: AtomicUsize,
@@ -681,6 +780,35 @@ pub struct BlobcacheMetrics {
     pub prefetch_total_size: BasicMetric,
     pub prefetch_mr_count: BasicMetric,
     pub prefetch_unmerged_chunks: BasicMetric,
+    // Times that user IO promotes a queued prefetch request.
+    pub prefetch_promoted: BasicMetric,
+    // Times that user IO waits for prefetch workers rather than fetching the chunk itself.
+    pub prefetch_waited: BasicMetric,
+    // Whether the scrubber is verifying cached chunks right now.
+    pub scrub_running: AtomicBool,
+    // Progress of the current or the latest scrub round in unit of chunks.
+    pub scrub_total_chunks: AtomicUsize,
+    pub scrub_checked_chunks: AtomicUsize,
+    pub scrub_rounds: BasicMetric,
+    // Chunks found corrupted and evicted by the scrubber.
+    pub scrub_corrupted_chunks: BasicMetric,
+    // Bytes of chunks cached by this blobcache, which are charged against its quota. Cache
+    // files left by previous runs are not counted.
+    pub quota_usage: AtomicUsize,
+    // In unit of Bytes and Zero means unlimited.
+    pub quota_soft_limit: AtomicUsize,
+    pub quota_hard_limit: AtomicUsize,
+    // Chunks evicted to keep usage within quota or capacity of work_dir.
+    pub quota_evicted_chunks: BasicMetric,
+    // In unit of Bytes. Cache file IO done with O_DIRECT doesn't take page cache, so
+    // cached chunks don't take memory twice.
+    pub direct_io_amount: BasicMetric,
+    pub buffered_io_amount: BasicMetric,
+    // Together with above two fields, we can figure out throughput of cache files.
+    pub cache_io_latency_us: BasicMetric,
+    // Batches of cache file IO submitted via io_uring and number of IOs in them.
+    pub uring_batches: BasicMetric,
+    pub uring_ios: BasicMetric,
 }
 
 impl BlobcacheMetrics {
@@ -739,4 +867,32 @@ mod tests {
         g.global_update(StatsFop::Read, 2015520, true);
         assert_eq!(g.block_count_read[3].load(Ordering::Relaxed), 2);
     }
+    #[test]
+    fn test_access_trace() {
+        let g = GlobalIOStats::default();
+        g.init();
+        g.toggle_access_pattern(true);
+        for ino in 1..4 {
+            g.new_file_counter(ino, |i| PathBuf::from(format!("/file{}", i)));
+        }
+
+        g.file_stats_update(2, StatsFop::Read, 4096, true);
+        g.file_stats_update(3, StatsFop::Getattr, 0, true);
+        g.file_stats_update(1, StatsFop::Read, 4096, true);
+        g.file_stats_update(2, StatsFop::Read, 4096, true);
+
+        let trace = g.access_trace();
+        assert_eq!(
+            trace.files,
+            vec![PathBuf::from("/file2"), PathBuf::from("/file1")]
+        );
+
+        let dir = vmm_sys_util::tempdir::TempDir::new().unwrap();
+        let path = dir.as_path().join("trace");
+        trace.persist(&path).unwrap();
+        trace.persist(&path).unwrap();
+        assert_eq!(AccessTrace::load(&path).unwrap().files, trace.files);
+        // Temporary files are all renamed.
+        assert_eq!(fs::read_dir(dir.as_path()).unwrap().count(), 1);
+    }
 }